@@ -0,0 +1,829 @@
+use std::fs;
+use std::fs::File;
+use std::io::{BufReader, BufWriter, Error, ErrorKind, Read, Write};
+use std::os::unix::fs::{MetadataExt, PermissionsExt};
+use std::path::{Component, Path, PathBuf};
+use std::process::{Command, Stdio};
+
+use colored::Colorize;
+use flate2::Compression;
+use globset::{Glob, GlobSet, GlobSetBuilder};
+use sha2::{Digest, Sha256};
+use zip::write::SimpleFileOptions;
+use zip::CompressionMethod;
+
+use crate::backup::manifest::{ManifestFile, MANIFEST_SUFFIX};
+use crate::backup::BackupFormat;
+
+/// Writes to the inner writer while computing the sha256 of everything written.
+pub(crate) struct HashingWriter<W: Write> {
+    inner: W,
+    hasher: Sha256,
+}
+
+impl<W: Write> HashingWriter<W> {
+    pub fn new(inner: W) -> Self {
+        HashingWriter {
+            inner,
+            hasher: Sha256::new(),
+        }
+    }
+
+    /// Flushes the inner writer and returns it with the hex encoded sha256.
+    pub fn finish(mut self) -> Result<(W, String), Error> {
+        self.inner.flush()?;
+        Ok((self.inner, format!("{:x}", self.hasher.finalize())))
+    }
+}
+
+impl<W: Write> Write for HashingWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let written = self.inner.write(buf)?;
+        self.hasher.update(&buf[..written]);
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// Reads from the inner reader while computing the sha256 and size of everything read.
+pub(crate) struct HashingReader<R: Read> {
+    inner: R,
+    hasher: Sha256,
+    size: u64,
+}
+
+impl<R: Read> HashingReader<R> {
+    pub fn new(inner: R) -> Self {
+        HashingReader {
+            inner,
+            hasher: Sha256::new(),
+            size: 0,
+        }
+    }
+
+    /// Returns how many bytes were read and their hex encoded sha256.
+    pub fn finish(self) -> (u64, String) {
+        (self.size, format!("{:x}", self.hasher.finalize()))
+    }
+}
+
+impl<R: Read> Read for HashingReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let read = self.inner.read(buf)?;
+        self.hasher.update(&buf[..read]);
+        self.size += read as u64;
+        Ok(read)
+    }
+}
+
+/// Computes the sha256 of a file without shelling out to sha256sum.
+pub(crate) fn sha256_of_file(path: &Path) -> Result<String, Error> {
+    let mut file = File::open(path)?;
+    let mut hasher = HashingWriter::new(std::io::sink());
+    std::io::copy(&mut file, &mut hasher)?;
+    Ok(hasher.finish()?.1)
+}
+
+/// Glob based exclusion, patterns are separated by a : (colon)
+/// Example: "logs:plugins/dynmap:*.log"
+///
+/// A pattern that matches a directory excludes everything inside it, and patterns
+/// aren't anchored so they can match at any depth (like tar does).
+pub(crate) struct ExcludeMatcher {
+    set: GlobSet,
+}
+
+impl ExcludeMatcher {
+    pub fn new(patterns: Option<&str>) -> Result<Self, Error> {
+        let mut builder = GlobSetBuilder::new();
+
+        if let Some(patterns) = patterns {
+            for pattern in patterns.split(':') {
+                let pattern = pattern.trim().trim_start_matches("./").trim_end_matches('/');
+                if pattern.is_empty() {
+                    continue;
+                }
+
+                let variants = [
+                    pattern.to_string(),
+                    format!("{}/**", pattern),
+                    format!("**/{}", pattern),
+                    format!("**/{}/**", pattern),
+                ];
+
+                for variant in variants {
+                    let glob = Glob::new(&variant)
+                        .map_err(|e| Error::new(ErrorKind::InvalidInput, format!("Invalid exclude pattern {}: {}", pattern, e)))?;
+                    builder.add(glob);
+                }
+            }
+        }
+
+        let set = builder.build()
+            .map_err(|e| Error::new(ErrorKind::InvalidInput, format!("Invalid exclude patterns: {}", e)))?;
+        Ok(ExcludeMatcher { set })
+    }
+
+    pub fn is_excluded(&self, archive_name: &Path) -> bool {
+        self.set.is_match(archive_name)
+    }
+}
+
+pub(crate) struct ArchiveEntry {
+    pub disk_path: PathBuf,
+    pub archive_name: PathBuf,
+}
+
+/// The name a path gets inside the archive, leading / . and .. are stripped like tar does.
+pub(crate) fn archive_name(path: &Path) -> PathBuf {
+    path.components()
+        .filter(|component| matches!(component, Component::Normal(_)))
+        .collect()
+}
+
+/// Walks the sources (separated by a : (colon)) and returns every file, directory and symlink
+/// that isn't excluded. Excluded directories are not walked into.
+pub(crate) fn collect_entries(sources: &str, excludes: &ExcludeMatcher) -> Result<Vec<ArchiveEntry>, Error> {
+    collect_entries_and_excluded(sources, excludes).map(|(entries, _)| entries)
+}
+
+/// Like `collect_entries`, also returns the archive names of the excluded files and folders (their contents aren't listed).
+pub(crate) fn collect_entries_and_excluded(sources: &str, excludes: &ExcludeMatcher) -> Result<(Vec<ArchiveEntry>, Vec<PathBuf>), Error> {
+    let mut entries = Vec::new();
+    let mut excluded = Vec::new();
+
+    for source in sources.split(':') {
+        let source_path = PathBuf::from(source);
+        if fs::symlink_metadata(&source_path).is_err() {
+            return Err(Error::new(ErrorKind::NotFound, format!("{} does not exist", source_path.display())));
+        }
+
+        collect_entries_recursive(&source_path, excludes, &mut entries, &mut excluded)?;
+    }
+
+    Ok((entries, excluded))
+}
+
+fn collect_entries_recursive(path: &Path, excludes: &ExcludeMatcher, entries: &mut Vec<ArchiveEntry>, excluded: &mut Vec<PathBuf>) -> Result<(), Error> {
+    let name = archive_name(path);
+    if !name.as_os_str().is_empty() {
+        if excludes.is_excluded(&name) {
+            excluded.push(name);
+            return Ok(());
+        }
+
+        entries.push(ArchiveEntry {
+            disk_path: path.to_path_buf(),
+            archive_name: name,
+        });
+    }
+
+    let metadata = fs::symlink_metadata(path)?;
+    if metadata.is_dir() {
+        let mut children = fs::read_dir(path)?
+            .map(|entry| entry.map(|entry| entry.path()))
+            .collect::<Result<Vec<_>, Error>>()?;
+        children.sort();
+
+        for child in children {
+            collect_entries_recursive(&child, excludes, entries, excluded)?;
+        }
+    }
+
+    Ok(())
+}
+
+/// The result of `write_archive`.
+pub(crate) struct WrittenArchive {
+    pub sha256: String,
+    /// Every regular file in the archive, for the manifest.
+    pub files: Vec<ManifestFile>,
+}
+
+/// Writes the entries into an archive at `output` and returns the sha256 of the archive with the size, mtime
+/// and sha256 of every file in it. Everything is hashed while it's being written, so files are only read once.
+///
+/// `compressor_override` pipes the tar stream through an external program (the old tar -I flag).
+pub(crate) fn write_archive(format: &BackupFormat, compression_level: Option<i64>, compressor_override: Option<&str>, entries: &[ArchiveEntry], output: &Path, verbose: bool) -> Result<WrittenArchive, Error> {
+    if let Some(level) = compression_level {
+        format.validate_compression_level(level)?;
+    }
+
+    let file = BufWriter::new(File::create(output)?);
+    let hashing_writer = HashingWriter::new(file);
+    let mut files = Vec::new();
+
+    let hashing_writer = match (format, compressor_override) {
+        (BackupFormat::Zip, _) => write_zip(hashing_writer, compression_level, entries, verbose, &mut files)?,
+        (_, Some(command)) => {
+            let (program, args) = split_command(command)?;
+            write_tar_through_program(hashing_writer, &program, &args, entries, verbose, &mut files)?
+        }
+        (BackupFormat::TarGz, None) => {
+            let level = compression_level.map(|level| Compression::new(level as u32)).unwrap_or_default();
+            let encoder = flate2::write::GzEncoder::new(hashing_writer, level);
+            write_tar(encoder, entries, verbose, Some(&mut files))?.finish()?
+        }
+        (BackupFormat::TarZst, None) => {
+            let level = compression_level.unwrap_or(zstd::DEFAULT_COMPRESSION_LEVEL as i64) as i32;
+            let encoder = zstd::stream::write::Encoder::new(hashing_writer, level)?;
+            write_tar(encoder, entries, verbose, Some(&mut files))?.finish()?
+        }
+    };
+
+    let (mut file, sha256) = hashing_writer.finish()?;
+    file.flush()?;
+    Ok(WrittenArchive { sha256, files })
+}
+
+/// The manifest line of a file that was just archived through `reader`.
+fn manifest_file(entry: &ArchiveEntry, metadata: &fs::Metadata, reader: HashingReader<impl Read>) -> ManifestFile {
+    let (size, sha256) = reader.finish();
+    ManifestFile {
+        path: entry.archive_name.to_string_lossy().to_string(),
+        size,
+        mtime: metadata.mtime(),
+        sha256,
+    }
+}
+
+/// Writes the entries as a tar stream. With `manifest` the regular files are hashed while they're added
+/// and listed in it, the bundle doesn't need that.
+fn write_tar<W: Write>(writer: W, entries: &[ArchiveEntry], verbose: bool, mut manifest: Option<&mut Vec<ManifestFile>>) -> Result<W, Error> {
+    let mut builder = tar::Builder::new(writer);
+    builder.follow_symlinks(false);
+
+    for entry in entries {
+        if verbose {
+            println!("{} {}", "Adding".green(), entry.archive_name.display());
+        }
+
+        let metadata = fs::symlink_metadata(&entry.disk_path)?;
+        match manifest.as_deref_mut() {
+            Some(files) if metadata.is_file() => {
+                // The same header append_path_with_name writes, but the contents go through the hasher
+                let mut header = tar::Header::new_gnu();
+                header.set_metadata(&metadata);
+                let mut reader = HashingReader::new(File::open(&entry.disk_path)?.take(metadata.len()));
+                builder.append_data(&mut header, &entry.archive_name, &mut reader)?;
+                files.push(manifest_file(entry, &metadata, reader));
+            }
+            _ => builder.append_path_with_name(&entry.disk_path, &entry.archive_name)?,
+        }
+    }
+
+    builder.into_inner()
+}
+
+fn write_zip<W: Write>(writer: W, compression_level: Option<i64>, entries: &[ArchiveEntry], verbose: bool, files: &mut Vec<ManifestFile>) -> Result<W, Error> {
+    let mut zip = zip::ZipWriter::new_stream(writer);
+
+    for entry in entries {
+        if verbose {
+            println!("{} {}", "Adding".green(), entry.archive_name.display());
+        }
+
+        let metadata = fs::symlink_metadata(&entry.disk_path)?;
+        let mut options = SimpleFileOptions::default()
+            .compression_method(CompressionMethod::Deflated)
+            .compression_level(compression_level)
+            .unix_permissions(metadata.permissions().mode())
+            .large_file(metadata.len() >= u32::MAX as u64);
+
+        if let Ok(modified) = metadata.modified() {
+            let modified: chrono::DateTime<chrono::Local> = modified.into();
+            if let Ok(zip_time) = zip::DateTime::try_from(modified.naive_local()) {
+                options = options.last_modified_time(zip_time);
+            }
+        }
+
+        let name = entry.archive_name.to_string_lossy().to_string();
+        if metadata.is_symlink() {
+            let target = fs::read_link(&entry.disk_path)?;
+            zip.add_symlink(name, target.to_string_lossy(), options).map_err(Error::other)?;
+        } else if metadata.is_dir() {
+            zip.add_directory(name, options).map_err(Error::other)?;
+        } else {
+            zip.start_file(name, options).map_err(Error::other)?;
+            let mut reader = HashingReader::new(File::open(&entry.disk_path)?);
+            std::io::copy(&mut reader, &mut zip)?;
+            files.push(manifest_file(entry, &metadata, reader));
+        }
+    }
+
+    Ok(zip.finish().map_err(Error::other)?.into_inner())
+}
+
+fn write_tar_through_program<W: Write + Send + 'static>(writer: W, program: &str, args: &[String], entries: &[ArchiveEntry], verbose: bool, files: &mut Vec<ManifestFile>) -> Result<W, Error> {
+    let mut child = Command::new(program)
+        .args(args)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .spawn()
+        .map_err(|e| Error::other(format!("Failed to start {}: {}", program, e)))?;
+
+    let mut stdout = child.stdout.take().unwrap();
+    let copier = std::thread::spawn(move || -> Result<W, Error> {
+        let mut writer = writer;
+        std::io::copy(&mut stdout, &mut writer)?;
+        Ok(writer)
+    });
+
+    let stdin = child.stdin.take().unwrap();
+    let tar_result = write_tar(stdin, entries, verbose, Some(files));
+    // Dropping stdin closes the pipe so the program knows the tar stream is done
+    drop(tar_result?);
+
+    let status = child.wait()?;
+    let writer = copier.join().map_err(|_| Error::other("The compressor output thread panicked"))??;
+    if !status.success() {
+        return Err(Error::other(format!("{} exited with {}", program, status)));
+    }
+
+    Ok(writer)
+}
+
+fn split_command(command: &str) -> Result<(String, Vec<String>), Error> {
+    let mut parts = command.split_whitespace().map(|part| part.to_string());
+    let program = parts.next()
+        .ok_or_else(|| Error::new(ErrorKind::InvalidInput, "The compressor override is empty"))?;
+    Ok((program, parts.collect()))
+}
+
+/// Writes the bundle (the inner archive, hash file and manifest) using the fastest settings,
+/// the inner archive is already compressed so compressing it again is wasted time.
+pub(crate) fn write_bundle(format: &BackupFormat, entries: &[ArchiveEntry], output: &Path) -> Result<String, Error> {
+    let file = BufWriter::new(File::create(output)?);
+    let hashing_writer = HashingWriter::new(file);
+
+    let hashing_writer = match format {
+        BackupFormat::TarGz => {
+            let encoder = flate2::write::GzEncoder::new(hashing_writer, Compression::none());
+            write_tar(encoder, entries, false, None)?.finish()?
+        }
+        BackupFormat::TarZst => {
+            let encoder = zstd::stream::write::Encoder::new(hashing_writer, 1)?;
+            write_tar(encoder, entries, false, None)?.finish()?
+        }
+        BackupFormat::Zip => {
+            let mut zip = zip::ZipWriter::new_stream(hashing_writer);
+            for entry in entries {
+                let size = fs::metadata(&entry.disk_path)?.len();
+                let options = SimpleFileOptions::default()
+                    .compression_method(CompressionMethod::Stored)
+                    .large_file(size >= u32::MAX as u64);
+                zip.start_file(entry.archive_name.to_string_lossy(), options).map_err(Error::other)?;
+                let mut file = File::open(&entry.disk_path)?;
+                std::io::copy(&mut file, &mut zip)?;
+            }
+            zip.finish().map_err(Error::other)?.into_inner()
+        }
+    };
+
+    let (mut file, hash) = hashing_writer.finish()?;
+    file.flush()?;
+    Ok(hash)
+}
+
+/// The contents of a bundle after `unpack_bundle`, the inner archive is hashed while it's unpacked.
+pub(crate) struct UnpackedBundle {
+    pub inner_path: PathBuf,
+    pub inner_sha256: String,
+    pub recorded_sha256: Option<String>,
+}
+
+impl UnpackedBundle {
+    pub fn hash_matches(&self) -> bool {
+        self.recorded_sha256.as_deref() == Some(self.inner_sha256.as_str())
+    }
+}
+
+/// The hash from a `_hash.txt` file (sha256sum format: "<hash>  <file name>").
+pub(crate) fn parse_hash_file(contents: &str) -> Option<String> {
+    contents.split_whitespace().next().map(|hash| hash.to_lowercase())
+}
+
+fn tar_reader(format: &BackupFormat, file: File) -> Result<Box<dyn Read>, Error> {
+    match format {
+        BackupFormat::TarGz => Ok(Box::new(flate2::read::GzDecoder::new(BufReader::new(file)))),
+        BackupFormat::TarZst => Ok(Box::new(zstd::stream::read::Decoder::new(file)?)),
+        BackupFormat::Zip => Err(Error::new(ErrorKind::InvalidInput, "A zip archive can't be read as a tar archive")),
+    }
+}
+
+/// Unpacks the inner archive of a bundle into `into_directory` and reads the recorded hash.
+pub(crate) fn unpack_bundle(format: &BackupFormat, bundle_path: &Path, into_directory: &Path) -> Result<UnpackedBundle, Error> {
+    let mut inner_path: Option<PathBuf> = None;
+    let mut inner_sha256 = String::new();
+    let mut recorded_sha256: Option<String> = None;
+
+    let mut handle_entry = |name: &str, reader: &mut dyn Read| -> Result<(), Error> {
+        if name.ends_with("_hash.txt") {
+            let mut contents = String::new();
+            reader.read_to_string(&mut contents)?;
+            recorded_sha256 = parse_hash_file(&contents);
+        } else if name.ends_with(MANIFEST_SUFFIX) {
+            // Read by `read_bundle_manifest`, nothing to unpack
+        } else if inner_path.is_none() {
+            let file_name = Path::new(name).file_name()
+                .ok_or_else(|| Error::new(ErrorKind::InvalidData, format!("Invalid file name in bundle: {}", name)))?;
+            let path = into_directory.join(file_name);
+            let mut writer = HashingWriter::new(BufWriter::new(File::create(&path)?));
+            std::io::copy(reader, &mut writer)?;
+            inner_sha256 = writer.finish()?.1;
+            inner_path = Some(path);
+        }
+        Ok(())
+    };
+
+    let file = File::open(bundle_path)?;
+    match format {
+        BackupFormat::Zip => {
+            let mut zip = zip::ZipArchive::new(BufReader::new(file)).map_err(Error::other)?;
+            for index in 0..zip.len() {
+                let mut entry = zip.by_index(index).map_err(Error::other)?;
+                let name = entry.name().to_string();
+                handle_entry(&name, &mut entry)?;
+            }
+        }
+        _ => {
+            let mut tar = tar::Archive::new(tar_reader(format, file)?);
+            for entry in tar.entries()? {
+                let mut entry = entry?;
+                let name = entry.path()?.to_string_lossy().to_string();
+                handle_entry(&name, &mut entry)?;
+            }
+        }
+    }
+
+    let inner_path = inner_path
+        .ok_or_else(|| Error::new(ErrorKind::InvalidData, format!("{} does not contain a backup archive", bundle_path.display())))?;
+
+    Ok(UnpackedBundle {
+        inner_path,
+        inner_sha256,
+        recorded_sha256,
+    })
+}
+
+/// Reads the manifest of a bundle without unpacking the inner archive, `None` for bundles made before manifests existed.
+pub(crate) fn read_bundle_manifest(format: &BackupFormat, bundle_path: &Path) -> Result<Option<String>, Error> {
+    let file = File::open(bundle_path)?;
+    let mut contents = String::new();
+    match format {
+        BackupFormat::Zip => {
+            let mut zip = zip::ZipArchive::new(BufReader::new(file)).map_err(Error::other)?;
+            let name = zip.file_names().find(|name| name.ends_with(MANIFEST_SUFFIX)).map(|name| name.to_string());
+            match name {
+                Some(name) => zip.by_name(&name).map_err(Error::other)?.read_to_string(&mut contents)?,
+                None => return Ok(None),
+            };
+        }
+        _ => {
+            let mut tar = tar::Archive::new(tar_reader(format, file)?);
+            let mut found = false;
+            for entry in tar.entries()? {
+                let mut entry = entry?;
+                if entry.path()?.to_string_lossy().ends_with(MANIFEST_SUFFIX) {
+                    entry.read_to_string(&mut contents)?;
+                    found = true;
+                    break;
+                }
+            }
+            if !found {
+                return Ok(None);
+            }
+        }
+    }
+    Ok(Some(contents))
+}
+
+/// Lists the regular files in an archive with their size, mtime and sha256, the way the manifest does.
+/// Used for bundles that were made before manifests existed.
+pub(crate) fn list_archive_files(format: &BackupFormat, archive_path: &Path) -> Result<Vec<ManifestFile>, Error> {
+    let mut files = Vec::new();
+
+    let file = File::open(archive_path)?;
+    match format {
+        BackupFormat::Zip => {
+            let mut zip = zip::ZipArchive::new(BufReader::new(file)).map_err(Error::other)?;
+            for index in 0..zip.len() {
+                let entry = zip.by_index(index).map_err(Error::other)?;
+                if !entry.is_file() || entry.is_symlink() {
+                    continue;
+                }
+                let path = entry.name().to_string();
+                let mtime = entry.last_modified()
+                    .and_then(|time| chrono::NaiveDateTime::try_from(time).ok())
+                    .and_then(|time| time.and_local_timezone(chrono::Local).single())
+                    .map(|time| time.timestamp())
+                    .unwrap_or(0);
+                let mut reader = HashingReader::new(entry);
+                std::io::copy(&mut reader, &mut std::io::sink())?;
+                let (size, sha256) = reader.finish();
+                files.push(ManifestFile { path, size, mtime, sha256 });
+            }
+        }
+        _ => {
+            let mut tar = tar::Archive::new(tar_reader(format, file)?);
+            for entry in tar.entries()? {
+                let entry = entry?;
+                if !entry.header().entry_type().is_file() {
+                    continue;
+                }
+                let path = entry.path()?.to_string_lossy().to_string();
+                let mtime = entry.header().mtime()? as i64;
+                let mut reader = HashingReader::new(entry);
+                std::io::copy(&mut reader, &mut std::io::sink())?;
+                let (size, sha256) = reader.finish();
+                files.push(ManifestFile { path, size, mtime, sha256 });
+            }
+        }
+    }
+
+    Ok(files)
+}
+
+/// True if `name` should be extracted when only the `only` paths were asked for (empty means everything).
+pub(crate) fn is_wanted(name: &Path, only: &[PathBuf]) -> bool {
+    only.is_empty() || only.iter().any(|wanted| name.starts_with(wanted))
+}
+
+/// Refuses to create `directory` (or write into it) when a symbolic link on its way leads outside of `target_directory`
+/// (canonicalized), a link extracted earlier would otherwise let the entries after it land anywhere on the disk.
+pub(crate) fn ensure_inside(target_directory: &Path, directory: &Path) -> Result<(), Error> {
+    let existing = directory.ancestors().find(|ancestor| ancestor.exists()).unwrap_or(target_directory);
+    if !fs::canonicalize(existing)?.starts_with(target_directory) {
+        return Err(Error::new(
+            ErrorKind::InvalidData,
+            format!("{} is outside of {} through a symbolic link, the backup may be malicious", directory.display(), target_directory.display())
+        ));
+    }
+    Ok(())
+}
+
+/// Extracts an archive into `target_directory` and returns how many entries were extracted.
+/// `only` limits the extraction to those paths (and everything inside them).
+pub(crate) fn extract_archive(format: &BackupFormat, archive_path: &Path, target_directory: &Path, only: &[PathBuf]) -> Result<u64, Error> {
+    let only: Vec<PathBuf> = only.iter().map(|path| archive_name(path)).collect();
+    let mut extracted = 0;
+
+    fs::create_dir_all(target_directory)?;
+    let canonical_target = fs::canonicalize(target_directory)?;
+    let file = File::open(archive_path)?;
+    match format {
+        BackupFormat::Zip => {
+            let mut zip = zip::ZipArchive::new(BufReader::new(file)).map_err(Error::other)?;
+            for index in 0..zip.len() {
+                let mut entry = zip.by_index(index).map_err(Error::other)?;
+                let name = match entry.enclosed_name() {
+                    Some(name) => name,
+                    None => continue, // Never extract outside of the target directory
+                };
+                if !is_wanted(&name, &only) {
+                    continue;
+                }
+
+                let path = target_directory.join(&name);
+                // Files replace a link at their own path, but must not be written through one in their parents
+                ensure_inside(&canonical_target, if entry.is_dir() { &path } else { path.parent().unwrap_or(target_directory) })?;
+                if entry.is_dir() {
+                    fs::create_dir_all(&path)?;
+                } else {
+                    if let Some(parent) = path.parent() {
+                        fs::create_dir_all(parent)?;
+                    }
+                    if fs::symlink_metadata(&path).is_ok() {
+                        fs::remove_file(&path)?;
+                    }
+
+                    if entry.is_symlink() {
+                        let mut link_target = String::new();
+                        entry.read_to_string(&mut link_target)?;
+                        std::os::unix::fs::symlink(link_target, &path)?;
+                        extracted += 1;
+                        continue;
+                    }
+
+                    let mut out = File::create(&path)?;
+                    std::io::copy(&mut entry, &mut out)?;
+                    let modified = entry.last_modified()
+                        .and_then(|time| chrono::NaiveDateTime::try_from(time).ok())
+                        .and_then(|time| time.and_local_timezone(chrono::Local).single());
+                    if let Some(modified) = modified {
+                        out.set_modified(modified.into())?;
+                    }
+                }
+
+                if let Some(mode) = entry.unix_mode() {
+                    fs::set_permissions(&path, fs::Permissions::from_mode(mode & 0o7777))?;
+                }
+                extracted += 1;
+            }
+        }
+        _ => {
+            let mut tar = tar::Archive::new(tar_reader(format, file)?);
+            tar.set_preserve_permissions(true);
+            tar.set_preserve_mtime(true);
+            tar.set_overwrite(true);
+
+            for entry in tar.entries()? {
+                let mut entry = entry?;
+                let name = entry.path()?.to_path_buf();
+                if !is_wanted(&archive_name(&name), &only) {
+                    continue;
+                }
+
+                // unpack_in refuses paths that would end up outside of the target directory
+                if entry.unpack_in(target_directory)? {
+                    extracted += 1;
+                }
+            }
+        }
+    }
+
+    Ok(extracted)
+}
+
+/// Reads every entry of an archive without extracting it, to catch truncated or corrupt archives.
+/// Returns how many entries were read.
+pub(crate) fn test_read_archive(format: &BackupFormat, archive_path: &Path) -> Result<u64, Error> {
+    let mut entries = 0;
+
+    let file = File::open(archive_path)?;
+    match format {
+        BackupFormat::Zip => {
+            let mut zip = zip::ZipArchive::new(BufReader::new(file)).map_err(Error::other)?;
+            for index in 0..zip.len() {
+                // Reading the whole entry makes the zip crate check its CRC
+                let mut entry = zip.by_index(index).map_err(Error::other)?;
+                std::io::copy(&mut entry, &mut std::io::sink())?;
+                entries += 1;
+            }
+        }
+        _ => {
+            let mut tar = tar::Archive::new(tar_reader(format, file)?);
+            for entry in tar.entries()? {
+                let mut entry = entry?;
+                std::io::copy(&mut entry, &mut std::io::sink())?;
+                entries += 1;
+            }
+        }
+    }
+
+    Ok(entries)
+}
+
+#[cfg(test)]
+mod archive_testing {
+    use super::*;
+
+    fn create_tree(root: &Path) {
+        fs::create_dir_all(root.join("world/region")).unwrap();
+        fs::create_dir_all(root.join("plugins/dynmap/web")).unwrap();
+        fs::write(root.join("world/level.dat"), b"level").unwrap();
+        fs::write(root.join("world/region/r.0.0.mca"), vec![7u8; 4096]).unwrap();
+        fs::write(root.join("plugins/dynmap/web/tile.png"), b"tile").unwrap();
+        fs::write(root.join("plugins/latest.log"), b"log").unwrap();
+        fs::write(root.join("plugins/config.yml"), b"config").unwrap();
+    }
+
+    fn names(entries: &[ArchiveEntry]) -> Vec<String> {
+        entries.iter().map(|e| e.archive_name.to_string_lossy().to_string()).collect()
+    }
+
+    #[test]
+    fn test_exclude_matcher() {
+        let excludes = ExcludeMatcher::new(Some("plugins/dynmap/:*.log")).unwrap();
+        assert!(excludes.is_excluded(Path::new("plugins/dynmap")));
+        assert!(excludes.is_excluded(Path::new("plugins/dynmap/web/tile.png")));
+        assert!(excludes.is_excluded(Path::new("plugins/latest.log")));
+        assert!(excludes.is_excluded(Path::new("logs/latest.log")));
+        assert!(!excludes.is_excluded(Path::new("plugins/config.yml")));
+        assert!(excludes.is_excluded(Path::new("srv/hub/plugins/dynmap/web")));
+        assert!(!excludes.is_excluded(Path::new("plugins/dynmap-extra/config.yml")));
+    }
+
+    #[test]
+    fn test_archive_name() {
+        assert_eq!(archive_name(Path::new("./world/level.dat")), PathBuf::from("world/level.dat"));
+        assert_eq!(archive_name(Path::new("/srv/mc/world")), PathBuf::from("srv/mc/world"));
+        assert_eq!(archive_name(Path::new(".")), PathBuf::new());
+    }
+
+    #[test]
+    fn test_collect_entries_with_excludes() {
+        let temp_dir = tempdir::TempDir::new("archive-collect").unwrap();
+        create_tree(temp_dir.path());
+
+        let excludes = ExcludeMatcher::new(Some("plugins/dynmap:*.log")).unwrap();
+        let sources = format!("{}/world:{}/plugins", temp_dir.path().display(), temp_dir.path().display());
+        let entries = collect_entries(&sources, &excludes).unwrap();
+        let names = names(&entries);
+
+        assert!(names.iter().any(|n| n.ends_with("world/region/r.0.0.mca")));
+        assert!(names.iter().any(|n| n.ends_with("plugins/config.yml")));
+        assert!(!names.iter().any(|n| n.contains("dynmap")));
+        assert!(!names.iter().any(|n| n.ends_with(".log")));
+    }
+
+    #[test]
+    fn test_write_archive_all_formats() {
+        let temp_dir = tempdir::TempDir::new("archive-write").unwrap();
+        create_tree(temp_dir.path());
+        let entries = collect_entries(&temp_dir.path().join("world").to_string_lossy(), &ExcludeMatcher::new(None).unwrap()).unwrap();
+
+        for format in [BackupFormat::TarGz, BackupFormat::TarZst, BackupFormat::Zip] {
+            let output = temp_dir.path().join(format!("out.{}", format.extension()));
+            let written = write_archive(&format, None, None, &entries, &output, false).unwrap();
+            assert_eq!(written.sha256, sha256_of_file(&output).unwrap());
+
+            // The manifest lists the regular files with the hashes of their contents
+            assert_eq!(written.files.len(), 2);
+            let region = written.files.iter().find(|file| file.path.ends_with("region/r.0.0.mca")).unwrap();
+            assert_eq!(region.size, 4096);
+            assert_eq!(region.sha256, sha256_of_file(&temp_dir.path().join("world/region/r.0.0.mca")).unwrap());
+
+            // And an old bundle's archive gives the same list (zip only keeps mtimes to 2 seconds)
+            let without_mtime = |files: &[ManifestFile]| files.iter().map(|file| (file.path.clone(), file.size, file.sha256.clone())).collect::<Vec<_>>();
+            assert_eq!(without_mtime(&list_archive_files(&format, &output).unwrap()), without_mtime(&written.files));
+        }
+
+        // tar.gz must be readable back
+        let output = temp_dir.path().join("out.tar.gz");
+        let mut archive = tar::Archive::new(flate2::read::GzDecoder::new(File::open(&output).unwrap()));
+        let mut found = false;
+        for entry in archive.entries().unwrap() {
+            let mut entry = entry.unwrap();
+            if entry.path().unwrap().ends_with("region/r.0.0.mca") {
+                assert_eq!(std::io::copy(&mut entry, &mut std::io::sink()).unwrap(), 4096);
+                found = true;
+            }
+        }
+        assert!(found);
+
+        // zip must be readable back
+        let output = temp_dir.path().join("out.zip");
+        let mut archive = zip::ZipArchive::new(File::open(&output).unwrap()).unwrap();
+        let name = archive.file_names().find(|n| n.ends_with("level.dat")).unwrap().to_string();
+        let mut contents = String::new();
+        archive.by_name(&name).unwrap().read_to_string(&mut contents).unwrap();
+        assert_eq!(contents, "level");
+    }
+
+    #[test]
+    fn test_test_read_archive_catches_truncation() {
+        let temp_dir = tempdir::TempDir::new("archive-test-read").unwrap();
+        create_tree(temp_dir.path());
+        let entries = collect_entries(&temp_dir.path().join("world").to_string_lossy(), &ExcludeMatcher::new(None).unwrap()).unwrap();
+
+        for format in [BackupFormat::TarGz, BackupFormat::TarZst, BackupFormat::Zip] {
+            let output = temp_dir.path().join(format!("out.{}", format.extension()));
+            write_archive(&format, None, None, &entries, &output, false).unwrap();
+            assert_eq!(test_read_archive(&format, &output).unwrap(), entries.len() as u64);
+
+            // Chop off the end like an interrupted upload would
+            let length = fs::metadata(&output).unwrap().len();
+            File::options().write(true).open(&output).unwrap().set_len(length / 2).unwrap();
+            assert!(test_read_archive(&format, &output).is_err());
+        }
+    }
+
+    #[test]
+    fn test_extract_zip_refuses_writing_through_links() {
+        let temp_dir = tempdir::TempDir::new("archive-zip-links").unwrap();
+        let outside = temp_dir.path().join("outside");
+        fs::create_dir_all(&outside).unwrap();
+
+        // A link to a folder outside of the target, then a file and a folder through it
+        for (name, is_dir) in [("a/x", false), ("a/y", true)] {
+            let output = temp_dir.path().join("evil.zip");
+            let mut zip = zip::ZipWriter::new(File::create(&output).unwrap());
+            zip.add_symlink("a", outside.to_string_lossy(), SimpleFileOptions::default()).unwrap();
+            if is_dir {
+                zip.add_directory(name, SimpleFileOptions::default()).unwrap();
+            } else {
+                zip.start_file(name, SimpleFileOptions::default()).unwrap();
+                zip.write_all(b"evil").unwrap();
+            }
+            zip.finish().unwrap();
+
+            let target = temp_dir.path().join(format!("target-{}", is_dir));
+            let error = extract_archive(&BackupFormat::Zip, &output, &target, &[]).unwrap_err();
+            assert_eq!(error.kind(), ErrorKind::InvalidData);
+            assert_eq!(fs::read_dir(&outside).unwrap().count(), 0);
+        }
+    }
+
+    #[test]
+    fn test_write_archive_rejects_bad_level() {
+        let temp_dir = tempdir::TempDir::new("archive-level").unwrap();
+        let output = temp_dir.path().join("out.tar.zst");
+        assert!(write_archive(&BackupFormat::TarZst, Some(23), None, &[], &output, false).is_err());
+        assert!(write_archive(&BackupFormat::TarGz, Some(10), None, &[], &output, false).is_err());
+        assert!(write_archive(&BackupFormat::Zip, Some(-1), None, &[], &output, false).is_err());
+    }
+}
@@ -0,0 +1,140 @@
+use std::io::{Error, ErrorKind, Read};
+use std::sync::OnceLock;
+
+// Chunks are cut where the rolling hash matches, so an edit in the middle of a file
+// only changes the chunks around it and the rest of the file is deduplicated.
+pub(crate) const MIN_CHUNK_SIZE: usize = 256 * 1024;
+pub(crate) const MAX_CHUNK_SIZE: usize = 4 * 1024 * 1024;
+
+// The top 20 bits of the hash must be zero for a cut, so chunks are about 1 MB past MIN_CHUNK_SIZE
+const CUT_MASK: u64 = 0xFFFFF << 44;
+
+/// The random (but fixed, chunk boundaries must never change) values of the gear hash.
+fn gear_table() -> &'static [u64; 256] {
+    static GEAR: OnceLock<[u64; 256]> = OnceLock::new();
+    GEAR.get_or_init(|| {
+        // splitmix64
+        let mut state: u64 = 0x6c69_6d6f_6e69_756d;
+        let mut table = [0u64; 256];
+        for value in table.iter_mut() {
+            state = state.wrapping_add(0x9E37_79B9_7F4A_7C15);
+            let mut z = state;
+            z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+            z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+            *value = z ^ (z >> 31);
+        }
+        table
+    })
+}
+
+/// Where the first chunk in `data` ends. `data` is either at least MAX_CHUNK_SIZE long or the end of the file.
+fn find_cut(data: &[u8]) -> usize {
+    if data.len() <= MIN_CHUNK_SIZE {
+        return data.len();
+    }
+
+    let gear = gear_table();
+    let end = data.len().min(MAX_CHUNK_SIZE);
+    let mut hash: u64 = 0;
+    for (index, byte) in data.iter().enumerate().take(end).skip(MIN_CHUNK_SIZE) {
+        hash = (hash << 1).wrapping_add(gear[*byte as usize]);
+        if hash & CUT_MASK == 0 {
+            return index + 1;
+        }
+    }
+    end
+}
+
+/// Splits everything read from `reader` into content-defined chunks.
+pub(crate) struct Chunker<R: Read> {
+    reader: R,
+    buffer: Vec<u8>,
+    eof: bool,
+}
+
+impl<R: Read> Chunker<R> {
+    pub fn new(reader: R) -> Self {
+        Chunker {
+            reader,
+            buffer: Vec::with_capacity(MAX_CHUNK_SIZE),
+            eof: false,
+        }
+    }
+
+    fn fill(&mut self) -> Result<(), Error> {
+        let mut read_buffer = vec![0u8; 256 * 1024];
+        while !self.eof && self.buffer.len() < MAX_CHUNK_SIZE {
+            match self.reader.read(&mut read_buffer) {
+                Ok(0) => self.eof = true,
+                Ok(read) => self.buffer.extend_from_slice(&read_buffer[..read]),
+                Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(())
+    }
+}
+
+impl<R: Read> Iterator for Chunker<R> {
+    type Item = Result<Vec<u8>, Error>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if let Err(e) = self.fill() {
+            return Some(Err(e));
+        }
+        if self.buffer.is_empty() {
+            return None;
+        }
+
+        let cut = find_cut(&self.buffer);
+        let rest = self.buffer.split_off(cut);
+        Some(Ok(std::mem::replace(&mut self.buffer, rest)))
+    }
+}
+
+#[cfg(test)]
+mod chunker_testing {
+    use super::*;
+
+    fn pseudo_random_data(length: usize, seed: u64) -> Vec<u8> {
+        let mut state = seed;
+        (0..length)
+            .map(|_| {
+                state = state.wrapping_mul(6364136223846793005).wrapping_add(1442695040888963407);
+                (state >> 56) as u8
+            })
+            .collect()
+    }
+
+    fn split(data: &[u8]) -> Vec<Vec<u8>> {
+        Chunker::new(data).collect::<Result<Vec<_>, Error>>().unwrap()
+    }
+
+    #[test]
+    fn test_chunks_put_back_together() {
+        let data = pseudo_random_data(9 * 1024 * 1024, 1);
+        let chunks = split(&data);
+
+        assert!(chunks.len() > 2);
+        assert!(chunks.iter().all(|chunk| chunk.len() <= MAX_CHUNK_SIZE));
+        assert!(chunks[..chunks.len() - 1].iter().all(|chunk| chunk.len() > MIN_CHUNK_SIZE));
+        assert_eq!(chunks.concat(), data);
+
+        assert!(split(&[]).is_empty());
+        assert_eq!(split(b"small"), vec![b"small".to_vec()]);
+    }
+
+    #[test]
+    fn test_edit_only_changes_nearby_chunks() {
+        let data = pseudo_random_data(12 * 1024 * 1024, 2);
+        let mut edited = data.clone();
+        // Insert a few bytes in the middle, which shifts everything after it
+        let middle = edited.len() / 2;
+        edited.splice(middle..middle, b"inserted".iter().copied());
+
+        let before = split(&data);
+        let after = split(&edited);
+        let unchanged = after.iter().filter(|chunk| before.contains(chunk)).count();
+        assert!(unchanged >= after.len() - 2, "only {} of {} chunks were reused", unchanged, after.len());
+    }
+}
@@ -0,0 +1,366 @@
+use std::collections::BTreeMap;
+use std::env;
+use std::fs::{self, File, OpenOptions};
+use std::io::{Error, ErrorKind, Write};
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+use std::time::Duration;
+
+use colored::Colorize;
+use reqwest::Url;
+
+use crate::destination::{credential, percent_decode};
+
+/// The config file with named databases, LIMONIUM_DATABASES overrides it.
+const DEFAULT_CONFIG_PATH: &str = ".config/limonium/databases.json";
+/// The folder the dumps are put in inside the backup archive.
+pub(crate) const DUMP_FOLDER: &str = "limonium-databases";
+
+/// Named databases: name -> {"url": "...", plus the same options as the URL query (password_env, command, ...)}.
+pub type DatabasesConfig = BTreeMap<String, BTreeMap<String, String>>;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum DatabaseKind {
+    /// Copied with SQLite's online backup API, so it is consistent while a plugin writes to it
+    Sqlite { path: PathBuf },
+    /// Dumped with mysqldump (or `command`) in a single transaction
+    MySql { host: String, port: Option<u16>, user: Option<String>, password: Option<String>, database: String, command: String },
+    /// Dumped with pg_dump (or `command`)
+    Postgres { host: String, port: Option<u16>, user: Option<String>, password: Option<String>, database: String, command: String },
+}
+
+/// A database that is dumped into the backup (--database).
+#[derive(Debug, Clone, PartialEq)]
+pub struct DatabaseSource {
+    /// The name of the dump file ("luckperms" -> limonium-databases/luckperms.sql)
+    pub label: String,
+    pub kind: DatabaseKind,
+}
+
+impl DatabaseSource {
+    /// Parses a database argument: sqlite:<path>, a URL (mysql://, mariadb://, postgres://), or the name of a database in the config file.
+    pub fn parse(arg: &str) -> Result<DatabaseSource, Error> {
+        if arg.starts_with("sqlite:") || arg.contains("://") {
+            return DatabaseSource::parse_with_config(arg, &DatabasesConfig::new());
+        }
+        DatabaseSource::parse_with_config(arg, &load_config()?)
+    }
+
+    fn parse_with_config(arg: &str, config: &DatabasesConfig) -> Result<DatabaseSource, Error> {
+        let arg = arg.trim();
+        if let Some(path) = arg.strip_prefix("sqlite:") {
+            let path = PathBuf::from(path.strip_prefix("//").unwrap_or(path));
+            let stem = path.file_stem().map(|stem| stem.to_string_lossy().to_string())
+                .ok_or_else(|| Error::new(ErrorKind::InvalidInput, format!("{} has no SQLite file (sqlite:plugins/CoreProtect/database.db)", arg)))?;
+            // Plugins often all call their file database.db, the folder tells them apart
+            let label = match path.parent().and_then(|parent| parent.file_name()) {
+                Some(folder) => format!("{}-{}", folder.to_string_lossy(), stem),
+                None => stem,
+            };
+            return Ok(DatabaseSource { label, kind: DatabaseKind::Sqlite { path } });
+        }
+        if arg.contains("://") {
+            return parse_url(arg, &BTreeMap::new(), None);
+        }
+
+        let entry = config.get(arg)
+            .ok_or_else(|| Error::new(ErrorKind::NotFound, format!("{} is not sqlite:<path>, a URL or a database in {}", arg, config_path().display())))?;
+        let url = entry.get("url")
+            .ok_or_else(|| Error::new(ErrorKind::InvalidData, format!("The database {} has no url", arg)))?;
+        if url.starts_with("sqlite:") {
+            let mut source = DatabaseSource::parse_with_config(url, config)?;
+            source.label = arg.to_string();
+            return Ok(source);
+        }
+        parse_url(url, entry, Some(arg))
+    }
+
+    fn file_name(&self) -> String {
+        match self.kind {
+            DatabaseKind::Sqlite { .. } => format!("{}.sqlite", self.label),
+            DatabaseKind::MySql { .. } | DatabaseKind::Postgres { .. } => format!("{}.sql", self.label),
+        }
+    }
+
+    /// Dumps the database into `directory` and returns the path of the dump.
+    pub fn dump(&self, directory: &Path) -> Result<PathBuf, Error> {
+        let dump_path = directory.join(self.file_name());
+        match &self.kind {
+            DatabaseKind::Sqlite { path } => snapshot_sqlite(path, &dump_path)?,
+            DatabaseKind::MySql { host, port, user, password, database, command } => {
+                let mut dump = Command::new(command);
+                // The password is put in an option file only we can read, so it isn't in the process list
+                let option_file = directory.join(format!(".{}.cnf", self.label));
+                if let Some(password) = password {
+                    write_private_file(&option_file, &format!("[client]\npassword=\"{}\"\n", password.replace('\\', "\\\\").replace('"', "\\\"")))?;
+                    dump.arg(format!("--defaults-extra-file={}", option_file.display()));
+                }
+                dump.args(["--single-transaction", "--quick", "--routines", "--triggers", "--host", host]);
+                if let Some(port) = port {
+                    dump.arg("--port").arg(port.to_string());
+                }
+                if let Some(user) = user {
+                    dump.arg("--user").arg(user);
+                }
+                dump.arg(database);
+
+                let result = run_dump(dump, command, &dump_path);
+                let _ = fs::remove_file(&option_file);
+                result?;
+            }
+            DatabaseKind::Postgres { host, port, user, password, database, command } => {
+                let mut dump = Command::new(command);
+                dump.args(["--no-password", "--host", host]);
+                if let Some(port) = port {
+                    dump.arg("--port").arg(port.to_string());
+                }
+                if let Some(user) = user {
+                    dump.arg("--username").arg(user);
+                }
+                if let Some(password) = password {
+                    dump.env("PGPASSWORD", password);
+                }
+                dump.arg(database);
+                run_dump(dump, command, &dump_path)?;
+            }
+        }
+        Ok(dump_path)
+    }
+}
+
+fn config_path() -> PathBuf {
+    if let Ok(path) = env::var("LIMONIUM_DATABASES") {
+        return PathBuf::from(path);
+    }
+    env::var_os("HOME").map(PathBuf::from).unwrap_or_default().join(DEFAULT_CONFIG_PATH)
+}
+
+/// The named databases, empty if there is no config file.
+pub fn load_config() -> Result<DatabasesConfig, Error> {
+    let path = config_path();
+    if !path.exists() {
+        return Ok(DatabasesConfig::new());
+    }
+
+    let contents = fs::read_to_string(&path)?;
+    serde_json::from_str(&contents)
+        .map_err(|e| Error::new(ErrorKind::InvalidData, format!("Invalid databases file {}: {}", path.display(), e)))
+}
+
+/// Parses mysql://user@host:port/database and postgres://user@host:port/database.
+/// `extra` are options from the config file, they are used like query parameters.
+fn parse_url(input: &str, extra: &BTreeMap<String, String>, name: Option<&str>) -> Result<DatabaseSource, Error> {
+    let invalid = |message: String| Error::new(ErrorKind::InvalidInput, message);
+    let url = Url::parse(input).map_err(|e| invalid(format!("Invalid database URL {}: {}", input, e)))?;
+
+    let mut options: BTreeMap<String, String> = url.query_pairs().map(|(key, value)| (key.to_string(), value.to_string())).collect();
+    options.extend(extra.iter().filter(|(key, _)| key.as_str() != "url").map(|(key, value)| (key.clone(), value.clone())));
+
+    let host = url.host_str().ok_or_else(|| invalid(format!("{} has no host", input)))?.to_string();
+    let user = Some(percent_decode(url.username())).filter(|user| !user.is_empty()).or_else(|| options.get("user").cloned());
+    let database = percent_decode(url.path().trim_matches('/'));
+    if database.is_empty() {
+        return Err(invalid(format!("{} has no database (mysql://user@host/database)", input)));
+    }
+    let inline_password = url.password().map(percent_decode);
+    if inline_password.is_some() {
+        println!("{}", "The password in the database URL is visible to other users in the process list, use password_env or password_file instead".yellow());
+    }
+
+    let kind = match url.scheme() {
+        "mysql" | "mariadb" => DatabaseKind::MySql {
+            host,
+            port: url.port(),
+            user,
+            password: match inline_password {
+                Some(password) => Some(password),
+                None => credential(&options, "password", "LIMONIUM_MYSQL_PASSWORD")?,
+            },
+            database: database.clone(),
+            command: options.get("command").cloned().unwrap_or("mysqldump".to_string()),
+        },
+        "postgres" | "postgresql" => DatabaseKind::Postgres {
+            host,
+            port: url.port(),
+            user,
+            password: match inline_password {
+                Some(password) => Some(password),
+                None => credential(&options, "password", "LIMONIUM_POSTGRES_PASSWORD")?,
+            },
+            database: database.clone(),
+            command: options.get("command").cloned().unwrap_or("pg_dump".to_string()),
+        },
+        scheme => return Err(invalid(format!("Unsupported database scheme {}, use sqlite:, mysql:// or postgres://", scheme))),
+    };
+
+    Ok(DatabaseSource {
+        label: name.map(|name| name.to_string()).unwrap_or(database),
+        kind,
+    })
+}
+
+/// Copies a SQLite database page by page while it is in use, the copy is a consistent snapshot.
+fn snapshot_sqlite(path: &Path, dump_path: &Path) -> Result<(), Error> {
+    let sqlite_error = |e: rusqlite::Error| Error::other(format!("Could not snapshot the SQLite database {}: {}", path.display(), e));
+    if !path.is_file() {
+        return Err(Error::new(ErrorKind::NotFound, format!("The SQLite database {} does not exist", path.display())));
+    }
+
+    let source = rusqlite::Connection::open_with_flags(path, rusqlite::OpenFlags::SQLITE_OPEN_READ_ONLY).map_err(sqlite_error)?;
+    let mut snapshot = rusqlite::Connection::open(dump_path).map_err(sqlite_error)?;
+    rusqlite::backup::Backup::new(&source, &mut snapshot)
+        .and_then(|backup| backup.run_to_completion(256, Duration::from_millis(10), None))
+        .map_err(sqlite_error)
+}
+
+/// Runs a dump program, what it prints is the dump.
+fn run_dump(mut dump: Command, program: &str, dump_path: &Path) -> Result<(), Error> {
+    let output = dump
+        .stdin(Stdio::null())
+        .stdout(File::create(dump_path)?)
+        .stderr(Stdio::piped())
+        .output()
+        .map_err(|e| Error::new(e.kind(), format!("Could not run {}: {} (install it, or point command= to it)", program, e)))?;
+
+    if !output.status.success() {
+        return Err(Error::other(format!("{} failed ({}): {}", program, output.status, String::from_utf8_lossy(&output.stderr).trim())));
+    }
+    Ok(())
+}
+
+fn write_private_file(path: &Path, contents: &str) -> Result<(), Error> {
+    let mut file = OpenOptions::new().write(true).create(true).truncate(true).mode(0o600).open(path)?;
+    file.write_all(contents.as_bytes())
+}
+
+#[cfg(test)]
+mod database_testing {
+    use super::*;
+    use std::os::unix::fs::PermissionsExt;
+
+    use crate::backup::{manifest, Backup, BackupFormat};
+
+    /// A dump program that prints its arguments, the option file it was given and PGPASSWORD
+    fn stub_dump_command(directory: &Path) -> String {
+        let path = directory.join("stub-dump");
+        fs::write(&path, "#!/bin/sh\necho \"args: $*\"\ncase \"$1\" in --defaults-extra-file=*) cat \"${1#--defaults-extra-file=}\";; esac\necho \"pgpassword: $PGPASSWORD\"\n").unwrap();
+        fs::set_permissions(&path, fs::Permissions::from_mode(0o755)).unwrap();
+        path.to_string_lossy().to_string()
+    }
+
+    #[test]
+    fn test_parse_database() {
+        assert_eq!(DatabaseSource::parse("sqlite:plugins/CoreProtect/database.db").unwrap(), DatabaseSource {
+            label: "CoreProtect-database".to_string(),
+            kind: DatabaseKind::Sqlite { path: PathBuf::from("plugins/CoreProtect/database.db") },
+        });
+        assert_eq!(DatabaseSource::parse("sqlite:plugins/Plan/database.db").unwrap().label, "Plan-database");
+        assert_eq!(DatabaseSource::parse("sqlite:world.db").unwrap().label, "world");
+
+        let source = DatabaseSource::parse("mariadb://lp@127.0.0.1:3307/luckperms?command=mariadb-dump").unwrap();
+        assert_eq!(source.label, "luckperms");
+        assert_eq!(source.kind, DatabaseKind::MySql {
+            host: "127.0.0.1".to_string(),
+            port: Some(3307),
+            user: Some("lp".to_string()),
+            password: env::var("LIMONIUM_MYSQL_PASSWORD").ok(),
+            database: "luckperms".to_string(),
+            command: "mariadb-dump".to_string(),
+        });
+
+        let temp_dir = tempdir::TempDir::new("database-config").unwrap();
+        let password_file = temp_dir.path().join("plan-password");
+        fs::write(&password_file, "secret\n").unwrap();
+        let config: DatabasesConfig = serde_json::from_str(&format!(
+            r#"{{"plan": {{"url": "postgres://plan@db.internal/plan", "password_file": "{}"}}, "coreprotect": {{"url": "sqlite:plugins/CoreProtect/database.db"}}}}"#,
+            password_file.display()
+        )).unwrap();
+        let source = DatabaseSource::parse_with_config("plan", &config).unwrap();
+        assert_eq!(source.label, "plan");
+        assert!(matches!(source.kind, DatabaseKind::Postgres { password: Some(ref password), .. } if password == "secret"));
+        assert_eq!(DatabaseSource::parse_with_config("coreprotect", &config).unwrap().label, "coreprotect");
+
+        assert!(DatabaseSource::parse_with_config("luckperms", &config).is_err());
+        assert!(DatabaseSource::parse("mysql://lp@127.0.0.1/").is_err());
+        assert!(DatabaseSource::parse("mongodb://127.0.0.1/plan").is_err());
+    }
+
+    #[test]
+    fn test_dump_commands() {
+        let temp_dir = tempdir::TempDir::new("database-dump").unwrap();
+        let command = stub_dump_command(temp_dir.path());
+
+        let mysql = DatabaseSource {
+            label: "luckperms".to_string(),
+            kind: DatabaseKind::MySql {
+                host: "127.0.0.1".to_string(),
+                port: Some(3306),
+                user: Some("lp".to_string()),
+                password: Some("pa\"ss".to_string()),
+                database: "luckperms".to_string(),
+                command: command.clone(),
+            },
+        };
+        let dump = fs::read_to_string(mysql.dump(temp_dir.path()).unwrap()).unwrap();
+        assert!(dump.contains("--single-transaction --quick --routines --triggers --host 127.0.0.1 --port 3306 --user lp luckperms"));
+        assert!(dump.contains("password=\"pa\\\"ss\""));
+        // The option file with the password is gone after the dump
+        assert!(!temp_dir.path().join(".luckperms.cnf").exists());
+
+        let postgres = DatabaseSource {
+            label: "plan".to_string(),
+            kind: DatabaseKind::Postgres {
+                host: "db.internal".to_string(),
+                port: None,
+                user: Some("plan".to_string()),
+                password: Some("secret".to_string()),
+                database: "plan".to_string(),
+                command: command.clone(),
+            },
+        };
+        let dump = fs::read_to_string(postgres.dump(temp_dir.path()).unwrap()).unwrap();
+        assert!(dump.contains("args: --no-password --host db.internal --username plan plan"));
+        assert!(dump.contains("pgpassword: secret"));
+
+        let failing = DatabaseSource {
+            label: "broken".to_string(),
+            kind: DatabaseKind::Postgres {
+                host: "db.internal".to_string(),
+                port: None,
+                user: None,
+                password: None,
+                database: "plan".to_string(),
+                command: "false".to_string(),
+            },
+        };
+        assert!(failing.dump(temp_dir.path()).is_err());
+    }
+
+    #[test]
+    fn test_sqlite_in_backup() {
+        let temp_dir = tempdir::TempDir::new("database-backup").unwrap();
+        let server = temp_dir.path().join("server");
+        fs::create_dir_all(server.join("plugins/CoreProtect")).unwrap();
+        let database_path = server.join("plugins/CoreProtect/database.db");
+
+        // The plugin keeps its connection open while the backup runs
+        let live = rusqlite::Connection::open(&database_path).unwrap();
+        live.execute_batch("CREATE TABLE blocks (x INTEGER, action TEXT); INSERT INTO blocks VALUES (1, 'break'), (2, 'place');").unwrap();
+
+        let backups = temp_dir.path().join("backups");
+        let source = DatabaseSource::parse(&format!("sqlite:{}", database_path.display())).unwrap();
+        let backup = Backup::new("hub".to_string(), server.to_string_lossy().to_string(), backups.clone(), BackupFormat::TarGz, None, None)
+            .with_databases(vec![source]);
+        let result = backup.backup().unwrap();
+
+        let bundle = manifest::read_bundle_files(&result.file_path, &temp_dir.path().join(".lmtmp-diff")).unwrap();
+        assert!(bundle.files.iter().any(|file| file.path == "limonium-databases/CoreProtect-database.sqlite"));
+
+        let target = temp_dir.path().join("restored");
+        crate::backup::restore::restore_bundle(&result.file_path, &target, &[]).unwrap();
+        let snapshot = rusqlite::Connection::open(target.join("limonium-databases/CoreProtect-database.sqlite")).unwrap();
+        let count: i64 = snapshot.query_row("SELECT COUNT(*) FROM blocks", [], |row| row.get(0)).unwrap();
+        assert_eq!(count, 2);
+    }
+}
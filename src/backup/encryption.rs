@@ -0,0 +1,262 @@
+use std::fs;
+use std::fs::File;
+use std::io::{BufRead, BufReader, BufWriter, Error, ErrorKind, Read, Write};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+use age::secrecy::SecretString;
+use age::{x25519, Decryptor, Encryptor, Identity, IdentityFile, Recipient};
+
+use crate::backup::archive::HashingWriter;
+use crate::sftp::check_key_file_permissions;
+
+/// The first line of the encrypted data, it records the hash and name of the plaintext bundle.
+/// Example: "limonium-sha256 <hash> hub-11-15-2023-1-bundle.tar.zst"
+const HEADER_PREFIX: &str = "limonium-sha256";
+
+/// What a bundle is encrypted to, age public keys (age1...) or a passphrase.
+pub enum EncryptionKey {
+    Recipients(Vec<x25519::Recipient>),
+    Passphrase(SecretString),
+}
+
+impl EncryptionKey {
+    /// Each value of --encrypt-to is an age public key, or a file with one public key per line (# starts a comment).
+    pub fn from_recipients(values: &[String]) -> Result<EncryptionKey, Error> {
+        let mut recipients = Vec::new();
+        for value in values {
+            if value.starts_with("age1") {
+                recipients.push(parse_recipient(value)?);
+                continue;
+            }
+
+            let contents = fs::read_to_string(value)
+                .map_err(|e| Error::new(e.kind(), format!("{} is not an age public key, and it could not be read as a recipients file: {}", value, e)))?;
+            for line in contents.lines().map(|line| line.trim()).filter(|line| !line.is_empty() && !line.starts_with('#')) {
+                recipients.push(parse_recipient(line)?);
+            }
+        }
+
+        if recipients.is_empty() {
+            return Err(Error::new(ErrorKind::InvalidInput, "No age public keys were given to encrypt to"));
+        }
+        Ok(EncryptionKey::Recipients(recipients))
+    }
+
+    pub fn from_passphrase_file(path: &Path) -> Result<EncryptionKey, Error> {
+        Ok(EncryptionKey::Passphrase(read_passphrase_file(path)?))
+    }
+}
+
+fn parse_recipient(value: &str) -> Result<x25519::Recipient, Error> {
+    x25519::Recipient::from_str(value)
+        .map_err(|e| Error::new(ErrorKind::InvalidInput, format!("Invalid age public key {}: {}", value, e)))
+}
+
+/// Reads the first line of a passphrase file, like the key files it must not be readable by other users.
+fn read_passphrase_file(path: &Path) -> Result<SecretString, Error> {
+    check_key_file_permissions(path)?;
+    let contents = fs::read_to_string(path)?;
+    let passphrase = contents.lines().next().unwrap_or_default();
+    if passphrase.is_empty() {
+        return Err(Error::new(ErrorKind::InvalidInput, format!("The passphrase file {} is empty", path.display())));
+    }
+    Ok(SecretString::from(passphrase.to_string()))
+}
+
+/// True for encrypted bundles like "hub-11-15-2023-1-bundle.tar.zst.age".
+pub(crate) fn is_encrypted(file_name: &str) -> bool {
+    file_name.ends_with(".age")
+}
+
+pub struct EncryptResult {
+    pub file_path: PathBuf,
+    pub sha256_hash: String,
+}
+
+/// Encrypts a bundle into "<bundle>.age" next to it, and deletes the plaintext bundle.
+/// `plaintext_sha256` is recorded inside the encrypted file, the returned hash is the one of the encrypted file.
+pub fn encrypt_bundle(bundle_path: &Path, plaintext_sha256: &str, key: &EncryptionKey) -> Result<EncryptResult, Error> {
+    let bundle_file_name = bundle_path.file_name().and_then(|name| name.to_str())
+        .ok_or_else(|| Error::new(ErrorKind::InvalidInput, format!("{} is not a bundle", bundle_path.display())))?;
+    let encrypted_path = bundle_path.with_file_name(format!("{}.age", bundle_file_name));
+    let tmp_path = bundle_path.with_file_name(format!("{}.age.tmp", bundle_file_name));
+
+    let encryptor = match key {
+        EncryptionKey::Recipients(recipients) => Encryptor::with_recipients(recipients.iter().map(|recipient| recipient as &dyn Recipient))
+            .map_err(|e| Error::other(format!("Could not encrypt the bundle: {}", e)))?,
+        EncryptionKey::Passphrase(passphrase) => Encryptor::with_user_passphrase(passphrase.clone()),
+    };
+
+    let output = HashingWriter::new(BufWriter::new(File::create(&tmp_path)?));
+    let result = (|| {
+        let mut writer = encryptor.wrap_output(output)?;
+        writeln!(writer, "{} {} {}", HEADER_PREFIX, plaintext_sha256, bundle_file_name)?;
+        std::io::copy(&mut BufReader::new(File::open(bundle_path)?), &mut writer)?;
+        let (file, hash) = writer.finish()?.finish()?;
+        file.into_inner().map_err(|e| e.into_error())?.sync_all()?;
+        Ok(hash)
+    })();
+
+    let sha256_hash = match result {
+        Ok(hash) => hash,
+        Err(e) => {
+            let _ = fs::remove_file(&tmp_path);
+            return Err(e);
+        }
+    };
+
+    fs::rename(&tmp_path, &encrypted_path)?;
+    fs::remove_file(bundle_path)?;
+    Ok(EncryptResult { file_path: encrypted_path, sha256_hash })
+}
+
+/// How an encrypted bundle is decrypted, an age identity file (AGE-SECRET-KEY-...) or a passphrase file.
+pub enum DecryptionKey {
+    IdentityFile(PathBuf),
+    PassphraseFile(PathBuf),
+}
+
+impl DecryptionKey {
+    fn identities(&self) -> Result<Vec<Box<dyn Identity>>, Error> {
+        match self {
+            DecryptionKey::IdentityFile(path) => {
+                check_key_file_permissions(path)?;
+                IdentityFile::from_file(path.to_string_lossy().to_string())?
+                    .into_identities()
+                    .map_err(|e| Error::new(ErrorKind::InvalidInput, format!("Could not read the identity file {}: {}", path.display(), e)))
+            }
+            DecryptionKey::PassphraseFile(path) => Ok(vec![Box::new(age::scrypt::Identity::new(read_passphrase_file(path)?))]),
+        }
+    }
+}
+
+pub struct DecryptResult {
+    pub file_path: PathBuf,
+    pub sha256_hash: String,
+}
+
+/// Decrypts an encrypted bundle into `output_directory` under its original name, and checks it against
+/// the recorded hash. Nothing is left behind in `output_directory` if the hash doesn't match.
+pub fn decrypt_bundle(encrypted_path: &Path, output_directory: &Path, key: &DecryptionKey) -> Result<DecryptResult, Error> {
+    let identities = key.identities()?;
+    let decryptor = Decryptor::new_buffered(BufReader::new(File::open(encrypted_path)?))
+        .map_err(|e| Error::new(ErrorKind::InvalidData, format!("{} is not an age encrypted file: {}", encrypted_path.display(), e)))?;
+    let mut reader = BufReader::new(decryptor.decrypt(identities.iter().map(|identity| identity.as_ref() as &dyn Identity))
+        .map_err(|e| Error::new(ErrorKind::PermissionDenied, format!("Could not decrypt {}: {}", encrypted_path.display(), e)))?);
+
+    let (recorded_sha256, bundle_file_name) = read_header(&mut reader)?;
+    let file_path = output_directory.join(&bundle_file_name);
+    if file_path.exists() {
+        return Err(Error::new(ErrorKind::AlreadyExists, format!("{} already exists", file_path.display())));
+    }
+
+    let mut output = HashingWriter::new(BufWriter::new(File::create(&file_path)?));
+    let result = std::io::copy(&mut reader, &mut output).and_then(|_| output.finish());
+    let sha256_hash = match result {
+        Ok((_, hash)) if hash == recorded_sha256 => hash,
+        Ok((_, hash)) => {
+            let _ = fs::remove_file(&file_path);
+            return Err(Error::new(ErrorKind::InvalidData, format!("The decrypted bundle has the hash {}, but {} was recorded", hash, recorded_sha256)));
+        }
+        Err(e) => {
+            let _ = fs::remove_file(&file_path);
+            return Err(e);
+        }
+    };
+
+    Ok(DecryptResult { file_path, sha256_hash })
+}
+
+/// Reads "limonium-sha256 <hash> <bundle file name>" from the start of the decrypted data.
+fn read_header(reader: &mut impl BufRead) -> Result<(String, String), Error> {
+    let mut line = String::new();
+    reader.take(512).read_line(&mut line)?;
+
+    let mut parts = line.trim_end().splitn(3, ' ');
+    match (parts.next(), parts.next(), parts.next()) {
+        (Some(HEADER_PREFIX), Some(hash), Some(file_name))
+            if hash.len() == 64 && !file_name.is_empty() && Path::new(file_name).file_name() == Some(file_name.as_ref()) => {
+            Ok((hash.to_string(), file_name.to_string()))
+        }
+        _ => Err(Error::new(ErrorKind::InvalidData, "The decrypted file does not start with a limonium-sha256 line, it wasn't encrypted by limonium")),
+    }
+}
+
+#[cfg(test)]
+mod encryption_testing {
+    use super::*;
+    use crate::backup::archive::sha256_of_file;
+    use std::os::unix::fs::PermissionsExt;
+
+    fn write_bundle(directory: &Path) -> (PathBuf, String) {
+        let bundle_path = directory.join("hub-11-15-2023-1-bundle.tar.gz");
+        fs::write(&bundle_path, "usercache.json and banned-ips.json").unwrap();
+        let hash = sha256_of_file(&bundle_path).unwrap();
+        (bundle_path, hash)
+    }
+
+    fn write_secret(path: &Path, contents: &str) {
+        fs::write(path, contents).unwrap();
+        fs::set_permissions(path, fs::Permissions::from_mode(0o600)).unwrap();
+    }
+
+    #[test]
+    fn test_encrypt_and_decrypt_with_recipient() {
+        let directory = tempdir::TempDir::new("encryption").unwrap();
+        let (bundle_path, plaintext_sha256) = write_bundle(directory.path());
+
+        let identity = x25519::Identity::generate();
+        let key = EncryptionKey::from_recipients(&[identity.to_public().to_string()]).unwrap();
+        let encrypted = encrypt_bundle(&bundle_path, &plaintext_sha256, &key).unwrap();
+        assert!(!bundle_path.exists());
+        assert_eq!(encrypted.file_path, directory.path().join("hub-11-15-2023-1-bundle.tar.gz.age"));
+        assert_eq!(encrypted.sha256_hash, sha256_of_file(&encrypted.file_path).unwrap());
+        assert!(!String::from_utf8_lossy(&fs::read(&encrypted.file_path).unwrap()).contains("usercache"));
+
+        let identity_path = directory.path().join("key.txt");
+        write_secret(&identity_path, &format!("# test key\n{}\n", age::secrecy::ExposeSecret::expose_secret(&identity.to_string())));
+        let output_directory = directory.path().join("out");
+        fs::create_dir(&output_directory).unwrap();
+        let decrypted = decrypt_bundle(&encrypted.file_path, &output_directory, &DecryptionKey::IdentityFile(identity_path)).unwrap();
+        assert_eq!(decrypted.file_path, output_directory.join("hub-11-15-2023-1-bundle.tar.gz"));
+        assert_eq!(decrypted.sha256_hash, plaintext_sha256);
+        assert_eq!(fs::read_to_string(&decrypted.file_path).unwrap(), "usercache.json and banned-ips.json");
+
+        // Decrypting with another key fails without writing anything
+        let other_path = directory.path().join("other.txt");
+        write_secret(&other_path, age::secrecy::ExposeSecret::expose_secret(&x25519::Identity::generate().to_string()));
+        let other_directory = directory.path().join("other");
+        fs::create_dir(&other_directory).unwrap();
+        assert!(decrypt_bundle(&encrypted.file_path, &other_directory, &DecryptionKey::IdentityFile(other_path)).is_err());
+        assert_eq!(fs::read_dir(&other_directory).unwrap().count(), 0);
+    }
+
+    #[test]
+    fn test_decrypt_checks_recorded_hash() {
+        let directory = tempdir::TempDir::new("encryption").unwrap();
+        let (bundle_path, _) = write_bundle(directory.path());
+
+        let passphrase_path = directory.path().join("passphrase");
+        write_secret(&passphrase_path, "correct horse battery staple\n");
+        let key = EncryptionKey::from_passphrase_file(&passphrase_path).unwrap();
+        let encrypted = encrypt_bundle(&bundle_path, &"0".repeat(64), &key).unwrap();
+
+        let error = decrypt_bundle(&encrypted.file_path, directory.path(), &DecryptionKey::PassphraseFile(passphrase_path)).err().unwrap();
+        assert_eq!(error.kind(), ErrorKind::InvalidData);
+        assert!(!bundle_path.exists());
+    }
+
+    #[test]
+    fn test_read_header() {
+        let hash = "a".repeat(64);
+        let header = format!("limonium-sha256 {} hub-11-15-2023-1-bundle.zip\nPK", hash);
+        let mut reader = header.as_bytes();
+        assert_eq!(read_header(&mut reader).unwrap(), (hash.clone(), "hub-11-15-2023-1-bundle.zip".to_string()));
+        assert_eq!(reader, b"PK");
+
+        assert!(read_header(&mut format!("limonium-sha256 {} ../../etc/passwd\n", hash).as_bytes()).is_err());
+        assert!(read_header(&mut "PK\u{3}\u{4}".as_bytes()).is_err());
+        assert!(EncryptionKey::from_recipients(&["age1notakey".to_string()]).is_err());
+    }
+}
@@ -0,0 +1,250 @@
+use std::cmp::Reverse;
+use std::collections::HashMap;
+use std::fs::{self, File};
+use std::io::{Error, ErrorKind, Read, Write};
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+
+use colored::Colorize;
+use flate2::Compression;
+
+use crate::backup::archive::ArchiveEntry;
+use crate::backup::list::human_size;
+use crate::backup::BackupFormat;
+
+/// How much of a sampled file is compressed
+const SAMPLE_BYTES: u64 = 256 * 1024;
+const SAMPLES_PER_EXTENSION: usize = 3;
+/// Only the extensions with the most bytes are sampled, the others get the average ratio of the samples
+const SAMPLED_EXTENSIONS: usize = 16;
+/// The tar header of an entry (zip's local header and central directory entry are about the same)
+const ENTRY_OVERHEAD: u64 = 512;
+/// The estimate is a guess, this much more free space is wanted on top of it
+const MARGIN_PERCENT: u64 = 10;
+const MARGIN_BYTES: u64 = 16 * 1024 * 1024;
+
+/// What a backup would archive, from walking the sources with the excludes applied.
+#[derive(Debug)]
+pub struct SizeEstimate {
+    pub file_count: u64,
+    pub folder_count: u64,
+    /// The size of the files before compression
+    pub total_bytes: u64,
+    /// The estimated size of the compressed archive
+    pub archive_bytes: u64,
+    /// The excluded files and folders, the contents of an excluded folder aren't listed
+    pub excluded: Vec<PathBuf>,
+}
+
+impl SizeEstimate {
+    /// The free space a backup needs in one directory for `copies` of the archive, with a margin for a bad guess.
+    pub fn needed_space(&self, copies: u64) -> u64 {
+        let archive = self.archive_bytes.saturating_add(self.archive_bytes / 100 * MARGIN_PERCENT).saturating_add(MARGIN_BYTES);
+        archive.saturating_mul(copies)
+    }
+}
+
+/// Estimates the archive size by compressing the start of a few files of every common extension with the
+/// compressor of the format, the extensions are scaled by the ratio of their samples. -I isn't used for this.
+pub(crate) fn estimate(format: &BackupFormat, compression_level: Option<i64>, entries: &[ArchiveEntry], excluded: Vec<PathBuf>) -> Result<SizeEstimate, Error> {
+    let mut file_count = 0;
+    let mut folder_count = 0;
+    let mut total_bytes = 0;
+    let mut extensions: HashMap<String, (u64, Vec<&Path>)> = HashMap::new();
+
+    for entry in entries {
+        let metadata = fs::symlink_metadata(&entry.disk_path)?;
+        if metadata.is_dir() {
+            folder_count += 1;
+        } else if metadata.is_file() {
+            file_count += 1;
+            total_bytes += metadata.len();
+
+            let extension = entry.archive_name.extension().map(|extension| extension.to_string_lossy().to_lowercase()).unwrap_or_default();
+            let (bytes, files) = extensions.entry(extension).or_default();
+            *bytes += metadata.len();
+            files.push(&entry.disk_path);
+        }
+    }
+
+    let mut extensions: Vec<_> = extensions.into_values().collect();
+    extensions.sort_by_key(|(bytes, _)| Reverse(*bytes));
+
+    let mut archive_bytes = entries.len() as u64 * ENTRY_OVERHEAD + 2 * ENTRY_OVERHEAD;
+    let mut sampled = (0, 0);
+    let mut unsampled_bytes = 0;
+    for (index, (bytes, files)) in extensions.iter().enumerate() {
+        if index < SAMPLED_EXTENSIONS {
+            let (raw, compressed) = sample_extension(format, compression_level, files)?;
+            if raw > 0 {
+                archive_bytes += scale(*bytes, compressed, raw);
+                sampled = (sampled.0 + raw, sampled.1 + compressed);
+                continue;
+            }
+        }
+        unsampled_bytes += bytes;
+    }
+
+    archive_bytes += match sampled {
+        (0, _) => unsampled_bytes,
+        (raw, compressed) => scale(unsampled_bytes, compressed, raw),
+    };
+
+    Ok(SizeEstimate { file_count, folder_count, total_bytes, archive_bytes, excluded })
+}
+
+fn scale(bytes: u64, compressed: u64, raw: u64) -> u64 {
+    (bytes as u128 * compressed as u128 / raw as u128) as u64
+}
+
+/// Compresses the start of a few files spread over the (sorted) list, returns the bytes read and their compressed size.
+fn sample_extension(format: &BackupFormat, compression_level: Option<i64>, files: &[&Path]) -> Result<(u64, u64), Error> {
+    let count = files.len().min(SAMPLES_PER_EXTENSION);
+    let mut raw = 0;
+    let mut compressed = 0;
+
+    for sample in 0..count {
+        let mut data = Vec::new();
+        File::open(files[sample * files.len() / count])?.take(SAMPLE_BYTES).read_to_end(&mut data)?;
+        raw += data.len() as u64;
+        compressed += compressed_size(format, compression_level, &data)?;
+    }
+
+    Ok((raw, compressed))
+}
+
+fn compressed_size(format: &BackupFormat, compression_level: Option<i64>, data: &[u8]) -> Result<u64, Error> {
+    match format {
+        BackupFormat::TarGz | BackupFormat::Zip => {
+            let level = compression_level.map(|level| Compression::new(level as u32)).unwrap_or_default();
+            let mut encoder = flate2::write::DeflateEncoder::new(Vec::new(), level);
+            encoder.write_all(data)?;
+            Ok(encoder.finish()?.len() as u64)
+        }
+        BackupFormat::TarZst => {
+            let level = compression_level.unwrap_or(zstd::DEFAULT_COMPRESSION_LEVEL as i64) as i32;
+            Ok(zstd::bulk::compress(data, level)?.len() as u64)
+        }
+    }
+}
+
+/// The space that can be written to the filesystem of `path`, by a user that isn't root.
+pub(crate) fn free_space(path: &Path) -> Result<u64, Error> {
+    let stats = nix::sys::statvfs::statvfs(path)
+        .map_err(|e| Error::other(format!("Could not read the free space of {}: {}", path.display(), e)))?;
+    Ok(stats.blocks_available() as u64 * stats.fragment_size() as u64)
+}
+
+/// Checks there is room for the archive in the temporary directory and for the bundle in the backup directory.
+/// The archive is only deleted after it's copied into the bundle, so on one filesystem both have to fit.
+pub(crate) fn check_free_space(estimate: &SizeEstimate, backup_directory: &Path, tmp_directory: &Path) -> Result<(), Error> {
+    let same_filesystem = fs::metadata(backup_directory)?.dev() == fs::metadata(tmp_directory)?.dev();
+    let checks = if same_filesystem {
+        vec![(backup_directory, estimate.needed_space(2))]
+    } else {
+        vec![(tmp_directory, estimate.needed_space(1)), (backup_directory, estimate.needed_space(1))]
+    };
+
+    for (directory, needed) in checks {
+        let free = free_space(directory)?;
+        if free < needed {
+            return Err(Error::new(
+                ErrorKind::StorageFull,
+                format!(
+                    "Not enough free space in {}: the backup needs about {} (the archive is estimated at {}{}) and {} is free. \
+                    Free up some space, or use --no-space-check if the estimate is wrong.",
+                    directory.display(),
+                    human_size(needed),
+                    human_size(estimate.archive_bytes),
+                    if same_filesystem { " and is written twice while the bundle is made" } else { "" },
+                    human_size(free)
+                )
+            ));
+        }
+    }
+
+    Ok(())
+}
+
+/// Prints the estimate of `backup --dry-run`, with the free space of the backup directory (or of the folder it would be created in).
+pub fn print_estimate(estimate: &SizeEstimate, format: &BackupFormat, backup_directory: &Path) {
+    println!(
+        "{} {} {}",
+        "Files to back up:".green(),
+        estimate.file_count.to_string().bright_yellow(),
+        format!("({} in {} folders)", human_size(estimate.total_bytes), estimate.folder_count).bright_cyan()
+    );
+    println!("{} {}", format!("Estimated {} archive size:", format.extension()).green(), human_size(estimate.archive_bytes).bright_yellow());
+
+    let existing_directory = backup_directory.ancestors().find(|directory| directory.exists());
+    if let Some(free) = existing_directory.and_then(|directory| free_space(directory).ok()) {
+        let needed = estimate.needed_space(2);
+        let free_text = format!("{} (about {} needed)", human_size(free), human_size(needed));
+        println!(
+            "{} {}",
+            format!("Free space in {}:", backup_directory.display()).green(),
+            if free < needed { free_text.bright_red() } else { free_text.bright_yellow() }
+        );
+    }
+
+    if estimate.excluded.is_empty() {
+        println!("{}", "Nothing is excluded".green());
+    } else {
+        println!("{} {}", "Excluded:".green(), estimate.excluded.len().to_string().bright_yellow());
+        for path in &estimate.excluded {
+            println!("  {}", path.display());
+        }
+    }
+}
+
+#[cfg(test)]
+mod estimate_testing {
+    use super::*;
+
+    use crate::backup::archive::{collect_entries_and_excluded, write_archive, ExcludeMatcher};
+
+    #[test]
+    fn test_estimate() {
+        let temp_dir = tempdir::TempDir::new("backup-estimate").unwrap();
+        let world = temp_dir.path().join("world");
+        fs::create_dir_all(world.join("region")).unwrap();
+        fs::create_dir_all(world.join("logs")).unwrap();
+        for index in 0..5 {
+            fs::write(world.join(format!("region/r.{}.0.mca", index)), "chunk data ".repeat(20000 + index * 1000)).unwrap();
+        }
+        fs::write(world.join("level.dat"), (0..200000u32).map(|_| fastrand::u8(..)).collect::<Vec<u8>>()).unwrap();
+        fs::write(world.join("logs/latest.log"), b"log").unwrap();
+
+        let excludes = ExcludeMatcher::new(Some("logs")).unwrap();
+        let (entries, excluded) = collect_entries_and_excluded(&world.to_string_lossy(), &excludes).unwrap();
+        assert_eq!(excluded.len(), 1);
+        assert!(excluded[0].ends_with("world/logs"));
+
+        for format in [BackupFormat::TarGz, BackupFormat::TarZst, BackupFormat::Zip] {
+            let estimate = estimate(&format, None, &entries, excluded.clone()).unwrap();
+            assert_eq!((estimate.file_count, estimate.folder_count), (6, 2));
+            assert_eq!(estimate.total_bytes, fs::read_dir(world.join("region")).unwrap().map(|file| file.unwrap().metadata().unwrap().len()).sum::<u64>() + 200000);
+
+            // The random level.dat doesn't compress and the region files do, the estimate has to be close to the real archive
+            let output = temp_dir.path().join(format!("world.{}", format.extension()));
+            write_archive(&format, None, None, &entries, &output, false).unwrap();
+            let real = fs::metadata(&output).unwrap().len();
+            assert!(estimate.archive_bytes > real * 8 / 10 && estimate.archive_bytes < real * 13 / 10, "{} estimated {} real {}", format.extension(), estimate.archive_bytes, real);
+        }
+    }
+
+    #[test]
+    fn test_check_free_space() {
+        let temp_dir = tempdir::TempDir::new("backup-free-space").unwrap();
+        let tmp_directory = temp_dir.path().join(".lmtmp");
+        fs::create_dir_all(&tmp_directory).unwrap();
+
+        let mut estimate = SizeEstimate { file_count: 1, folder_count: 0, total_bytes: 1024, archive_bytes: 512, excluded: Vec::new() };
+        check_free_space(&estimate, temp_dir.path(), &tmp_directory).unwrap();
+
+        estimate.archive_bytes = u64::MAX / 4;
+        let error = check_free_space(&estimate, temp_dir.path(), &tmp_directory).err().unwrap();
+        assert_eq!(error.kind(), ErrorKind::StorageFull);
+        assert!(error.to_string().contains("written twice"));
+    }
+}
@@ -0,0 +1,187 @@
+use std::collections::BTreeMap;
+use std::fs;
+use std::io::Error;
+use std::path::Path;
+
+use chrono::NaiveDateTime;
+use colored::Colorize;
+
+use crate::backup::{extract_date_from_file_name, naming, volume, BundleFileName};
+
+/// One backup bundle and every destination that holds a copy of it.
+pub struct ListedBackup {
+    pub file_name: String,
+    pub date: String,
+    pub number: u64,
+    pub format: String,
+    pub size: u64,
+    /// How many volumes a split bundle has, 0 when it isn't split
+    pub volumes: usize,
+    pub locations: Vec<String>,
+}
+
+impl ListedBackup {
+    fn sort_key(&self) -> (Option<NaiveDateTime>, u64) {
+        (naming::parse_date_time(&self.date), self.number)
+    }
+}
+
+/// The (file name, size) of every file in a local backup directory.
+pub fn local_files_with_sizes(backup_directory: &Path) -> Result<Vec<(String, u64)>, Error> {
+    let mut files = Vec::new();
+    for entry in fs::read_dir(backup_directory)? {
+        let entry = entry?;
+        let metadata = entry.metadata()?;
+        if !metadata.is_file() {
+            continue;
+        }
+
+        if let Some(file_name) = entry.file_name().to_str() {
+            files.push((file_name.to_string(), metadata.len()));
+        }
+    }
+    Ok(files)
+}
+
+/// Merges the files of every destination into one list of the bundles of `name`, oldest first.
+/// `destinations` is a list of (location, files) like ("sftp", [("hub-11-15-2023-1-bundle.tar.zst", 1024)]).
+/// The volumes of a split bundle are listed as the bundle, only complete sets (with their hash file) are listed.
+pub fn merge_listings(name: &str, destinations: &[(String, Vec<(String, u64)>)]) -> Vec<ListedBackup> {
+    let mut backups: BTreeMap<String, ListedBackup> = BTreeMap::new();
+
+    for (location, files) in destinations {
+        // (size, volumes, has the hash file) of every bundle in this location
+        let mut bundles: BTreeMap<&str, (u64, usize, bool)> = BTreeMap::new();
+        for (file_name, size) in files {
+            let set_name = volume::set_name(file_name);
+            let bundle = bundles.entry(set_name).or_insert((0, 0, set_name == file_name));
+            if file_name.ends_with(volume::HASH_FILE_SUFFIX) && set_name != file_name {
+                bundle.2 = true;
+            } else {
+                bundle.0 += size;
+                bundle.1 += usize::from(set_name != file_name);
+            }
+        }
+
+        for (file_name, (size, volumes, complete)) in bundles {
+            let bundle = match BundleFileName::parse(file_name) {
+                Some(bundle) if bundle.name == name && complete => bundle,
+                _ => continue,
+            };
+
+            let listed = backups.entry(file_name.to_string()).or_insert_with(|| ListedBackup {
+                file_name: file_name.to_string(),
+                date: extract_date_from_file_name(&file_name.to_string()),
+                number: bundle.number,
+                format: bundle.extension.clone(),
+                size,
+                volumes,
+                locations: Vec::new(),
+            });
+            if !listed.locations.contains(location) {
+                listed.locations.push(location.clone());
+            }
+        }
+    }
+
+    let mut backups: Vec<ListedBackup> = backups.into_values().collect();
+    backups.sort_by_key(|backup| backup.sort_key());
+    backups
+}
+
+pub(crate) fn human_size(size: u64) -> String {
+    let size_mb = size as f64 / 1024.0 / 1024.0;
+    if size_mb >= 1024.0 {
+        format!("{:.2} GB", size_mb / 1024.0)
+    } else {
+        format!("{:.2} MB", size_mb)
+    }
+}
+
+/// Prints the backups as a table. When more than one destination was listed,
+/// backups that only exist in one of them are flagged.
+pub fn print_listing(backups: &[ListedBackup], destination_count: usize) {
+    if backups.is_empty() {
+        println!("{}", "No backups found!".yellow());
+        return;
+    }
+
+    println!(
+        "{}",
+        format!("{:<17} {:>3} {:<8} {:>12}  {:<20} {}", "Date", "#", "Format", "Size", "Destinations", "File").bold()
+    );
+    for backup in backups {
+        let line = format!(
+            "{:<17} {:>3} {:<8} {:>12}  {:<20} {}",
+            backup.date,
+            backup.number,
+            backup.format,
+            human_size(backup.size),
+            backup.locations.join(", "),
+            if backup.volumes > 0 { format!("{} ({} volumes)", backup.file_name, backup.volumes) } else { backup.file_name.clone() }
+        );
+
+        if destination_count > 1 && backup.locations.len() == 1 {
+            println!("{} {}", line.yellow(), "(only in one place)".red().bold());
+        } else {
+            println!("{}", line.green());
+        }
+    }
+
+    let single_copy = backups.iter().filter(|backup| backup.locations.len() == 1).count();
+    if destination_count > 1 && single_copy > 0 {
+        println!(
+            "{} {}",
+            format!("{} backups", backups.len()).green().bold(),
+            format!("({} only in one place)", single_copy).red()
+        );
+    } else {
+        println!("{}", format!("{} backups", backups.len()).green().bold());
+    }
+}
+
+#[cfg(test)]
+mod list_testing {
+    use super::*;
+
+    #[test]
+    fn test_merge_listings() {
+        let destinations = vec![
+            ("local".to_string(), vec![
+                ("hub-2023-11-15T180000-1-bundle.zip".to_string(), 50),
+                ("hub-11-15-2023-2-bundle.tar.zst".to_string(), 200),
+                ("hub-11-15-2023-1-bundle.tar.zst".to_string(), 100),
+                ("hub-everything-11-15-2023-1-bundle.tar.zst".to_string(), 5),
+                ("notes.txt".to_string(), 1),
+            ]),
+            ("sftp".to_string(), vec![
+                ("hub-11-15-2023-1-bundle.tar.zst".to_string(), 100),
+                ("hub-9-1-2023-1-bundle.zip".to_string(), 300),
+                ("hub-11-16-2023-1-bundle.tar.zst.001".to_string(), 1000),
+                ("hub-11-16-2023-1-bundle.tar.zst.002".to_string(), 400),
+                ("hub-11-16-2023-1-bundle.tar.zst.sha256".to_string(), 1),
+                ("hub-11-17-2023-1-bundle.tar.zst.001".to_string(), 1000),
+            ]),
+        ];
+
+        let backups = merge_listings("hub", &destinations);
+        let names: Vec<&str> = backups.iter().map(|backup| backup.file_name.as_str()).collect();
+        assert_eq!(names, vec![
+            "hub-9-1-2023-1-bundle.zip",
+            "hub-11-15-2023-1-bundle.tar.zst",
+            "hub-11-15-2023-2-bundle.tar.zst",
+            "hub-2023-11-15T180000-1-bundle.zip",
+            "hub-11-16-2023-1-bundle.tar.zst",
+        ]);
+
+        assert_eq!(backups[0].locations, vec!["sftp".to_string()]);
+        assert_eq!(backups[0].format, "zip");
+        assert_eq!(backups[1].locations, vec!["local".to_string(), "sftp".to_string()]);
+        assert_eq!(backups[1].date, "11-15-2023");
+        assert_eq!(backups[2].number, 2);
+        assert_eq!(backups[2].size, 200);
+        assert_eq!(backups[3].date, "2023-11-15T180000");
+        // A split bundle is one backup, one without its hash file is still being uploaded
+        assert_eq!((backups[4].size, backups[4].volumes), (1400, 2));
+    }
+}
@@ -0,0 +1,146 @@
+use std::fs::{File, OpenOptions, TryLockError};
+use std::io::{Error, ErrorKind, Read, Seek, SeekFrom, Write};
+use std::path::Path;
+use std::process;
+
+use colored::Colorize;
+
+use crate::backup::manifest;
+
+/// The lock file in the backup directory, it stays there between backups and is empty while no backup runs.
+pub(crate) const LOCK_FILE_NAME: &str = ".limonium.lock";
+
+/// Who holds the lock, written into the lock file.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct LockOwner {
+    pub pid: u32,
+    pub host: String,
+    pub started: String,
+}
+
+impl LockOwner {
+    fn current() -> LockOwner {
+        LockOwner {
+            pid: process::id(),
+            host: manifest::host_name(),
+            started: chrono::Local::now().to_rfc3339(),
+        }
+    }
+
+    /// Only a process on this machine can be checked, one on another machine is taken to be running.
+    fn is_alive(&self) -> bool {
+        self.host != manifest::host_name() || Path::new("/proc").join(self.pid.to_string()).exists()
+    }
+}
+
+/// An advisory lock on a backup directory, so two backups never write into it at the same time.
+/// The lock is released when this is dropped, and by the system when the process dies.
+pub struct BackupLock {
+    file: File,
+}
+
+impl BackupLock {
+    /// Takes the lock of `backup_directory`, or fails when another backup holds it.
+    /// A lock left behind by a backup that didn't finish (its process is gone) is taken over.
+    pub fn acquire(backup_directory: &Path) -> Result<BackupLock, Error> {
+        let path = backup_directory.join(LOCK_FILE_NAME);
+        let mut file = OpenOptions::new().read(true).write(true).create(true).truncate(false).open(&path)
+            .map_err(|e| Error::new(e.kind(), format!("Could not open the lock file {}: {}", path.display(), e)))?;
+        let previous = read_owner(&mut file);
+
+        match file.try_lock() {
+            Ok(()) => {}
+            Err(TryLockError::WouldBlock) => return Err(another_backup_error(previous.as_ref(), &path)),
+            // Some network filesystems don't support locks, the PID in the file is all there is to go on
+            Err(TryLockError::Error(_)) => {
+                if previous.as_ref().is_some_and(LockOwner::is_alive) {
+                    return Err(another_backup_error(previous.as_ref(), &path));
+                }
+            }
+        }
+
+        if let Some(previous) = &previous {
+            println!(
+                "{} {}",
+                "Removed the lock of a backup that didn't finish".yellow(),
+                format!("(PID {} on {}, started at {})", previous.pid, previous.host, previous.started).bright_yellow()
+            );
+        }
+
+        let json = serde_json::to_string(&LockOwner::current()).map_err(Error::other)?;
+        file.set_len(0)?;
+        file.seek(SeekFrom::Start(0))?;
+        file.write_all(json.as_bytes())?;
+        file.sync_all()?;
+        Ok(BackupLock { file })
+    }
+}
+
+impl Drop for BackupLock {
+    fn drop(&mut self) {
+        // The file is emptied instead of deleted, deleting it would let a waiting backup lock a file nobody else sees
+        let _ = self.file.set_len(0);
+        let _ = self.file.unlock();
+    }
+}
+
+fn read_owner(file: &mut File) -> Option<LockOwner> {
+    let mut contents = String::new();
+    file.read_to_string(&mut contents).ok()?;
+    serde_json::from_str(&contents).ok()
+}
+
+fn another_backup_error(owner: Option<&LockOwner>, path: &Path) -> Error {
+    let owner = owner
+        .map(|owner| format!(" (PID {} on {}, started at {})", owner.pid, owner.host, owner.started))
+        .unwrap_or_default();
+    Error::new(
+        ErrorKind::ResourceBusy,
+        format!("Another backup is running in this backup directory{}. If it isn't, delete {} and try again.", owner, path.display())
+    )
+}
+
+#[cfg(test)]
+mod lock_testing {
+    use super::*;
+    use std::fs;
+
+    use crate::backup::{Backup, BackupFormat};
+
+    #[test]
+    fn test_lock() {
+        let temp_dir = tempdir::TempDir::new("backup-lock").unwrap();
+        let lock = BackupLock::acquire(temp_dir.path()).unwrap();
+        let owner: LockOwner = serde_json::from_str(&fs::read_to_string(temp_dir.path().join(LOCK_FILE_NAME)).unwrap()).unwrap();
+        assert_eq!(owner.pid, process::id());
+
+        let error = BackupLock::acquire(temp_dir.path()).err().unwrap();
+        assert_eq!(error.kind(), ErrorKind::ResourceBusy);
+        assert!(error.to_string().contains(&format!("PID {}", process::id())));
+
+        drop(lock);
+        assert_eq!(fs::read_to_string(temp_dir.path().join(LOCK_FILE_NAME)).unwrap(), "");
+        BackupLock::acquire(temp_dir.path()).unwrap();
+    }
+
+    #[test]
+    fn test_stale_lock() {
+        let temp_dir = tempdir::TempDir::new("backup-lock-stale").unwrap();
+        let server = temp_dir.path().join("server");
+        let backups = temp_dir.path().join("backups");
+        fs::create_dir_all(&server).unwrap();
+        fs::write(server.join("server.properties"), b"motd=hub").unwrap();
+
+        // A backup that was killed: its lock names a process that is gone and its temporary files are still there
+        fs::create_dir_all(backups.join(".lmtmp")).unwrap();
+        fs::write(backups.join(".lmtmp/hub-11-15-2023.tar.gz"), b"half written").unwrap();
+        let dead = LockOwner { pid: u32::MAX, host: manifest::host_name(), started: "2023-11-15T03:00:00+00:00".to_string() };
+        assert!(!dead.is_alive());
+        fs::write(backups.join(LOCK_FILE_NAME), serde_json::to_string(&dead).unwrap()).unwrap();
+
+        let backup = Backup::new("hub".to_string(), server.to_string_lossy().to_string(), backups.clone(), BackupFormat::TarGz, None, None);
+        backup.backup().unwrap();
+        assert!(!backups.join(".lmtmp").exists());
+        assert_eq!(fs::read_to_string(backups.join(LOCK_FILE_NAME)).unwrap(), "");
+    }
+}
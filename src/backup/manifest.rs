@@ -0,0 +1,248 @@
+use std::collections::BTreeMap;
+use std::fs;
+use std::io::{Error, ErrorKind};
+use std::path::{Path, PathBuf};
+
+use colored::Colorize;
+
+use crate::backup::{archive, encryption, BackupFormat};
+
+/// The manifest is stored in the bundle as "<name>-<date>_manifest.json", next to "_hash.txt".
+pub(crate) const MANIFEST_SUFFIX: &str = "_manifest.json";
+
+/// One regular file in the backup archive.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct ManifestFile {
+    /// The path inside the archive (example: "world/region/r.0.0.mca")
+    pub path: String,
+    pub size: u64,
+    /// Seconds since the Unix epoch
+    pub mtime: i64,
+    pub sha256: String,
+}
+
+/// What `Backup::backup` writes into the bundle next to the hash of the archive.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct Manifest {
+    pub limonium_version: String,
+    pub host: String,
+    pub created: String,
+    /// The backed up folders as absolute paths
+    pub sources: Vec<String>,
+    pub files: Vec<ManifestFile>,
+}
+
+impl Manifest {
+    /// A manifest for the sources (separated by a : (colon)) and the files that were archived.
+    pub fn new(sources: &str, files: Vec<ManifestFile>) -> Manifest {
+        Manifest {
+            limonium_version: env!("CARGO_PKG_VERSION").to_string(),
+            host: host_name(),
+            created: chrono::Local::now().to_rfc3339(),
+            sources: sources.split(':')
+                .map(|source| fs::canonicalize(source).unwrap_or_else(|_| PathBuf::from(source)).to_string_lossy().to_string())
+                .collect(),
+            files,
+        }
+    }
+
+    pub fn to_json(&self) -> Result<String, Error> {
+        serde_json::to_string_pretty(self).map_err(Error::other)
+    }
+
+    pub fn from_json(json: &str) -> Result<Manifest, Error> {
+        serde_json::from_str(json).map_err(|e| Error::new(ErrorKind::InvalidData, format!("Invalid manifest: {}", e)))
+    }
+}
+
+/// The name of this machine, recorded in manifests and notifications.
+pub(crate) fn host_name() -> String {
+    fs::read_to_string("/proc/sys/kernel/hostname").ok()
+        .or_else(|| std::env::var("HOSTNAME").ok())
+        .map(|host| host.trim().to_string())
+        .filter(|host| !host.is_empty())
+        .unwrap_or_else(|| "unknown".to_string())
+}
+
+/// The files of a bundle for `backup diff`. Bundles made before manifests existed have no `manifest`,
+/// their files are listed by reading the inner archive.
+pub struct BundleFiles {
+    pub manifest: Option<Manifest>,
+    pub files: Vec<ManifestFile>,
+}
+
+/// Reads the files of a bundle from its manifest, or from the inner archive (unpacked into `work_directory`) when it has none.
+pub fn read_bundle_files(bundle_path: &Path, work_directory: &Path) -> Result<BundleFiles, Error> {
+    let file_name = bundle_path.file_name().and_then(|name| name.to_str()).unwrap_or_default();
+    if encryption::is_encrypted(file_name) {
+        return Err(Error::new(ErrorKind::InvalidInput, format!("{} is encrypted, decrypt it with \"limonium backup decrypt\" first", bundle_path.display())));
+    }
+    let format = BackupFormat::from_file_name(file_name)
+        .ok_or_else(|| Error::new(ErrorKind::InvalidInput, format!("{} is not a tar.gz, tar.zst or zip bundle", bundle_path.display())))?;
+
+    if let Some(json) = archive::read_bundle_manifest(&format, bundle_path)? {
+        let manifest = Manifest::from_json(&json)?;
+        let files = manifest.files.clone();
+        return Ok(BundleFiles { manifest: Some(manifest), files });
+    }
+
+    println!("{} {}", file_name.bright_yellow(), "has no manifest (made with an older Limonium), reading the whole archive instead...".yellow());
+    if work_directory.exists() {
+        fs::remove_dir_all(work_directory)?;
+    }
+    fs::create_dir_all(work_directory)?;
+    let files = archive::unpack_bundle(&format, bundle_path, work_directory)
+        .and_then(|unpacked| archive::list_archive_files(&format, &unpacked.inner_path));
+    fs::remove_dir_all(work_directory)?;
+
+    Ok(BundleFiles { manifest: None, files: files? })
+}
+
+/// The differences between the files of two backups.
+#[derive(Debug, Default)]
+pub struct ManifestDiff {
+    pub added: Vec<ManifestFile>,
+    pub removed: Vec<ManifestFile>,
+    /// (before, after) for files whose contents changed
+    pub changed: Vec<(ManifestFile, ManifestFile)>,
+    pub unchanged: usize,
+}
+
+/// Compares the files of backup `a` with backup `b`, only the paths in `only` (and everything inside them) when it isn't empty.
+/// A file only counts as changed when its contents did, a new mtime alone doesn't.
+pub fn diff_files(a: &[ManifestFile], b: &[ManifestFile], only: &[PathBuf]) -> ManifestDiff {
+    let wanted = |file: &&ManifestFile| archive::is_wanted(&archive::archive_name(Path::new(&file.path)), only);
+    let a: BTreeMap<&str, &ManifestFile> = a.iter().filter(wanted).map(|file| (file.path.as_str(), file)).collect();
+    let b: BTreeMap<&str, &ManifestFile> = b.iter().filter(wanted).map(|file| (file.path.as_str(), file)).collect();
+
+    let mut diff = ManifestDiff::default();
+    for (path, before) in &a {
+        match b.get(path) {
+            None => diff.removed.push((*before).clone()),
+            Some(after) if before.sha256 != after.sha256 => diff.changed.push(((*before).clone(), (*after).clone())),
+            Some(_) => diff.unchanged += 1,
+        }
+    }
+    diff.added = b.iter()
+        .filter(|(path, _)| !a.contains_key(*path))
+        .map(|(_, file)| (*file).clone())
+        .collect();
+
+    diff
+}
+
+fn format_mtime(mtime: i64) -> String {
+    chrono::DateTime::from_timestamp(mtime, 0)
+        .map(|time| time.with_timezone(&chrono::Local).format("%Y-%m-%d %H:%M:%S").to_string())
+        .unwrap_or_else(|| mtime.to_string())
+}
+
+/// Prints where a bundle's manifest was made.
+pub fn print_manifest_details(file_name: &str, manifest: &Manifest) {
+    println!(
+        "{} {} {} {} {} {} {}",
+        file_name.bright_yellow(),
+        "made by Limonium".green(),
+        manifest.limonium_version.bright_cyan(),
+        "on".green(),
+        manifest.host.bright_cyan(),
+        "from".green(),
+        manifest.sources.join(", ").bright_cyan()
+    );
+}
+
+/// Prints the differences, + for added, - for removed and ~ for changed files.
+pub fn print_diff(diff: &ManifestDiff) {
+    for file in &diff.added {
+        println!("{}", format!("+ {} ({} bytes, modified {})", file.path, file.size, format_mtime(file.mtime)).green());
+    }
+    for file in &diff.removed {
+        println!("{}", format!("- {} ({} bytes, modified {})", file.path, file.size, format_mtime(file.mtime)).red());
+    }
+    for (before, after) in &diff.changed {
+        println!(
+            "{}",
+            format!(
+                "~ {} ({} -> {} bytes, modified {} -> {})",
+                before.path, before.size, after.size, format_mtime(before.mtime), format_mtime(after.mtime)
+            ).yellow()
+        );
+    }
+
+    println!(
+        "{} {} {} {} {} {} {} {}",
+        diff.added.len().to_string().bright_green(),
+        "added,".green(),
+        diff.removed.len().to_string().bright_red(),
+        "removed,".green(),
+        diff.changed.len().to_string().bright_yellow(),
+        "changed,".green(),
+        diff.unchanged.to_string().bright_cyan(),
+        "unchanged".green()
+    );
+}
+
+#[cfg(test)]
+mod manifest_testing {
+    use super::*;
+    use crate::backup::Backup;
+
+    fn file(path: &str, size: u64, sha256: &str) -> ManifestFile {
+        ManifestFile { path: path.to_string(), size, mtime: 1700000000, sha256: sha256.to_string() }
+    }
+
+    #[test]
+    fn test_diff_files() {
+        let a = vec![
+            file("world/level.dat", 5, "aa"),
+            file("world/region/r.0.0.mca", 4096, "bb"),
+            file("world/region/r.1.0.mca", 4096, "cc"),
+        ];
+        let mut touched = file("world/level.dat", 5, "aa");
+        touched.mtime += 60;
+        let b = vec![
+            touched,
+            file("world/region/r.0.0.mca", 8192, "dd"),
+            file("world/region/r.0.1.mca", 4096, "ee"),
+        ];
+
+        let diff = diff_files(&a, &b, &[]);
+        assert_eq!(diff.added, vec![file("world/region/r.0.1.mca", 4096, "ee")]);
+        assert_eq!(diff.removed, vec![file("world/region/r.1.0.mca", 4096, "cc")]);
+        assert_eq!(diff.changed.len(), 1);
+        assert_eq!(diff.changed[0].1.sha256, "dd");
+        // Only the mtime of level.dat changed
+        assert_eq!(diff.unchanged, 1);
+
+        let diff = diff_files(&a, &b, &[PathBuf::from("world/level.dat")]);
+        assert!(diff.added.is_empty() && diff.removed.is_empty() && diff.changed.is_empty());
+        assert_eq!(diff.unchanged, 1);
+    }
+
+    #[test]
+    fn test_backup_writes_manifest() {
+        let temp_dir = tempdir::TempDir::new("manifest-backup").unwrap();
+        let server = temp_dir.path().join("server");
+        fs::create_dir_all(server.join("world/region")).unwrap();
+        fs::write(server.join("world/level.dat"), b"level").unwrap();
+        fs::write(server.join("world/region/r.0.0.mca"), vec![7u8; 4096]).unwrap();
+
+        for format in [BackupFormat::TarGz, BackupFormat::Zip] {
+            let backups = temp_dir.path().join(format!("backups-{}", format.extension()));
+            let backup = Backup::new("hub".to_string(), server.to_string_lossy().to_string(), backups.clone(), format, None, None);
+            let result = backup.backup().unwrap();
+
+            let work_directory = temp_dir.path().join(".lmtmp-diff");
+            let bundle = read_bundle_files(&result.file_path, &work_directory).unwrap();
+            let manifest = bundle.manifest.unwrap();
+            assert_eq!(manifest.limonium_version, env!("CARGO_PKG_VERSION"));
+            assert_eq!(manifest.sources, vec![fs::canonicalize(&server).unwrap().to_string_lossy().to_string()]);
+            assert_eq!(bundle.files.len(), 2);
+
+            // The manifest doesn't get in the way of restoring
+            let format = BackupFormat::from_file_name(&result.file_name).unwrap();
+            let unpacked = archive::unpack_bundle(&format, &result.file_path, temp_dir.path()).unwrap();
+            assert!(unpacked.hash_matches());
+        }
+    }
+}
@@ -0,0 +1,538 @@
+use std::fs;
+use std::io::{Error, ErrorKind};
+use std::path::{Path, PathBuf};
+use std::sync::OnceLock;
+use std::time::{Duration, Instant};
+
+use crate::{clap_utils, world};
+use colored::Colorize;
+use indicatif::{HumanDuration, ProgressBar, ProgressStyle};
+use regex::Regex;
+
+pub(crate) mod archive;
+pub(crate) mod chunker;
+pub(crate) mod database;
+pub(crate) mod encryption;
+pub(crate) mod estimate;
+pub(crate) mod list;
+pub(crate) mod lock;
+pub(crate) mod manifest;
+pub(crate) mod naming;
+pub(crate) mod repository;
+pub(crate) mod restore;
+pub(crate) mod retention;
+pub(crate) mod verify;
+pub(crate) mod volume;
+
+#[derive(PartialEq)]
+pub enum BackupFormat {
+    TarGz,
+    TarZst,
+    Zip,
+}
+
+impl BackupFormat {
+    /// Works out the format from a file name like "hub-11-15-2023-1-bundle.tar.zst"
+    pub fn from_file_name(file_name: &str) -> Option<BackupFormat> {
+        if file_name.ends_with(".tar.gz") {
+            Some(BackupFormat::TarGz)
+        } else if file_name.ends_with(".tar.zst") {
+            Some(BackupFormat::TarZst)
+        } else if file_name.ends_with(".zip") {
+            Some(BackupFormat::Zip)
+        } else {
+            None
+        }
+    }
+
+    pub fn extension(&self) -> &'static str {
+        match self {
+            BackupFormat::TarGz => "tar.gz",
+            BackupFormat::TarZst => "tar.zst",
+            BackupFormat::Zip => "zip",
+        }
+    }
+
+    /// Checks the compression level is valid for this format (tar.gz 0-9) (tar.zst 1-22) (zip 0-9)
+    pub fn validate_compression_level(&self, compression_level: i64) -> Result<(), Error> {
+        let (min, max) = match self {
+            BackupFormat::TarGz => (0, 9),
+            BackupFormat::TarZst => (1, 22),
+            BackupFormat::Zip => (0, 9),
+        };
+
+        if !(min..=max).contains(&compression_level) {
+            return Err(Error::new(ErrorKind::InvalidInput, format!("The compression level for {} must be between {} and {}", self.extension(), min, max)));
+        }
+        Ok(())
+    }
+}
+
+pub struct BackupResult {
+    pub file_name: String,
+    pub file_path: PathBuf,
+    pub sha256_hash: String,
+}
+
+pub struct Backup {
+    name: String,
+    directory_to_backup: String,
+    backup_directory: PathBuf,
+    backup_format: BackupFormat,
+    exclude: Option<String>,
+    compression_level: Option<i64>,
+    naming: naming::NamingTemplate,
+    timezone: naming::BackupTimezone,
+    databases: Vec<database::DatabaseSource>,
+    check_space: bool,
+    skip_chunks_below: Option<i64>,
+}
+
+impl Backup {
+    pub fn new(name: String, directory_to_backup: String, backup_directory: PathBuf, backup_format: BackupFormat, exclude: Option<String>, compression_level: Option<i64>) -> Self {
+        Backup {
+            name,
+            directory_to_backup,
+            backup_directory,
+            backup_format,
+            exclude,
+            compression_level,
+            naming: naming::NamingTemplate::default(),
+            timezone: naming::BackupTimezone::default(),
+            databases: Vec::new(),
+            check_space: true,
+            skip_chunks_below: None,
+        }
+    }
+
+    /// Names the bundles with `naming`, with the date and time in `timezone`.
+    pub fn with_naming(mut self, naming: naming::NamingTemplate, timezone: naming::BackupTimezone) -> Self {
+        self.naming = naming;
+        self.timezone = timezone;
+        self
+    }
+
+    /// Dumps these databases into the backup archive, next to the files.
+    pub fn with_databases(mut self, databases: Vec<database::DatabaseSource>) -> Self {
+        self.databases = databases;
+        self
+    }
+
+    /// Turns the free space check before the archive is written on or off.
+    pub fn with_space_check(mut self, check_space: bool) -> Self {
+        self.check_space = check_space;
+        self
+    }
+
+    /// Leaves the chunks with an InhabitedTime below this many ticks out of the archived region files, the world isn't changed.
+    pub fn with_chunk_skipping(mut self, skip_chunks_below: Option<i64>) -> Self {
+        self.skip_chunks_below = skip_chunks_below;
+        self
+    }
+
+    /// Walks the sources like a backup would and prints what it would archive, nothing is written.
+    pub fn dry_run(&self) -> Result<estimate::SizeEstimate, Error> {
+        let excludes = archive::ExcludeMatcher::new(self.exclude.as_deref())?;
+        let (entries, excluded) = archive::collect_entries_and_excluded(&self.directory_to_backup, &excludes)?;
+        let size_estimate = estimate::estimate(&self.backup_format, self.compression_level, &entries, excluded)?;
+
+        estimate::print_estimate(&size_estimate, &self.backup_format, &self.backup_directory);
+        for database in &self.databases {
+            println!("{} {}", "Not dumped in a dry run, so not in the estimate:".yellow(), database.label.bright_yellow());
+        }
+        if self.skip_chunks_below.is_some() {
+            println!("{}", "Chunks aren't left out in a dry run, so the estimate includes them".yellow());
+        }
+        Ok(size_estimate)
+    }
+
+    /// Creates the backup directory if it does not exist and takes its lock, so no other backup changes its bundles
+    /// while it's held. A backup that does more with its bundle afterwards keeps it until then.
+    pub fn lock(&self) -> Result<lock::BackupLock, Error> {
+        if !self.backup_directory.exists() {
+            fs::create_dir_all(&self.backup_directory)?;
+            println!("The backup directory did not exist, so it was created at {}", &self.backup_directory.display());
+        }
+        lock::BackupLock::acquire(&self.backup_directory)
+    }
+
+    /// Locks the backup directory for just this backup, `limonium backup` holds the lock for the whole run instead.
+    #[cfg(test)]
+    pub fn backup(&self) -> Result<BackupResult, Error> {
+        let lock = self.lock()?;
+        self.backup_holding(&lock)
+    }
+
+    /// Makes the backup while the caller holds the lock of the backup directory from `lock`.
+    pub fn backup_holding(&self, _lock: &lock::BackupLock) -> Result<BackupResult, Error> {
+        let now = self.timezone.now();
+        let timestamp = self.naming.format_date(now);
+
+        // The extension of the backup archive
+        let extension = self.backup_format.extension();
+
+        // Validate the compression level before doing any work
+        let i_override = clap_utils::clap_get_one_or_fallback("I", "NONE");
+        let compressor_override = if i_override.eq("NONE") { None } else { Some(i_override) };
+        if let Some(compression_level) = self.compression_level {
+            if compressor_override.is_some() {
+                return Err(Error::other("The compression level flag (--level) and the override flag (-I) cannot be used at the same time. Please use one or the other."));
+            }
+            self.backup_format.validate_compression_level(compression_level)?;
+        }
+
+        // Find everything to back up, excluded folders are never walked into
+        let verbose = clap_utils::clap_get_flag_or_false("verbose");
+        let excludes = archive::ExcludeMatcher::new(self.exclude.as_deref())?;
+        let mut entries = archive::collect_entries(&self.directory_to_backup, &excludes)?;
+        if verbose {
+            println!("{} {}", "Files and folders to archive:".green(), entries.len().to_string().bright_yellow());
+        }
+
+        // Create a hidden temporary directory in the backup directory
+        let our_tmp_directory = self.backup_directory.join(".lmtmp");
+
+        // We hold the lock, so a temporary directory that is already there was left by a backup that didn't finish
+        if our_tmp_directory.exists() {
+            println!("{}", "Deleting the temporary files of a backup that didn't finish...".yellow());
+            fs::remove_dir_all(&our_tmp_directory)?;
+        }
+        fs::create_dir_all(&our_tmp_directory)?;
+
+        // Dump the databases into the temporary directory, they are archived in limonium-databases/ with the files
+        if !self.databases.is_empty() {
+            let dump_directory = our_tmp_directory.join("databases");
+            fs::create_dir_all(&dump_directory)?;
+            for database in &self.databases {
+                println!("{} {}", "Dumping the database".yellow(), database.label.bright_yellow());
+                let dump_path = database.dump(&dump_directory)?;
+                entries.push(archive::ArchiveEntry {
+                    archive_name: Path::new(database::DUMP_FOLDER).join(dump_path.file_name().unwrap()),
+                    disk_path: dump_path,
+                });
+            }
+        }
+
+        // Archive copies of the region files without the chunks nobody spent time in
+        if let Some(below_ticks) = self.skip_chunks_below {
+            let (chunks, bytes) = world::leave_out_chunks(&mut entries, &our_tmp_directory.join("chunks"), below_ticks)?;
+            println!("{} {} {}", "Chunks left out:".green(), chunks.to_string().bright_yellow(), format!("({})", list::human_size(bytes)).bright_cyan());
+        }
+
+        // Refuse to start when the archive and the bundle won't fit, instead of failing with a full disk halfway through
+        if self.check_space {
+            let size_estimate = estimate::estimate(&self.backup_format, self.compression_level, &entries, Vec::new())?;
+            if verbose {
+                println!("{} {}", "Estimated archive size:".green(), list::human_size(size_estimate.archive_bytes).bright_yellow());
+            }
+            if let Err(e) = estimate::check_free_space(&size_estimate, &self.backup_directory, &our_tmp_directory) {
+                fs::remove_dir_all(&our_tmp_directory)?;
+                return Err(e);
+            }
+        }
+
+        let backup_path = our_tmp_directory.join(format!("{}-{}.{}", &self.name, timestamp, extension));
+        let hash_path = our_tmp_directory.join(format!("{}-{}_hash.txt", &self.name, timestamp));
+        let manifest_path = our_tmp_directory.join(format!("{}-{}{}", &self.name, timestamp, manifest::MANIFEST_SUFFIX));
+
+        println!("{}", format!("Please wait while the backup is being created...").yellow());
+
+        // Progress bar
+        let bar = ProgressBar::new_spinner();
+        let style = ProgressStyle::default_spinner()
+            .tick_strings(&["-", "\\", "|", "/"])
+            // Use ANSI escape code `\x1b[31m` for red, and `\x1b[0m` to reset the color
+            .template("{spinner} \x1b[31m{elapsed_precise}\x1b[0m")
+            .unwrap_or_else(|e| {
+                eprintln!("Template error: {}", e);
+                ProgressStyle::default_spinner()
+            });
+        bar.set_style(style);
+        let start = Instant::now();
+        bar.enable_steady_tick(Duration::from_millis(100));
+
+        // Create compressed tar or zip archive of the Minecraft server files, it's hashed while it's written
+        let written_archive = archive::write_archive(&self.backup_format, self.compression_level, compressor_override.as_deref(), &entries, &backup_path, verbose)
+            .map_err(|e| Error::other(format!("Failed to create backup archive of Minecraft server files: {}", e)))?;
+
+        // Write the hash to a file in the backup directory (same format as sha256sum)
+        let backup_file_name = backup_path.file_name().unwrap().to_string_lossy().to_string();
+        fs::write(&hash_path, format!("{}  {}\n", written_archive.sha256, backup_file_name))?;
+
+        // Write the manifest with the size, mtime and hash of every file, for "limonium backup diff"
+        let manifest = manifest::Manifest::new(&self.directory_to_backup, written_archive.files);
+        fs::write(&manifest_path, manifest.to_json()?)?;
+
+        let sequence_number = self.next_sequence_number(&timestamp)?;
+        let combined_backup_path = self.backup_directory.join(format!("{}-bundle.{}", self.naming.render(&self.name, now, sequence_number), extension));
+
+        // This should never happen, but just in case
+        if combined_backup_path.exists() {
+            return Err(Error::new(ErrorKind::Other, format!("The combined backup archive already exists at {}. This shouldn't have happened", combined_backup_path.display())));
+        }
+
+        // Create the combined backup archive with the backup archive, hash file and manifest, and it will be placed in the backup directory.
+        // The backup archive comes first, older versions of Limonium take the first file that isn't the hash file as the archive.
+        let bundle_entries = [
+            archive::ArchiveEntry { disk_path: backup_path.clone(), archive_name: PathBuf::from(&backup_file_name) },
+            archive::ArchiveEntry { disk_path: hash_path.clone(), archive_name: PathBuf::from(hash_path.file_name().unwrap()) },
+            archive::ArchiveEntry { disk_path: manifest_path.clone(), archive_name: PathBuf::from(manifest_path.file_name().unwrap()) },
+        ];
+        let combined_backup_hash = archive::write_bundle(&self.backup_format, &bundle_entries, &combined_backup_path)
+            .map_err(|e| Error::other(format!("Failed to create combined backup archive of Minecraft server files and hash file: {}", e)))?;
+
+        // Delete the temporary backup archive and hash file in the temporary directory
+        fs::remove_file(&backup_path).expect("Failed to delete temporary backup archive");
+        fs::remove_file(&hash_path).expect("Failed to delete temporary hash file");
+        fs::remove_file(&manifest_path).expect("Failed to delete temporary manifest");
+
+        // Delete the temporary directory
+        fs::remove_dir_all(&our_tmp_directory).expect("Failed to delete temporary directory");
+
+        let backup_result = BackupResult {
+            file_name: combined_backup_path.file_name().unwrap().to_str().unwrap().to_string(),
+            file_path: combined_backup_path,
+            sha256_hash: combined_backup_hash,
+        };
+
+        // Finish the progress bar
+        let duration = HumanDuration(start.elapsed());
+        let progress_bar_ending_message = format!("Task completed in {}", duration);
+        bar.finish_with_message(progress_bar_ending_message);
+
+        // Display the result of the backup
+        println!("{} {}", format!("Local backup finished!").bright_green(), format!("Details below:").yellow());
+        println!("{} {}", format!("Backup file:").green(), format!("{}", &backup_result.file_name).bright_yellow());
+        println!("{}", format!("Backup (sha256) hash: {}", &backup_result.sha256_hash).green());
+        // Size show in MB, but if higher than 1GB show in GB
+        if backup_result.file_path.metadata().unwrap().len() > 1073741824 {
+            let size = backup_result.file_path.metadata().unwrap().len() as f64 / 1073741824.0;
+            println!("{} {} {}", format!("Backup size:").green(), format!("{:.2}", size).bright_yellow(), format!("GB").bright_cyan());
+        } else {
+            let size = backup_result.file_path.metadata().unwrap().len() as f64 / 1048576.0;
+            println!("{} {} {}", format!("Backup size:").green(), format!("{:.2}", size).bright_yellow(), format!("MB").bright_cyan());
+        }
+
+        Ok(backup_result)
+    }
+
+
+    /// Applies a retention policy to the backups of this name in the local backup directory.
+    pub fn apply_retention(&self, policy: &retention::RetentionPolicy, dry_run: bool) -> Result<Vec<String>, Error> {
+        retention::apply_local(&self.backup_directory, &self.name, policy, dry_run)
+    }
+
+
+    /*
+    Determine the number that comes after the date in the bundle name
+    If there are no bundles of this name and date, the return will be 1
+    Otherwise it's one more than the highest number, so a bundle deleted by retention never leads to two bundles with the same name
+     */
+    fn next_sequence_number(&self, date: &str) -> Result<u64, Error> {
+        let mut highest = 0;
+
+        for entry in fs::read_dir(&self.backup_directory)? {
+            let file_name = entry?.file_name();
+            // The volumes of a split bundle count as their bundle
+            if let Some(bundle) = file_name.to_str().map(volume::set_name).and_then(BundleFileName::parse)
+                && bundle.name == self.name
+                && bundle.date == date {
+                highest = highest.max(bundle.number);
+            }
+        }
+        Ok(highest + 1)
+    }
+}
+
+/// The parts of a bundle file name like "hub-11-15-2023-2-bundle.tar.zst" (or "hub-11-15-2023-2-bundle.tar.zst.age" when encrypted),
+/// or one made with a name template like "hub-2023-11-15T030000-1-bundle.tar.zst"
+#[derive(Debug, PartialEq)]
+pub(crate) struct BundleFileName {
+    pub name: String,
+    /// The date as it is in the name
+    pub date: String,
+    /// The date read from the name, at midnight when the name has no time of day
+    pub time: chrono::NaiveDateTime,
+    pub number: u64,
+    pub extension: String,
+}
+
+impl BundleFileName {
+    pub fn parse(file_name: &str) -> Option<BundleFileName> {
+        static BUNDLE_PATTERN: OnceLock<Regex> = OnceLock::new();
+        let pattern = BUNDLE_PATTERN.get_or_init(|| {
+            Regex::new(&format!(r"^(.+)-({})-(\d+)-bundle\.((?:tar\.gz|tar\.zst|zip)(?:\.age)?)$", naming::DATE_PATTERN)).unwrap()
+        });
+
+        let captures = pattern.captures(file_name)?;
+        Some(BundleFileName {
+            name: captures[1].to_string(),
+            date: captures[2].to_string(),
+            time: naming::parse_date_time(&captures[2])?,
+            number: captures[3].parse().ok()?,
+            extension: captures[4].to_string(),
+        })
+    }
+}
+
+pub(crate) fn extract_date_from_file_name(file_name: &String) -> String {
+    // Define a regex pattern for capturing the date part
+    let date_pattern = Regex::new(naming::DATE_PATTERN).unwrap();
+
+    // Find the first match in the file name
+    if let Some(captures) = date_pattern.find(file_name) {
+        return captures.as_str().to_string();
+    }
+
+    // Default value if no match is found
+    String::new()
+}
+
+#[cfg(test)]
+mod backup_testing {
+    use std::fs::File;
+
+    use chrono::{Duration, NaiveDate};
+
+    use crate::backup::extract_date_from_file_name;
+
+    use super::*;
+
+    #[test]
+    fn test_bundle_file_name_parse() {
+        assert_eq!(BundleFileName::parse("hub-everything-10-19-2023-3-bundle.tar.zst"), Some(BundleFileName {
+            name: "hub-everything".to_string(),
+            date: "10-19-2023".to_string(),
+            time: NaiveDate::from_ymd_opt(2023, 10, 19).unwrap().and_hms_opt(0, 0, 0).unwrap(),
+            number: 3,
+            extension: "tar.zst".to_string(),
+        }));
+        assert_eq!(BundleFileName::parse("testing-9-29-2023-1-bundle.zip").unwrap().name, "testing");
+        assert_eq!(BundleFileName::parse("testing-9-29-2023-2-bundle.tar.gz.age").unwrap().extension, "tar.gz.age");
+        assert_eq!(BundleFileName::parse("testing-9-29-2023.tar.gz"), None);
+        assert_eq!(BundleFileName::parse("testing-9-29-2023-1-bundle.tar.gz.part"), None);
+
+        let bundle = BundleFileName::parse("hub-2023-10-19T031500-1-bundle.zip").unwrap();
+        assert_eq!((bundle.name.as_str(), bundle.date.as_str(), bundle.number), ("hub", "2023-10-19T031500", 1));
+        assert_eq!(bundle.time, NaiveDate::from_ymd_opt(2023, 10, 19).unwrap().and_hms_opt(3, 15, 0).unwrap());
+        assert_eq!(BundleFileName::parse("hub-2023-02-30-1-bundle.zip"), None);
+    }
+
+    #[test]
+    fn test_extract_date_from_file_name() {
+        assert_eq!(extract_date_from_file_name(&"hub-everything-10-19-2023-1-bundle.tar.zst".to_string()), "10-19-2023");
+        assert_eq!(extract_date_from_file_name(&"hub-11-15-2023-1-bundle.tar.zst".to_string()), "11-15-2023");
+        assert_eq!(extract_date_from_file_name(&"hub-11-15-2023-2-bundle.tar.zst".to_string()), "11-15-2023");
+        assert_eq!(extract_date_from_file_name(&"hub-10-30-2023-1-bundle.tar.zst".to_string()), "10-30-2023");
+        assert_eq!(extract_date_from_file_name(&"testing-9-29-2023-1-bundle.tar.zst".to_string()), "9-29-2023");
+        assert_eq!(extract_date_from_file_name(&"testing-9-29-2023-1-bundle.zip".to_string()), "9-29-2023");
+        assert_eq!(extract_date_from_file_name(&"testing-9-29-2023-2-bundle.zip".to_string()), "9-29-2023");
+        assert_eq!(extract_date_from_file_name(&"testing-everything-9-29-2023-1-bundle.zip".to_string()), "9-29-2023");
+        assert_eq!(extract_date_from_file_name(&"hub-11-15-2023-1-bundle.tar.zst".to_string()), "11-15-2023");
+        assert_eq!(extract_date_from_file_name(&"can-have-infinite-dashes-right-here-11-4-2023-1-bundle.tar.zst".to_string()), "11-4-2023");
+        assert_eq!(extract_date_from_file_name(&"can-have-infinite-dashes-right-here-11-4-2023-2-bundle.tar.zst".to_string()), "11-4-2023");
+        assert_eq!(extract_date_from_file_name(&"can-have-infinite-dashes-right-here-11-4-2023-1-bundle.zip".to_string()), "11-4-2023");
+        assert_eq!(extract_date_from_file_name(&"hub-2023-11-04T031500-1-bundle.zip".to_string()), "2023-11-04T031500");
+    }
+
+    #[test]
+    fn test_local_delete_after_time() {
+        // Create a temporary directory for testing
+        let temp_dir_dir_to_backup = tempdir::TempDir::new("directory-to-backup").expect("Failed to create temp dir");
+        let temp_dir_backup_directory = tempdir::TempDir::new("backup-directory").expect("Failed to create temp dir");
+
+        // Define a backup object with a sample configuration
+        let backup = Backup {
+            name: "testing-backup".to_string(),
+            directory_to_backup: temp_dir_dir_to_backup.path().to_string_lossy().to_string(),
+            backup_directory: temp_dir_backup_directory.path().to_path_buf(),
+            backup_format: BackupFormat::TarGz,
+            exclude: None,
+            compression_level: None,
+            naming: naming::NamingTemplate::default(),
+            timezone: naming::BackupTimezone::default(),
+            databases: Vec::new(),
+            check_space: true,
+            skip_chunks_below: None,
+        };
+
+        // Create a backup file with a date that should be deleted based on the provided input
+        let old_backup_date = naming::BackupTimezone::default().today() - Duration::days(10); // Example: 10 days old
+        let old_backup_file_name = format!("{}-{}.tar.gz", backup.name, old_backup_date.format("%-m-%-d-%Y"));
+        let old_backup_file_path = backup.backup_directory.join(&old_backup_file_name);
+        File::create(&old_backup_file_path).expect("Failed to create old backup file");
+
+        // Create a backup file with a recent date that should not be deleted
+        let recent_backup_date = naming::BackupTimezone::default().today() - Duration::days(2); // Example: 2 days old
+        let recent_backup_file_name = format!("{}-{}.tar.gz", backup.name, recent_backup_date.format("%-m-%-d-%Y"));
+        let recent_backup_file_path = backup.backup_directory.join(&recent_backup_file_name);
+        File::create(&recent_backup_file_path).expect("Failed to create recent backup file");
+
+        // Define the input for deletion (e.g., 7d for 7 days)
+        let deletion_input = "7d".to_string();
+
+        // Apply the retention policy, keeping everything newer than the input
+        let policy = retention::RetentionPolicy {
+            keep_within_days: Some(retention::RetentionPolicy::parse_days(&deletion_input).unwrap()),
+            ..Default::default()
+        };
+        backup.apply_retention(&policy, false).unwrap();
+
+        // Check if the old backup file is deleted
+        assert!(!old_backup_file_path.exists(), "Old backup file should be deleted");
+
+        // Check if the recent backup file is not deleted
+        assert!(recent_backup_file_path.exists(), "Recent backup file should not be deleted");
+    }
+
+    #[test]
+    fn test_local_delete_after_time_with_always_keep() {
+        // Create a temporary directory for testing
+        let temp_dir_dir_to_backup = tempdir::TempDir::new("directory-to-backup").expect("Failed to create temp dir");
+        let temp_dir_backup_directory = tempdir::TempDir::new("backup-directory").expect("Failed to create temp dir");
+
+        // Define a backup object with a sample configuration
+        let backup = Backup {
+            name: "testing-backup".to_string(),
+            directory_to_backup: temp_dir_dir_to_backup.path().to_string_lossy().to_string(),
+            backup_directory: temp_dir_backup_directory.path().to_path_buf(),
+            backup_format: BackupFormat::TarGz,
+            exclude: None,
+            compression_level: None,
+            naming: naming::NamingTemplate::default(),
+            timezone: naming::BackupTimezone::default(),
+            databases: Vec::new(),
+            check_space: true,
+            skip_chunks_below: None,
+        };
+
+        // Create a backup file with a date that should be deleted based on the provided input
+        let old_backup_date = naming::BackupTimezone::default().today() - Duration::days(10); // Example: 10 days old
+        let old_backup_file_name = format!("{}-{}.tar.gz", backup.name, old_backup_date.format("%-m-%-d-%Y"));
+        let old_backup_file_path = backup.backup_directory.join(&old_backup_file_name);
+        File::create(&old_backup_file_path).expect("Failed to create old backup file");
+
+        // Create a backup file with a recent date that should not be deleted
+        let recent_backup_date = naming::BackupTimezone::default().today() - Duration::days(2); // Example: 2 days old
+        let recent_backup_file_name = format!("{}-{}.tar.gz", backup.name, recent_backup_date.format("%-m-%-d-%Y"));
+        let recent_backup_file_path = backup.backup_directory.join(&recent_backup_file_name);
+        File::create(&recent_backup_file_path).expect("Failed to create recent backup file");
+
+        // Define the input for deletion (e.g., 7d for 7 days)
+        let deletion_input = "7d".to_string();
+
+        // Apply the retention policy with always keep (keep last) set to 2
+        let policy = retention::RetentionPolicy {
+            keep_last: 2,
+            keep_within_days: Some(retention::RetentionPolicy::parse_days(&deletion_input).unwrap()),
+            ..Default::default()
+        };
+        backup.apply_retention(&policy, false).unwrap();
+
+        // Check if the old backup file is not deleted because of the always_keep threshold
+        assert!(old_backup_file_path.exists(), "Old backup file should not be deleted due to always_keep");
+
+        // Check if the recent backup file is also not deleted
+        assert!(recent_backup_file_path.exists(), "Recent backup file should not be deleted");
+    }
+}
\ No newline at end of file
@@ -0,0 +1,180 @@
+use std::io::{Error, ErrorKind};
+use std::sync::OnceLock;
+
+use chrono::format::{Item, StrftimeItems};
+use chrono::{NaiveDate, NaiveDateTime, NaiveTime, Utc};
+use chrono_tz::Tz;
+use regex::Regex;
+
+use crate::backup::BundleFileName;
+
+/// The names Limonium has always used, like "hub-11-15-2023-1-bundle.tar.zst".
+pub const DEFAULT_TEMPLATE: &str = "{name}-{date:%-m-%-d-%Y}-{seq}";
+/// The date of {date} without a format, it sorts by date.
+const DEFAULT_DATE_FORMAT: &str = "%Y-%m-%d";
+
+/// Every date that is read back from file names: the old "11-15-2023", and "2023-11-15" or "20231115"
+/// with an optional time of day ("2023-11-15T030000", "2023-11-15_03-00-00", "20231115T0300").
+pub(crate) const DATE_PATTERN: &str = r"\d{1,2}-\d{1,2}-\d{4}|\d{4}-?\d{2}-?\d{2}(?:[T_]\d{2}-?\d{2}(?:-?\d{2})?)?";
+
+/// Reads a date of `DATE_PATTERN`, dates without a time of day are at midnight.
+pub(crate) fn parse_date_time(text: &str) -> Option<NaiveDateTime> {
+    static PARTS_PATTERN: OnceLock<Regex> = OnceLock::new();
+    let pattern = PARTS_PATTERN.get_or_init(|| {
+        Regex::new(r"^(?:(\d{1,2})-(\d{1,2})-(\d{4})|(\d{4})-?(\d{2})-?(\d{2})(?:[T_](\d{2})-?(\d{2})(?:-?(\d{2}))?)?)$").unwrap()
+    });
+
+    let captures = pattern.captures(text)?;
+    let number = |index: usize| captures.get(index).and_then(|part| part.as_str().parse::<u32>().ok());
+    let (year, month, day) = match number(1) {
+        Some(month) => (number(3)?, month, number(2)?),
+        None => (number(4)?, number(5)?, number(6)?),
+    };
+
+    let date = NaiveDate::from_ymd_opt(year as i32, month, day)?;
+    let time = NaiveTime::from_hms_opt(number(7).unwrap_or(0), number(8).unwrap_or(0), number(9).unwrap_or(0))?;
+    Some(date.and_time(time))
+}
+
+/// The timezone of the dates in backup names, and of "today" for retention.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum BackupTimezone {
+    /// The timezone of the machine
+    #[default]
+    Local,
+    Zone(Tz),
+}
+
+impl BackupTimezone {
+    /// "local", "utc" or a timezone name like "Europe/Berlin"
+    pub fn parse(name: &str) -> Result<BackupTimezone, Error> {
+        match name.to_lowercase().as_str() {
+            "local" => Ok(BackupTimezone::Local),
+            "utc" => Ok(BackupTimezone::Zone(Tz::UTC)),
+            _ => name.parse::<Tz>()
+                .map(BackupTimezone::Zone)
+                .map_err(|_| Error::new(ErrorKind::InvalidInput, format!("Unknown timezone: {} (examples: local, utc, Europe/Berlin)", name))),
+        }
+    }
+
+    pub fn now(&self) -> NaiveDateTime {
+        match self {
+            BackupTimezone::Local => chrono::Local::now().naive_local(),
+            BackupTimezone::Zone(timezone) => Utc::now().with_timezone(timezone).naive_local(),
+        }
+    }
+
+    pub fn today(&self) -> NaiveDate {
+        self.now().date()
+    }
+}
+
+/// How bundles are named, from a template like "{name}-{date:%Y-%m-%dT%H%M%S}-{seq}". "-bundle.<extension>" is added to it.
+#[derive(Clone, Debug, PartialEq)]
+pub struct NamingTemplate {
+    /// The template with the date format taken out ("{name}-{date}-{seq}")
+    template: String,
+    date_format: String,
+}
+
+impl Default for NamingTemplate {
+    fn default() -> Self {
+        NamingTemplate::parse(DEFAULT_TEMPLATE).unwrap()
+    }
+}
+
+impl NamingTemplate {
+    /// Parses a template, the names it makes have to be readable by restore, list and retention.
+    pub fn parse(template: &str) -> Result<NamingTemplate, Error> {
+        static DATE_PLACEHOLDER: OnceLock<Regex> = OnceLock::new();
+        let placeholder = DATE_PLACEHOLDER.get_or_init(|| Regex::new(r"\{date(?::([^}]*))?\}").unwrap());
+        let invalid = |reason: String| Error::new(ErrorKind::InvalidInput, format!("Invalid name template \"{}\": {}", template, reason));
+
+        let dates: Vec<_> = placeholder.captures_iter(template).collect();
+        if template.matches("{name}").count() != 1 || template.matches("{seq}").count() != 1 || dates.len() != 1 {
+            return Err(invalid("it needs {name}, {date} and {seq} once each".to_string()));
+        }
+
+        let date_format = dates[0].get(1).map(|format| format.as_str()).unwrap_or(DEFAULT_DATE_FORMAT).to_string();
+        if StrftimeItems::new(&date_format).any(|item| item == Item::Error) {
+            return Err(invalid(format!("{} is not a valid date format", date_format)));
+        }
+
+        let naming = NamingTemplate {
+            template: placeholder.replace(template, "{date}").to_string(),
+            date_format,
+        };
+
+        let sample_time = NaiveDate::from_ymd_opt(2023, 11, 5).unwrap().and_hms_opt(3, 4, 5).unwrap();
+        let sample = format!("{}-bundle.tar.gz", naming.render("hub", sample_time, 7));
+        match BundleFileName::parse(&sample) {
+            Some(bundle) if bundle.name == "hub" && bundle.number == 7 && naming.format_date(bundle.time) == naming.format_date(sample_time) => Ok(naming),
+            _ => Err(invalid(format!(
+                "Limonium couldn't read the names back (like {}), use {{name}}-{{date:FORMAT}}-{{seq}} with a date like %Y-%m-%d or %Y-%m-%dT%H%M%S",
+                sample
+            ))),
+        }
+    }
+
+    /// The date part of the names, the archive, hash and manifest inside the bundle are named with it too.
+    pub fn format_date(&self, time: NaiveDateTime) -> String {
+        time.format(&self.date_format).to_string()
+    }
+
+    /// The name of a bundle without "-bundle.<extension>".
+    pub fn render(&self, name: &str, time: NaiveDateTime, sequence_number: u64) -> String {
+        self.template
+            .replace("{name}", name)
+            .replace("{date}", &self.format_date(time))
+            .replace("{seq}", &sequence_number.to_string())
+    }
+}
+
+#[cfg(test)]
+mod naming_testing {
+    use super::*;
+
+    fn time(year: i32, month: u32, day: u32, hour: u32, minute: u32, second: u32) -> NaiveDateTime {
+        NaiveDate::from_ymd_opt(year, month, day).unwrap().and_hms_opt(hour, minute, second).unwrap()
+    }
+
+    #[test]
+    fn test_parse_date_time() {
+        assert_eq!(parse_date_time("11-5-2023"), Some(time(2023, 11, 5, 0, 0, 0)));
+        assert_eq!(parse_date_time("2023-11-05"), Some(time(2023, 11, 5, 0, 0, 0)));
+        assert_eq!(parse_date_time("2023-11-05T030405"), Some(time(2023, 11, 5, 3, 4, 5)));
+        assert_eq!(parse_date_time("2023-11-05_03-04"), Some(time(2023, 11, 5, 3, 4, 0)));
+        assert_eq!(parse_date_time("20231105T0304"), Some(time(2023, 11, 5, 3, 4, 0)));
+        assert_eq!(parse_date_time("2023-13-05"), None);
+        assert_eq!(parse_date_time("hub"), None);
+    }
+
+    #[test]
+    fn test_naming_template() {
+        let backup_time = time(2023, 11, 5, 3, 4, 5);
+        assert_eq!(NamingTemplate::default().render("hub", backup_time, 1), "hub-11-5-2023-1");
+
+        let naming = NamingTemplate::parse("{name}-{date:%Y-%m-%dT%H%M%S}-{seq}").unwrap();
+        let file_name = format!("{}-bundle.tar.zst", naming.render("hub-everything", backup_time, 2));
+        assert_eq!(file_name, "hub-everything-2023-11-05T030405-2-bundle.tar.zst");
+        let bundle = BundleFileName::parse(&file_name).unwrap();
+        assert_eq!((bundle.name.as_str(), bundle.time, bundle.number), ("hub-everything", backup_time, 2));
+
+        assert_eq!(NamingTemplate::parse("{name}-{date}-{seq}").unwrap().render("hub", backup_time, 1), "hub-2023-11-05-1");
+        assert!(NamingTemplate::parse("{name}-{seq}").is_err());
+        assert!(NamingTemplate::parse("{name}-{date:%Y-%m-%d %H:%M}-{seq}").is_err());
+        assert!(NamingTemplate::parse("{date}-{name}-{seq}").is_err());
+        assert!(NamingTemplate::parse("{name}-{date:%Q}-{seq}").is_err());
+    }
+
+    #[test]
+    fn test_timezone() {
+        assert_eq!(BackupTimezone::parse("local").unwrap(), BackupTimezone::Local);
+        assert_eq!(BackupTimezone::parse("UTC").unwrap(), BackupTimezone::Zone(Tz::UTC));
+        assert_eq!(BackupTimezone::parse("Europe/Berlin").unwrap(), BackupTimezone::Zone(Tz::Europe__Berlin));
+        assert!(BackupTimezone::parse("Mars/Olympus").is_err());
+
+        let utc = BackupTimezone::Zone(Tz::UTC).now();
+        assert!((utc - Utc::now().naive_utc()).num_seconds().abs() < 5);
+    }
+}
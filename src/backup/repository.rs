@@ -0,0 +1,597 @@
+use std::collections::HashSet;
+use std::fs::{self, File};
+use std::io::{BufReader, Error, ErrorKind, Write};
+use std::os::unix::fs::{MetadataExt, PermissionsExt};
+use std::path::{Component, Path, PathBuf};
+use std::time::{Duration, UNIX_EPOCH};
+
+use colored::Colorize;
+use sha2::{Digest, Sha256};
+
+use crate::backup::archive;
+use crate::backup::chunker::Chunker;
+use crate::backup::lock::BackupLock;
+use crate::backup::naming;
+
+pub(crate) const REPOSITORY_VERSION: u32 = 1;
+const CHUNK_COMPRESSION_LEVEL: i32 = 3;
+
+#[derive(Serialize, Deserialize)]
+struct RepositoryConfig {
+    version: u32,
+}
+
+#[derive(Serialize, Deserialize, PartialEq, Clone, Copy, Debug)]
+#[serde(rename_all = "lowercase")]
+pub enum EntryKind {
+    File,
+    Directory,
+    Symlink,
+}
+
+/// A file, directory or symlink in a snapshot. Files are the concatenation of their chunks.
+#[derive(Serialize, Deserialize)]
+pub struct SnapshotEntry {
+    pub path: String,
+    pub kind: EntryKind,
+    pub mode: u32,
+    pub modified: i64,
+    pub size: u64,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub chunks: Vec<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub link_target: Option<String>,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct Snapshot {
+    pub id: String,
+    pub name: String,
+    pub time: String,
+    pub sources: String,
+    pub entries: Vec<SnapshotEntry>,
+}
+
+impl Snapshot {
+    pub fn total_size(&self) -> u64 {
+        self.entries.iter().map(|entry| entry.size).sum()
+    }
+}
+
+pub struct SnapshotResult {
+    pub id: String,
+    pub entries: u64,
+    pub total_bytes: u64,
+    pub new_chunks: u64,
+    pub new_bytes: u64,
+    pub reused_chunks: u64,
+}
+
+pub struct PruneResult {
+    pub removed_snapshots: Vec<String>,
+    pub removed_chunks: u64,
+    pub removed_bytes: u64,
+}
+
+/// What has to happen on a remote copy of the repository to make it match the local one.
+/// Run it in this order so the remote copy never has a snapshot that's missing chunks.
+#[derive(Debug, PartialEq)]
+pub struct SyncPlan {
+    pub upload_chunks: Vec<String>,
+    pub upload_snapshots: Vec<String>,
+    pub delete_snapshots: Vec<String>,
+    pub delete_chunks: Vec<String>,
+}
+
+/// An incremental, deduplicated backup repository:
+///
+/// ```text
+/// <name>-repository/
+///   repository.json
+///   chunks/<sha256>           (zstd compressed)
+///   snapshots/<snapshot>.json
+/// ```
+pub struct Repository {
+    path: PathBuf,
+    naming: naming::NamingTemplate,
+    timezone: naming::BackupTimezone,
+}
+
+impl Repository {
+    /// Where the repository of a backup name lives in a backup directory.
+    pub fn path_for(backup_directory: &Path, name: &str) -> PathBuf {
+        backup_directory.join(format!("{}-repository", name))
+    }
+
+    pub fn open_or_create(path: &Path) -> Result<Repository, Error> {
+        if !path.join("repository.json").exists() {
+            fs::create_dir_all(path.join("chunks"))?;
+            fs::create_dir_all(path.join("snapshots"))?;
+            let config = serde_json::to_string_pretty(&RepositoryConfig { version: REPOSITORY_VERSION }).map_err(Error::other)?;
+            fs::write(path.join("repository.json"), config)?;
+            println!("{} {}", "Created a new backup repository at".green(), path.display());
+        }
+        Repository::open(path)
+    }
+
+    pub fn open(path: &Path) -> Result<Repository, Error> {
+        let config = fs::read_to_string(path.join("repository.json"))
+            .map_err(|_| Error::new(ErrorKind::NotFound, format!("There is no backup repository at {}", path.display())))?;
+        let config: RepositoryConfig = serde_json::from_str(&config).map_err(|e| Error::new(ErrorKind::InvalidData, e))?;
+        if config.version != REPOSITORY_VERSION {
+            return Err(Error::new(ErrorKind::InvalidData, format!("The repository at {} is version {}, this Limonium only knows version {}", path.display(), config.version, REPOSITORY_VERSION)));
+        }
+
+        Ok(Repository {
+            path: path.to_path_buf(),
+            naming: naming::NamingTemplate::default(),
+            timezone: naming::BackupTimezone::default(),
+        })
+    }
+
+    /// Names the snapshots with `naming`, with the date and time in `timezone`, like the bundles.
+    pub fn with_naming(mut self, naming: naming::NamingTemplate, timezone: naming::BackupTimezone) -> Self {
+        self.naming = naming;
+        self.timezone = timezone;
+        self
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Held while the repository is changed, so a prune never runs while a snapshot is being written.
+    /// The lock of a snapshot or prune that was killed is taken over.
+    fn lock(&self) -> Result<BackupLock, Error> {
+        BackupLock::acquire(&self.path)
+    }
+
+    fn chunk_path(&self, hash: &str) -> PathBuf {
+        self.path.join("chunks").join(hash)
+    }
+
+    fn snapshot_path(&self, id: &str) -> PathBuf {
+        self.path.join("snapshots").join(format!("{}.json", id))
+    }
+
+    /// Stores a chunk if the repository doesn't have it yet. Returns the hash and whether it was new.
+    fn store_chunk(&self, data: &[u8]) -> Result<(String, bool), Error> {
+        let hash = format!("{:x}", Sha256::digest(data));
+        let path = self.chunk_path(&hash);
+        if path.exists() {
+            return Ok((hash, false));
+        }
+
+        // Written to a temporary file first so a crash never leaves a half written chunk behind
+        let temp_path = path.with_extension("tmp");
+        let compressed = zstd::bulk::compress(data, CHUNK_COMPRESSION_LEVEL)?;
+        fs::write(&temp_path, compressed)?;
+        fs::rename(&temp_path, &path)?;
+        Ok((hash, true))
+    }
+
+    /// Reads a chunk and checks it still has the hash it was stored under.
+    fn read_chunk(&self, hash: &str) -> Result<Vec<u8>, Error> {
+        let compressed = fs::read(self.chunk_path(hash))
+            .map_err(|e| Error::new(e.kind(), format!("Chunk {} is missing: {}", hash, e)))?;
+        let data = zstd::stream::decode_all(compressed.as_slice())?;
+        if format!("{:x}", Sha256::digest(&data)) != hash {
+            return Err(Error::new(ErrorKind::InvalidData, format!("Chunk {} is corrupt", hash)));
+        }
+        Ok(data)
+    }
+
+    /// The id of the next snapshot, snapshots are named like bundles ("survival-11-15-2023-2").
+    fn next_snapshot_id(&self, name: &str) -> String {
+        let now = self.timezone.now();
+        let mut number = 1;
+        while self.snapshot_path(&self.naming.render(name, now, number)).exists() {
+            number += 1;
+        }
+        self.naming.render(name, now, number)
+    }
+
+    /// Takes a snapshot of the sources (separated by a : (colon)), only chunks the repository doesn't have yet are stored.
+    pub fn snapshot(&self, name: &str, sources: &str, exclude: Option<&str>, verbose: bool) -> Result<SnapshotResult, Error> {
+        let _lock = self.lock()?;
+
+        let excludes = archive::ExcludeMatcher::new(exclude)?;
+        let archive_entries = archive::collect_entries(sources, &excludes)?;
+
+        let mut result = SnapshotResult {
+            id: self.next_snapshot_id(name),
+            entries: 0,
+            total_bytes: 0,
+            new_chunks: 0,
+            new_bytes: 0,
+            reused_chunks: 0,
+        };
+
+        let mut entries = Vec::with_capacity(archive_entries.len());
+        for archive_entry in &archive_entries {
+            let metadata = fs::symlink_metadata(&archive_entry.disk_path)?;
+            let mut entry = SnapshotEntry {
+                path: archive_entry.archive_name.to_string_lossy().to_string(),
+                kind: EntryKind::File,
+                mode: metadata.mode() & 0o7777,
+                modified: metadata.mtime(),
+                size: 0,
+                chunks: Vec::new(),
+                link_target: None,
+            };
+
+            if metadata.is_dir() {
+                entry.kind = EntryKind::Directory;
+            } else if metadata.file_type().is_symlink() {
+                entry.kind = EntryKind::Symlink;
+                entry.link_target = Some(fs::read_link(&archive_entry.disk_path)?.to_string_lossy().to_string());
+            } else if metadata.is_file() {
+                if verbose {
+                    println!("{}", entry.path);
+                }
+
+                let file = BufReader::new(File::open(&archive_entry.disk_path)?);
+                for chunk in Chunker::new(file) {
+                    let chunk = chunk?;
+                    let (hash, new) = self.store_chunk(&chunk)?;
+                    if new {
+                        result.new_chunks += 1;
+                        result.new_bytes += chunk.len() as u64;
+                    } else {
+                        result.reused_chunks += 1;
+                    }
+                    entry.size += chunk.len() as u64;
+                    entry.chunks.push(hash);
+                }
+                result.total_bytes += entry.size;
+            } else {
+                // Sockets, fifos and devices are not backed up
+                continue;
+            }
+
+            entries.push(entry);
+        }
+        result.entries = entries.len() as u64;
+
+        let snapshot = Snapshot {
+            id: result.id.clone(),
+            name: name.to_string(),
+            time: chrono::Local::now().to_rfc3339(),
+            sources: sources.to_string(),
+            entries,
+        };
+
+        // The snapshot is written last, so it only ever points at chunks that are already stored
+        let path = self.snapshot_path(&snapshot.id);
+        let temp_path = path.with_extension("tmp");
+        fs::write(&temp_path, serde_json::to_vec(&snapshot).map_err(Error::other)?)?;
+        fs::rename(&temp_path, &path)?;
+
+        Ok(result)
+    }
+
+    /// The ids of every snapshot, oldest first.
+    pub fn snapshot_ids(&self) -> Result<Vec<String>, Error> {
+        let mut snapshots = Vec::new();
+        for entry in fs::read_dir(self.path.join("snapshots"))? {
+            let file_name = entry?.file_name().to_string_lossy().to_string();
+            if let Some(id) = file_name.strip_suffix(".json") {
+                snapshots.push(self.load_snapshot(id)?);
+            }
+        }
+        snapshots.sort_by_key(|snapshot| (chrono::DateTime::parse_from_rfc3339(&snapshot.time).ok(), snapshot.id.clone()));
+        Ok(snapshots.into_iter().map(|snapshot| snapshot.id).collect())
+    }
+
+    pub fn load_snapshot(&self, id: &str) -> Result<Snapshot, Error> {
+        let contents = fs::read(self.snapshot_path(id))
+            .map_err(|_| Error::new(ErrorKind::NotFound, format!("There is no snapshot {} in {}", id, self.path.display())))?;
+        serde_json::from_slice(&contents).map_err(|e| Error::new(ErrorKind::InvalidData, format!("Snapshot {} is corrupt: {}", id, e)))
+    }
+
+    /// Restores a snapshot into `target_directory` and returns how many entries were restored.
+    /// `only` limits the restore to those paths (and everything inside them).
+    pub fn restore(&self, id: &str, target_directory: &Path, only: &[PathBuf]) -> Result<u64, Error> {
+        let snapshot = self.load_snapshot(id)?;
+        let only: Vec<PathBuf> = only.iter().map(|path| archive::archive_name(path)).collect();
+        let mut restored = 0;
+        let mut directories = Vec::new();
+
+        fs::create_dir_all(target_directory)?;
+        let canonical_target = fs::canonicalize(target_directory)?;
+        for entry in &snapshot.entries {
+            let relative = Path::new(&entry.path);
+            // Never restore outside of the target directory
+            if !relative.components().all(|component| matches!(component, Component::Normal(_))) {
+                continue;
+            }
+            if !archive::is_wanted(relative, &only) {
+                continue;
+            }
+
+            let path = target_directory.join(relative);
+            // Links and files replace a link at their own path, but must not be written through one in their parents
+            let directory = if entry.kind == EntryKind::Directory { &path } else { path.parent().unwrap_or(target_directory) };
+            archive::ensure_inside(&canonical_target, directory)?;
+            if let Some(parent) = path.parent() {
+                fs::create_dir_all(parent)?;
+            }
+            if entry.kind != EntryKind::Directory && fs::symlink_metadata(&path).is_ok() {
+                fs::remove_file(&path)?;
+            }
+
+            match entry.kind {
+                EntryKind::Directory => {
+                    fs::create_dir_all(&path)?;
+                    directories.push((path, entry.mode));
+                }
+                EntryKind::Symlink => {
+                    std::os::unix::fs::symlink(entry.link_target.as_deref().unwrap_or_default(), &path)?;
+                }
+                EntryKind::File => {
+                    let mut file = File::create(&path)?;
+                    for hash in &entry.chunks {
+                        file.write_all(&self.read_chunk(hash)?)?;
+                    }
+                    file.set_modified(UNIX_EPOCH + Duration::from_secs(entry.modified.max(0) as u64))?;
+                    fs::set_permissions(&path, fs::Permissions::from_mode(entry.mode))?;
+                }
+            }
+            restored += 1;
+        }
+
+        // Directories get their permissions last, a read-only directory would stop its files from being restored
+        for (path, mode) in directories {
+            fs::set_permissions(&path, fs::Permissions::from_mode(mode))?;
+        }
+
+        Ok(restored)
+    }
+
+    /// Deletes the snapshots and then every chunk that no remaining snapshot uses.
+    pub fn prune(&self, snapshot_ids: &[String]) -> Result<PruneResult, Error> {
+        let _lock = self.lock()?;
+
+        for id in snapshot_ids {
+            fs::remove_file(self.snapshot_path(id))
+                .map_err(|e| Error::new(e.kind(), format!("Failed to delete snapshot {}: {}", id, e)))?;
+        }
+
+        let (removed_chunks, removed_bytes) = self.garbage_collect()?;
+        Ok(PruneResult {
+            removed_snapshots: snapshot_ids.to_vec(),
+            removed_chunks,
+            removed_bytes,
+        })
+    }
+
+    /// The chunks used by any snapshot. Fails if a snapshot can't be read, so chunks are never
+    /// deleted based on a partial view of the repository.
+    fn referenced_chunks(&self) -> Result<HashSet<String>, Error> {
+        let mut referenced = HashSet::new();
+        for id in self.snapshot_ids()? {
+            for entry in self.load_snapshot(&id)?.entries {
+                referenced.extend(entry.chunks);
+            }
+        }
+        Ok(referenced)
+    }
+
+    fn garbage_collect(&self) -> Result<(u64, u64), Error> {
+        let referenced = self.referenced_chunks()?;
+
+        let mut removed_chunks = 0;
+        let mut removed_bytes = 0;
+        for entry in fs::read_dir(self.path.join("chunks"))? {
+            let entry = entry?;
+            let file_name = entry.file_name().to_string_lossy().to_string();
+            // Leftovers of an interrupted snapshot (.tmp) are never referenced either
+            if referenced.contains(&file_name) {
+                continue;
+            }
+
+            removed_bytes += entry.metadata()?.len();
+            fs::remove_file(entry.path())?;
+            removed_chunks += 1;
+        }
+
+        Ok((removed_chunks, removed_bytes))
+    }
+
+    fn file_names(&self, directory: &str) -> Result<Vec<String>, Error> {
+        let mut file_names = Vec::new();
+        for entry in fs::read_dir(self.path.join(directory))? {
+            let file_name = entry?.file_name().to_string_lossy().to_string();
+            if !file_name.ends_with(".tmp") {
+                file_names.push(file_name);
+            }
+        }
+        file_names.sort();
+        Ok(file_names)
+    }
+
+    /// Works out what to upload and delete on a remote copy that has `remote_chunks` and `remote_snapshots`
+    /// (the file names in its chunks/ and snapshots/ folders).
+    pub fn plan_sync(&self, remote_chunks: &[String], remote_snapshots: &[String]) -> Result<SyncPlan, Error> {
+        let local_chunks = self.file_names("chunks")?;
+        let local_snapshots = self.file_names("snapshots")?;
+
+        let missing = |local: &[String], remote: &[String]| -> Vec<String> {
+            let remote: HashSet<&String> = remote.iter().collect();
+            local.iter().filter(|name| !remote.contains(name)).cloned().collect()
+        };
+
+        Ok(SyncPlan {
+            upload_chunks: missing(&local_chunks, remote_chunks),
+            upload_snapshots: missing(&local_snapshots, remote_snapshots),
+            delete_snapshots: missing(remote_snapshots, &local_snapshots),
+            delete_chunks: missing(remote_chunks, &local_chunks),
+        })
+    }
+}
+
+pub fn print_snapshot_result(result: &SnapshotResult) {
+    let megabytes = |bytes: u64| format!("{:.2} MB", bytes as f64 / 1024.0 / 1024.0);
+    println!("{} {}", "Snapshot:".green(), result.id.bright_yellow());
+    println!("{} {}", "Files and folders:".green(), result.entries.to_string().bright_yellow());
+    println!("{} {}", "Size of the files:".green(), megabytes(result.total_bytes).bright_yellow());
+    println!(
+        "{} {} {}",
+        "New data stored:".green(),
+        megabytes(result.new_bytes).bright_yellow(),
+        format!("({} new chunks, {} reused)", result.new_chunks, result.reused_chunks).bright_cyan()
+    );
+}
+
+#[cfg(test)]
+mod repository_testing {
+    use super::*;
+    use crate::backup::lock::{LockOwner, LOCK_FILE_NAME};
+
+    fn create_world(root: &Path) {
+        fs::create_dir_all(root.join("world/region")).unwrap();
+        fs::create_dir_all(root.join("plugins")).unwrap();
+        let region: Vec<u8> = (0..3 * 1024 * 1024u32).map(|i| (i.wrapping_mul(2654435761) >> 13) as u8).collect();
+        fs::write(root.join("world/region/r.0.0.mca"), &region).unwrap();
+        fs::write(root.join("world/level.dat"), b"level").unwrap();
+        fs::write(root.join("plugins/config.yml"), b"config").unwrap();
+        std::os::unix::fs::symlink("config.yml", root.join("plugins/link.yml")).unwrap();
+    }
+
+    #[test]
+    fn test_snapshot_deduplicates_and_restores() {
+        let source = tempdir::TempDir::new("repository-source").unwrap();
+        let backups = tempdir::TempDir::new("repository-backups").unwrap();
+        let target = tempdir::TempDir::new("repository-target").unwrap();
+        create_world(source.path());
+
+        let repository = Repository::open_or_create(&Repository::path_for(backups.path(), "survival")).unwrap();
+        let sources = format!("{}/world:{}/plugins", source.path().display(), source.path().display());
+
+        let first = repository.snapshot("survival", &sources, None, false).unwrap();
+        assert!(first.new_chunks > 0);
+        assert_eq!(first.reused_chunks, 0);
+
+        // Nothing changed, so nothing new is stored
+        let second = repository.snapshot("survival", &sources, None, false).unwrap();
+        assert_eq!(second.new_chunks, 0);
+        assert_eq!(second.new_bytes, 0);
+        assert_ne!(first.id, second.id);
+
+        // Only the changed file is stored again
+        fs::write(source.path().join("world/level.dat"), b"level changed").unwrap();
+        let third = repository.snapshot("survival", &sources, Some("*.yml"), false).unwrap();
+        assert_eq!(third.new_chunks, 1);
+        assert_eq!(repository.snapshot_ids().unwrap(), vec![first.id.clone(), second.id.clone(), third.id.clone()]);
+
+        repository.restore(&first.id, target.path(), &[]).unwrap();
+        let restored_root = target.path().join(archive::archive_name(source.path()));
+        assert_eq!(fs::read(restored_root.join("world/region/r.0.0.mca")).unwrap(), fs::read(source.path().join("world/region/r.0.0.mca")).unwrap());
+        assert_eq!(fs::read_to_string(restored_root.join("world/level.dat")).unwrap(), "level");
+        assert_eq!(fs::read_link(restored_root.join("plugins/link.yml")).unwrap(), PathBuf::from("config.yml"));
+
+        // The excludes are honoured, and a partial restore only brings back what was asked for
+        let partial = target.path().join("partial");
+        let world = source.path().join("world");
+        repository.restore(&third.id, &partial, std::slice::from_ref(&world)).unwrap();
+        let partial_root = partial.join(archive::archive_name(source.path()));
+        assert_eq!(fs::read_to_string(partial_root.join("world/level.dat")).unwrap(), "level changed");
+        assert!(!partial_root.join("plugins").exists());
+    }
+
+    #[test]
+    fn test_snapshot_ids_follow_the_naming_template() {
+        let source = tempdir::TempDir::new("repository-source").unwrap();
+        let backups = tempdir::TempDir::new("repository-backups").unwrap();
+        create_world(source.path());
+
+        let naming = naming::NamingTemplate::parse("{name}-{date:%Y-%m-%d}-{seq}").unwrap();
+        let timezone = naming::BackupTimezone::parse("utc").unwrap();
+        let repository = Repository::open_or_create(&Repository::path_for(backups.path(), "survival")).unwrap().with_naming(naming, timezone);
+        let sources = format!("{}/world", source.path().display());
+
+        let today = timezone.today().format("%Y-%m-%d");
+        assert_eq!(repository.snapshot("survival", &sources, None, false).unwrap().id, format!("survival-{}-1", today));
+        assert_eq!(repository.snapshot("survival", &sources, None, false).unwrap().id, format!("survival-{}-2", today));
+    }
+
+    #[test]
+    fn test_restore_refuses_writing_through_links() {
+        let backups = tempdir::TempDir::new("repository-links-backups").unwrap();
+        let outside = tempdir::TempDir::new("repository-links-outside").unwrap();
+        let target = tempdir::TempDir::new("repository-links-target").unwrap();
+        let repository = Repository::open_or_create(&Repository::path_for(backups.path(), "survival")).unwrap();
+
+        // A tampered snapshot with a link to a folder outside of the target, then a file through it
+        let entry = |path: &str, kind: EntryKind, link_target: Option<String>| SnapshotEntry { path: path.to_string(), kind, mode: 0o644, modified: 0, size: 0, chunks: Vec::new(), link_target };
+        let snapshot = Snapshot {
+            id: "survival-1-1-2024-1".to_string(),
+            name: "survival".to_string(),
+            time: String::new(),
+            sources: String::new(),
+            entries: vec![entry("a", EntryKind::Symlink, Some(outside.path().to_string_lossy().to_string())), entry("a/x", EntryKind::File, None)],
+        };
+        fs::write(repository.snapshot_path(&snapshot.id), serde_json::to_vec(&snapshot).unwrap()).unwrap();
+
+        let error = repository.restore(&snapshot.id, target.path(), &[]).unwrap_err();
+        assert_eq!(error.kind(), ErrorKind::InvalidData);
+        assert_eq!(fs::read_dir(outside.path()).unwrap().count(), 0);
+    }
+
+    #[test]
+    fn test_lock_of_killed_snapshot_is_taken_over() {
+        let source = tempdir::TempDir::new("repository-lock-source").unwrap();
+        let backups = tempdir::TempDir::new("repository-lock-backups").unwrap();
+        create_world(source.path());
+        let repository = Repository::open_or_create(&Repository::path_for(backups.path(), "survival")).unwrap();
+
+        let dead = LockOwner { pid: u32::MAX, host: crate::backup::manifest::host_name(), started: "2023-11-15T03:00:00+00:00".to_string() };
+        fs::write(repository.path().join(LOCK_FILE_NAME), serde_json::to_string(&dead).unwrap()).unwrap();
+        repository.snapshot("survival", &source.path().join("world").to_string_lossy(), None, false).unwrap();
+    }
+
+    #[test]
+    fn test_prune_keeps_shared_chunks() {
+        let source = tempdir::TempDir::new("repository-prune-source").unwrap();
+        let backups = tempdir::TempDir::new("repository-prune-backups").unwrap();
+        let target = tempdir::TempDir::new("repository-prune-target").unwrap();
+        create_world(source.path());
+
+        let repository = Repository::open_or_create(&Repository::path_for(backups.path(), "survival")).unwrap();
+        let sources = source.path().join("world").to_string_lossy().to_string();
+
+        let first = repository.snapshot("survival", &sources, None, false).unwrap();
+        fs::write(source.path().join("world/level.dat"), b"level changed").unwrap();
+        let second = repository.snapshot("survival", &sources, None, false).unwrap();
+
+        // Only the old level.dat chunk is unused after the first snapshot is gone
+        let result = repository.prune(std::slice::from_ref(&first.id)).unwrap();
+        assert_eq!(result.removed_chunks, 1);
+        assert_eq!(repository.snapshot_ids().unwrap(), vec![second.id.clone()]);
+
+        repository.restore(&second.id, target.path(), &[]).unwrap();
+        let restored = target.path().join(archive::archive_name(&source.path().join("world")));
+        assert_eq!(fs::read(restored.join("region/r.0.0.mca")).unwrap(), fs::read(source.path().join("world/region/r.0.0.mca")).unwrap());
+        assert!(BackupLock::acquire(repository.path()).is_ok());
+    }
+
+    #[test]
+    fn test_plan_sync() {
+        let source = tempdir::TempDir::new("repository-sync-source").unwrap();
+        let backups = tempdir::TempDir::new("repository-sync-backups").unwrap();
+        create_world(source.path());
+
+        let repository = Repository::open_or_create(&Repository::path_for(backups.path(), "survival")).unwrap();
+        let snapshot = repository.snapshot("survival", &source.path().join("plugins").to_string_lossy(), None, false).unwrap();
+        let chunk = repository.file_names("chunks").unwrap().remove(0);
+
+        let remote_chunks = vec![chunk.clone(), "old-chunk".to_string()];
+        let remote_snapshots = vec!["old-snapshot.json".to_string()];
+        let plan = repository.plan_sync(&remote_chunks, &remote_snapshots).unwrap();
+
+        assert!(!plan.upload_chunks.contains(&chunk));
+        assert_eq!(plan.upload_snapshots, vec![format!("{}.json", snapshot.id)]);
+        assert_eq!(plan.delete_snapshots, vec!["old-snapshot.json".to_string()]);
+        assert_eq!(plan.delete_chunks, vec!["old-chunk".to_string()]);
+    }
+}
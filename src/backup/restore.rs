@@ -0,0 +1,228 @@
+use std::fs;
+use std::io::{Error, ErrorKind};
+use std::path::{Path, PathBuf};
+
+use colored::Colorize;
+
+use crate::backup::{archive, encryption, naming, volume, BackupFormat, BundleFileName};
+
+pub struct RestoreResult {
+    pub extracted_entries: u64,
+    pub sha256_hash: String,
+}
+
+/// Finds the bundle for a backup name and date (like 11-15-2023 or 2023-11-15) in a list of file names.
+/// If `number` is None the last backup of that day is picked. A split bundle is found by the name of the whole bundle.
+pub(crate) fn pick_bundle(file_names: &[String], name: &str, date: &str, number: Option<u64>) -> Option<String> {
+    let date = naming::parse_date_time(date)?.date();
+    file_names.iter()
+        .map(|file_name| volume::set_name(file_name))
+        .filter_map(|file_name| BundleFileName::parse(file_name).map(|bundle| (bundle, file_name)))
+        .filter(|(bundle, _)| bundle.name == name && bundle.time.date() == date)
+        .filter(|(bundle, _)| number.is_none() || number == Some(bundle.number))
+        .max_by_key(|(bundle, _)| (bundle.time, bundle.number))
+        .map(|(_, file_name)| file_name.to_string())
+}
+
+/// Finds a bundle in a local backup directory.
+pub(crate) fn find_local_bundle(backup_directory: &Path, name: &str, date: &str, number: Option<u64>) -> Option<PathBuf> {
+    let file_names = fs::read_dir(backup_directory).ok()?
+        .filter_map(|entry| entry.ok())
+        .filter(|entry| entry.path().is_file())
+        .filter_map(|entry| entry.file_name().to_str().map(|name| name.to_string()))
+        .collect::<Vec<String>>();
+
+    pick_bundle(&file_names, name, date, number).map(|file_name| backup_directory.join(file_name))
+}
+
+/// Returns the pid of a `program` process (compared with the file name of its executable)
+/// whose working directory is `directory` or inside of it, so restoring would overwrite its files.
+pub(crate) fn find_process_using_directory(directory: &Path, program: &str) -> Option<u32> {
+    let directory = fs::canonicalize(directory).ok()?;
+
+    for entry in fs::read_dir("/proc").ok()?.flatten() {
+        let pid = match entry.file_name().to_str().and_then(|pid| pid.parse::<u32>().ok()) {
+            Some(pid) => pid,
+            None => continue,
+        };
+
+        // Processes we aren't allowed to look at are skipped
+        let cwd = match fs::read_link(entry.path().join("cwd")) {
+            Ok(cwd) => cwd,
+            Err(_) => continue,
+        };
+        if !cwd.starts_with(&directory) {
+            continue;
+        }
+
+        if process_executable_name(&entry.path()).is_some_and(|name| name == program) {
+            return Some(pid);
+        }
+    }
+
+    None
+}
+
+/// The file name of the executable of a /proc/<pid> process, from its exe link or else the first argument of its command line.
+fn process_executable_name(process: &Path) -> Option<String> {
+    let executable = match fs::read_link(process.join("exe")) {
+        Ok(executable) => executable,
+        Err(_) => {
+            let cmdline = fs::read(process.join("cmdline")).ok()?;
+            PathBuf::from(String::from_utf8_lossy(cmdline.split(|byte| *byte == 0).next()?).to_string())
+        }
+    };
+    executable.file_name().map(|name| name.to_string_lossy().to_string())
+}
+
+/// Verifies a bundle against its `_hash.txt` and extracts it into `target_directory`.
+/// A split bundle (only its volumes and "<bundle>.sha256" are there) is joined first.
+/// Refuses to run if a Minecraft server (java) is running in the target directory.
+pub fn restore_bundle(bundle_path: &Path, target_directory: &Path, only: &[PathBuf]) -> Result<RestoreResult, Error> {
+    let file_name = bundle_path.file_name().and_then(|name| name.to_str()).unwrap_or_default();
+    if encryption::is_encrypted(file_name) {
+        return Err(Error::new(ErrorKind::InvalidInput, format!("{} is encrypted, decrypt it with \"limonium backup decrypt\" first", bundle_path.display())));
+    }
+    let format = BackupFormat::from_file_name(file_name)
+        .ok_or_else(|| Error::new(ErrorKind::InvalidInput, format!("{} is not a tar.gz, tar.zst or zip bundle", bundle_path.display())))?;
+
+    fs::create_dir_all(target_directory)?;
+    if let Some(pid) = find_process_using_directory(target_directory, "java") {
+        return Err(Error::other(format!("A server (pid {}) is running in {}. Stop it before restoring.", pid, target_directory.display())));
+    }
+
+    // Unpack next to the target so we don't fill up /tmp with a whole world
+    let work_directory = restore_work_directory(target_directory);
+    if work_directory.exists() {
+        fs::remove_dir_all(&work_directory)?;
+    }
+    fs::create_dir_all(&work_directory)?;
+
+    let hash_file = volume::hash_file_of(bundle_path);
+    let result = if !bundle_path.exists() && hash_file.exists() {
+        println!("{}", "Joining the volumes of the backup...".yellow());
+        volume::join_volumes(&hash_file, &work_directory)
+            .and_then(|(joined_path, _)| verify_and_extract(&format, &joined_path, &work_directory, target_directory, only))
+    } else {
+        verify_and_extract(&format, bundle_path, &work_directory, target_directory, only)
+    };
+    fs::remove_dir_all(&work_directory)?;
+    result
+}
+
+pub(crate) fn restore_work_directory(target_directory: &Path) -> PathBuf {
+    let parent = target_directory.parent().filter(|parent| !parent.as_os_str().is_empty()).unwrap_or(Path::new("."));
+    parent.join(".lmtmp-restore")
+}
+
+fn verify_and_extract(format: &BackupFormat, bundle_path: &Path, work_directory: &Path, target_directory: &Path, only: &[PathBuf]) -> Result<RestoreResult, Error> {
+    println!("{}", "Checking the backup archive inside the bundle...".yellow());
+    let unpacked = archive::unpack_bundle(format, bundle_path, work_directory)?;
+
+    match &unpacked.recorded_sha256 {
+        None => return Err(Error::new(ErrorKind::InvalidData, "The bundle does not contain a _hash.txt file")),
+        Some(recorded) if !unpacked.hash_matches() => {
+            return Err(Error::new(ErrorKind::InvalidData, format!("The hash of the backup archive ({}) does not match the hash in _hash.txt ({})", unpacked.inner_sha256, recorded)));
+        }
+        Some(_) => println!("{}", "Hash of the backup archive matches _hash.txt".green()),
+    }
+
+    println!("{} {}", "Extracting to".yellow(), target_directory.display());
+    let extracted_entries = archive::extract_archive(format, &unpacked.inner_path, target_directory, only)?;
+    if extracted_entries == 0 && !only.is_empty() {
+        return Err(Error::new(ErrorKind::NotFound, "Nothing in the backup matched --only"));
+    }
+
+    Ok(RestoreResult {
+        extracted_entries,
+        sha256_hash: unpacked.inner_sha256,
+    })
+}
+
+#[cfg(test)]
+mod restore_testing {
+    use super::*;
+    use crate::backup::Backup;
+
+    #[test]
+    fn test_pick_bundle() {
+        let file_names = vec![
+            "hub-11-15-2023-1-bundle.tar.zst".to_string(),
+            "hub-11-15-2023-2-bundle.tar.zst".to_string(),
+            "hub-everything-11-15-2023-3-bundle.tar.zst".to_string(),
+            "hub-11-16-2023-1-bundle.zip".to_string(),
+            "hub-2023-11-17T150000-1-bundle.zip".to_string(),
+            "hub-2023-11-17T030000-1-bundle.zip".to_string(),
+            "hub-11-19-2023-1-bundle.tar.gz.001".to_string(),
+            "hub-11-19-2023-1-bundle.tar.gz.sha256".to_string(),
+        ];
+
+        assert_eq!(pick_bundle(&file_names, "hub", "11-15-2023", None), Some("hub-11-15-2023-2-bundle.tar.zst".to_string()));
+        assert_eq!(pick_bundle(&file_names, "hub", "11-15-2023", Some(1)), Some("hub-11-15-2023-1-bundle.tar.zst".to_string()));
+        assert_eq!(pick_bundle(&file_names, "hub-everything", "11-15-2023", None), Some("hub-everything-11-15-2023-3-bundle.tar.zst".to_string()));
+        assert_eq!(pick_bundle(&file_names, "hub", "11-16-2023", None), Some("hub-11-16-2023-1-bundle.zip".to_string()));
+        assert_eq!(pick_bundle(&file_names, "hub", "2023-11-16", None), Some("hub-11-16-2023-1-bundle.zip".to_string()));
+        assert_eq!(pick_bundle(&file_names, "hub", "11-17-2023", None), Some("hub-2023-11-17T150000-1-bundle.zip".to_string()));
+        assert_eq!(pick_bundle(&file_names, "hub", "11-18-2023", None), None);
+        assert_eq!(pick_bundle(&file_names, "hub", "11-19-2023", None), Some("hub-11-19-2023-1-bundle.tar.gz".to_string()));
+    }
+
+    #[test]
+    fn test_find_process_using_directory() {
+        let current_dir = std::env::current_dir().unwrap();
+        let our_exe = std::env::current_exe().unwrap();
+        let our_name = our_exe.file_name().unwrap().to_str().unwrap();
+
+        assert_eq!(find_process_using_directory(&current_dir, our_name), Some(std::process::id()));
+        assert_eq!(find_process_using_directory(&current_dir, "not-a-real-program-name"), None);
+        // Only the executable name counts, not the rest of the command line
+        assert_eq!(find_process_using_directory(&current_dir, &our_name[..our_name.len() - 1]), None);
+        // A process in a parent directory isn't using a directory inside of it
+        let temp_dir = tempdir::TempDir::new_in(&current_dir, "process").unwrap();
+        assert_eq!(find_process_using_directory(temp_dir.path(), our_name), None);
+    }
+
+    #[test]
+    fn test_restore_bundle() {
+        let source = tempdir::TempDir::new("restore-source").unwrap();
+        let backups = tempdir::TempDir::new("restore-backups").unwrap();
+        let target = tempdir::TempDir::new("restore-target").unwrap();
+
+        fs::create_dir_all(source.path().join("world/region")).unwrap();
+        fs::create_dir_all(source.path().join("plugins")).unwrap();
+        fs::write(source.path().join("world/region/r.0.0.mca"), b"region").unwrap();
+        fs::write(source.path().join("plugins/config.yml"), b"config").unwrap();
+
+        for format in [BackupFormat::TarGz, BackupFormat::TarZst, BackupFormat::Zip] {
+            let backup = Backup::new(
+                "restore-test".to_string(),
+                source.path().to_string_lossy().to_string(),
+                backups.path().to_path_buf(),
+                format,
+                None,
+                None,
+            );
+            let result = backup.backup().unwrap();
+
+            // Partial restore only brings back the world
+            let world = archive::archive_name(&source.path().join("world"));
+            let partial_target = target.path().join(format!("partial-{}", result.file_name));
+            restore_bundle(&result.file_path, &partial_target, std::slice::from_ref(&world)).unwrap();
+            assert!(partial_target.join(&world).join("region/r.0.0.mca").exists());
+            assert!(!partial_target.join(archive::archive_name(&source.path().join("plugins"))).exists());
+
+            let full_target = target.path().join(format!("full-{}", result.file_name));
+            restore_bundle(&result.file_path, &full_target, &[]).unwrap();
+            let config = full_target.join(archive::archive_name(&source.path().join("plugins/config.yml")));
+            assert_eq!(fs::read_to_string(config).unwrap(), "config");
+
+            assert!(restore_bundle(&result.file_path, &full_target, &[PathBuf::from("does-not-exist")]).is_err());
+
+            // A split bundle is joined before it's restored
+            volume::split_bundle(&result.file_path, &result.sha256_hash, 200).unwrap();
+            let split_target = target.path().join(format!("split-{}", result.file_name));
+            restore_bundle(&result.file_path, &split_target, &[]).unwrap();
+            assert!(split_target.join(archive::archive_name(&source.path().join("plugins/config.yml"))).exists());
+        }
+    }
+}
@@ -0,0 +1,360 @@
+use std::collections::{BTreeMap, HashSet};
+use std::fs;
+use std::io::{Error, ErrorKind};
+use std::path::Path;
+use std::sync::OnceLock;
+
+use chrono::{Datelike, NaiveDate, NaiveTime};
+use colored::Colorize;
+use regex::Regex;
+
+use crate::backup::naming::{self, BackupTimezone};
+use crate::backup::volume;
+
+/// Which backups to keep, a backup is kept when any of the rules keeps it.
+/// A policy without any rules keeps everything.
+#[derive(Default, Clone, Debug, PartialEq)]
+pub struct RetentionPolicy {
+    pub keep_last: u64,
+    pub keep_daily: u64,
+    pub keep_weekly: u64,
+    pub keep_monthly: u64,
+    pub keep_yearly: u64,
+    pub keep_within_days: Option<i64>,
+    /// The timezone of "today" for keep_within_days
+    pub timezone: BackupTimezone,
+}
+
+impl RetentionPolicy {
+    pub fn is_empty(&self) -> bool {
+        self.keep_last == 0
+            && self.keep_daily == 0
+            && self.keep_weekly == 0
+            && self.keep_monthly == 0
+            && self.keep_yearly == 0
+            && self.keep_within_days.is_none()
+    }
+
+    /// Parses the old delete-after-time format: 1d = 1 day, 1w = 1 week, 1m = 1 month (30 days), 1y = 1 year (365 days)
+    pub fn parse_days(input: &str) -> Result<i64, Error> {
+        let invalid = || Error::new(ErrorKind::InvalidInput, format!("Invalid time: {} (examples: 7d, 4w, 6m, 1y)", input));
+        if input.len() < 2 {
+            return Err(invalid());
+        }
+
+        let (amount, unit) = input.split_at(input.len() - 1);
+        let amount: i64 = amount.parse().map_err(|_| invalid())?;
+        match unit {
+            "d" => Ok(amount),
+            "w" => Ok(amount * 7),
+            "m" => Ok(amount * 30),
+            "y" => Ok(amount * 365),
+            _ => Err(invalid()),
+        }
+    }
+}
+
+/// A backup (bundle, old archive or repository snapshot) of one name, found by its file name.
+#[derive(Debug, Clone)]
+pub struct RetentionCandidate {
+    pub file_name: String,
+    pub date: NaiveDate,
+    /// Midnight when the name has no time of day
+    pub time: NaiveTime,
+    pub number: u64,
+}
+
+impl RetentionCandidate {
+    /// Matches "<name>-<date>" and "<name>-<date>-<number>..." so "hub" never matches the backups of "hub-everything".
+    /// Unfinished uploads ("....part") aren't backups yet and are never matched.
+    pub fn parse(file_name: &str, name: &str) -> Option<RetentionCandidate> {
+        if file_name.ends_with(".part") {
+            return None;
+        }
+
+        static DATE_PATTERN: OnceLock<Regex> = OnceLock::new();
+        let pattern = DATE_PATTERN.get_or_init(|| Regex::new(&format!(r"^({})(?:-(\d+))?(?:[-.]|$)", naming::DATE_PATTERN)).unwrap());
+
+        let rest = file_name.strip_prefix(name)?.strip_prefix('-')?;
+        let captures = pattern.captures(rest)?;
+        let date_time = naming::parse_date_time(&captures[1])?;
+        Some(RetentionCandidate {
+            file_name: file_name.to_string(),
+            date: date_time.date(),
+            time: date_time.time(),
+            number: captures.get(2).and_then(|number| number.as_str().parse().ok()).unwrap_or(0),
+        })
+    }
+}
+
+#[derive(Debug)]
+pub struct RetentionDecision {
+    pub file_name: String,
+    pub keep: bool,
+    pub reasons: Vec<&'static str>,
+}
+
+/// Keeps the newest backup of each of the first `count` periods (days, weeks, ...) that have a backup.
+fn keep_per_period<K: PartialEq>(candidates: &[RetentionCandidate], count: u64, reason: &'static str, period: impl Fn(NaiveDate) -> K, decisions: &mut [RetentionDecision]) {
+    let mut last_period: Option<K> = None;
+    let mut periods = 0;
+    for (index, candidate) in candidates.iter().enumerate() {
+        if periods >= count {
+            break;
+        }
+
+        let candidate_period = period(candidate.date);
+        if last_period.as_ref() != Some(&candidate_period) {
+            decisions[index].reasons.push(reason);
+            last_period = Some(candidate_period);
+            periods += 1;
+        }
+    }
+}
+
+/// Decides which backups to keep, the decisions are sorted newest first.
+pub fn decide(policy: &RetentionPolicy, mut candidates: Vec<RetentionCandidate>, today: NaiveDate) -> Vec<RetentionDecision> {
+    candidates.sort_by_key(|candidate| std::cmp::Reverse((candidate.date, candidate.time, candidate.number)));
+
+    let mut decisions: Vec<RetentionDecision> = candidates.iter()
+        .map(|candidate| RetentionDecision {
+            file_name: candidate.file_name.clone(),
+            keep: false,
+            reasons: Vec::new(),
+        })
+        .collect();
+
+    if policy.is_empty() {
+        for decision in decisions.iter_mut() {
+            decision.keep = true;
+        }
+        return decisions;
+    }
+
+    for decision in decisions.iter_mut().take(policy.keep_last as usize) {
+        decision.reasons.push("last");
+    }
+    keep_per_period(&candidates, policy.keep_daily, "daily", |date| date, &mut decisions);
+    keep_per_period(&candidates, policy.keep_weekly, "weekly", |date| (date.iso_week().year(), date.iso_week().week()), &mut decisions);
+    keep_per_period(&candidates, policy.keep_monthly, "monthly", |date| (date.year(), date.month()), &mut decisions);
+    keep_per_period(&candidates, policy.keep_yearly, "yearly", |date| date.year(), &mut decisions);
+    if let Some(days) = policy.keep_within_days {
+        for (index, candidate) in candidates.iter().enumerate() {
+            if (today - candidate.date).num_days() <= days {
+                decisions[index].reasons.push("within");
+            }
+        }
+    }
+
+    for decision in decisions.iter_mut() {
+        decision.keep = !decision.reasons.is_empty();
+    }
+    decisions
+}
+
+/// Decides which of `file_names` to keep, files that aren't backups of `name` are ignored.
+/// The volumes and hash file of a split bundle are one backup, they get the decision of their bundle.
+pub fn decide_for_files(policy: &RetentionPolicy, file_names: &[String], name: &str) -> Vec<RetentionDecision> {
+    // File names can show up twice when listing remote folders, each backup should only be decided once
+    let mut seen = HashSet::new();
+    let mut sets: BTreeMap<&str, Vec<&String>> = BTreeMap::new();
+    for file_name in file_names.iter().filter(|file_name| seen.insert(file_name.as_str())) {
+        sets.entry(volume::set_name(file_name)).or_default().push(file_name);
+    }
+    sets.values_mut().for_each(|files| files.sort());
+
+    let candidates = sets.keys()
+        .filter_map(|set_name| RetentionCandidate::parse(set_name, name))
+        .collect();
+    decide(policy, candidates, policy.timezone.today())
+        .into_iter()
+        .flat_map(|decision| sets[decision.file_name.as_str()].iter()
+            .map(|file_name| RetentionDecision { file_name: file_name.to_string(), keep: decision.keep, reasons: decision.reasons.clone() })
+            .collect::<Vec<_>>())
+        .collect()
+}
+
+/// Prints what is kept and deleted in a location ("local", "SFTP", ...).
+pub fn print_decisions(location: &str, decisions: &[RetentionDecision], dry_run: bool) {
+    let deleting = decisions.iter().filter(|decision| !decision.keep).count();
+    println!(
+        "{} {}",
+        format!("Retention ({}):", location).bold(),
+        format!("keeping {}, {} {}", decisions.len() - deleting, if dry_run { "would delete" } else { "deleting" }, deleting).yellow()
+    );
+
+    for decision in decisions {
+        if decision.keep {
+            println!("  {} {} {}", "keep".green(), decision.file_name, format!("({})", decision.reasons.join(", ")).bright_black());
+        } else if dry_run {
+            println!("  {} {}", "would delete".red(), decision.file_name);
+        } else {
+            println!("  {} {}", "delete".red(), decision.file_name);
+        }
+    }
+}
+
+/// Applies a policy to the backups of `name` in a local folder and returns the deleted file names.
+pub fn apply_local(backup_directory: &Path, name: &str, policy: &RetentionPolicy, dry_run: bool) -> Result<Vec<String>, Error> {
+    let file_names = fs::read_dir(backup_directory)?
+        .filter_map(|entry| entry.ok())
+        .filter(|entry| entry.path().is_file())
+        .filter_map(|entry| entry.file_name().to_str().map(|file_name| file_name.to_string()))
+        .collect::<Vec<String>>();
+
+    let decisions = decide_for_files(policy, &file_names, name);
+    print_decisions("local", &decisions, dry_run);
+
+    let mut deleted = Vec::new();
+    for decision in decisions.iter().filter(|decision| !decision.keep) {
+        if dry_run {
+            continue;
+        }
+        match fs::remove_file(backup_directory.join(&decision.file_name)) {
+            Ok(_) => deleted.push(decision.file_name.clone()),
+            Err(e) => println!("{} {}: {}", "Error deleting file".red(), decision.file_name, e),
+        }
+    }
+    Ok(deleted)
+}
+
+#[cfg(test)]
+mod retention_testing {
+    use super::*;
+
+    fn date(month: u32, day: u32, year: i32) -> NaiveDate {
+        NaiveDate::from_ymd_opt(year, month, day).unwrap()
+    }
+
+    /// One backup a day from `from` until `to`
+    fn daily_candidates(from: NaiveDate, to: NaiveDate) -> Vec<RetentionCandidate> {
+        from.iter_days()
+            .take_while(|day| *day <= to)
+            .map(|day| RetentionCandidate {
+                file_name: format!("survival-{}-1-bundle.tar.zst", day.format("%-m-%-d-%Y")),
+                date: day,
+                time: NaiveTime::MIN,
+                number: 1,
+            })
+            .collect()
+    }
+
+    fn kept(decisions: &[RetentionDecision]) -> Vec<String> {
+        decisions.iter().filter(|decision| decision.keep).map(|decision| decision.file_name.clone()).collect()
+    }
+
+    #[test]
+    fn test_candidate_parse() {
+        let candidate = RetentionCandidate::parse("hub-11-15-2023-2-bundle.tar.zst", "hub").unwrap();
+        assert_eq!(candidate.date, date(11, 15, 2023));
+        assert_eq!(candidate.number, 2);
+
+        assert!(RetentionCandidate::parse("hub-11-15-2023.tar.gz", "hub").is_some());
+        assert!(RetentionCandidate::parse("hub-11-15-2023-1", "hub").is_some());
+        assert!(RetentionCandidate::parse("hub-everything-11-15-2023-1-bundle.tar.zst", "hub").is_none());
+        assert!(RetentionCandidate::parse("hub-repository", "hub").is_none());
+        assert!(RetentionCandidate::parse("lobby-11-15-2023-1-bundle.zip", "hub").is_none());
+        assert!(RetentionCandidate::parse("hub-11-15-2023-1-bundle.zip.part", "hub").is_none());
+
+        let candidate = RetentionCandidate::parse("hub-2023-11-15T031500-1-bundle.tar.zst", "hub").unwrap();
+        assert_eq!((candidate.date, candidate.time), (date(11, 15, 2023), NaiveTime::from_hms_opt(3, 15, 0).unwrap()));
+        assert!(RetentionCandidate::parse("hub-everything-2023-11-15-1-bundle.tar.zst", "hub").is_none());
+    }
+
+    #[test]
+    fn test_empty_policy_keeps_everything() {
+        let candidates = daily_candidates(date(1, 1, 2023), date(3, 1, 2023));
+        let decisions = decide(&RetentionPolicy::default(), candidates.clone(), date(3, 1, 2023));
+        assert_eq!(kept(&decisions).len(), candidates.len());
+    }
+
+    #[test]
+    fn test_grandfather_father_son() {
+        // A year and a half of daily backups, keeping 7 dailies, 4 weeklies and 12 monthlies
+        let today = date(6, 30, 2024);
+        let candidates = daily_candidates(date(1, 1, 2023), today);
+        let policy = RetentionPolicy {
+            keep_daily: 7,
+            keep_weekly: 4,
+            keep_monthly: 12,
+            ..Default::default()
+        };
+
+        let decisions = decide(&policy, candidates, today);
+        let kept = kept(&decisions);
+
+        // The last 7 days
+        for day in 24..=30 {
+            assert!(kept.contains(&format!("survival-6-{}-2024-1-bundle.tar.zst", day)));
+        }
+        // Sundays are the newest backup of their (ISO) week
+        assert!(kept.contains(&"survival-6-23-2024-1-bundle.tar.zst".to_string()));
+        assert!(kept.contains(&"survival-6-16-2024-1-bundle.tar.zst".to_string()));
+        assert!(kept.contains(&"survival-6-9-2024-1-bundle.tar.zst".to_string()));
+        // The last day of the 11 months before this one (this month is already kept by 6-30)
+        assert!(kept.contains(&"survival-5-31-2024-1-bundle.tar.zst".to_string()));
+        assert!(kept.contains(&"survival-7-31-2023-1-bundle.tar.zst".to_string()));
+        assert!(!kept.contains(&"survival-6-30-2023-1-bundle.tar.zst".to_string()));
+
+        // 7 dailies, 3 more weeklies (this week is already kept), 11 more monthlies
+        assert_eq!(kept.len(), 7 + 3 + 11);
+    }
+
+    #[test]
+    fn test_keep_last_and_within() {
+        let today = date(3, 10, 2024);
+        let mut candidates = daily_candidates(date(3, 1, 2024), today);
+        // Two backups on the last day, the newest number is the newest backup
+        candidates.push(RetentionCandidate {
+            file_name: "survival-3-10-2024-2-bundle.tar.zst".to_string(),
+            date: today,
+            time: NaiveTime::MIN,
+            number: 2,
+        });
+
+        let policy = RetentionPolicy { keep_last: 2, ..Default::default() };
+        assert_eq!(kept(&decide(&policy, candidates.clone(), today)), vec![
+            "survival-3-10-2024-2-bundle.tar.zst".to_string(),
+            "survival-3-10-2024-1-bundle.tar.zst".to_string(),
+        ]);
+
+        let policy = RetentionPolicy { keep_within_days: Some(RetentionPolicy::parse_days("1w").unwrap()), ..Default::default() };
+        let kept = kept(&decide(&policy, candidates, today));
+        assert!(kept.contains(&"survival-3-3-2024-1-bundle.tar.zst".to_string()));
+        assert!(!kept.contains(&"survival-3-2-2024-1-bundle.tar.zst".to_string()));
+    }
+
+    #[test]
+    fn test_volume_sets_are_one_backup() {
+        let file_names: Vec<String> = [
+            "hub-3-8-2024-1-bundle.tar.zst",
+            "hub-3-9-2024-1-bundle.tar.zst.001",
+            "hub-3-9-2024-1-bundle.tar.zst.002",
+            "hub-3-9-2024-1-bundle.tar.zst.sha256",
+            "hub-3-10-2024-1-bundle.tar.zst.001",
+            "hub-3-10-2024-1-bundle.tar.zst.sha256",
+            "hub-3-10-2024-1-bundle.tar.zst.002.part",
+        ].iter().map(|file_name| file_name.to_string()).collect();
+
+        let policy = RetentionPolicy { keep_last: 1, ..Default::default() };
+        let decisions = decide_for_files(&policy, &file_names, "hub");
+        assert_eq!(kept(&decisions), vec!["hub-3-10-2024-1-bundle.tar.zst.001", "hub-3-10-2024-1-bundle.tar.zst.sha256"]);
+        let deleted: Vec<&str> = decisions.iter().filter(|decision| !decision.keep).map(|decision| decision.file_name.as_str()).collect();
+        assert_eq!(deleted, vec![
+            "hub-3-9-2024-1-bundle.tar.zst.001",
+            "hub-3-9-2024-1-bundle.tar.zst.002",
+            "hub-3-9-2024-1-bundle.tar.zst.sha256",
+            "hub-3-8-2024-1-bundle.tar.zst",
+        ]);
+    }
+
+    #[test]
+    fn test_parse_days() {
+        assert_eq!(RetentionPolicy::parse_days("7d").unwrap(), 7);
+        assert_eq!(RetentionPolicy::parse_days("2w").unwrap(), 14);
+        assert_eq!(RetentionPolicy::parse_days("1m").unwrap(), 30);
+        assert_eq!(RetentionPolicy::parse_days("1y").unwrap(), 365);
+        assert!(RetentionPolicy::parse_days("7").is_err());
+        assert!(RetentionPolicy::parse_days("xd").is_err());
+    }
+}
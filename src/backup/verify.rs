@@ -0,0 +1,287 @@
+use std::collections::BTreeMap;
+use std::fs;
+use std::io::Error;
+use std::path::Path;
+
+use colored::Colorize;
+
+use crate::backup::{archive, encryption, volume, BackupFormat, BundleFileName};
+
+/// The result of verifying one bundle, printed as a table or as JSON with `--json`.
+#[derive(Serialize)]
+pub struct VerifyReport {
+    pub file_name: String,
+    pub location: String,
+    pub size: u64,
+    pub ok: bool,
+    pub recorded_sha256: Option<String>,
+    pub actual_sha256: Option<String>,
+    pub entries: Option<u64>,
+    pub error: Option<String>,
+}
+
+impl VerifyReport {
+    /// A report for a bundle that couldn't be checked at all (for example a failed download).
+    pub fn failed(file_name: &str, location: &str, error: String) -> VerifyReport {
+        VerifyReport {
+            file_name: file_name.to_string(),
+            location: location.to_string(),
+            size: 0,
+            ok: false,
+            recorded_sha256: None,
+            actual_sha256: None,
+            entries: None,
+            error: Some(error),
+        }
+    }
+}
+
+/// True if `file_name` is a bundle (or a volume or the hash file of a split bundle), and belongs to the backup `name` when one is given.
+pub(crate) fn is_bundle_of(file_name: &str, name: Option<&str>) -> bool {
+    match BundleFileName::parse(volume::set_name(file_name)) {
+        Some(bundle) => name.is_none() || name == Some(bundle.name.as_str()),
+        None => false,
+    }
+}
+
+/// Unpacks a bundle, checks the inner archive against `_hash.txt` and reads the inner archive all the way
+/// through, so truncated or corrupt archives are caught. `work_directory` is used for the inner archive.
+pub fn verify_bundle(bundle_path: &Path, location: &str, work_directory: &Path) -> VerifyReport {
+    let file_name = bundle_path.file_name().and_then(|name| name.to_str()).unwrap_or_default().to_string();
+    let mut report = VerifyReport {
+        file_name,
+        location: location.to_string(),
+        size: fs::metadata(bundle_path).map(|metadata| metadata.len()).unwrap_or(0),
+        ok: false,
+        recorded_sha256: None,
+        actual_sha256: None,
+        entries: None,
+        error: None,
+    };
+
+    if encryption::is_encrypted(&report.file_name) {
+        report.error = Some("The bundle is encrypted, decrypt it with \"limonium backup decrypt\" first".to_string());
+        return report;
+    }
+
+    let format = match BackupFormat::from_file_name(&report.file_name) {
+        Some(format) => format,
+        None => {
+            report.error = Some("Not a tar.gz, tar.zst or zip bundle".to_string());
+            return report;
+        }
+    };
+
+    if let Err(e) = fs::create_dir_all(work_directory) {
+        report.error = Some(format!("Could not create {}: {}", work_directory.display(), e));
+        return report;
+    }
+
+    let unpacked = match archive::unpack_bundle(&format, bundle_path, work_directory) {
+        Ok(unpacked) => unpacked,
+        Err(e) => {
+            report.error = Some(format!("Could not read the bundle: {}", e));
+            return report;
+        }
+    };
+    report.recorded_sha256 = unpacked.recorded_sha256.clone();
+    report.actual_sha256 = Some(unpacked.inner_sha256.clone());
+
+    if unpacked.recorded_sha256.is_none() {
+        report.error = Some("The bundle does not contain a _hash.txt file".to_string());
+    } else if !unpacked.hash_matches() {
+        report.error = Some("The hash of the backup archive does not match _hash.txt".to_string());
+    } else {
+        match archive::test_read_archive(&format, &unpacked.inner_path) {
+            Ok(entries) => {
+                report.entries = Some(entries);
+                report.ok = true;
+            }
+            Err(e) => report.error = Some(format!("The backup archive is corrupt: {}", e)),
+        }
+    }
+
+    let _ = fs::remove_file(&unpacked.inner_path);
+    report
+}
+
+/// The bundles of `name` (every backup if None) in a list of file names, with the files that belong to each:
+/// the bundle itself, or the volumes and hash file of a split bundle.
+pub(crate) fn bundle_sets(file_names: &[String], name: Option<&str>) -> BTreeMap<String, Vec<String>> {
+    let mut sets: BTreeMap<String, Vec<String>> = BTreeMap::new();
+    for file_name in file_names.iter().filter(|file_name| is_bundle_of(file_name, name)) {
+        let files = sets.entry(volume::set_name(file_name).to_string()).or_default();
+        if !files.contains(file_name) {
+            files.push(file_name.clone());
+        }
+    }
+    sets.values_mut().for_each(|files| files.sort());
+    sets
+}
+
+/// Verifies the bundle `set_name` whose `file_names` are in `directory`. The volumes of a split bundle are checked
+/// against its hash file and joined into `work_directory` first.
+pub fn verify_set(directory: &Path, set_name: &str, file_names: &[String], location: &str, work_directory: &Path) -> VerifyReport {
+    if file_names.iter().any(|file_name| file_name == set_name) {
+        return verify_bundle(&directory.join(set_name), location, work_directory);
+    }
+
+    let hash_file_name = format!("{}{}", set_name, volume::HASH_FILE_SUFFIX);
+    if !file_names.contains(&hash_file_name) {
+        return VerifyReport::failed(set_name, location, format!("The split bundle has no hash file ({})", hash_file_name));
+    }
+    if let Err(e) = fs::create_dir_all(work_directory) {
+        return VerifyReport::failed(set_name, location, format!("Could not create {}: {}", work_directory.display(), e));
+    }
+
+    let joined_path = match volume::join_volumes(&directory.join(&hash_file_name), work_directory) {
+        Ok((joined_path, _)) => joined_path,
+        Err(e) => return VerifyReport::failed(set_name, location, format!("Could not join the volumes: {}", e)),
+    };
+    let report = verify_bundle(&joined_path, location, work_directory);
+    let _ = fs::remove_file(&joined_path);
+    report
+}
+
+/// Verifies every bundle in a local backup directory (only the ones of `name` if given).
+pub fn verify_directory(backup_directory: &Path, name: Option<&str>) -> Result<Vec<VerifyReport>, Error> {
+    let file_names = fs::read_dir(backup_directory)?
+        .filter_map(|entry| entry.ok())
+        .filter(|entry| entry.path().is_file())
+        .filter_map(|entry| entry.file_name().to_str().map(|file_name| file_name.to_string()))
+        .collect::<Vec<String>>();
+
+    let work_directory = backup_directory.join(".lmtmp-verify");
+    let reports = bundle_sets(&file_names, name).iter()
+        .map(|(set_name, files)| {
+            println!("{} {}", "Verifying".yellow(), set_name);
+            verify_set(backup_directory, set_name, files, "local", &work_directory)
+        })
+        .collect();
+
+    let _ = fs::remove_dir_all(&work_directory);
+    Ok(reports)
+}
+
+pub fn print_reports(reports: &[VerifyReport]) {
+    for report in reports {
+        if report.ok {
+            println!(
+                "{} {} {} {}",
+                "OK".green().bold(),
+                format!("[{}]", report.location).cyan(),
+                report.file_name,
+                format!("({} entries)", report.entries.unwrap_or(0)).bright_black()
+            );
+        } else {
+            println!(
+                "{} {} {} {}",
+                "FAILED".red().bold(),
+                format!("[{}]", report.location).cyan(),
+                report.file_name,
+                report.error.clone().unwrap_or_default().red()
+            );
+        }
+    }
+
+    let failed = reports.iter().filter(|report| !report.ok).count();
+    if failed == 0 {
+        println!("{}", format!("All {} backups are OK!", reports.len()).green().bold());
+    } else {
+        println!("{}", format!("{} of {} backups FAILED verification!", failed, reports.len()).red().bold());
+    }
+}
+
+#[cfg(test)]
+mod verify_testing {
+    use super::*;
+    use crate::backup::Backup;
+
+    #[test]
+    fn test_is_bundle_of() {
+        assert!(is_bundle_of("hub-11-15-2023-1-bundle.tar.zst", None));
+        assert!(is_bundle_of("hub-11-15-2023-1-bundle.tar.zst", Some("hub")));
+        assert!(!is_bundle_of("hub-everything-11-15-2023-1-bundle.tar.zst", Some("hub")));
+        assert!(!is_bundle_of("hub-11-15-2023.tar.zst", None));
+        assert!(is_bundle_of("hub-11-15-2023-1-bundle.tar.zst.002", Some("hub")));
+        assert!(is_bundle_of("hub-11-15-2023-1-bundle.tar.zst.sha256", Some("hub")));
+
+        let file_names = ["hub-11-15-2023-1-bundle.tar.zst.002", "hub-11-15-2023-1-bundle.tar.zst.sha256", "hub-11-15-2023-1-bundle.tar.zst.001", "hub-11-16-2023-1-bundle.zip", "notes.txt"]
+            .map(|file_name| file_name.to_string());
+        let sets = bundle_sets(&file_names, Some("hub"));
+        assert_eq!(sets.len(), 2);
+        assert_eq!(sets["hub-11-15-2023-1-bundle.tar.zst"], vec![
+            "hub-11-15-2023-1-bundle.tar.zst.001", "hub-11-15-2023-1-bundle.tar.zst.002", "hub-11-15-2023-1-bundle.tar.zst.sha256",
+        ]);
+        assert_eq!(sets["hub-11-16-2023-1-bundle.zip"], vec!["hub-11-16-2023-1-bundle.zip"]);
+    }
+
+    #[test]
+    fn test_verify_directory() {
+        let source = tempdir::TempDir::new("verify-source").unwrap();
+        let backups = tempdir::TempDir::new("verify-backups").unwrap();
+        fs::create_dir_all(source.path().join("world")).unwrap();
+        fs::write(source.path().join("world/level.dat"), vec![3u8; 100_000]).unwrap();
+
+        let mut results = Vec::new();
+        for format in [BackupFormat::TarGz, BackupFormat::TarZst, BackupFormat::Zip] {
+            let backup = Backup::new(
+                "verify-test".to_string(),
+                source.path().join("world").to_string_lossy().to_string(),
+                backups.path().to_path_buf(),
+                format,
+                None,
+                None,
+            );
+            results.push(backup.backup().unwrap());
+        }
+
+        let reports = verify_directory(backups.path(), Some("verify-test")).unwrap();
+        assert_eq!(reports.len(), 3);
+        assert!(reports.iter().all(|report| report.ok));
+        assert!(!backups.path().join(".lmtmp-verify").exists());
+
+        // Truncate one of them like an interrupted upload would
+        let broken = &results[0].file_path;
+        let length = fs::metadata(broken).unwrap().len();
+        fs::File::options().write(true).open(broken).unwrap().set_len(length / 2).unwrap();
+
+        let reports = verify_directory(backups.path(), None).unwrap();
+        let broken_report = reports.iter().find(|report| report.file_name == results[0].file_name).unwrap();
+        assert!(!broken_report.ok);
+        assert!(broken_report.error.is_some());
+        assert_eq!(reports.iter().filter(|report| report.ok).count(), 2);
+    }
+
+    #[test]
+    fn test_verify_split_bundle() {
+        let source = tempdir::TempDir::new("verify-source").unwrap();
+        let backups = tempdir::TempDir::new("verify-backups").unwrap();
+        fs::create_dir_all(source.path().join("world")).unwrap();
+        fs::write(source.path().join("world/level.dat"), (0..100_000u32).map(|i| (i % 251) as u8).collect::<Vec<u8>>()).unwrap();
+
+        let backup = Backup::new("split".to_string(), source.path().join("world").to_string_lossy().to_string(), backups.path().to_path_buf(), BackupFormat::TarGz, None, None);
+        let result = backup.backup().unwrap();
+        let set = volume::split_bundle(&result.file_path, &result.sha256_hash, 1000).unwrap();
+        assert!(set.volumes.len() > 1);
+
+        let reports = verify_directory(backups.path(), Some("split")).unwrap();
+        assert_eq!(reports.len(), 1);
+        assert!(reports[0].ok, "{:?}", reports[0].error);
+        assert_eq!(reports[0].file_name, result.file_name);
+        assert!(!backups.path().join(".lmtmp-verify").exists());
+
+        // A corrupt volume fails the whole bundle
+        let volume_path = backups.path().join(&set.volumes[1].file_name);
+        let mut data = fs::read(&volume_path).unwrap();
+        data[10] ^= 0xFF;
+        fs::write(&volume_path, data).unwrap();
+        let reports = verify_directory(backups.path(), Some("split")).unwrap();
+        assert!(!reports[0].ok);
+        assert!(reports[0].error.as_ref().unwrap().contains("corrupt"));
+
+        // So does a missing hash file
+        fs::remove_file(backups.path().join(set.hash_file_name())).unwrap();
+        assert!(!verify_directory(backups.path(), Some("split")).unwrap()[0].ok);
+    }
+}
@@ -0,0 +1,234 @@
+use std::fs::{self, File};
+use std::io::{BufWriter, Error, ErrorKind, Read};
+use std::path::{Path, PathBuf};
+use std::sync::OnceLock;
+
+use regex::Regex;
+
+use crate::backup::archive::{self, HashingWriter};
+use crate::backup::BundleFileName;
+
+/// The hash file of a split bundle, "<bundle>.sha256" next to the volumes "<bundle>.001", "<bundle>.002", ...
+pub(crate) const HASH_FILE_SUFFIX: &str = ".sha256";
+
+/// One volume of a split bundle.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Volume {
+    pub file_name: String,
+    pub size: u64,
+    pub sha256_hash: String,
+}
+
+/// A bundle that is split into volumes. The hash file has the hash of every volume and of the whole bundle
+/// (in the format of sha256sum), so `sha256sum -c` works on the volumes and on the joined bundle.
+#[derive(Debug, Clone, PartialEq)]
+pub struct VolumeSet {
+    /// The file name of the whole bundle, like "hub-11-15-2023-1-bundle.tar.zst"
+    pub bundle_file_name: String,
+    pub sha256_hash: String,
+    pub volumes: Vec<Volume>,
+}
+
+impl VolumeSet {
+    pub fn hash_file_name(&self) -> String {
+        format!("{}{}", self.bundle_file_name, HASH_FILE_SUFFIX)
+    }
+
+    fn to_hash_file(&self) -> String {
+        let mut contents = String::new();
+        for volume in &self.volumes {
+            contents.push_str(&format!("{}  {}\n", volume.sha256_hash, volume.file_name));
+        }
+        contents.push_str(&format!("{}  {}\n", self.sha256_hash, self.bundle_file_name));
+        contents
+    }
+
+    /// Reads a hash file, the sizes of the volumes are taken from the files next to it when they are there.
+    pub fn read(hash_file: &Path) -> Result<VolumeSet, Error> {
+        let bundle_file_name = hash_file.file_name()
+            .and_then(|name| name.to_str())
+            .and_then(|name| name.strip_suffix(HASH_FILE_SUFFIX))
+            .ok_or_else(|| Error::new(ErrorKind::InvalidInput, format!("{} is not the hash file of a split bundle", hash_file.display())))?
+            .to_string();
+        let invalid = |reason: &str| Error::new(ErrorKind::InvalidData, format!("{} is not a valid hash file: {}", hash_file.display(), reason));
+
+        let mut sha256_hash = None;
+        let mut volumes = Vec::new();
+        for line in fs::read_to_string(hash_file)?.lines().filter(|line| !line.trim().is_empty()) {
+            let (hash, file_name) = line.split_once("  ").ok_or_else(|| invalid("every line has to be \"<sha256>  <file name>\""))?;
+            if file_name == bundle_file_name {
+                sha256_hash = Some(hash.to_string());
+            } else if set_name(file_name) == bundle_file_name {
+                let size = hash_file.with_file_name(file_name).metadata().map(|metadata| metadata.len()).unwrap_or(0);
+                volumes.push(Volume { file_name: file_name.to_string(), size, sha256_hash: hash.to_string() });
+            } else {
+                return Err(invalid(&format!("{} isn't a volume of {}", file_name, bundle_file_name)));
+            }
+        }
+
+        volumes.sort_by_key(|volume| volume_number(&volume.file_name));
+        match sha256_hash {
+            Some(sha256_hash) if !volumes.is_empty() => Ok(VolumeSet { bundle_file_name, sha256_hash, volumes }),
+            _ => Err(invalid("it needs the hashes of the volumes and of the whole bundle")),
+        }
+    }
+}
+
+fn volume_file_name(bundle_file_name: &str, number: usize) -> String {
+    format!("{}.{:03}", bundle_file_name, number)
+}
+
+fn volume_number(file_name: &str) -> u64 {
+    file_name.rsplit('.').next().and_then(|number| number.parse().ok()).unwrap_or(0)
+}
+
+/// The bundle a volume or hash file of a split bundle belongs to ("hub-11-15-2023-1-bundle.tar.zst.002" is
+/// "hub-11-15-2023-1-bundle.tar.zst"), any other file name is returned as it is.
+pub(crate) fn set_name(file_name: &str) -> &str {
+    static VOLUME_PATTERN: OnceLock<Regex> = OnceLock::new();
+    let pattern = VOLUME_PATTERN.get_or_init(|| Regex::new(r"^(.+)\.(?:\d{3,}|sha256)$").unwrap());
+
+    match pattern.captures(file_name) {
+        Some(captures) if BundleFileName::parse(&captures[1]).is_some() => captures.get(1).unwrap().as_str(),
+        _ => file_name,
+    }
+}
+
+/// The hash file of the split bundle `path` belongs to, `path` can be the bundle, a volume or the hash file itself.
+pub(crate) fn hash_file_of(path: &Path) -> PathBuf {
+    let file_name = path.file_name().map(|name| name.to_string_lossy().to_string()).unwrap_or_default();
+    path.with_file_name(format!("{}{}", set_name(&file_name), HASH_FILE_SUFFIX))
+}
+
+/// Splits a bundle into volumes of `volume_size` bytes (the last one is smaller) and writes the hash file next to them.
+/// The bundle is deleted once every volume is written, `sha256_hash` is its hash.
+pub fn split_bundle(bundle_path: &Path, sha256_hash: &str, volume_size: u64) -> Result<VolumeSet, Error> {
+    if volume_size == 0 {
+        return Err(Error::new(ErrorKind::InvalidInput, "The volume size has to be at least 1 byte"));
+    }
+    let bundle_file_name = bundle_path.file_name().and_then(|name| name.to_str())
+        .ok_or_else(|| Error::new(ErrorKind::InvalidInput, format!("{} is not a bundle", bundle_path.display())))?
+        .to_string();
+
+    let mut bundle = File::open(bundle_path)?;
+    let mut set = VolumeSet { bundle_file_name, sha256_hash: sha256_hash.to_string(), volumes: Vec::new() };
+    let bundle_size = bundle.metadata()?.len();
+    let volume_count = bundle_size.div_ceil(volume_size).max(1);
+
+    let result = (1..=volume_count as usize).try_for_each(|number| {
+        let file_name = volume_file_name(&set.bundle_file_name, number);
+        let mut writer = HashingWriter::new(BufWriter::new(File::create(bundle_path.with_file_name(&file_name))?));
+        let size = std::io::copy(&mut (&mut bundle).take(volume_size), &mut writer)?;
+        let (writer, sha256_hash) = writer.finish()?;
+        writer.into_inner().map_err(|e| e.into_error())?.sync_all()?;
+        set.volumes.push(Volume { file_name, size, sha256_hash });
+        Ok::<(), Error>(())
+    });
+
+    // Half a set is no use to anyone, and the bundle is still there
+    if let Err(e) = result {
+        for volume in &set.volumes {
+            let _ = fs::remove_file(bundle_path.with_file_name(&volume.file_name));
+        }
+        return Err(e);
+    }
+
+    fs::write(bundle_path.with_file_name(set.hash_file_name()), set.to_hash_file())?;
+    fs::remove_file(bundle_path)?;
+    Ok(set)
+}
+
+/// Joins the volumes of the set described by `hash_file` into `output_directory`, checking every volume and
+/// the joined bundle against the hash file. Returns the path of the joined bundle and the set.
+pub fn join_volumes(hash_file: &Path, output_directory: &Path) -> Result<(PathBuf, VolumeSet), Error> {
+    let set = VolumeSet::read(hash_file)?;
+    let output_path = output_directory.join(&set.bundle_file_name);
+    if output_path.exists() {
+        return Err(Error::new(ErrorKind::AlreadyExists, format!("{} already exists", output_path.display())));
+    }
+
+    let result = write_joined(hash_file, &set, &output_path);
+    if result.is_err() {
+        let _ = fs::remove_file(&output_path);
+    }
+    result.map(|_| (output_path, set))
+}
+
+fn write_joined(hash_file: &Path, set: &VolumeSet, output_path: &Path) -> Result<(), Error> {
+    let mut writer = HashingWriter::new(BufWriter::new(File::create(output_path)?));
+
+    for volume in &set.volumes {
+        let volume_path = hash_file.with_file_name(&volume.file_name);
+        let mut reader = archive::HashingReader::new(File::open(&volume_path)
+            .map_err(|e| Error::new(e.kind(), format!("Could not open the volume {}: {}", volume_path.display(), e)))?);
+        std::io::copy(&mut reader, &mut writer)?;
+
+        let (_, sha256_hash) = reader.finish();
+        if sha256_hash != volume.sha256_hash {
+            return Err(Error::new(ErrorKind::InvalidData, format!("The volume {} is corrupt, its hash doesn't match the hash file", volume.file_name)));
+        }
+    }
+
+    let (writer, sha256_hash) = writer.finish()?;
+    writer.into_inner().map_err(|e| e.into_error())?.sync_all()?;
+    if sha256_hash != set.sha256_hash {
+        return Err(Error::new(ErrorKind::InvalidData, format!("The joined bundle's hash ({}) doesn't match the hash file ({})", sha256_hash, set.sha256_hash)));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod volume_testing {
+    use super::*;
+
+    #[test]
+    fn test_set_name() {
+        assert_eq!(set_name("hub-11-15-2023-1-bundle.tar.zst.002"), "hub-11-15-2023-1-bundle.tar.zst");
+        assert_eq!(set_name("hub-11-15-2023-1-bundle.tar.zst.age.sha256"), "hub-11-15-2023-1-bundle.tar.zst.age");
+        assert_eq!(set_name("hub-11-15-2023-1-bundle.tar.zst"), "hub-11-15-2023-1-bundle.tar.zst");
+        assert_eq!(set_name("hub-11-15-2023-1-bundle.tar.zst.001.part"), "hub-11-15-2023-1-bundle.tar.zst.001.part");
+        assert_eq!(set_name("world.001"), "world.001");
+        assert_eq!(set_name("hub-11-15-2023_hash.txt"), "hub-11-15-2023_hash.txt");
+        assert_eq!(hash_file_of(Path::new("backups/hub-11-15-2023-1-bundle.zip.003")), Path::new("backups/hub-11-15-2023-1-bundle.zip.sha256"));
+        assert_eq!(hash_file_of(Path::new("hub-11-15-2023-1-bundle.zip")), Path::new("hub-11-15-2023-1-bundle.zip.sha256"));
+    }
+
+    #[test]
+    fn test_split_and_join() {
+        let temp_dir = tempdir::TempDir::new("backup-volumes").unwrap();
+        let bundle_path = temp_dir.path().join("hub-11-15-2023-1-bundle.tar.gz");
+        let data = (0..2500u32).map(|i| (i % 251) as u8).collect::<Vec<u8>>();
+        fs::write(&bundle_path, &data).unwrap();
+        let sha256_hash = archive::sha256_of_file(&bundle_path).unwrap();
+
+        let set = split_bundle(&bundle_path, &sha256_hash, 1000).unwrap();
+        assert!(!bundle_path.exists());
+        assert_eq!(set.volumes.iter().map(|volume| volume.file_name.as_str()).collect::<Vec<&str>>(), vec![
+            "hub-11-15-2023-1-bundle.tar.gz.001",
+            "hub-11-15-2023-1-bundle.tar.gz.002",
+            "hub-11-15-2023-1-bundle.tar.gz.003",
+        ]);
+        assert_eq!(set.hash_file_name(), "hub-11-15-2023-1-bundle.tar.gz.sha256");
+        assert_eq!(set.volumes.iter().map(|volume| volume.size).collect::<Vec<u64>>(), vec![1000, 1000, 500]);
+
+        let hash_file = temp_dir.path().join(set.hash_file_name());
+        assert_eq!(VolumeSet::read(&hash_file).unwrap(), set);
+
+        let output = temp_dir.path().join("joined");
+        fs::create_dir_all(&output).unwrap();
+        let (joined, _) = join_volumes(&hash_file, &output).unwrap();
+        assert_eq!(fs::read(&joined).unwrap(), data);
+
+        // A damaged volume is named, and no half joined bundle is left behind
+        fs::remove_file(&joined).unwrap();
+        fs::write(temp_dir.path().join("hub-11-15-2023-1-bundle.tar.gz.002"), vec![0; 1000]).unwrap();
+        let error = join_volumes(&hash_file, &output).err().unwrap();
+        assert_eq!(error.kind(), ErrorKind::InvalidData);
+        assert!(error.to_string().contains(".002"));
+        assert!(!joined.exists());
+
+        fs::write(temp_dir.path().join("hub-11-15-2023-1-bundle.tar.gz.002"), &data[1000..2000]).unwrap();
+        fs::remove_file(temp_dir.path().join("hub-11-15-2023-1-bundle.tar.gz.003")).unwrap();
+        assert_eq!(join_volumes(&hash_file, &output).err().unwrap().kind(), ErrorKind::NotFound);
+    }
+}
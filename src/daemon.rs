@@ -0,0 +1,386 @@
+use std::collections::BTreeMap;
+use std::env;
+use std::fs;
+use std::io::{Error, ErrorKind};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::Arc;
+use std::time::Duration;
+
+use chrono::{DateTime, Local, Timelike};
+use colored::Colorize;
+use croner::Cron;
+use tokio::sync::Mutex;
+
+/// The schedule file, LIMONIUM_SCHEDULE overrides it.
+const DEFAULT_SCHEDULE_PATH: &str = ".config/limonium/schedule.json";
+const STATE_FILE_NAME: &str = "schedule-state.json";
+/// Long sleeps are cut into pieces so a changed clock or a suspended machine doesn't make a job run late.
+const MAX_SLEEP: Duration = Duration::from_secs(60);
+
+/// A backup job in the schedule file, it runs `limonium backup <name> <sources> <backup_folder>` with the rest as flags.
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct Job {
+    /// A cron expression like "0 3 * * *" (minute hour day month weekday)
+    pub schedule: String,
+    /// The name of the backup, the name of the job when it isn't given
+    pub name: Option<String>,
+    /// The folder the backup runs in, relative to the schedule file
+    pub directory: Option<PathBuf>,
+    /// The folders to back up, separated by a : (colon)
+    pub sources: String,
+    pub backup_folder: String,
+    /// Named destinations or destination URLs, each one is given as --destination
+    #[serde(default)]
+    pub destinations: Vec<String>,
+    /// Any other `limonium backup` flags, like ["--format", "tar.zst", "--keep-daily", "7"]
+    #[serde(default)]
+    pub args: Vec<String>,
+    /// Waits a random time up to this long before starting, so servers don't all back up at the same second
+    #[serde(default)]
+    pub jitter_seconds: u64,
+    /// Runs once right away when the daemon starts after missing a run
+    #[serde(default = "default_catch_up")]
+    pub catch_up: bool,
+}
+
+fn default_catch_up() -> bool {
+    true
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+struct ScheduleFile {
+    /// Where the last runs are recorded, next to the schedule file by default
+    state_file: Option<PathBuf>,
+    jobs: BTreeMap<String, Job>,
+}
+
+pub struct ScheduledJob {
+    pub id: String,
+    pub job: Job,
+    pub cron: Cron,
+    /// Where the backup runs
+    pub directory: PathBuf,
+}
+
+impl ScheduledJob {
+    fn name(&self) -> &str {
+        self.job.name.as_deref().unwrap_or(&self.id)
+    }
+
+    /// The arguments of the `limonium backup` run.
+    pub fn backup_args(&self) -> Vec<String> {
+        let mut args = vec!["backup".to_string(), self.name().to_string(), self.job.sources.clone(), self.job.backup_folder.clone()];
+        for destination in &self.job.destinations {
+            args.push("--destination".to_string());
+            args.push(destination.clone());
+        }
+        args.extend(self.job.args.iter().cloned());
+        args
+    }
+
+    fn next_run(&self, after: &DateTime<Local>) -> Result<DateTime<Local>, Error> {
+        self.cron.find_next_occurrence(&whole_seconds(*after), false)
+            .map_err(|e| Error::other(format!("Could not find the next run of {} ({}): {}", self.id, self.job.schedule, e)))
+    }
+}
+
+pub struct Schedule {
+    pub jobs: Vec<ScheduledJob>,
+    pub state_path: PathBuf,
+}
+
+impl Schedule {
+    pub fn default_path() -> PathBuf {
+        if let Ok(path) = env::var("LIMONIUM_SCHEDULE") {
+            return PathBuf::from(path);
+        }
+        env::var_os("HOME").map(PathBuf::from).unwrap_or_default().join(DEFAULT_SCHEDULE_PATH)
+    }
+
+    /// Reads the schedule file and checks every cron expression.
+    pub fn load(path: &Path) -> Result<Schedule, Error> {
+        let contents = fs::read_to_string(path)
+            .map_err(|e| Error::new(e.kind(), format!("Could not read the schedule file {}: {}", path.display(), e)))?;
+        let base = path.parent().filter(|parent| !parent.as_os_str().is_empty()).unwrap_or(Path::new("."));
+        Schedule::parse(&contents, base)
+            .map_err(|e| Error::new(e.kind(), format!("Invalid schedule file {}: {}", path.display(), e)))
+    }
+
+    /// Parses a schedule, relative paths are relative to `base` (the folder of the schedule file).
+    fn parse(contents: &str, base: &Path) -> Result<Schedule, Error> {
+        let file: ScheduleFile = serde_json::from_str(contents).map_err(|e| Error::new(ErrorKind::InvalidData, e.to_string()))?;
+        if file.jobs.is_empty() {
+            return Err(Error::new(ErrorKind::InvalidData, "There are no jobs"));
+        }
+
+        let mut jobs = Vec::new();
+        for (id, job) in file.jobs {
+            let cron = Cron::from_str(&job.schedule)
+                .map_err(|e| Error::new(ErrorKind::InvalidData, format!("The job {} has an invalid schedule \"{}\": {}", id, job.schedule, e)))?;
+            let directory = base.join(job.directory.clone().unwrap_or_default());
+            jobs.push(ScheduledJob { id, job, cron, directory });
+        }
+
+        Ok(Schedule {
+            jobs,
+            state_path: base.join(file.state_file.unwrap_or_else(|| PathBuf::from(STATE_FILE_NAME))),
+        })
+    }
+}
+
+/// What the state file records about the last run of a job.
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+pub struct JobState {
+    /// The scheduled time of the last run, missed runs are found from it
+    pub last_scheduled: Option<String>,
+    pub last_started: Option<String>,
+    pub last_finished: Option<String>,
+    /// "success", or what went wrong
+    pub last_result: Option<String>,
+}
+
+impl JobState {
+    fn last_scheduled(&self) -> Option<DateTime<Local>> {
+        self.last_scheduled.as_deref()
+            .and_then(|time| DateTime::parse_from_rfc3339(time).ok())
+            .map(|time| time.with_timezone(&Local))
+    }
+}
+
+type State = BTreeMap<String, JobState>;
+
+fn load_state(path: &Path) -> Result<State, Error> {
+    if !path.exists() {
+        return Ok(State::new());
+    }
+    let contents = fs::read_to_string(path)?;
+    serde_json::from_str(&contents)
+        .map_err(|e| Error::new(ErrorKind::InvalidData, format!("Invalid state file {}: {}", path.display(), e)))
+}
+
+/// Writes the state to a temporary file first, so a crash never leaves a half written state file.
+fn save_state(path: &Path, state: &State) -> Result<(), Error> {
+    let temporary_path = path.with_extension("json.tmp");
+    fs::write(&temporary_path, serde_json::to_string_pretty(state).map_err(Error::other)?)?;
+    fs::rename(&temporary_path, path)
+}
+
+/// The run that was missed while the daemon wasn't running: the latest scheduled time after `last_scheduled`
+/// that has already passed. Nothing is caught up for a job that never ran.
+pub fn missed_run(cron: &Cron, last_scheduled: Option<DateTime<Local>>, now: DateTime<Local>) -> Option<DateTime<Local>> {
+    let next_after_last = cron.find_next_occurrence(&whole_seconds(last_scheduled?), false).ok()?;
+    if next_after_last > now {
+        return None;
+    }
+    cron.find_previous_occurrence(&whole_seconds(now), true).ok()
+}
+
+/// Croner keeps the fraction of a second it is given, scheduled times shouldn't have one.
+fn whole_seconds(time: DateTime<Local>) -> DateTime<Local> {
+    time.with_nanosecond(0).unwrap_or(time)
+}
+
+fn jitter(job: &Job) -> Duration {
+    Duration::from_secs(fastrand::u64(0..=job.jitter_seconds))
+}
+
+async fn sleep_until(time: DateTime<Local>) {
+    loop {
+        let remaining = match (time - Local::now()).to_std() {
+            Ok(remaining) if !remaining.is_zero() => remaining,
+            _ => return,
+        };
+        tokio::time::sleep(remaining.min(MAX_SLEEP)).await;
+    }
+}
+
+/// Runs every job on its schedule until the daemon is stopped.
+pub async fn run(schedule: Schedule) -> Result<(), Error> {
+    let state = Arc::new(Mutex::new(load_state(&schedule.state_path)?));
+    let state_path = Arc::new(schedule.state_path);
+    let executable = env::current_exe()?;
+
+    println!("{}", format!("Limonium daemon started with {} jobs", schedule.jobs.len()).green().bold());
+    let tasks = schedule.jobs.into_iter().map(|job| {
+        let task = tokio::spawn(run_job(job, executable.clone(), state.clone(), state_path.clone()));
+        async move { task.await.map_err(Error::other)? }
+    });
+    // The jobs never end on their own, so the first one that stops with an error stops the daemon with it
+    futures_util::future::try_join_all(tasks).await?;
+    Ok(())
+}
+
+/// Runs one job forever. A job only ever runs once at a time: the next run is planned when the previous one finished,
+/// and the runs that passed in the meantime are skipped.
+async fn run_job(job: ScheduledJob, executable: PathBuf, state: Arc<Mutex<State>>, state_path: Arc<PathBuf>) -> Result<(), Error> {
+    let last_scheduled = state.lock().await.get(&job.id).and_then(JobState::last_scheduled);
+    if job.job.catch_up
+        && let Some(missed) = missed_run(&job.cron, last_scheduled, Local::now()) {
+        println!("{} {}", format!("[{}]", job.id).bright_cyan(), format!("Missed the run of {}, running it now", missed.format("%Y-%m-%d %H:%M")).yellow());
+        run_backup(&job, &executable, missed, &state, &state_path).await;
+    }
+
+    loop {
+        let scheduled = job.next_run(&Local::now())?;
+        let start = scheduled + jitter(&job.job);
+        println!("{} {} {}", format!("[{}]", job.id).bright_cyan(), "Next run:".green(), start.format("%Y-%m-%d %H:%M:%S").to_string().bright_yellow());
+        sleep_until(start).await;
+
+        run_backup(&job, &executable, scheduled, &state, &state_path).await;
+
+        let following = job.next_run(&scheduled)?;
+        if following <= Local::now() {
+            println!("{} {}", format!("[{}]", job.id).bright_cyan(), "The backup took longer than the schedule, the runs in the meantime were skipped".yellow());
+        }
+    }
+}
+
+/// Runs the backup of a job as `limonium backup` in a child process, started from the tokio runtime.
+/// It can't run in the daemon itself: the sources of a job are relative to its own working directory, the backup reads
+/// its flags from the process-wide arguments, and a failing backup exits its process, which would stop every job.
+async fn run_backup(job: &ScheduledJob, executable: &Path, scheduled: DateTime<Local>, state: &Mutex<State>, state_path: &Path) {
+    update_state(state, state_path, &job.id, |job_state| {
+        job_state.last_scheduled = Some(scheduled.to_rfc3339());
+        job_state.last_started = Some(Local::now().to_rfc3339());
+    }).await;
+
+    println!("{} {} {}", format!("[{}]", job.id).bright_cyan(), "Starting the backup in".green(), job.directory.display());
+    let status = tokio::process::Command::new(executable)
+        .arg("--nb")
+        .args(job.backup_args())
+        .current_dir(&job.directory)
+        .status()
+        .await;
+    let result = match status {
+        Ok(status) if status.success() => "success".to_string(),
+        Ok(status) => format!("failed ({})", status),
+        Err(e) => format!("could not start: {}", e),
+    };
+
+    if result == "success" {
+        println!("{} {}", format!("[{}]", job.id).bright_cyan(), "The backup finished".green().bold());
+    } else {
+        println!("{} {} {}", format!("[{}]", job.id).bright_cyan(), "The backup".red().bold(), result.red().bold());
+    }
+    update_state(state, state_path, &job.id, |job_state| {
+        job_state.last_finished = Some(Local::now().to_rfc3339());
+        job_state.last_result = Some(result);
+    }).await;
+}
+
+async fn update_state(state: &Mutex<State>, state_path: &Path, id: &str, update: impl FnOnce(&mut JobState)) {
+    let mut state = state.lock().await;
+    update(state.entry(id.to_string()).or_default());
+    if let Err(e) = save_state(state_path, &state) {
+        println!("{} {} {}", "Could not save the state file".red().bold(), state_path.display(), e.to_string().red());
+    }
+}
+
+/// Prints the jobs with their next run, for `limonium daemon --check`.
+pub fn print_schedule(schedule: &Schedule) -> Result<(), Error> {
+    let state = load_state(&schedule.state_path)?;
+    let now = Local::now();
+    for job in &schedule.jobs {
+        println!("{} {} {}", job.id.bright_cyan().bold(), "schedule:".green(), job.job.schedule);
+        println!("  {} {}", "next run:".green(), job.next_run(&now)?.format("%Y-%m-%d %H:%M"));
+        if let Some(last_started) = state.get(&job.id).and_then(|job_state| job_state.last_started.as_ref()) {
+            println!(
+                "  {} {} ({})",
+                "last run:".green(),
+                last_started,
+                state[&job.id].last_result.as_deref().unwrap_or("still running")
+            );
+        }
+        println!("  {} limonium {}", "runs:".green(), job.backup_args().join(" "));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod daemon_testing {
+    use super::*;
+    use chrono::TimeZone;
+
+    const SCHEDULE: &str = r#"{
+        "jobs": {
+            "survival": {
+                "schedule": "0 3 * * *",
+                "directory": "servers/survival",
+                "sources": "world:world_nether",
+                "backup_folder": "../backups",
+                "destinations": ["storagebox"],
+                "args": ["--format", "tar.zst"],
+                "jitter_seconds": 300
+            },
+            "hub": {
+                "schedule": "*/30 * * * *",
+                "name": "lobby",
+                "sources": ".",
+                "backup_folder": "/backups/hub",
+                "catch_up": false
+            }
+        }
+    }"#;
+
+    fn local(day: u32, hour: u32, minute: u32) -> DateTime<Local> {
+        Local.with_ymd_and_hms(2023, 11, day, hour, minute, 0).unwrap()
+    }
+
+    #[test]
+    fn test_parse_schedule() {
+        let schedule = Schedule::parse(SCHEDULE, Path::new("/etc/limonium")).unwrap();
+        assert_eq!(schedule.state_path, PathBuf::from("/etc/limonium/schedule-state.json"));
+
+        let hub = &schedule.jobs[0];
+        assert_eq!(hub.id, "hub");
+        assert!(!hub.job.catch_up);
+        assert_eq!(hub.backup_args(), vec!["backup", "lobby", ".", "/backups/hub"]);
+
+        let survival = &schedule.jobs[1];
+        assert!(survival.job.catch_up);
+        assert_eq!(survival.directory, PathBuf::from("/etc/limonium/servers/survival"));
+        assert_eq!(
+            survival.backup_args(),
+            vec!["backup", "survival", "world:world_nether", "../backups", "--destination", "storagebox", "--format", "tar.zst"]
+        );
+        assert_eq!(survival.next_run(&local(15, 12, 0)).unwrap(), local(16, 3, 0));
+
+        assert!(Schedule::parse(r#"{"jobs": {"a": {"schedule": "every day", "sources": ".", "backup_folder": "b"}}}"#, Path::new(".")).is_err());
+        assert!(Schedule::parse(r#"{"jobs": {"a": {"schedule": "0 3 * * *", "source": ".", "backup_folder": "b"}}}"#, Path::new(".")).is_err());
+        assert!(Schedule::parse(r#"{"jobs": {}}"#, Path::new(".")).is_err());
+    }
+
+    #[test]
+    fn test_missed_run() {
+        let cron = Cron::from_str("0 3 * * *").unwrap();
+
+        // Never ran, nothing to catch up
+        assert_eq!(missed_run(&cron, None, local(15, 12, 0)), None);
+        // Ran at 3:00 today, the next run is tomorrow
+        assert_eq!(missed_run(&cron, Some(local(15, 3, 0)), local(15, 12, 0)), None);
+        // Down for two nights, only the latest run is caught up
+        assert_eq!(missed_run(&cron, Some(local(13, 3, 0)), local(15, 12, 0)), Some(local(15, 3, 0)));
+    }
+
+    #[test]
+    fn test_state_round_trip() {
+        let temp_dir = tempdir::TempDir::new("daemon-state").unwrap();
+        let path = temp_dir.path().join(STATE_FILE_NAME);
+        assert!(load_state(&path).unwrap().is_empty());
+
+        let mut state = State::new();
+        state.insert("survival".to_string(), JobState {
+            last_scheduled: Some(local(15, 3, 0).to_rfc3339()),
+            last_result: Some("success".to_string()),
+            ..Default::default()
+        });
+        save_state(&path, &state).unwrap();
+
+        let loaded = load_state(&path).unwrap();
+        assert_eq!(loaded, state);
+        assert_eq!(loaded["survival"].last_scheduled(), Some(local(15, 3, 0)));
+    }
+}
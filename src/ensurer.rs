@@ -4,17 +4,13 @@ use colored::Colorize;
 #[derive(Debug)]
 pub enum Program {
     Java,
-    Zip,
     Unzip,
     Wget,
     Dos2Unix,
     Mvn,
     Git,
-    Tar,
     Sha256Sum,
     Ls,
-    Zstd,
-    Gzip
 }
 
 impl Program {
@@ -22,17 +18,13 @@ impl Program {
     fn command(&self) -> (&'static str, &'static [&'static str]) {
         match self {
             Program::Java => ("java", &["-version"]),
-            Program::Zip => ("zip", &["-v"]),
             Program::Unzip => ("unzip", &["-v"]),
             Program::Wget => ("wget", &["--version"]),
             Program::Dos2Unix => ("dos2unix", &["--version"]),
             Program::Mvn => ("mvn", &["--version"]),
             Program::Git => ("git", &["--version"]),
-            Program::Tar => ("tar", &["--version"]),
             Program::Sha256Sum => ("sha256sum", &["--version"]),
             Program::Ls => ("ls", &["--version"]),
-            Program::Zstd => ("zstd", &["--version"]),
-            Program::Gzip => ("gzip", &["--version"]),
         }
     }
 }
@@ -0,0 +1,250 @@
+use std::io::{Error, ErrorKind};
+use std::time::Duration;
+
+use colored::Colorize;
+
+use crate::rcon::{RconClient, RconConfig};
+
+/// Something to run before or after a backup (--pre-hook, --post-hook and --failure-hook).
+#[derive(Debug, Clone, PartialEq)]
+pub enum Hook {
+    /// A shell command, run with sh -c and the backup details as environment variables
+    Command(String),
+    /// An URL that gets a POST with the backup details as JSON
+    Http(String),
+    /// A command sent to the server over RCON (example: "rcon:say Backup starting")
+    Rcon(String),
+}
+
+impl Hook {
+    /// URLs are called, "rcon:<command>" is sent over RCON and everything else is a shell command.
+    pub fn parse(spec: &str) -> Result<Hook, Error> {
+        let spec = spec.trim();
+        let hook = if spec.starts_with("http://") || spec.starts_with("https://") {
+            Hook::Http(spec.to_string())
+        } else if let Some(command) = spec.strip_prefix("rcon:") {
+            Hook::Rcon(command.trim().to_string())
+        } else {
+            Hook::Command(spec.to_string())
+        };
+
+        match &hook {
+            Hook::Command(value) | Hook::Http(value) | Hook::Rcon(value) if value.is_empty() => {
+                Err(Error::new(ErrorKind::InvalidInput, format!("Empty hook: \"{}\"", spec)))
+            }
+            _ => Ok(hook),
+        }
+    }
+
+    fn is_rcon(&self) -> bool {
+        matches!(self, Hook::Rcon(_))
+    }
+}
+
+/// The backup details given to hooks. Shell commands get them as LIMONIUM_BACKUP_<KEY> environment variables,
+/// HTTP hooks as a JSON object, and ${LIMONIUM_BACKUP_<KEY>} in URLs and RCON commands is replaced with them.
+#[derive(Debug, Clone, Default)]
+pub struct HookVariables {
+    values: Vec<(&'static str, String)>,
+}
+
+impl HookVariables {
+    pub fn new(name: &str) -> HookVariables {
+        HookVariables::default().with("name", name)
+    }
+
+    pub fn with(mut self, key: &'static str, value: impl Into<String>) -> HookVariables {
+        self.values.retain(|(existing, _)| *existing != key);
+        self.values.push((key, value.into()));
+        self
+    }
+
+    fn environment(&self) -> impl Iterator<Item = (String, &str)> {
+        self.values.iter().map(|(key, value)| (format!("LIMONIUM_BACKUP_{}", key.to_uppercase()), value.as_str()))
+    }
+
+    fn expand(&self, text: &str) -> String {
+        self.environment().fold(text.to_string(), |text, (variable, value)| text.replace(&format!("${{{}}}", variable), value))
+    }
+
+    fn to_json(&self) -> String {
+        let object: serde_json::Map<String, serde_json::Value> = self.values.iter()
+            .map(|(key, value)| (key.to_string(), serde_json::Value::String(value.clone())))
+            .collect();
+        serde_json::Value::Object(object).to_string()
+    }
+}
+
+/// The hooks of a backup, every hook has to finish within `timeout`.
+pub struct Hooks {
+    pub pre: Vec<Hook>,
+    pub post: Vec<Hook>,
+    pub failure: Vec<Hook>,
+    pub timeout: Duration,
+    /// Needed when one of the hooks is an RCON command
+    pub rcon: Option<RconConfig>,
+}
+
+impl Hooks {
+    pub fn uses_rcon(&self) -> bool {
+        self.pre.iter().chain(&self.post).chain(&self.failure).any(Hook::is_rcon)
+    }
+
+    /// Runs the pre-hooks in order, the first one that fails (or times out) stops the backup.
+    pub async fn run_pre(&self, variables: &HookVariables) -> Result<(), Error> {
+        for hook in &self.pre {
+            self.run(hook, variables).await
+                .map_err(|e| Error::new(e.kind(), format!("The pre-hook failed, not backing up: {}", e)))?;
+        }
+        Ok(())
+    }
+
+    /// Runs the post-hooks, a failing hook is only a warning because the backup itself is fine.
+    pub async fn run_post(&self, variables: &HookVariables) {
+        self.run_all(&self.post, &variables.clone().with("status", "success")).await;
+    }
+
+    /// Runs the failure hooks with the error that stopped the backup.
+    pub async fn run_failure(&self, variables: &HookVariables, error: &str) {
+        self.run_all(&self.failure, &variables.clone().with("status", "failure").with("error", error)).await;
+    }
+
+    async fn run_all(&self, hooks: &[Hook], variables: &HookVariables) {
+        for hook in hooks {
+            if let Err(e) = self.run(hook, variables).await {
+                println!("{} {}", "A hook failed:".red().bold(), e.to_string().red());
+            }
+        }
+    }
+
+    async fn run(&self, hook: &Hook, variables: &HookVariables) -> Result<(), Error> {
+        match hook {
+            Hook::Command(command) => {
+                println!("{} {}", "(Hook) Running".yellow(), command);
+                run_command(command, variables, self.timeout).await
+            }
+            Hook::Http(url) => {
+                let url = variables.expand(url);
+                println!("{} {}", "(Hook) Calling".yellow(), url);
+                call_url(&url, variables, self.timeout).await
+            }
+            Hook::Rcon(command) => {
+                let config = self.rcon.as_ref()
+                    .ok_or_else(|| Error::new(ErrorKind::InvalidInput, "RCON hooks need RCON, set enable-rcon=true in server.properties or use --rcon"))?;
+                let command = variables.expand(command);
+                println!("{} {}", "(Hook) Sending over RCON:".yellow(), command);
+                send_rcon(config, &command, self.timeout).await
+            }
+        }
+    }
+}
+
+async fn run_command(command: &str, variables: &HookVariables, timeout: Duration) -> Result<(), Error> {
+    let mut child = tokio::process::Command::new("sh")
+        .arg("-c")
+        .arg(command)
+        .envs(variables.environment())
+        .kill_on_drop(true)
+        .spawn()
+        .map_err(|e| Error::new(e.kind(), format!("Could not run {}: {}", command, e)))?;
+
+    match tokio::time::timeout(timeout, child.wait()).await {
+        Ok(status) => {
+            let status = status?;
+            if !status.success() {
+                return Err(Error::other(format!("{} exited with {}", command, status)));
+            }
+            Ok(())
+        }
+        Err(_) => {
+            child.kill().await?;
+            Err(Error::new(ErrorKind::TimedOut, format!("{} did not finish within {} seconds", command, timeout.as_secs())))
+        }
+    }
+}
+
+async fn send_rcon(config: &RconConfig, command: &str, timeout: Duration) -> Result<(), Error> {
+    // The RCON client blocks, so it runs on its own thread to not hold up the runtime
+    let (config, owned_command) = (config.clone(), command.to_string());
+    let send = tokio::task::spawn_blocking(move || RconClient::connect(&config)?.command(&owned_command).map(|_| ()));
+
+    match tokio::time::timeout(timeout, send).await {
+        Ok(result) => result.map_err(Error::other)?,
+        Err(_) => Err(Error::new(ErrorKind::TimedOut, format!("{} was not answered over RCON within {} seconds", command, timeout.as_secs()))),
+    }
+}
+
+async fn call_url(url: &str, variables: &HookVariables, timeout: Duration) -> Result<(), Error> {
+    let client = reqwest::Client::builder()
+        .timeout(timeout)
+        .build()
+        .map_err(Error::other)?;
+    let response = client.post(url)
+        .header(reqwest::header::CONTENT_TYPE, "application/json")
+        .body(variables.to_json())
+        .send()
+        .await
+        .map_err(|e| if e.is_timeout() {
+            Error::new(ErrorKind::TimedOut, format!("{} did not answer within {} seconds", url, timeout.as_secs()))
+        } else {
+            Error::other(format!("Could not call {}: {}", url, e))
+        })?;
+
+    if !response.status().is_success() {
+        return Err(Error::other(format!("{} answered with {}", url, response.status())));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod hooks_testing {
+    use super::*;
+
+    fn hooks(pre: Vec<Hook>, timeout: Duration) -> Hooks {
+        Hooks { pre, post: Vec::new(), failure: Vec::new(), timeout, rcon: None }
+    }
+
+    #[test]
+    fn test_parse_hook() {
+        assert_eq!(Hook::parse("./notify.sh --all").unwrap(), Hook::Command("./notify.sh --all".to_string()));
+        assert_eq!(Hook::parse("https://hc-ping.com/abc").unwrap(), Hook::Http("https://hc-ping.com/abc".to_string()));
+        assert_eq!(Hook::parse("rcon: say Backup starting").unwrap(), Hook::Rcon("say Backup starting".to_string()));
+        assert!(Hook::parse("rcon:").is_err());
+        assert!(Hook::parse(" ").is_err());
+    }
+
+    #[test]
+    fn test_hook_variables() {
+        let variables = HookVariables::new("hub").with("file_name", "hub-11-15-2023-1-bundle.tar.gz").with("status", "success");
+        assert_eq!(variables.expand("say Backed up ${LIMONIUM_BACKUP_FILE_NAME} (${LIMONIUM_BACKUP_STATUS})"), "say Backed up hub-11-15-2023-1-bundle.tar.gz (success)");
+        assert_eq!(variables.to_json(), r#"{"file_name":"hub-11-15-2023-1-bundle.tar.gz","name":"hub","status":"success"}"#);
+    }
+
+    #[tokio::test]
+    async fn test_command_hooks() {
+        let temp_dir = tempdir::TempDir::new("hooks").unwrap();
+        let output = temp_dir.path().join("out.txt");
+        let variables = HookVariables::new("hub").with("file_name", "hub-11-15-2023-1-bundle.tar.gz");
+
+        let hook = Hook::Command(format!("echo \"$LIMONIUM_BACKUP_NAME $LIMONIUM_BACKUP_FILE_NAME\" > {}", output.display()));
+        hooks(vec![hook], Duration::from_secs(60)).run_pre(&variables).await.unwrap();
+        assert_eq!(std::fs::read_to_string(&output).unwrap(), "hub hub-11-15-2023-1-bundle.tar.gz\n");
+
+        assert!(hooks(vec![Hook::Command("exit 3".to_string())], Duration::from_secs(60)).run_pre(&variables).await.is_err());
+
+        let error = hooks(vec![Hook::Command("sleep 5".to_string())], Duration::from_millis(200)).run_pre(&variables).await.unwrap_err();
+        assert_eq!(error.kind(), ErrorKind::TimedOut);
+    }
+
+    #[tokio::test]
+    async fn test_rcon_hook_timeout() {
+        // A server that accepts the connection but never answers the login
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let config = RconConfig { host: "127.0.0.1".to_string(), port: listener.local_addr().unwrap().port(), password: "secret".to_string() };
+        let mut hooks = hooks(vec![Hook::Rcon("say Backup starting".to_string())], Duration::from_millis(200));
+        hooks.rcon = Some(config);
+
+        let error = hooks.run_pre(&HookVariables::new("hub")).await.unwrap_err();
+        assert_eq!(error.kind(), ErrorKind::TimedOut);
+    }
+}
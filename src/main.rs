@@ -7,6 +7,9 @@ extern crate serde_derive;
 extern crate serde_json;
 
 use crate::backup::BackupFormat;
+use crate::backup::retention::RetentionPolicy;
+use crate::destination::{Destination, Protocol};
+use crate::upload_target::UploadTarget;
 use crate::log_search::LogSearch;
 use crate::objects::downloaded_file::DownloadedFile;
 use clap::builder::TypedValueParser;
@@ -15,23 +18,34 @@ use colored::Colorize;
 use std::ops::Index;
 use std::path::{Path, PathBuf};
 use std::string::String;
-use std::time::Instant;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
 use std::{env, fs, process};
 
 mod backup;
+mod destination;
 mod clap_utils;
 mod compile_controllers;
+mod daemon;
 mod download_controllers;
 mod github_utils;
 mod hash_utils;
+mod hooks;
 mod jenkins_utils;
 mod log_search;
+mod notify;
 mod number_utils;
+mod rcon;
+mod s3;
 mod objects;
+mod world;
 mod ensurer;
 mod file_utils;
 mod jvm_downgrader;
 mod sftp;
+mod transfer;
+mod updater;
+mod upload_target;
 mod webdav;
 
 fn show_example() {
@@ -129,7 +143,26 @@ async fn main() {
             .help("Do not display the cool banner when program starts")
             .action(ArgAction::SetTrue))
         .subcommand(clap::Command::new("self-update")
-            .about("Updates Limonium"))
+            .about("Updates Limonium")
+            .arg(clap::Arg::new("to")
+                .help("Installs a specific release of Limonium (example: 2.3.1)")
+                .long("to")
+                .action(ArgAction::Set)
+                .required(false))
+            .arg(clap::Arg::new("channel")
+                .help("The release channel to update from (stable, prerelease)")
+                .long("channel")
+                .action(ArgAction::Set)
+                .required(false)
+                .default_value("stable")
+                .value_parser(["stable", "prerelease"]))
+            .arg(clap::Arg::new("check")
+                .help("Only checks if an update is available (exit code 0 = up to date, 100 = update available, 102 = error)")
+                .long("check")
+                .action(ArgAction::SetTrue)
+                .required(false)))
+        .subcommand(clap::Command::new("self-rollback")
+            .about("Rolls Limonium back to the binary that was kept before the last update"))
         .subcommand(clap::Command::new("cleanup")
             .about("Cleans up the temp directory and exits"))
         .subcommand(clap::Command::new("compile")
@@ -198,9 +231,138 @@ async fn main() {
                 .help("After downloading the server, run the JAR through the JVM Downgrader")
                 .long("run-jvmdowngrader")
                 .action(ArgAction::Set)
-                .required(false)))
+                .required(false))
+            .args(notify_args()))
         .subcommand(clap::Command::new("backup")
             .about("Backs up the server")
+            .args_conflicts_with_subcommands(true)
+            .subcommand_negates_reqs(true)
+            .subcommand(clap::Command::new("verify")
+                .about("Checks that backup bundles are not corrupt")
+                .arg(clap::Arg::new("backup_folder")
+                    .help("The local folder with the backups to check (default is the current folder when no remote is given)")
+                    .action(ArgAction::Set)
+                    .required(false)
+                    .index(1))
+                .arg(clap::Arg::new("name")
+                    .help("Only checks the backups with this name")
+                    .long("name")
+                    .action(ArgAction::Set)
+                    .required(false))
+                .args(destination_args("Checks the backups on"))
+                .arg(clap::Arg::new("json")
+                    .help("Writes the results as JSON to this file")
+                    .long("json")
+                    .action(ArgAction::Set)
+                    .required(false)))
+            .subcommand(clap::Command::new("list")
+                .about("Lists the backups of a name across the local folder, SFTP and WebDAV")
+                .arg(clap::Arg::new("name")
+                    .help("The name of the backup")
+                    .action(ArgAction::Set)
+                    .required(true)
+                    .index(1))
+                .arg(clap::Arg::new("backup_folder")
+                    .help("The local folder with the backups (default is the current folder when no remote is given)")
+                    .action(ArgAction::Set)
+                    .required(false)
+                    .index(2))
+                .args(destination_args("Lists the backups on")))
+            .subcommand(clap::Command::new("snapshots")
+                .about("Lists the snapshots in the repository of a name (backups made with --repository)")
+                .arg(clap::Arg::new("name")
+                    .help("The name of the backup")
+                    .action(ArgAction::Set)
+                    .required(true)
+                    .index(1))
+                .arg(clap::Arg::new("backup_folder")
+                    .help("The folder with the repository")
+                    .action(ArgAction::Set)
+                    .required(false)
+                    .default_value(".")
+                    .index(2)))
+            .subcommand(clap::Command::new("prune")
+                .about("Applies a retention policy to the backups of a name, locally and on SFTP and WebDAV, and to its repository")
+                .arg(clap::Arg::new("name")
+                    .help("The name of the backup")
+                    .action(ArgAction::Set)
+                    .required(true)
+                    .index(1))
+                .arg(clap::Arg::new("backup_folder")
+                    .help("The folder with the backups and the repository")
+                    .action(ArgAction::Set)
+                    .required(false)
+                    .default_value(".")
+                    .index(2))
+                .args(retention_args())
+                .arg(timezone_arg())
+                .arg(clap::Arg::new("dry-run")
+                    .help("Only lists what would be deleted")
+                    .long("dry-run")
+                    .action(ArgAction::SetTrue)
+                    .required(false))
+                .arg(clap::Arg::new("snapshot")
+                    .help("The snapshots to delete, separated by a : (example: survival-11-15-2023-1)")
+                    .long("snapshot")
+                    .action(ArgAction::Set)
+                    .required(false))
+                .args(destination_args("Prunes the backups (and syncs the pruned repository) on"))
+                .args(transfer_args()))
+            .subcommand(clap::Command::new("decrypt")
+                .about("Decrypts a bundle made with --encrypt-to or --encrypt-passphrase-file, and checks it against the recorded hash")
+                .arg(clap::Arg::new("bundle")
+                    .help("The encrypted bundle (example: hub-11-15-2023-1-bundle.tar.zst.age)")
+                    .action(ArgAction::Set)
+                    .required(true)
+                    .index(1))
+                .arg(clap::Arg::new("output_folder")
+                    .help("The folder to write the decrypted bundle to")
+                    .action(ArgAction::Set)
+                    .required(false)
+                    .default_value(".")
+                    .index(2))
+                .arg(clap::Arg::new("identity")
+                    .help("The age identity file with the private key (AGE-SECRET-KEY-...)")
+                    .long("identity")
+                    .short('i')
+                    .action(ArgAction::Set)
+                    .required_unless_present("passphrase-file")
+                    .conflicts_with("passphrase-file"))
+                .arg(clap::Arg::new("passphrase-file")
+                    .help("The file with the passphrase the bundle was encrypted with")
+                    .long("passphrase-file")
+                    .action(ArgAction::Set)
+                    .required(false)))
+            .subcommand(clap::Command::new("join")
+                .about("Joins the volumes of a bundle made with --split-size, and checks them against its hash file")
+                .arg(clap::Arg::new("volumes")
+                    .help("The hash file or a volume of the split bundle (example: hub-11-15-2023-1-bundle.tar.zst.sha256)")
+                    .action(ArgAction::Set)
+                    .required(true)
+                    .index(1))
+                .arg(clap::Arg::new("output_folder")
+                    .help("The folder to write the joined bundle to")
+                    .action(ArgAction::Set)
+                    .required(false)
+                    .default_value(".")
+                    .index(2)))
+            .subcommand(clap::Command::new("diff")
+                .about("Shows the files that were added, removed or changed between two backup bundles")
+                .arg(clap::Arg::new("a")
+                    .help("The older bundle (example: hub-11-15-2023-1-bundle.tar.zst)")
+                    .action(ArgAction::Set)
+                    .required(true)
+                    .index(1))
+                .arg(clap::Arg::new("b")
+                    .help("The newer bundle")
+                    .action(ArgAction::Set)
+                    .required(true)
+                    .index(2))
+                .arg(clap::Arg::new("only")
+                    .help("Only compares these paths, separated by a : (example: world/region:plugins/LuckPerms)")
+                    .long("only")
+                    .action(ArgAction::Set)
+                    .required(false)))
             .arg(clap::Arg::new("name")
                 .help("The name of the backup")
                 .action(ArgAction::Set)
@@ -217,9 +379,40 @@ async fn main() {
                 .required(true)
                 .index(3))
             .arg(clap::Arg::new("exclude")
-                .help("The files to exclude from the backup")
+                .help("Glob patterns to exclude from the backup, separated by a : (example: \"plugins/dynmap:*.log\")")
+                .long("exclude")
                 .action(ArgAction::Set)
                 .required(false))
+            .arg(clap::Arg::new("database")
+                .help("Dumps a database into the backup: sqlite:<path>, mysql://user@host/database, postgres://user@host/database or a name from the databases file (can be given more than once)")
+                .long("database")
+                .action(ArgAction::Append)
+                .required(false)
+                .conflicts_with("repository"))
+            .arg(clap::Arg::new("dry-run")
+                .help("Only prints the number of files, their size, the estimated archive size and the excluded paths")
+                .long("dry-run")
+                .action(ArgAction::SetTrue)
+                .required(false)
+                .conflicts_with("repository"))
+            .arg(clap::Arg::new("no-space-check")
+                .help("Starts the backup even when the estimated archive doesn't fit in the free space")
+                .long("no-space-check")
+                .action(ArgAction::SetTrue)
+                .required(false)
+                .conflicts_with("repository"))
+            .arg(clap::Arg::new("split-size")
+                .help("Splits bundles bigger than this into numbered volumes, for storage with a file size limit (example: 2G)")
+                .long("split-size")
+                .action(ArgAction::Set)
+                .required(false)
+                .conflicts_with("repository"))
+            .arg(clap::Arg::new("skip-chunks-below")
+                .help("Leaves chunks players spent less than this time in out of the region files in the archive, the world isn't changed (example: 60s)")
+                .long("skip-chunks-below")
+                .action(ArgAction::Set)
+                .required(false)
+                .conflicts_with("repository"))
             .arg(clap::Arg::new("format")
                 .help("The format to use (tar.gz, tar.zst, zip)")
                 .long("format")
@@ -227,34 +420,46 @@ async fn main() {
                 .required(false)
                 .default_value("tar.gz")
                 .value_parser(["tar.gz", "tar.zst", "zip"]))
+            .arg(clap::Arg::new("name-template")
+                .help("How the bundles are named, \"-bundle.<format>\" is added to it (example: \"{name}-{date:%Y-%m-%dT%H%M%S}-{seq}\")")
+                .long("name-template")
+                .action(ArgAction::Set)
+                .required(false)
+                .default_value(backup::naming::DEFAULT_TEMPLATE))
+            .arg(timezone_arg())
             .arg(clap::Arg::new("level")
                 .help("The compression level to use")
                 .long("level")
                 .action(ArgAction::Set)
                 .required(false)
                 .value_parser(clap::value_parser!(i64)))
-            .arg(clap::Arg::new("sftp")
-                .help("The SFTP server to backup to (format: \"user@host:optional_port key_file remote_dir\")")
-                .long("sftp")
-                .action(ArgAction::Set)
-                .required(false))
-            .arg(clap::Arg::new("webdav")
-                .help("The WebDAV server to backup to (format: \"url username password\")")
-                .long("webdav")
+            .arg(clap::Arg::new("encrypt-to")
+                .help("Encrypts the bundle with age before it is uploaded, to this public key (age1...) or the public keys in this file (can be given more than once)")
+                .long("encrypt-to")
+                .action(ArgAction::Append)
+                .required(false)
+                .conflicts_with("repository"))
+            .arg(clap::Arg::new("encrypt-passphrase-file")
+                .help("Encrypts the bundle with age before it is uploaded, with the passphrase in this file")
+                .long("encrypt-passphrase-file")
                 .action(ArgAction::Set)
-                .required(false))
+                .required(false)
+                .conflicts_with_all(["encrypt-to", "repository"]))
+            .args(destination_args("Uploads the backup to"))
+            .args(transfer_args())
             .arg(clap::Arg::new("delete-after-upload")
                 .help("Deletes the backup after uploading it")
                 .long("delete-after-upload")
                 .action(ArgAction::SetTrue)
                 .required(false))
+            .args(retention_args())
             .arg(clap::Arg::new("local-delete-after-time")
                 .help("Deletes backups after a certain amount of time LOCALLY")
                 .long("local-delete-after-time")
                 .action(ArgAction::Set)
                 .required(false))
             .arg(clap::Arg::new("local-always-keep")
-                .help("Always keep a certain amount of backups LOCALLY")
+                .help("Always keep a certain amount of backups LOCALLY when using --local-delete-after-time")
                 .long("local-always-keep")
                 .action(ArgAction::Set)
                 .required(false)
@@ -276,12 +481,89 @@ async fn main() {
                 .default_value("false")
                 .action(ArgAction::SetTrue)
                 .required(false))
+            .arg(clap::Arg::new("rcon")
+                .help("Turns saving off with RCON while backing up (save-off, save-all flush, save-on), the port and password are read from server.properties")
+                .long("rcon")
+                .action(ArgAction::SetTrue)
+                .required(false))
+            .arg(clap::Arg::new("rcon-host")
+                .help("The host of the RCON server")
+                .long("rcon-host")
+                .action(ArgAction::Set)
+                .required(false)
+                .requires("rcon"))
+            .arg(clap::Arg::new("rcon-port")
+                .help("The port of the RCON server")
+                .long("rcon-port")
+                .action(ArgAction::Set)
+                .required(false)
+                .requires("rcon")
+                .value_parser(clap::value_parser!(u16)))
+            .arg(clap::Arg::new("rcon-password")
+                .help("The password of the RCON server, server.properties isn't read when this is given")
+                .long("rcon-password")
+                .action(ArgAction::Set)
+                .required(false)
+                .requires("rcon"))
+            .arg(clap::Arg::new("server-properties")
+                .help("The server.properties to read the RCON port and password from")
+                .long("server-properties")
+                .action(ArgAction::Set)
+                .required(false)
+                .default_value("server.properties"))
+            .args(hook_args())
+            .args(notify_args())
+            .arg(clap::Arg::new("repository")
+                .help("Stores the backup as a snapshot in an incremental, deduplicated repository instead of a bundle")
+                .long("repository")
+                .action(ArgAction::SetTrue)
+                .required(false))
             .arg(clap::Arg::new("I")
                 .help("Overrides -I for tar")
                 .long("I")
                 .short('I')
                 .action(ArgAction::Set)
                 .required(false)))
+        .subcommand(clap::Command::new("restore")
+            .about("Restores a backup bundle")
+            .arg(clap::Arg::new("backup")
+                .help("The bundle to restore, or the name of the backup when using --date")
+                .action(ArgAction::Set)
+                .required(true)
+                .index(1))
+            .arg(clap::Arg::new("target")
+                .help("The folder to restore to")
+                .action(ArgAction::Set)
+                .required(true)
+                .index(2))
+            .arg(clap::Arg::new("date")
+                .help("The date of the backup to restore (example: 11-15-2023 or 2023-11-15)")
+                .long("date")
+                .action(ArgAction::Set)
+                .required(false))
+            .arg(clap::Arg::new("number")
+                .help("Which backup of that day to restore (default is the last one)")
+                .long("number")
+                .action(ArgAction::Set)
+                .required(false)
+                .value_parser(clap::value_parser!(u64)))
+            .arg(clap::Arg::new("from")
+                .help("The local backup folder to look in")
+                .long("from")
+                .action(ArgAction::Set)
+                .required(false)
+                .default_value("."))
+            .arg(clap::Arg::new("snapshot")
+                .help("Restores this snapshot from the repository of <backup> in --from (backups made with --repository)")
+                .long("snapshot")
+                .action(ArgAction::Set)
+                .required(false))
+            .arg(clap::Arg::new("only")
+                .help("Only restores these paths from the backup, separated by a : (example: world:plugins/LuckPerms)")
+                .long("only")
+                .action(ArgAction::Set)
+                .required(false))
+            .args(destination_args("Looks for the backup on")))
         .subcommand(clap::Command::new("log")
             .about("Searches the server logs folder for a string")
             .arg(clap::Arg::new("days-back")
@@ -316,7 +598,43 @@ async fn main() {
                 .aliases(["p"])
                 .action(ArgAction::Set)
                 .required(false)
-                .default_value("./logs")));
+                .default_value("./logs")))
+        .subcommand(clap::Command::new("world")
+            .about("Reports the sizes of the dimensions of worlds, and prunes chunks nobody spent time in")
+            .subcommand_required(true)
+            .subcommand(clap::Command::new("report")
+                .about("Shows the region, entity and point of interest sizes of every dimension")
+                .arg(world_folders_arg())
+                .arg(clap::Arg::new("inhabited-below")
+                    .help("Also counts the chunks players spent less than this time in (example: 60s, 5m or 1200t)")
+                    .long("inhabited-below")
+                    .action(ArgAction::Set)
+                    .required(false)))
+            .subcommand(clap::Command::new("prune")
+                .about("Removes the chunks players spent less than a time in, Minecraft generates them again when they are visited")
+                .arg(world_folders_arg())
+                .arg(clap::Arg::new("inhabited-below")
+                    .help("Removes the chunks players spent less than this time in (example: 60s, 5m or 1200t)")
+                    .long("inhabited-below")
+                    .action(ArgAction::Set)
+                    .required(true))
+                .arg(clap::Arg::new("dry-run")
+                    .help("Only shows what would be removed")
+                    .long("dry-run")
+                    .action(ArgAction::SetTrue)
+                    .required(false))))
+        .subcommand(clap::Command::new("daemon")
+            .about("Runs the backup jobs of a schedule file on their cron schedules")
+            .arg(clap::Arg::new("schedule")
+                .help("The schedule file (default: $LIMONIUM_SCHEDULE or ~/.config/limonium/schedule.json)")
+                .action(ArgAction::Set)
+                .required(false)
+                .index(1))
+            .arg(clap::Arg::new("check")
+                .help("Checks the schedule file and shows when every job runs next, without running anything")
+                .long("check")
+                .action(ArgAction::SetTrue)
+                .required(false)));
 
     let command_matches: ArgMatches = matches_commands.get_matches();
 
@@ -328,7 +646,7 @@ async fn main() {
 
     // Handle self-update flag
     if command_matches.get_flag("self-update") {
-        if self_update() {
+        if matches!(self_update(updater::SelfUpdateOptions::default()).await, updater::UpdateStatus::Updated(_)) {
             process::exit(0); // Exit if updated
         }
     }
@@ -344,8 +662,28 @@ async fn main() {
 
     match command_matches.subcommand() {
         // Handle self-update subcommand
-        Some(("self-update", _)) => {
-            self_update();
+        Some(("self-update", self_update_matches)) => {
+            let options = updater::SelfUpdateOptions {
+                to_version: self_update_matches.get_one::<String>("to").cloned(),
+                channel: updater::UpdateChannel::from_name(self_update_matches.get_one::<String>("channel").unwrap())
+                    .unwrap_or(updater::UpdateChannel::Stable),
+                check_only: self_update_matches.get_flag("check"),
+            };
+
+            let check_only = options.check_only;
+            let status = self_update(options).await;
+            process::exit(if check_only { status.check_exit_code() } else { 0 });
+        }
+        Some(("self-rollback", _)) => {
+            if let Err(e) = updater::self_rollback() {
+                println!(
+                    "{} {} {}",
+                    "Something went wrong!".red().bold(),
+                    "Error:".yellow(),
+                    e.to_string().red()
+                );
+                process::exit(102);
+            }
             process::exit(0);
         }
         Some(("cleanup", _)) => {
@@ -372,7 +710,22 @@ async fn main() {
             // Set the subcommand arg matches
             clap_utils::write_sub_command_arg_matches(backup_matches.clone());
 
-            handle_backup(&backup_matches).await;
+            match backup_matches.subcommand() {
+                Some(("verify", verify_matches)) => handle_backup_verify(verify_matches).await,
+                Some(("list", list_matches)) => handle_backup_list(list_matches).await,
+                Some(("snapshots", snapshots_matches)) => handle_backup_snapshots(snapshots_matches),
+                Some(("prune", prune_matches)) => handle_backup_prune(prune_matches).await,
+                Some(("decrypt", decrypt_matches)) => handle_backup_decrypt(decrypt_matches),
+                Some(("join", join_matches)) => handle_backup_join(join_matches),
+                Some(("diff", diff_matches)) => handle_backup_diff(diff_matches),
+                _ => handle_backup(&backup_matches).await,
+            }
+        }
+        Some(("restore", restore_matches)) => {
+            // Set the subcommand arg matches
+            clap_utils::write_sub_command_arg_matches(restore_matches.clone());
+
+            handle_restore(restore_matches).await;
         }
         Some(("log", log_matches)) => {
             // Set the subcommand arg matches
@@ -380,6 +733,16 @@ async fn main() {
 
             handle_log_search(&log_matches).await;
         }
+        Some(("world", world_matches)) => {
+            match world_matches.subcommand() {
+                Some(("report", report_matches)) => handle_world(report_matches, false),
+                Some(("prune", prune_matches)) => handle_world(prune_matches, true),
+                _ => unreachable!(),
+            }
+        }
+        Some(("daemon", daemon_matches)) => {
+            handle_daemon(daemon_matches).await;
+        }
         _ => {
             show_example();
             process::exit(1);
@@ -392,6 +755,8 @@ async fn handle_download(download_matches: &ArgMatches) {
     let current_path = current_dir_path_buffer.as_path();
 
     let software = download_matches.get_one::<String>("software").unwrap().to_lowercase();
+    let notifications = notifications_from_args(download_matches);
+    let mut report = notify::Report::new("download", &software);
     let mut version: String = download_matches
         .get_one::<String>("version")
         .unwrap()
@@ -414,7 +779,7 @@ async fn handle_download(download_matches: &ArgMatches) {
         );
         print!("");
         println!("{}", format!("This is because you don't want your Minecraft Server randomly getting updated to a new Minecraft version without you knowing!").yellow());
-        process::exit(102);
+        fail_download(&notifications, report, "Using latest needs --latest-use-at-your-own-risk").await;
     }
 
     let temp = String::from("");
@@ -432,7 +797,7 @@ async fn handle_download(download_matches: &ArgMatches) {
             format!("{}", &software).red(),
             format!("is not valid!").yellow()
         );
-        process::exit(102);
+        fail_download(&notifications, report, &format!("Project {} is not valid", &software)).await;
     }
 
     // Handle SpigotMC
@@ -470,7 +835,7 @@ async fn handle_download(download_matches: &ArgMatches) {
             );
             println!("{}", format!("This is most likely because the platform({}) doesn't support getting the latest version!", &software).yellow());
 
-            process::exit(102);
+            fail_download(&notifications, report, "Couldn't get the latest version").await;
         }
 
         version = latest_version.unwrap();
@@ -488,7 +853,7 @@ async fn handle_download(download_matches: &ArgMatches) {
         );
         println!("{}", format!("This is most likely because that platform({}) has no build for that version({})", &software, &version).yellow());
 
-        process::exit(102);
+        fail_download(&notifications, report, &format!("Couldn't get the latest build of {}", &version)).await;
     }
     let build = build_option.unwrap();
 
@@ -575,9 +940,638 @@ async fn handle_download(download_matches: &ArgMatches) {
         format!("Time In Milliseconds:").purple().bold(),
         format!("{}", &duration).yellow().bold()
     );
+
+    report.file_name = Some(path_string.clone());
+    report.size = fs::metadata(current_path.join(&path_string)).ok().map(|metadata| metadata.len());
+    report.finish(None);
+    notifications.send(&report).await;
+}
+
+/// Sends the report of a failed download and exits with 102.
+async fn fail_download(notifications: &notify::Notifications, mut report: notify::Report, error: &str) -> ! {
+    report.finish(Some(error.to_string()));
+    notifications.send(&report).await;
+    process::exit(102);
+}
+
+async fn handle_backup_verify(verify_matches: &ArgMatches) {
+    let name = verify_matches.get_one::<String>("name").map(|name| name.as_str());
+    let destinations = destinations_from_args(verify_matches);
+
+    let mut reports: Vec<backup::verify::VerifyReport> = Vec::new();
+
+    // Check the local folder if one was given, or if there is nothing else to check
+    let backup_folder = verify_matches.get_one::<String>("backup_folder");
+    if backup_folder.is_some() || destinations.is_empty() {
+        let backup_folder = PathBuf::from(backup_folder.map(|folder| folder.as_str()).unwrap_or("."));
+        match backup::verify::verify_directory(&backup_folder, name) {
+            Ok(local_reports) => reports.extend(local_reports),
+            Err(e) => {
+                println!(
+                    "{} {} {}",
+                    "Something went wrong!".red().bold(),
+                    "Error:".yellow(),
+                    e.to_string().red()
+                );
+                process::exit(102);
+            }
+        }
+    }
+
+    // Remote bundles are downloaded one at a time to the temp directory, checked and deleted again
+    for destination in &destinations {
+        let work_directory = file_utils::get_or_create_limonium_dir();
+        let remote = connect(destination).await;
+        let file_names = match upload_target::list_file_names(remote.as_ref()).await {
+            Ok(file_names) => file_names,
+            Err(e) => {
+                reports.push(backup::verify::VerifyReport::failed("(all backups)", &destination.label, format!("Could not list the backups: {}", e)));
+                continue;
+            }
+        };
+
+        // Every volume of a split bundle is downloaded before it is checked
+        for (set_name, files) in backup::verify::bundle_sets(&file_names, name) {
+            println!("{} {}", "Verifying".yellow(), set_name);
+            let mut downloaded = Ok(());
+            for file_name in &files {
+                downloaded = remote.download(file_name, &work_directory.join(file_name)).await;
+                if downloaded.is_err() {
+                    break;
+                }
+            }
+            let report = match downloaded {
+                Ok(_) => backup::verify::verify_set(&work_directory, &set_name, &files, &destination.label, &work_directory.join("verify")),
+                Err(e) => backup::verify::VerifyReport::failed(&set_name, &destination.label, format!("Could not download the bundle: {}", e)),
+            };
+            for file_name in &files {
+                let _ = fs::remove_file(work_directory.join(file_name));
+            }
+            reports.push(report);
+        }
+    }
+    if !destinations.is_empty() {
+        let _ = file_utils::delete_limonium_folder();
+    }
+
+    backup::verify::print_reports(&reports);
+
+    if let Some(json_path) = verify_matches.get_one::<String>("json") {
+        let json = serde_json::to_string_pretty(&reports).unwrap();
+        if let Err(e) = fs::write(json_path, json) {
+            println!("{} {}", "Failed to write the JSON results:".red(), e);
+            process::exit(102);
+        }
+        println!("{} {}", "Wrote the results to".green(), json_path);
+    }
+
+    if reports.iter().any(|report| !report.ok) {
+        process::exit(102);
+    }
+}
+
+async fn handle_backup_list(list_matches: &ArgMatches) {
+    let name = list_matches.get_one::<String>("name").unwrap();
+    let remote_destinations = destinations_from_args(list_matches);
+
+    let mut destinations: Vec<(String, Vec<(String, u64)>)> = Vec::new();
+
+    let backup_folder = list_matches.get_one::<String>("backup_folder");
+    if backup_folder.is_some() || remote_destinations.is_empty() {
+        let backup_folder = PathBuf::from(backup_folder.map(|folder| folder.as_str()).unwrap_or("."));
+        match backup::list::local_files_with_sizes(&backup_folder) {
+            Ok(files) => destinations.push(("local".to_string(), files)),
+            Err(e) => println!("{} {}", "Failed to list the local backups:".red(), e),
+        }
+    }
+
+    for destination in &remote_destinations {
+        match connect(destination).await.list().await {
+            Ok(files) => destinations.push((destination.label.clone(), files)),
+            Err(e) => println!("{} {}", format!("Failed to list the backups on {}:", destination.label).red(), e),
+        }
+    }
+
+    let backups = backup::list::merge_listings(name, &destinations);
+    backup::list::print_listing(&backups, destinations.len());
+}
+
+fn exit_with_error(e: std::io::Error) -> ! {
+    println!(
+        "{} {} {}",
+        "Something went wrong!".red().bold(),
+        "Error:".yellow(),
+        e.to_string().red()
+    );
+    process::exit(102);
+}
+
+/// The --keep-* flags of `backup` and `backup prune`.
+fn retention_args() -> Vec<clap::Arg> {
+    [
+        ("keep-last", "Keeps this many of the newest backups"),
+        ("keep-daily", "Keeps the newest backup of this many days"),
+        ("keep-weekly", "Keeps the newest backup of this many weeks"),
+        ("keep-monthly", "Keeps the newest backup of this many months"),
+        ("keep-yearly", "Keeps the newest backup of this many years"),
+    ]
+        .into_iter()
+        .map(|(id, help)| clap::Arg::new(id)
+            .help(help)
+            .long(id)
+            .action(ArgAction::Set)
+            .required(false)
+            .value_parser(clap::value_parser!(u64)))
+        .collect()
+}
+
+/// The --timezone flag of `backup` and `backup prune`.
+fn timezone_arg() -> clap::Arg {
+    clap::Arg::new("timezone")
+        .help("The timezone of the dates in backup names and of \"today\" for retention: local, utc or a name like Europe/Berlin")
+        .long("timezone")
+        .action(ArgAction::Set)
+        .required(false)
+        .default_value("local")
+}
+
+/// The timezone of --timezone, exits on an unknown timezone.
+fn timezone_from_args(matches: &ArgMatches) -> backup::naming::BackupTimezone {
+    backup::naming::BackupTimezone::parse(matches.get_one::<String>("timezone").unwrap()).unwrap_or_else(|e| exit_with_error(e))
+}
+
+/// The --bwlimit, --chunk-size and --pipeline-depth flags of the commands that upload.
+fn transfer_args() -> Vec<clap::Arg> {
+    vec![
+        clap::Arg::new("bwlimit")
+            .help("Limits the combined upload speed of all destinations, in bytes per second (example: 10M for 10 MB/s, K, M and G are powers of 1024)")
+            .long("bwlimit")
+            .action(ArgAction::Set)
+            .required(false),
+        clap::Arg::new("chunk-size")
+            .help("How much of the backup is read and sent at once (default 3M for SFTP and WebDAV, 16M parts for S3, at least 5M)")
+            .long("chunk-size")
+            .action(ArgAction::Set)
+            .required(false),
+        clap::Arg::new("pipeline-depth")
+            .help("How many SFTP writes or S3 parts are in flight at the same time")
+            .long("pipeline-depth")
+            .action(ArgAction::Set)
+            .required(false)
+            .default_value("4")
+            .value_parser(clap::value_parser!(u64).range(1..=64)),
+    ]
+}
+
+/// The upload settings from --bwlimit, --chunk-size and --pipeline-depth, exits on an invalid size.
+fn transfer_options_from_args(matches: &ArgMatches) -> transfer::TransferOptions {
+    let size = |id: &str| matches.get_one::<String>(id)
+        .map(|value| transfer::parse_size(value).unwrap_or_else(|e| exit_with_error(e)));
+    transfer::TransferOptions {
+        chunk_size: size("chunk-size").map(|chunk_size| chunk_size as usize),
+        pipeline_depth: matches.get_one::<u64>("pipeline-depth").map(|depth| *depth as usize).unwrap_or(4),
+        limiter: size("bwlimit").map(|bytes_per_second| Arc::new(transfer::RateLimiter::new(bytes_per_second))),
+    }
+}
+
+/// The --pre-hook, --post-hook, --failure-hook and --hook-timeout flags.
+fn hook_args() -> Vec<clap::Arg> {
+    vec![
+        clap::Arg::new("pre-hook")
+            .help("Runs before backing up, a failing hook stops the backup. A shell command, an URL to POST to, or rcon:<command> (can be given more than once)")
+            .long("pre-hook")
+            .action(ArgAction::Append)
+            .required(false),
+        clap::Arg::new("post-hook")
+            .help("Runs after a successful backup, with the backup details as LIMONIUM_BACKUP_* environment variables (can be given more than once)")
+            .long("post-hook")
+            .action(ArgAction::Append)
+            .required(false),
+        clap::Arg::new("failure-hook")
+            .help("Runs after a failed backup, with the error in LIMONIUM_BACKUP_ERROR (can be given more than once)")
+            .long("failure-hook")
+            .action(ArgAction::Append)
+            .required(false),
+        clap::Arg::new("hook-timeout")
+            .help("How many seconds a hook can take before it is stopped")
+            .long("hook-timeout")
+            .action(ArgAction::Set)
+            .required(false)
+            .default_value("60")
+            .value_parser(clap::value_parser!(u64).range(1..)),
+    ]
+}
+
+/// The hooks from --pre-hook, --post-hook and --failure-hook, exits if one of them is invalid.
+/// RCON hooks use the --rcon settings, or server.properties without --rcon.
+fn hooks_from_args(matches: &ArgMatches) -> hooks::Hooks {
+    let parse = |id: &str| -> Vec<hooks::Hook> {
+        matches.get_many::<String>(id)
+            .map(|specs| specs.map(|spec| hooks::Hook::parse(spec).unwrap_or_else(|e| exit_with_error(e))).collect())
+            .unwrap_or_default()
+    };
+    let mut hooks = hooks::Hooks {
+        pre: parse("pre-hook"),
+        post: parse("post-hook"),
+        failure: parse("failure-hook"),
+        timeout: Duration::from_secs(*matches.get_one::<u64>("hook-timeout").unwrap()),
+        rcon: None,
+    };
+
+    if hooks.uses_rcon() {
+        hooks.rcon = rcon_config_from_args(matches).or_else(|| {
+            Some(rcon::RconConfig::from_server_properties(Path::new(matches.get_one::<String>("server-properties").unwrap()))
+                .unwrap_or_else(|e| exit_with_error(e)))
+        });
+    }
+    hooks
+}
+
+fn notify_args() -> Vec<clap::Arg> {
+    vec![
+        clap::Arg::new("notify")
+            .help("Sends a report when done to a Discord or Slack webhook, an URL that gets the report as JSON, or smtp(s)://user@host?from=...&to=... (can be given more than once)")
+            .long("notify")
+            .action(ArgAction::Append)
+            .required(false),
+        clap::Arg::new("notify-on")
+            .help("When to send the report")
+            .long("notify-on")
+            .action(ArgAction::Set)
+            .required(false)
+            .default_value("always")
+            .value_parser(["always", "failure"]),
+    ]
+}
+
+/// The notifiers from --notify, exits if one of them is invalid.
+fn notifications_from_args(matches: &ArgMatches) -> notify::Notifications {
+    notify::Notifications {
+        notifiers: matches.get_many::<String>("notify")
+            .map(|args| args.map(|arg| notify::Notifier::parse(arg).unwrap_or_else(|e| exit_with_error(e))).collect())
+            .unwrap_or_default(),
+        only_failures: matches.get_one::<String>("notify-on").map(|on| on.as_str()) == Some("failure"),
+    }
+}
+
+/// What a backup reports to when it's done: the hooks (--*-hook) and the notifications (--notify).
+struct BackupReporting {
+    hooks: hooks::Hooks,
+    hook_variables: hooks::HookVariables,
+    notifications: notify::Notifications,
+    report: notify::Report,
+    /// The lock of the backup directory, released once the backup is reported so an exit doesn't leave it behind
+    lock: Option<backup::lock::BackupLock>,
+}
+
+impl BackupReporting {
+    fn from_args(matches: &ArgMatches, name: &str) -> BackupReporting {
+        BackupReporting {
+            hooks: hooks_from_args(matches),
+            hook_variables: hooks::HookVariables::new(name),
+            notifications: notifications_from_args(matches),
+            report: notify::Report::new("backup", name),
+            lock: None,
+        }
+    }
+
+    /// Takes the lock of the backup directory for the rest of the run, the backup fails if another one holds it.
+    async fn lock(&mut self, lock: Result<backup::lock::BackupLock, std::io::Error>) {
+        match lock {
+            Ok(lock) => self.lock = Some(lock),
+            Err(e) => self.fail(e).await,
+        }
+    }
+
+    /// Runs the pre-hooks, the backup fails if one of them failed.
+    async fn run_pre_hooks(&mut self) {
+        if let Err(e) = self.hooks.run_pre(&self.hook_variables).await {
+            self.fail(e).await;
+        }
+    }
+
+    fn set_bundle(&mut self, result: &backup::BackupResult) {
+        self.hook_variables = self.hook_variables.clone()
+            .with("file_name", result.file_name.clone())
+            .with("file_path", result.file_path.display().to_string())
+            .with("sha256", result.sha256_hash.clone());
+        self.report.file_name = Some(result.file_name.clone());
+        self.report.size = fs::metadata(&result.file_path).ok().map(|metadata| metadata.len());
+        self.report.sha256 = Some(result.sha256_hash.clone());
+    }
+
+    fn set_snapshot(&mut self, result: &backup::repository::SnapshotResult) {
+        self.hook_variables = self.hook_variables.clone().with("snapshot", result.id.clone());
+        self.report.file_name = Some(result.id.clone());
+        self.report.size = Some(result.total_bytes);
+    }
+
+    async fn succeeded(&mut self) {
+        self.hooks.run_post(&self.hook_variables).await;
+        self.report.finish(None);
+        self.notifications.send(&self.report).await;
+        self.lock = None;
+    }
+
+    async fn failed(&mut self, error: &str) {
+        self.hooks.run_failure(&self.hook_variables, error).await;
+        self.report.finish(Some(error.to_string()));
+        self.notifications.send(&self.report).await;
+        self.lock = None;
+    }
+
+    /// Reports the error and exits with it.
+    async fn fail(&mut self, e: std::io::Error) -> ! {
+        self.failed(&e.to_string()).await;
+        exit_with_error(e);
+    }
+}
+
+/// The --sftp, --webdav, --s3 and --destination flags, `action` is what is done with them ("Uploads the backup to").
+fn destination_args(action: &str) -> Vec<clap::Arg> {
+    [
+        ("sftp", format!("{} a SFTP server (sftp://user@host:port/path?key=key_file, a destination name or \"user@host:optional_port key_file remote_dir\")", action)),
+        ("webdav", format!("{} a WebDAV server (webdav+https://username@host/path, a destination name or \"url username\")", action)),
+        ("s3", format!("{} a S3 bucket (s3+https://host/bucket/folder?region=region, a destination name or \"endpoint bucket/folder access_key secret_key optional_region\")", action)),
+        ("destination", format!("{} a destination from ~/.config/limonium/destinations.json, or a destination URL", action)),
+    ]
+        .into_iter()
+        .map(|(id, help)| clap::Arg::new(id)
+            .help(help)
+            .long(id)
+            .action(ArgAction::Append)
+            .required(false))
+        .collect()
+}
+
+/// The retention policy of the --keep-* flags, plus a keep-within rule from a delete-after-time flag if given.
+fn retention_policy_from_args(matches: &ArgMatches, delete_after_time_id: Option<&str>) -> RetentionPolicy {
+    let keep = |id: &str| matches.get_one::<u64>(id).copied().unwrap_or(0);
+    let keep_within_days = delete_after_time_id
+        .and_then(|id| matches.get_one::<String>(id))
+        .map(|input| RetentionPolicy::parse_days(input).unwrap_or_else(|e| exit_with_error(e)));
+
+    RetentionPolicy {
+        keep_last: keep("keep-last"),
+        keep_daily: keep("keep-daily"),
+        keep_weekly: keep("keep-weekly"),
+        keep_monthly: keep("keep-monthly"),
+        keep_yearly: keep("keep-yearly"),
+        keep_within_days,
+        timezone: timezone_from_args(matches),
+    }
+}
+
+/// Deletes the snapshots the policy doesn't keep, and the `extra` snapshots, from a repository.
+fn prune_repository(repository: &backup::repository::Repository, name: &str, policy: &RetentionPolicy, extra: &[String], dry_run: bool) -> Result<(), std::io::Error> {
+    let snapshot_ids = repository.snapshot_ids()?;
+
+    let mut to_delete: Vec<String> = Vec::new();
+    if !policy.is_empty() {
+        let decisions = backup::retention::decide_for_files(policy, &snapshot_ids, name);
+        backup::retention::print_decisions("repository", &decisions, dry_run);
+        to_delete.extend(decisions.into_iter().filter(|decision| !decision.keep).map(|decision| decision.file_name));
+    }
+    for snapshot in extra {
+        if !snapshot_ids.contains(snapshot) {
+            return Err(std::io::Error::new(std::io::ErrorKind::NotFound, format!("There is no snapshot {}", snapshot)));
+        }
+        if !to_delete.contains(snapshot) {
+            if dry_run {
+                println!("  {} {}", "would delete".red(), snapshot);
+            }
+            to_delete.push(snapshot.clone());
+        }
+    }
+
+    if dry_run {
+        return Ok(());
+    }
+
+    let result = repository.prune(&to_delete)?;
+    for id in &result.removed_snapshots {
+        println!("{} {}", "Deleted snapshot".yellow(), id);
+    }
+    println!(
+        "{} {}",
+        format!("Deleted {} snapshots and {} chunks", result.removed_snapshots.len(), result.removed_chunks).green().bold(),
+        format!("({:.2} MB freed)", result.removed_bytes as f64 / 1024.0 / 1024.0).bright_cyan()
+    );
+    Ok(())
+}
+
+/// The RCON server from --rcon-* or server.properties when --rcon is given.
+fn rcon_config_from_args(matches: &ArgMatches) -> Option<rcon::RconConfig> {
+    if !matches.get_flag("rcon") {
+        return None;
+    }
+
+    let mut config = match matches.get_one::<String>("rcon-password") {
+        Some(password) => rcon::RconConfig {
+            host: "127.0.0.1".to_string(),
+            port: 25575,
+            password: password.clone(),
+        },
+        None => rcon::RconConfig::from_server_properties(Path::new(matches.get_one::<String>("server-properties").unwrap()))
+            .unwrap_or_else(|e| exit_with_error(e)),
+    };
+    if let Some(host) = matches.get_one::<String>("rcon-host") {
+        config.host = host.clone();
+    }
+    if let Some(port) = matches.get_one::<u16>("rcon-port") {
+        config.port = *port;
+    }
+    Some(config)
+}
+
+/// Turns saving back on after a backup, a failure is only a warning because the backup itself is fine.
+fn resume_saving(paused: Option<rcon::SavingPaused>) {
+    if let Some(paused) = paused
+        && let Err(e) = paused.resume() {
+        println!("{} {}", "(RCON) Could not turn saving back on! Run save-on on the server:".red().bold(), e);
+    }
+}
+
+/// Syncs a repository to every destination given with --sftp, --webdav, --s3 and --destination at the same time.
+/// Returns false after the summary if any of them failed.
+async fn sync_repository_to_remotes(repository: &backup::repository::Repository, matches: &ArgMatches) -> bool {
+    let destinations = destinations_from_args(matches);
+    if destinations.is_empty() {
+        return true;
+    }
+
+    println!("{}", format!("Syncing the repository to {} destinations!", destinations.len()).green().bold());
+    let reports = upload_target::sync_repository_to_all(&destinations, repository, &transfer_options_from_args(matches)).await;
+    upload_target::print_upload_reports(&reports);
+    reports.iter().all(|report| report.ok())
+}
+
+async fn handle_repository_backup(backup_matches: &ArgMatches) {
+    let name = backup_matches.get_one::<String>("name").unwrap();
+    let to_backup = backup_matches.get_one::<String>("to_backup").unwrap();
+    let backup_folder = env::current_dir().unwrap().join(backup_matches.get_one::<String>("backup_folder").unwrap());
+    let exclude = backup_matches.get_one::<String>("exclude").map(|exclude| exclude.as_str());
+
+    // These only make sense for bundles, old snapshots are removed with the --keep-* flags or `limonium backup prune`
+    for flag in ["level", "I", "local-delete-after-time", "remote-delete-after-time"] {
+        if backup_matches.value_source(flag) == Some(clap::parser::ValueSource::CommandLine) {
+            exit_with_error(std::io::Error::other(format!("--{} can't be used with --repository", flag)));
+        }
+    }
+    if backup_matches.get_flag("delete-after-upload") {
+        exit_with_error(std::io::Error::other("--delete-after-upload can't be used with --repository, the repository is needed for the next backup"));
+    }
+
+    let has_remote_upload = !destinations_from_args(backup_matches).is_empty();
+    let skip_upload = has_remote_upload && backup_matches.get_flag("ask-before-uploading") && ask_for_input_to_upload();
+    let naming = backup::naming::NamingTemplate::parse(backup_matches.get_one::<String>("name-template").unwrap()).unwrap_or_else(|e| exit_with_error(e));
+    let timezone = timezone_from_args(backup_matches);
+    let rcon_config = rcon_config_from_args(backup_matches);
+    let mut reporting = BackupReporting::from_args(backup_matches, name);
+
+    // Like with bundles, the backup directory stays locked until the snapshot is pruned and synced
+    reporting.lock(fs::create_dir_all(&backup_folder).and_then(|_| backup::lock::BackupLock::acquire(&backup_folder))).await;
+
+    let time = Instant::now();
+    reporting.run_pre_hooks().await;
+    println!("{}", "Please wait while the snapshot is being created...".yellow());
+
+    let repository = match backup::repository::Repository::open_or_create(&backup::repository::Repository::path_for(&backup_folder, name)) {
+        Ok(repository) => repository.with_naming(naming, timezone),
+        Err(e) => reporting.fail(e).await,
+    };
+    let paused = match rcon_config.as_ref().map(rcon::pause_saving).transpose() {
+        Ok(paused) => paused,
+        Err(e) => reporting.fail(e).await,
+    };
+    let result = repository.snapshot(name, to_backup, exclude, backup_matches.get_flag("verbose"));
+    resume_saving(paused);
+    let result = match result {
+        Ok(result) => result,
+        Err(e) => reporting.fail(e).await,
+    };
+    backup::repository::print_snapshot_result(&result);
+    reporting.set_snapshot(&result);
+
+    let policy = retention_policy_from_args(backup_matches, None);
+    if !policy.is_empty()
+        && let Err(e) = prune_repository(&repository, name, &policy, &[], false) {
+        reporting.fail(e).await;
+    }
+
+    let mut all_synced = true;
+    if !skip_upload {
+        all_synced = sync_repository_to_remotes(&repository, backup_matches).await;
+    } else {
+        println!("{}", "Skipping remote upload!".yellow());
+    }
+
+    print_backup_time_elapsed(time);
+    if !all_synced {
+        reporting.failed("Not every destination was synced").await;
+        process::exit(102);
+    }
+    reporting.succeeded().await;
+}
+
+fn handle_backup_snapshots(snapshots_matches: &ArgMatches) {
+    let name = snapshots_matches.get_one::<String>("name").unwrap();
+    let backup_folder = PathBuf::from(snapshots_matches.get_one::<String>("backup_folder").unwrap());
+
+    let repository = backup::repository::Repository::open(&backup::repository::Repository::path_for(&backup_folder, name))
+        .unwrap_or_else(|e| exit_with_error(e));
+    let snapshot_ids = repository.snapshot_ids().unwrap_or_else(|e| exit_with_error(e));
+
+    println!("{}", format!("{:<32} {:<20} {:>8} {:>12}", "Snapshot", "Time", "Files", "Size").bold());
+    for id in &snapshot_ids {
+        let snapshot = repository.load_snapshot(id).unwrap_or_else(|e| exit_with_error(e));
+        let time = chrono::DateTime::parse_from_rfc3339(&snapshot.time)
+            .map(|time| time.with_timezone(&chrono::Local).format("%Y-%m-%d %H:%M:%S").to_string())
+            .unwrap_or(snapshot.time.clone());
+        println!(
+            "{:<32} {:<20} {:>8} {:>12}",
+            snapshot.id.green(),
+            time,
+            snapshot.entries.len(),
+            format!("{:.2} MB", snapshot.total_size() as f64 / 1024.0 / 1024.0)
+        );
+    }
+    println!("{}", format!("{} snapshots", snapshot_ids.len()).green().bold());
+}
+
+async fn handle_backup_prune(prune_matches: &ArgMatches) {
+    let name = prune_matches.get_one::<String>("name").unwrap();
+    let backup_folder = PathBuf::from(prune_matches.get_one::<String>("backup_folder").unwrap());
+    let dry_run = prune_matches.get_flag("dry-run");
+    let policy = retention_policy_from_args(prune_matches, None);
+    let snapshots: Vec<String> = prune_matches.get_one::<String>("snapshot")
+        .map(|snapshots| snapshots.split(':').map(|snapshot| snapshot.to_string()).collect())
+        .unwrap_or_default();
+
+    if policy.is_empty() && snapshots.is_empty() {
+        exit_with_error(std::io::Error::new(std::io::ErrorKind::InvalidInput, "Nothing to prune, use the --keep-* flags or --snapshot"));
+    }
+    if dry_run {
+        println!("{}", "Dry run, nothing will be deleted!".yellow().bold());
+    }
+    // A backup that is running prunes the same bundles, so pruning waits for its turn like a backup
+    let lock = (!dry_run).then(|| backup::lock::BackupLock::acquire(&backup_folder).unwrap_or_else(|e| exit_with_error(e)));
+
+    let repository_path = backup::repository::Repository::path_for(&backup_folder, name);
+    let repository = if repository_path.exists() || !snapshots.is_empty() {
+        Some(backup::repository::Repository::open(&repository_path).unwrap_or_else(|e| exit_with_error(e)))
+    } else {
+        None
+    };
+    if let Some(repository) = &repository {
+        prune_repository(repository, name, &policy, &snapshots, dry_run).unwrap_or_else(|e| exit_with_error(e));
+    }
+
+    if !policy.is_empty() {
+        backup::retention::apply_local(&backup_folder, name, &policy, dry_run).unwrap_or_else(|e| exit_with_error(e));
+
+        for destination in destinations_from_args(prune_matches) {
+            upload_target::apply_retention(connect(&destination).await.as_ref(), name, &policy, dry_run).await
+                .unwrap_or_else(|e| exit_with_error(e));
+        }
+    }
+
+    if let Some(repository) = &repository
+        && !dry_run
+        && !sync_repository_to_remotes(repository, prune_matches).await {
+        drop(lock);
+        process::exit(102);
+    }
+}
+
+fn restore_snapshot(backup_folder: &Path, name: &str, snapshot: &str, target: &Path, only: &[PathBuf]) {
+    let repository = backup::repository::Repository::open(&backup::repository::Repository::path_for(backup_folder, name))
+        .unwrap_or_else(|e| exit_with_error(e));
+
+    fs::create_dir_all(target).unwrap_or_else(|e| exit_with_error(e));
+    if let Some(pid) = backup::restore::find_process_using_directory(target, "java") {
+        exit_with_error(std::io::Error::other(format!("A server (pid {}) is running in {}. Stop it before restoring.", pid, target.display())));
+    }
+
+    println!("{} {}", "Restoring snapshot!".green().bold(), snapshot.bright_yellow());
+    let restored = repository.restore(snapshot, target, only).unwrap_or_else(|e| exit_with_error(e));
+    if restored == 0 && !only.is_empty() {
+        exit_with_error(std::io::Error::new(std::io::ErrorKind::NotFound, "Nothing in the snapshot matched --only"));
+    }
+    println!(
+        "{} {} {}",
+        "Restore completed!".green().bold(),
+        "Entries restored:".yellow(),
+        restored.to_string().green()
+    );
 }
 
 async fn handle_backup(backup_matches: &ArgMatches) {
+    if backup_matches.get_flag("repository") {
+        handle_repository_backup(backup_matches).await;
+        return;
+    }
+
     let current_dir_path_buffer = env::current_dir().unwrap();
     let current_path = current_dir_path_buffer.as_path();
 
@@ -619,246 +1613,294 @@ async fn handle_backup(backup_matches: &ArgMatches) {
         backup_format,
         exclude_ours,
         compression_level_ours,
-    );
+    ).with_naming(
+        backup::naming::NamingTemplate::parse(backup_matches.get_one::<String>("name-template").unwrap()).unwrap_or_else(|e| exit_with_error(e)),
+        timezone_from_args(backup_matches),
+    ).with_databases(databases_from_args(backup_matches))
+    .with_space_check(!backup_matches.get_flag("no-space-check"))
+    .with_chunk_skipping(backup_matches.get_one::<String>("skip-chunks-below")
+        .map(|below| world::parse_inhabited_time(below).unwrap_or_else(|e| exit_with_error(e))));
+
+    if backup_matches.get_flag("dry-run") {
+        backup.dry_run().unwrap_or_else(|e| exit_with_error(e));
+        return;
+    }
 
     // Ask if you want to upload the backup to a remote server before creating the backup
     let ask_before_upload = backup_matches.get_flag("ask-before-uploading");
-    let sftp_option = backup_matches.get_one::<String>("sftp");
-    let webdav_option = backup_matches.get_one::<String>("webdav");
-    let has_remote_upload = sftp_option.is_some() || webdav_option.is_some();
+    let destinations = destinations_from_args(backup_matches);
+    let has_remote_upload = !destinations.is_empty();
     let mut skip_upload = false;
     if ask_before_upload && has_remote_upload {
         skip_upload = ask_for_input_to_upload();
     }
 
+    // The retention policies are checked before backing up, so a typo doesn't fail after a long backup
+    let mut local_policy = retention_policy_from_args(backup_matches, Some("local-delete-after-time"));
+    if local_policy.keep_within_days.is_some()
+        && let Some(always_keep) = backup_matches.get_one::<u64>("local-always-keep") {
+        local_policy.keep_last = local_policy.keep_last.max(*always_keep);
+    }
+    let remote_policy = retention_policy_from_args(backup_matches, Some("remote-delete-after-time"));
+    let encryption_key = encryption_key_from_args(backup_matches);
+    let transfer = transfer_options_from_args(backup_matches);
+    let split_size = backup_matches.get_one::<String>("split-size")
+        .map(|split_size| transfer::parse_size(split_size).unwrap_or_else(|e| exit_with_error(e)));
+    let rcon_config = rcon_config_from_args(backup_matches);
+    let mut reporting = BackupReporting::from_args(backup_matches, name);
+
+    // The lock is held until the bundle is encrypted, split, pruned and uploaded, so an overlapping run can't touch it
+    reporting.lock(backup.lock()).await;
+
     let time = Instant::now();
+    reporting.run_pre_hooks().await;
+
+    // Saving is turned off during the backup so region files aren't written to while they are archived
+    let paused = match rcon_config.as_ref().map(rcon::pause_saving).transpose() {
+        Ok(paused) => paused,
+        Err(e) => reporting.fail(e).await,
+    };
 
     // If error show error
-    let the_backup = backup.backup();
-    if the_backup.is_err() {
+    let the_backup = backup.backup_holding(reporting.lock.as_ref().unwrap());
+    resume_saving(paused);
+    let mut backup_result = match the_backup {
+        Ok(backup_result) => backup_result,
+        Err(e) => reporting.fail(e).await,
+    };
+
+    // The bundle is encrypted before anything else, so only the encrypted bundle is kept and uploaded
+    if let Some(key) = &encryption_key {
+        println!("{}", "Encrypting the backup...".yellow());
+        let encrypted = match backup::encryption::encrypt_bundle(&backup_result.file_path, &backup_result.sha256_hash, key) {
+            Ok(encrypted) => encrypted,
+            Err(e) => reporting.fail(e).await,
+        };
+        println!("{} {}", "Encrypted backup file:".green(), encrypted.file_path.file_name().unwrap().to_string_lossy().bright_yellow());
+        println!("{}", format!("Plaintext (sha256) hash: {}", &backup_result.sha256_hash).green());
+        println!("{}", format!("Encrypted (sha256) hash: {}", &encrypted.sha256_hash).green());
+
+        backup_result.file_name = encrypted.file_path.file_name().unwrap().to_string_lossy().to_string();
+        backup_result.file_path = encrypted.file_path;
+        backup_result.sha256_hash = encrypted.sha256_hash;
+    }
+    reporting.set_bundle(&backup_result);
+
+    // Split into volumes after encrypting, so the volumes are encrypted too. Bundles that fit aren't split.
+    let mut upload_files = vec![upload_target::UploadFile::new(&backup_result.file_path, &backup_result.sha256_hash)];
+    if let Some(split_size) = split_size
+        && fs::metadata(&backup_result.file_path).is_ok_and(|metadata| metadata.len() > split_size) {
+        let volume_set = match backup::volume::split_bundle(&backup_result.file_path, &backup_result.sha256_hash, split_size) {
+            Ok(volume_set) => volume_set,
+            Err(e) => reporting.fail(e).await,
+        };
         println!(
             "{} {} {}",
-            format!("Something went wrong!").red().bold(),
-            format!("Error:").yellow(),
-            format!("{}", the_backup.err().unwrap()).red()
+            "Split the backup into".green(),
+            volume_set.volumes.len().to_string().bright_yellow(),
+            format!("volumes, the hashes are in {}", volume_set.hash_file_name()).green()
         );
-        process::exit(102);
+        upload_files = match upload_target::UploadFile::for_volume_set(backup_result.file_path.parent().unwrap(), &volume_set) {
+            Ok(upload_files) => upload_files,
+            Err(e) => reporting.fail(e).await,
+        };
     }
 
-    let backup_result = the_backup.unwrap();
-
-    // Handle deleting backups after a certain amount of time LOCALLY
-    let local_delete_after_time = backup_matches.get_one::<String>("local-delete-after-time");
-    let local_always_keep = backup_matches.get_one::<u64>("local-always-keep");
-    if local_delete_after_time.is_some() {
-        let local_delete_after_time_input = local_delete_after_time.unwrap().to_string();
-        backup.local_delete_after_time(
-            &local_delete_after_time_input,
-            local_always_keep.map(|&v| v as usize),
-        );
+    // Handle the retention policy LOCALLY
+    if !local_policy.is_empty()
+        && let Err(e) = backup.apply_retention(&local_policy, false) {
+        reporting.fail(e).await;
+    }
 
+    if has_remote_upload && skip_upload {
         println!(
             "{} {}",
-            format!("Deleting LOCAL backups after").yellow(),
-            format!("{}", local_delete_after_time_input).green()
+            "Skipping remote upload!".green().bold(),
+            "Skipping remote upload!".yellow()
         );
+        print_backup_time_elapsed(time);
+        reporting.succeeded().await;
+        return;
     }
 
-    let mut did_upload = false;
-
-    // Handle uploading to SFTP if SFTP is specified
-    if sftp_option.is_some() && !skip_upload {
+    let mut all_uploaded = true;
+    if has_remote_upload {
         println!(
             "{} {}",
-            format!("Uploading to SFTP!").green().bold(),
-            format!("This may take a while depending on the size of the backup!").yellow()
+            format!("Uploading to {} destinations!", destinations.len()).green().bold(),
+            "This may take a while depending on the size of the backup!".yellow()
         );
 
-        let sftp_args = sftp_option.unwrap();
-        let sftp_args_vector = sftp_args.split(" ").collect::<Vec<&str>>();
-        let sftp_user_and_host_vector = sftp_args_vector[0].split("@").collect::<Vec<&str>>();
-
-        // --sftp user@host:optional_port key_file remote_dir
-
-        let sftp_user = sftp_user_and_host_vector[0];
-        let mut sftp_host = sftp_user_and_host_vector[1];
-
-        // Check if port is specified
-        let mut sftp_port: Option<u16> = None;
-        if sftp_host.contains(":") {
-            let sftp_host_vector = sftp_host.split(":").collect::<Vec<&str>>();
-            sftp_host = sftp_host_vector[0];
-            sftp_port = Some(sftp_host_vector[1].parse::<u16>().unwrap());
-        }
-
-        // If sftp_args_vector length is 3, then we have a key file
-        let mut sftp_key_file: Option<&Path> = None;
-        if sftp_args_vector.len() == 3 {
-            sftp_key_file = Some(Path::new(sftp_args_vector[1]));
+        let reports = upload_target::upload_to_all(&destinations, &upload_target::BundleUpload {
+            files: &upload_files,
+            backup_name: name,
+            policy: &remote_policy,
+        }, &transfer).await;
+        upload_target::print_upload_reports(&reports);
+        reporting.report.set_destinations(&reports);
+        all_uploaded = reports.iter().all(|report| report.ok());
+
+        // The local file is the only complete copy until every destination has it
+        if backup_matches.get_flag("delete-after-upload") {
+            if all_uploaded {
+                for file in &upload_files {
+                    println!(
+                        "{} {}",
+                        "Deleting file after upload!".green().bold(),
+                        format!("File: {}", file.path.display()).yellow()
+                    );
+                    if let Err(e) = fs::remove_file(&file.path) {
+                        reporting.fail(e).await;
+                    }
+                }
+            } else {
+                println!("{}", "Keeping the local backup because not every upload succeeded".yellow());
+            }
         }
+    }
 
-        let sftp_remote_dir = sftp_args_vector[sftp_args_vector.len() - 1];
+    print_backup_time_elapsed(time);
+    if !all_uploaded {
+        reporting.failed("Not every upload succeeded").await;
+        process::exit(102);
+    }
+    reporting.succeeded().await;
+}
 
-        // Login to SFTP once and reuse the session
-        let sftp_session = sftp::login(
-            sftp_user.to_string(),
-            sftp_host.to_string(),
-            sftp_port,
-            sftp_key_file,
-        )
-            .await;
+/// The encryption of `backup` from --encrypt-to or --encrypt-passphrase-file, exits if the keys can't be read.
+fn encryption_key_from_args(backup_matches: &ArgMatches) -> Option<backup::encryption::EncryptionKey> {
+    if let Some(recipients) = backup_matches.get_many::<String>("encrypt-to") {
+        let recipients = recipients.cloned().collect::<Vec<String>>();
+        return Some(backup::encryption::EncryptionKey::from_recipients(&recipients).unwrap_or_else(|e| exit_with_error(e)));
+    }
+    backup_matches.get_one::<String>("encrypt-passphrase-file").map(|path| {
+        backup::encryption::EncryptionKey::from_passphrase_file(Path::new(path)).unwrap_or_else(|e| exit_with_error(e))
+    })
+}
 
-        if sftp_session.is_err() {
-            println!(
-                "{} {} {}",
-                format!("Something went wrong!").red().bold(),
-                format!("Error:").yellow(),
-                format!("{}", sftp_session.err().unwrap()).red()
-            );
-            process::exit(102);
-        }
+fn handle_backup_decrypt(decrypt_matches: &ArgMatches) {
+    let bundle = PathBuf::from(decrypt_matches.get_one::<String>("bundle").unwrap());
+    let output_folder = PathBuf::from(decrypt_matches.get_one::<String>("output_folder").unwrap());
+    let key = match decrypt_matches.get_one::<String>("identity") {
+        Some(identity) => backup::encryption::DecryptionKey::IdentityFile(PathBuf::from(identity)),
+        None => backup::encryption::DecryptionKey::PassphraseFile(PathBuf::from(decrypt_matches.get_one::<String>("passphrase-file").unwrap())),
+    };
 
-        let sftp_session = sftp_session.unwrap();
+    fs::create_dir_all(&output_folder).unwrap_or_else(|e| exit_with_error(e));
+    println!("{}", "Please wait while the backup is being decrypted...".yellow());
+    let result = backup::encryption::decrypt_bundle(&bundle, &output_folder, &key).unwrap_or_else(|e| exit_with_error(e));
 
-        let result = sftp::upload_file(
-            &sftp_session,
-            &backup_result.file_path,
-            &backup_result.file_name,
-            sftp_remote_dir,
-            &backup_result.sha256_hash,
-        )
-            .await;
+    println!("{}", "Decrypted the backup, and it matches the recorded hash!".green().bold());
+    println!("{} {}", "Backup file:".green(), result.file_path.display().to_string().bright_yellow());
+    println!("{}", format!("Backup (sha256) hash: {}", result.sha256_hash).green());
+}
 
-        if result.is_err() {
-            println!(
-                "{} {} {}",
-                format!("Something went wrong!").red().bold(),
-                format!("Error:").yellow(),
-                format!("{}", result.err().unwrap()).red()
-            );
-            process::exit(102);
-        }
+fn handle_backup_join(join_matches: &ArgMatches) {
+    let volumes = PathBuf::from(join_matches.get_one::<String>("volumes").unwrap());
+    let output_folder = PathBuf::from(join_matches.get_one::<String>("output_folder").unwrap());
 
-        did_upload = true;
+    fs::create_dir_all(&output_folder).unwrap_or_else(|e| exit_with_error(e));
+    println!("{}", "Please wait while the volumes are being joined...".yellow());
+    let (bundle_path, volume_set) = backup::volume::join_volumes(&backup::volume::hash_file_of(&volumes), &output_folder)
+        .unwrap_or_else(|e| exit_with_error(e));
 
-        // Handle deleting backups after a certain amount of time REMOTELY (SFTP)
-        let remote_delete_after_time = backup_matches.get_one::<String>("remote-delete-after-time");
-        if remote_delete_after_time.is_some() {
-            let remote_delete_after_time_input = remote_delete_after_time.unwrap().to_string();
-            sftp::delete_after_time(
-                &sftp_session,
-                name,
-                &remote_delete_after_time_input,
-                sftp_remote_dir,
-            )
-                .await;
+    println!("{}", format!("Joined {} volumes, and every one matches the hash file!", volume_set.volumes.len()).green().bold());
+    println!("{} {}", "Backup file:".green(), bundle_path.display().to_string().bright_yellow());
+    println!("{}", format!("Backup (sha256) hash: {}", volume_set.sha256_hash).green());
+}
 
-            println!(
-                "{} {}",
-                format!("Deleting REMOTE (SFTP) backups after").yellow(),
-                format!("{}", remote_delete_after_time_input).green()
-            );
+fn handle_backup_diff(diff_matches: &ArgMatches) {
+    let a = PathBuf::from(diff_matches.get_one::<String>("a").unwrap());
+    let b = PathBuf::from(diff_matches.get_one::<String>("b").unwrap());
+    let only = diff_matches.get_one::<String>("only")
+        .map(|only| only.split(':').map(PathBuf::from).collect::<Vec<PathBuf>>())
+        .unwrap_or_default();
+
+    let mut bundles = Vec::new();
+    for bundle_path in [&a, &b] {
+        // Bundles without a manifest are unpacked next to themselves, not into /tmp
+        let parent = bundle_path.parent().filter(|parent| !parent.as_os_str().is_empty()).unwrap_or(Path::new("."));
+        let bundle = backup::manifest::read_bundle_files(bundle_path, &parent.join(".lmtmp-diff")).unwrap_or_else(|e| exit_with_error(e));
+        if let Some(manifest) = &bundle.manifest {
+            let file_name = bundle_path.file_name().unwrap_or_default().to_string_lossy();
+            backup::manifest::print_manifest_details(&file_name, manifest);
         }
+        bundles.push(bundle);
     }
 
-    // Handle uploading to WebDAV if WebDAV is specified
-    if webdav_option.is_some() && !skip_upload {
-        println!(
-            "{} {}",
-            format!("Uploading to WebDAV!").green().bold(),
-            format!("This may take a while depending on the size of the backup!").yellow()
-        );
-
-        let webdav_args = webdav_option.unwrap();
-        let webdav_args_vector = webdav_args.split(" ").collect::<Vec<&str>>();
-
-        if webdav_args_vector.len() != 3 {
-            println!(
-                "{} {} {}",
-                format!("Something went wrong!").red().bold(),
-                format!("Error:").yellow(),
-                format!("Invalid WebDAV arguments. Expected format: \"url username password\"").red()
-            );
-            process::exit(102);
-        }
+    println!("{} {} {} {}", "Changes from".yellow(), a.display().to_string().bright_yellow(), "to".yellow(), b.display().to_string().bright_yellow());
+    let diff = backup::manifest::diff_files(&bundles[0].files, &bundles[1].files, &only);
+    backup::manifest::print_diff(&diff);
+}
 
-        // --webdav "url username password"
-        let webdav_url = webdav_args_vector[0];
-        let webdav_username = webdav_args_vector[1];
-        let webdav_password = webdav_args_vector[2];
+fn world_folders_arg() -> clap::Arg {
+    clap::Arg::new("worlds")
+        .help("The world folders, separated by a : (the folders that don't exist are skipped)")
+        .action(ArgAction::Set)
+        .required(false)
+        .default_value("world:world_nether:world_the_end")
+        .index(1)
+}
 
-        let webdav_client = webdav::WebDavClient::new(
-            webdav_url.to_string(),
-            webdav_username.to_string(),
-            webdav_password.to_string(),
-        );
+fn handle_world(world_matches: &ArgMatches, prune: bool) {
+    let below = world_matches.get_one::<String>("inhabited-below");
+    let below_ticks = below.map(|below| world::parse_inhabited_time(below).unwrap_or_else(|e| exit_with_error(e)));
+    let dry_run = prune && world_matches.get_flag("dry-run");
 
-        let result = webdav_client.upload_file(
-            &backup_result.file_path,
-            &backup_result.file_name,
-        )
-            .await;
+    let worlds: Vec<PathBuf> = world_matches.get_one::<String>("worlds").unwrap().split(':').map(PathBuf::from).filter(|world| world.is_dir()).collect();
+    if worlds.is_empty() {
+        exit_with_error(std::io::Error::new(std::io::ErrorKind::NotFound, "None of the world folders exist"));
+    }
 
-        if result.is_err() {
-            println!(
-                "{} {} {}",
-                format!("Something went wrong!").red().bold(),
-                format!("Error:").yellow(),
-                format!("{}", result.err().unwrap()).red()
-            );
-            process::exit(102);
+    // Minecraft keeps the region files it uses open, and would write the pruned chunks back.
+    // The server runs in the folder the world is in.
+    if prune && !dry_run {
+        for world in &worlds {
+            let server_directory = fs::canonicalize(world).ok().and_then(|world| world.parent().map(Path::to_path_buf)).unwrap_or(world.clone());
+            if let Some(pid) = backup::restore::find_process_using_directory(&server_directory, "java") {
+                exit_with_error(std::io::Error::other(format!("A server (pid {}) is running in {}, stop it before pruning", pid, world.display())));
+            }
         }
+    }
 
-        did_upload = true;
+    println!("{}", "Please wait while the region files are being read...".yellow());
+    let mut reports = Vec::new();
+    for world in &worlds {
+        for dimension in world::find_dimensions(world).unwrap_or_else(|e| exit_with_error(e)) {
+            reports.push(world::survey(dimension, below_ticks).unwrap_or_else(|e| exit_with_error(e)));
+        }
+    }
+    world::print_reports(&reports, below.map(|below| below.as_str()));
 
-        // Handle deleting backups after a certain amount of time REMOTELY (WebDAV)
-        let remote_delete_after_time = backup_matches.get_one::<String>("remote-delete-after-time");
-        if remote_delete_after_time.is_some() {
-            let remote_delete_after_time_input = remote_delete_after_time.unwrap().to_string();
-            webdav_client.delete_after_time(
-                name,
-                &remote_delete_after_time_input,
-            )
-                .await;
+    if !prune {
+        return;
+    }
+    let chunks: usize = reports.iter().map(|report| report.prunable_chunks).sum();
+    let regions: usize = reports.iter().map(|report| report.plans.len()).sum();
+    if dry_run {
+        println!("{}", format!("Would remove {} chunks from {} region files, nothing was changed", chunks, regions).yellow());
+        return;
+    }
 
-            println!(
-                "{} {}",
-                format!("Deleting REMOTE (WebDAV) backups after").yellow(),
-                format!("{}", remote_delete_after_time_input).green()
-            );
-        }
+    let mut freed = 0;
+    for report in &reports {
+        freed += world::prune(report).unwrap_or_else(|e| exit_with_error(e));
     }
+    println!("{}", format!("Removed {} chunks from {} region files, freeing {}", chunks, regions, backup::list::human_size(freed)).green().bold());
+}
 
-    // Handle post-upload actions
-    if did_upload {
-        // Handle deleting the file after upload if specified
-        let delete_after_upload = backup_matches.get_flag("delete-after-upload");
-        if delete_after_upload {
-            let file_to_delete = &backup_result.file_path;
-            let result = fs::remove_file(file_to_delete);
-            println!(
-                "{} {}",
-                format!("Deleting file after upload!").green().bold(),
-                format!("File:").yellow()
-            );
-            if result.is_err() {
-                println!(
-                    "{} {} {}",
-                    format!("Something went wrong!").red().bold(),
-                    format!("Error:").yellow(),
-                    format!("{}", result.err().unwrap()).red()
-                );
-                process::exit(102);
-            }
-        }
-    } else if has_remote_upload && skip_upload {
-        println!(
-            "{} {}",
-            format!("Skipping remote upload!").green().bold(),
-            format!("Skipping remote upload!").yellow()
-        );
+async fn handle_daemon(daemon_matches: &ArgMatches) {
+    let schedule_path = daemon_matches.get_one::<String>("schedule").map(PathBuf::from).unwrap_or_else(daemon::Schedule::default_path);
+    let schedule = daemon::Schedule::load(&schedule_path).unwrap_or_else(|e| exit_with_error(e));
+
+    if daemon_matches.get_flag("check") {
+        daemon::print_schedule(&schedule).unwrap_or_else(|e| exit_with_error(e));
+        return;
     }
+    daemon::run(schedule).await.unwrap_or_else(|e| exit_with_error(e));
+}
 
+fn print_backup_time_elapsed(time: Instant) {
     let time_elapsed_seconds = time.elapsed().as_secs();
     if time_elapsed_seconds > 65 {
         let time_elapsed_minutes = time_elapsed_seconds / 60;
@@ -878,6 +1920,171 @@ async fn handle_backup(backup_matches: &ArgMatches) {
     }
 }
 
+/// Every database of --database, two databases with the same name get a number so their dumps don't overwrite each other.
+fn databases_from_args(matches: &ArgMatches) -> Vec<backup::database::DatabaseSource> {
+    let mut databases: Vec<backup::database::DatabaseSource> = Vec::new();
+    for arg in matches.get_many::<String>("database").unwrap_or_default() {
+        let mut database = backup::database::DatabaseSource::parse(arg).unwrap_or_else(|e| exit_with_error(e));
+
+        let label = database.label.clone();
+        let mut number = 1;
+        while databases.iter().any(|other| other.label == database.label) {
+            number += 1;
+            database.label = format!("{}-{}", label, number);
+        }
+        databases.push(database);
+    }
+    databases
+}
+
+/// Every destination of --sftp, --webdav, --s3 and --destination, in that order.
+fn destinations_from_args(matches: &ArgMatches) -> Vec<Destination> {
+    let mut destinations: Vec<Destination> = Vec::new();
+    for (id, legacy) in [("sftp", Some(Protocol::Sftp)), ("webdav", Some(Protocol::WebDav)), ("s3", Some(Protocol::S3)), ("destination", None)] {
+        for arg in matches.get_many::<String>(id).unwrap_or_default() {
+            let mut destination = Destination::parse(arg, legacy).unwrap_or_else(|e| exit_with_error(e));
+
+            // Listings and reports are grouped by label, so two servers of the same kind need different ones
+            let label = destination.label.clone();
+            let mut number = 1;
+            while destinations.iter().any(|other| other.label == destination.label) {
+                number += 1;
+                destination.label = format!("{}-{}", label, number);
+            }
+            destinations.push(destination);
+        }
+    }
+    destinations
+}
+
+/// Logs in to a destination for listing, downloading and deleting, exits if that fails.
+async fn connect(destination: &Destination) -> Box<dyn UploadTarget> {
+    destination.connect(&transfer::TransferOptions::default()).await.unwrap_or_else(|e| exit_with_error(e))
+}
+
+async fn handle_restore(restore_matches: &ArgMatches) {
+    let backup = restore_matches.get_one::<String>("backup").unwrap();
+    let target = PathBuf::from(restore_matches.get_one::<String>("target").unwrap());
+    let only = restore_matches.get_one::<String>("only")
+        .map(|only| only.split(':').map(PathBuf::from).collect::<Vec<PathBuf>>())
+        .unwrap_or_default();
+
+    if let Some(snapshot) = restore_matches.get_one::<String>("snapshot") {
+        let backup_folder = PathBuf::from(restore_matches.get_one::<String>("from").unwrap());
+        restore_snapshot(&backup_folder, backup, snapshot, &target, &only);
+        return;
+    }
+
+    let mut downloaded_bundle: Option<PathBuf> = None;
+    let bundle_path = if Path::new(backup).is_file() {
+        PathBuf::from(backup)
+    } else {
+        // Not a file, so it's the name of a backup and we need a date to find it
+        let date = match restore_matches.get_one::<String>("date") {
+            Some(date) => date,
+            None => {
+                println!(
+                    "{} {}",
+                    "Something went wrong!".red().bold(),
+                    format!("{} is not a file, use --date to restore a backup by its name", backup).yellow()
+                );
+                process::exit(102);
+            }
+        };
+        let number = restore_matches.get_one::<u64>("number").copied();
+        let backup_folder = PathBuf::from(restore_matches.get_one::<String>("from").unwrap());
+
+        match find_bundle_to_restore(restore_matches, &backup_folder, backup, date, number, &target).await {
+            Some((path, downloaded)) => {
+                if downloaded {
+                    downloaded_bundle = Some(path.clone());
+                }
+                path
+            }
+            None => {
+                println!(
+                    "{} {}",
+                    "Something went wrong!".red().bold(),
+                    format!("Couldn't find a backup named {} from {}", backup, date).yellow()
+                );
+                process::exit(102);
+            }
+        }
+    };
+
+    println!(
+        "{} {}",
+        "Restoring backup!".green().bold(),
+        bundle_path.display().to_string().bright_yellow()
+    );
+
+    let result = backup::restore::restore_bundle(&bundle_path, &target, &only);
+
+    // Downloaded bundles are only needed for the restore
+    if let Some(downloaded_bundle) = downloaded_bundle {
+        let _ = fs::remove_dir_all(downloaded_bundle.parent().unwrap());
+    }
+
+    match result {
+        Ok(result) => {
+            println!(
+                "{} {} {}",
+                "Restore completed!".green().bold(),
+                "Entries restored:".yellow(),
+                result.extracted_entries.to_string().green()
+            );
+            println!("{}", format!("Backup archive (sha256) hash: {}", result.sha256_hash).green());
+        }
+        Err(e) => {
+            println!(
+                "{} {} {}",
+                "Something went wrong!".red().bold(),
+                "Error:".yellow(),
+                e.to_string().red()
+            );
+            process::exit(102);
+        }
+    }
+}
+
+/// Looks for the bundle locally first, then on every destination (downloading it next to the target).
+/// Returns the path of the bundle and whether it was downloaded.
+async fn find_bundle_to_restore(restore_matches: &ArgMatches, backup_folder: &Path, name: &str, date: &str, number: Option<u64>, target: &Path) -> Option<(PathBuf, bool)> {
+    if let Some(path) = backup::restore::find_local_bundle(backup_folder, name, date, number) {
+        return Some((path, false));
+    }
+
+    let download_directory = backup::restore::restore_work_directory(target).with_file_name(".lmtmp-download");
+    for destination in destinations_from_args(restore_matches) {
+        let remote = connect(&destination).await;
+        let file_names = match upload_target::list_file_names(remote.as_ref()).await {
+            Ok(file_names) => file_names,
+            Err(e) => {
+                println!("{} {}", format!("Failed to list the backups on {}:", destination.label).red(), e);
+                continue;
+            }
+        };
+
+        if let Some(file_name) = backup::restore::pick_bundle(&file_names, name, date, number) {
+            fs::create_dir_all(&download_directory).ok()?;
+            // The bundle, or every volume and the hash file of a split bundle
+            let mut downloaded = Ok(());
+            for part in file_names.iter().filter(|part| backup::volume::set_name(part) == file_name) {
+                downloaded = remote.download(part, &download_directory.join(part)).await;
+                if downloaded.is_err() {
+                    break;
+                }
+            }
+            match downloaded {
+                Ok(_) => return Some((download_directory.join(&file_name), true)),
+                Err(e) => println!("{} {}", format!("Failed to download from {}:", destination.label).red(), e),
+            }
+        }
+    }
+
+    None
+}
+
 async fn handle_log_search(log_search: &ArgMatches) {
     let days_back = log_search.get_one::<u64>("days-back").unwrap();
     let to_search = log_search.get_one::<String>("search").unwrap();
@@ -922,43 +2129,23 @@ fn ask_for_input_to_upload() -> bool {
     }
 }
 
-fn self_update() -> bool {
-    println!("Current Version: {}", cargo_crate_version!());
+async fn self_update(options: updater::SelfUpdateOptions) -> updater::UpdateStatus {
+    // self_update uses a blocking HTTP client, so keep it off the async runtime
+    let check_only = options.check_only;
+    let result = tokio::task::spawn_blocking(move || updater::self_update(&options))
+        .await
+        .unwrap_or_else(|e| Err(std::io::Error::other(e)));
 
-    // Determine the target architecture (x86_64 or aarch64)
-    let target = if std::env::consts::ARCH == "x86_64" {
-        "limonium-x86_64-unknown-linux-gnu.zip"
-    } else if std::env::consts::ARCH == "aarch64" {
-        "limonium-aarch64-unknown-linux-gnu.zip"
-    } else {
-        panic!("Unsupported architecture: {}", env::consts::ARCH);
-    };
-
-    println!("Target: {}", target);
-
-    let status = self_update::backends::github::Update::configure()
-        .repo_owner("andrew121410")
-        .repo_name("limonium")
-        .target(target)
-        .bin_name("limonium")
-        .no_confirm(true)
-        .show_download_progress(false)
-        .show_output(false)
-        .current_version(cargo_crate_version!())
-        .build()
-        .expect("Failed to build update")
-        .update()
-        .expect("Failed to update");
-
-    if status.updated() {
-        println!(
-            "Updated Limonium from {} to {}",
-            cargo_crate_version!(),
-            &status.version()
-        );
-        true
-    } else {
-        println!("Limonium is already up to date!");
-        false
+    match result {
+        Ok(status) => status,
+        Err(e) => {
+            println!(
+                "{} {} {}",
+                "Something went wrong!".red().bold(),
+                "Error:".yellow(),
+                e.to_string().red()
+            );
+            process::exit(if check_only { updater::CHECK_FAILED } else { 102 });
+        }
     }
 }
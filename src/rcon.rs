@@ -0,0 +1,336 @@
+use std::fs;
+use std::io::{Error, ErrorKind, Read, Write};
+use std::net::{TcpStream, ToSocketAddrs};
+use std::path::Path;
+use std::time::{Duration, Instant};
+
+use colored::Colorize;
+
+// Source RCON packet types (https://developer.valvesoftware.com/wiki/Source_RCON_Protocol)
+const SERVERDATA_AUTH: i32 = 3;
+const SERVERDATA_EXECCOMMAND: i32 = 2;
+
+const DEFAULT_RCON_PORT: u16 = 25575;
+const READ_TIMEOUT: Duration = Duration::from_secs(10);
+// Flushing a big world to disk can take a while
+const SAVE_TIMEOUT: Duration = Duration::from_secs(300);
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct RconConfig {
+    pub host: String,
+    pub port: u16,
+    pub password: String,
+}
+
+impl RconConfig {
+    /// Reads the RCON port and password from a server.properties file, the host is always localhost.
+    pub fn from_server_properties(path: &Path) -> Result<RconConfig, Error> {
+        let contents = fs::read_to_string(path)
+            .map_err(|e| Error::new(e.kind(), format!("Could not read {}: {}", path.display(), e)))?;
+
+        let mut config = RconConfig {
+            host: "127.0.0.1".to_string(),
+            port: DEFAULT_RCON_PORT,
+            password: String::new(),
+        };
+        let mut enabled = false;
+        for line in contents.lines().map(|line| line.trim()) {
+            if line.is_empty() || line.starts_with('#') || line.starts_with('!') {
+                continue;
+            }
+
+            let Some((key, value)) = line.split_once('=') else {
+                continue;
+            };
+            // Java properties escape : and = with a backslash
+            let value = value.trim().replace("\\:", ":").replace("\\=", "=");
+            match key.trim() {
+                "enable-rcon" => enabled = value == "true",
+                "rcon.port" => config.port = value.parse()
+                    .map_err(|_| Error::new(ErrorKind::InvalidData, format!("Invalid rcon.port in {}: {}", path.display(), value)))?,
+                "rcon.password" => config.password = value,
+                _ => {}
+            }
+        }
+
+        if !enabled {
+            return Err(Error::new(ErrorKind::InvalidInput, format!("RCON is not enabled in {} (enable-rcon=true)", path.display())));
+        }
+        Ok(config)
+    }
+}
+
+/// A connection to the RCON port of a Minecraft server.
+pub struct RconClient {
+    stream: TcpStream,
+    next_id: i32,
+}
+
+impl RconClient {
+    pub fn connect(config: &RconConfig) -> Result<RconClient, Error> {
+        let address = (config.host.as_str(), config.port).to_socket_addrs()?
+            .next()
+            .ok_or_else(|| Error::new(ErrorKind::NotFound, format!("Could not resolve {}", config.host)))?;
+        let stream = TcpStream::connect_timeout(&address, READ_TIMEOUT)
+            .map_err(|e| Error::new(e.kind(), format!("Could not connect to RCON at {}:{}: {}", config.host, config.port, e)))?;
+        stream.set_read_timeout(Some(READ_TIMEOUT))?;
+        stream.set_write_timeout(Some(READ_TIMEOUT))?;
+
+        let mut client = RconClient { stream, next_id: 1 };
+        let id = client.send(SERVERDATA_AUTH, &config.password)?;
+        // The server answers with the id of the login packet, or -1 if the password is wrong
+        let (response_id, _, _) = client.read_packet()?;
+        if response_id == -1 || response_id != id {
+            return Err(Error::new(ErrorKind::PermissionDenied, "RCON login failed, is the password right?"));
+        }
+        Ok(client)
+    }
+
+    fn send(&mut self, packet_type: i32, body: &str) -> Result<i32, Error> {
+        let id = self.next_id;
+        self.next_id += 1;
+
+        let mut packet = Vec::with_capacity(body.len() + 14);
+        packet.extend_from_slice(&((body.len() + 10) as i32).to_le_bytes());
+        packet.extend_from_slice(&id.to_le_bytes());
+        packet.extend_from_slice(&packet_type.to_le_bytes());
+        packet.extend_from_slice(body.as_bytes());
+        packet.extend_from_slice(&[0, 0]);
+        self.stream.write_all(&packet)?;
+        Ok(id)
+    }
+
+    /// Reads one packet as (id, type, body).
+    fn read_packet(&mut self) -> Result<(i32, i32, String), Error> {
+        let mut length = [0u8; 4];
+        self.stream.read_exact(&mut length)?;
+        let length = i32::from_le_bytes(length);
+        if !(10..=4096 + 10).contains(&length) {
+            return Err(Error::new(ErrorKind::InvalidData, format!("Invalid RCON packet length {}", length)));
+        }
+
+        let mut packet = vec![0u8; length as usize];
+        self.stream.read_exact(&mut packet)?;
+        let id = i32::from_le_bytes(packet[0..4].try_into().unwrap());
+        let packet_type = i32::from_le_bytes(packet[4..8].try_into().unwrap());
+        let body = String::from_utf8_lossy(&packet[8..packet.len() - 2]).to_string();
+        Ok((id, packet_type, body))
+    }
+
+    /// Runs a command and returns the response.
+    pub fn command(&mut self, command: &str) -> Result<String, Error> {
+        let id = self.send(SERVERDATA_EXECCOMMAND, command)?;
+        loop {
+            let (response_id, _, body) = self.read_packet()?;
+            if response_id == id {
+                return Ok(body);
+            }
+        }
+    }
+
+    /// Runs a command and keeps reading until a response contains one of `expected`, for up to `timeout`.
+    pub fn command_and_wait(&mut self, command: &str, expected: &[&str], timeout: Duration) -> Result<String, Error> {
+        let result = self.wait_for_response(command, expected, timeout);
+        self.stream.set_read_timeout(Some(READ_TIMEOUT))?;
+        result
+    }
+
+    fn wait_for_response(&mut self, command: &str, expected: &[&str], timeout: Duration) -> Result<String, Error> {
+        let started = Instant::now();
+        let id = self.send(SERVERDATA_EXECCOMMAND, command)?;
+        let mut response = String::new();
+        while !expected.iter().any(|expected| response.contains(expected)) {
+            // The server only answers once the command is done (like flushing a big world), which can take longer than READ_TIMEOUT.
+            // A read that times out part-way through a packet can't be continued, so the socket waits for the rest of `timeout` instead of retrying.
+            let remaining = timeout.saturating_sub(started.elapsed()).max(Duration::from_millis(1));
+            self.stream.set_read_timeout(Some(remaining))?;
+
+            match self.read_packet() {
+                Ok((response_id, _, body)) if response_id == id => response.push_str(&body),
+                Ok(_) => {}
+                Err(e) if e.kind() == ErrorKind::WouldBlock || e.kind() == ErrorKind::TimedOut => {
+                    return Err(Error::new(ErrorKind::TimedOut, format!("Timed out waiting for \"{}\", the server said: {}", expected[0], response)));
+                }
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(response)
+    }
+}
+
+/// Saving is turned off while this lives, and turned back on when it is dropped (even if the backup failed or panicked).
+pub struct SavingPaused<'a> {
+    config: &'a RconConfig,
+    client: Option<RconClient>,
+}
+
+impl SavingPaused<'_> {
+    /// Turns saving back on, reconnecting once if the connection was lost during the backup.
+    pub fn resume(mut self) -> Result<(), Error> {
+        self.save_on()
+    }
+
+    fn save_on(&mut self) -> Result<(), Error> {
+        let mut client = match self.client.take() {
+            Some(client) => client,
+            None => return Ok(()),
+        };
+
+        if client.command("save-on").is_err() {
+            client = RconClient::connect(self.config)?;
+            client.command("save-on")?;
+        }
+        println!("{}", "(RCON) Saving is turned back on".green());
+        Ok(())
+    }
+}
+
+impl Drop for SavingPaused<'_> {
+    fn drop(&mut self) {
+        if let Err(e) = self.save_on() {
+            println!("{} {}", "(RCON) Could not turn saving back on! Run save-on on the server:".red().bold(), e);
+        }
+    }
+}
+
+/// Sends save-off and save-all flush, and waits until the world is saved so the files don't change during the backup.
+pub fn pause_saving(config: &RconConfig) -> Result<SavingPaused<'_>, Error> {
+    let mut client = RconClient::connect(config)?;
+    client.command("save-off")?;
+    let mut paused = SavingPaused { config, client: Some(client) };
+
+    println!("{}", "(RCON) Saving is turned off, flushing the world to disk...".yellow());
+    paused.client.as_mut().unwrap()
+        .command_and_wait("save-all flush", &["Saved the game", "Saved the world"], SAVE_TIMEOUT)?;
+    println!("{}", "(RCON) The world is saved".green());
+    Ok(paused)
+}
+
+#[cfg(test)]
+mod rcon_testing {
+    use super::*;
+    use std::net::TcpListener;
+    use std::sync::{Arc, Mutex};
+    use std::thread;
+
+    /// A fake Minecraft RCON server that takes `flush_time` to answer save-all flush, returns its config and every command it received.
+    fn fake_server(password: &str, flush_time: Duration) -> (RconConfig, Arc<Mutex<Vec<String>>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let config = RconConfig {
+            host: "127.0.0.1".to_string(),
+            port: listener.local_addr().unwrap().port(),
+            password: password.to_string(),
+        };
+        let commands = Arc::new(Mutex::new(Vec::new()));
+
+        let received = commands.clone();
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                let Ok(mut stream) = stream else { return };
+                let received = received.clone();
+                thread::spawn(move || {
+                    let write = |stream: &mut TcpStream, id: i32, packet_type: i32, body: &str| {
+                        let mut packet = Vec::new();
+                        packet.extend_from_slice(&((body.len() + 10) as i32).to_le_bytes());
+                        packet.extend_from_slice(&id.to_le_bytes());
+                        packet.extend_from_slice(&packet_type.to_le_bytes());
+                        packet.extend_from_slice(body.as_bytes());
+                        packet.extend_from_slice(&[0, 0]);
+                        stream.write_all(&packet).unwrap();
+                    };
+
+                    loop {
+                        let mut header = [0u8; 12];
+                        if stream.read_exact(&mut header).is_err() {
+                            return;
+                        }
+                        let length = i32::from_le_bytes(header[0..4].try_into().unwrap()) as usize;
+                        let id = i32::from_le_bytes(header[4..8].try_into().unwrap());
+                        let packet_type = i32::from_le_bytes(header[8..12].try_into().unwrap());
+                        let mut body = vec![0u8; length - 8];
+                        stream.read_exact(&mut body).unwrap();
+                        let body = String::from_utf8_lossy(&body[..body.len() - 2]).to_string();
+
+                        if packet_type == SERVERDATA_AUTH {
+                            write(&mut stream, if body == "hunter2" { id } else { -1 }, 2, "");
+                            continue;
+                        }
+
+                        received.lock().unwrap().push(body.clone());
+                        match body.as_str() {
+                            "save-off" => write(&mut stream, id, 0, "Automatic saving is now disabled"),
+                            "save-all flush" => {
+                                thread::sleep(flush_time);
+                                // Like a real server, the saved message can arrive in a later packet
+                                write(&mut stream, id, 0, "Saving the game (this may take a moment!)");
+                                write(&mut stream, id, 0, "Saved the game");
+                            }
+                            "save-on" => write(&mut stream, id, 0, "Automatic saving is now enabled"),
+                            _ => write(&mut stream, id, 0, "Unknown command"),
+                        }
+                    }
+                });
+            }
+        });
+
+        (config, commands)
+    }
+
+    #[test]
+    fn test_pause_saving() {
+        let (config, commands) = fake_server("hunter2", Duration::ZERO);
+
+        let paused = pause_saving(&config).unwrap();
+        assert_eq!(*commands.lock().unwrap(), vec!["save-off", "save-all flush"]);
+        paused.resume().unwrap();
+        assert_eq!(*commands.lock().unwrap(), vec!["save-off", "save-all flush", "save-on"]);
+    }
+
+    #[test]
+    fn test_pause_saving_of_slow_flush() {
+        // Flushing takes longer than a normal RCON read may
+        let (config, commands) = fake_server("hunter2", READ_TIMEOUT + Duration::from_secs(1));
+
+        let paused = pause_saving(&config).unwrap();
+        paused.resume().unwrap();
+        assert_eq!(*commands.lock().unwrap(), vec!["save-off", "save-all flush", "save-on"]);
+
+        let mut client = RconClient::connect(&config).unwrap();
+        let error = client.command_and_wait("save-all flush", &["Saved the game"], Duration::from_millis(200)).unwrap_err();
+        assert_eq!(error.kind(), ErrorKind::TimedOut);
+    }
+
+    #[test]
+    fn test_save_on_after_failed_backup() {
+        let (config, commands) = fake_server("hunter2", Duration::ZERO);
+
+        let backup = || -> Result<(), Error> {
+            let _paused = pause_saving(&config)?;
+            Err(Error::other("the backup failed"))
+        };
+        assert!(backup().is_err());
+        assert_eq!(commands.lock().unwrap().last().unwrap(), "save-on");
+    }
+
+    #[test]
+    fn test_wrong_password() {
+        let (config, commands) = fake_server("wrong", Duration::ZERO);
+
+        let error = pause_saving(&config).err().unwrap();
+        assert_eq!(error.kind(), ErrorKind::PermissionDenied);
+        assert!(commands.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_from_server_properties() {
+        let directory = tempdir::TempDir::new("rcon-properties").unwrap();
+        let path = directory.path().join("server.properties");
+
+        fs::write(&path, "#Minecraft server properties\nenable-rcon=true\nrcon.port=25580\nrcon.password=p\\=ss\nserver-port=25565\n").unwrap();
+        let config = RconConfig::from_server_properties(&path).unwrap();
+        assert_eq!(config, RconConfig { host: "127.0.0.1".to_string(), port: 25580, password: "p=ss".to_string() });
+
+        fs::write(&path, "enable-rcon=false\nrcon.password=secret\n").unwrap();
+        assert!(RconConfig::from_server_properties(&path).is_err());
+    }
+}
@@ -1,220 +1,445 @@
 use std::fs;
-use std::io::{Error, ErrorKind, Read};
+use std::io::{Error, ErrorKind, Read, Seek, SeekFrom, Write};
 use std::os::unix::fs::PermissionsExt;
 use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
 
-use chrono::{NaiveDate, Utc};
+use async_trait::async_trait;
 use colored::Colorize;
 use indicatif::{ProgressBar, ProgressStyle};
-use openssh::{Session, Stdio};
-use openssh_sftp_client::Sftp;
-
-use crate::backup::extract_date_from_file_name;
-
-pub async fn login(user: String, host: String, port: Option<u16>, key_file: Option<&Path>) -> Result<Session, Error> {
-    let mut session_builder = openssh::SessionBuilder::default();
+use russh::client;
+use russh::keys::{self, HashAlg, PrivateKeyWithHashAlg};
+use russh_sftp::client::{RawSftpSession, SftpSession};
+use russh_sftp::protocol::{OpenFlags, Packet};
+use sha2::{Digest, Sha256};
+use tokio::io::{AsyncReadExt, AsyncSeekExt, AsyncWriteExt};
+
+use crate::backup::repository::Repository;
+use crate::transfer::TransferOptions;
+use crate::upload_target::{self, UploadTarget};
+
+/// A logged in SSH connection with the sftp subsystem started. Everything goes through SFTP, no remote shell is needed.
+pub struct Session {
+    handle: client::Handle<KnownHostsCheck>,
+    sftp: SftpSession,
+}
 
-    if key_file.is_some() {
-        session_builder.keyfile(key_file.unwrap());
-        println!("Using key file: {}", key_file.unwrap().display());
+/// Only connects to servers whose host key is in ~/.ssh/known_hosts.
+struct KnownHostsCheck {
+    host: String,
+    port: u16,
+}
 
-        // Check if right permissions are set on the key file
-        let metadata = fs::metadata(key_file.unwrap()).unwrap();
-        let permissions = metadata.permissions();
-        if permissions.mode() != 33152 { // chmod 600
-            println!("{}", format!("The key file must have the permissions 600 (rw-------). Please run \"chmod 600 {}\" to set the correct permissions.", key_file.unwrap().display()).red());
-            return Err(Error::new(ErrorKind::Other, "Wrong permissions on key file"));
+impl client::Handler for KnownHostsCheck {
+    type Error = russh::Error;
+
+    async fn check_server_key(&mut self, server_public_key: &keys::PublicKey) -> Result<bool, Self::Error> {
+        let known = keys::check_known_hosts(&self.host, self.port, server_public_key)?;
+        if !known {
+            println!(
+                "{} {}",
+                format!("The host key of {} ({}) is not in ~/.ssh/known_hosts.", self.host, server_public_key.fingerprint(HashAlg::Sha256)).red(),
+                format!("Check it and add it with: ssh-keyscan -p {} {} >> ~/.ssh/known_hosts", self.port, self.host).yellow()
+            );
         }
+        Ok(known)
     }
+}
 
-    session_builder.user(user);
-
-    if port.is_some() {
-        session_builder.port(port.unwrap());
+/// Like ssh, refuses private keys that other users can read.
+pub(crate) fn check_key_file_permissions(key_file: &Path) -> Result<(), Error> {
+    let mode = fs::metadata(key_file)?.permissions().mode();
+    if mode & 0o077 != 0 {
+        println!("{}", format!("The key file must not be readable by other users. Please run \"chmod 600 {}\" to set the correct permissions.", key_file.display()).red());
+        return Err(Error::new(ErrorKind::PermissionDenied, "Wrong permissions on key file"));
     }
+    Ok(())
+}
+
+/// Logs in with the key file if one is given. Otherwise the keys of the SSH agent, the default key files (~/.ssh/id_ed25519, ...)
+/// and the password are tried in that order. Up to `pipeline_depth` writes are in flight at the same time.
+pub async fn login(user: String, host: String, port: Option<u16>, key_file: Option<&Path>, password: Option<&str>, pipeline_depth: usize) -> Result<Session, Error> {
+    let port = port.unwrap_or(22);
+    let config = Arc::new(client::Config {
+        keepalive_interval: Some(Duration::from_secs(30)),
+        ..Default::default()
+    });
 
-    let session_result = session_builder.connect(&host).await;
-    if session_result.is_err() {
-        println!("{}", format!("Failed to connect to {}: {}", host, session_result.err().unwrap()).red());
-        return Err(Error::new(ErrorKind::Other, "Failed to connect to host"));
+    let mut handle = client::connect(config, (host.as_str(), port), KnownHostsCheck { host: host.clone(), port }).await
+        .map_err(|e| Error::other(format!("Failed to connect to {}:{}: {}", host, port, e)))?;
+
+    if !authenticate(&mut handle, &user, key_file, password).await? {
+        return Err(Error::new(ErrorKind::PermissionDenied, format!("Failed to log in to {} as {}", host, user)));
     }
 
-    Ok(session_result.unwrap())
-}
+    let channel = handle.channel_open_session().await
+        .map_err(|e| Error::other(format!("Failed to open a channel: {}", e)))?;
+    channel.request_subsystem(true, "sftp").await
+        .map_err(|e| Error::other(format!("Failed to start the sftp subsystem: {}", e)))?;
+    let sftp_config = russh_sftp::client::Config { max_concurrent_writes: pipeline_depth.max(1), ..Default::default() };
+    let sftp = SftpSession::new_with_config(channel.into_stream(), sftp_config).await
+        .map_err(|e| Error::other(format!("Failed to start the sftp subsystem: {}", e)))?;
 
-pub async fn upload_file(session: &Session, path: &PathBuf, file_name: &str, remote_dir: &str, local_hash: &str) -> Result<(), Error> {
-    let mut child = session
-        .subsystem("sftp")
-        .stdin(Stdio::piped())
-        .stdout(Stdio::piped())
-        .spawn()
-        .await
-        .unwrap();
+    Ok(Session { handle, sftp })
+}
 
-    let sftp = Sftp::new(
-        child.stdin().take().unwrap(),
-        child.stdout().take().unwrap(),
-        Default::default(), )
-        .await
-        .unwrap();
+async fn authenticate_with_key(handle: &mut client::Handle<KnownHostsCheck>, user: &str, key: keys::PrivateKey) -> Result<bool, Error> {
+    let hash_alg = handle.best_supported_rsa_hash().await.ok().flatten().flatten();
+    let result = handle.authenticate_publickey(user, PrivateKeyWithHashAlg::new(Arc::new(key), hash_alg)).await
+        .map_err(|e| Error::other(format!("Failed to authenticate: {}", e)))?;
+    Ok(result.success())
+}
 
-    let mut fs = sftp.fs();
+async fn authenticate(handle: &mut client::Handle<KnownHostsCheck>, user: &str, key_file: Option<&Path>, password: Option<&str>) -> Result<bool, Error> {
+    if let Some(key_file) = key_file {
+        println!("Using key file: {}", key_file.display());
+        check_key_file_permissions(key_file)?;
+        let key = keys::load_secret_key(key_file, None)
+            .map_err(|e| Error::other(format!("Failed to load the key file {}: {}", key_file.display(), e)))?;
+        return authenticate_with_key(handle, user, key).await;
+    }
 
-    let remote_create_dir_result = fs.create_dir(Path::new(remote_dir)).await;
-    if remote_create_dir_result.is_err() {
-        // Ignore error the directory probably already exists
+    if let Ok(mut agent) = keys::agent::client::AgentClient::connect_env().await {
+        for key in agent.request_identities().await.unwrap_or_default() {
+            let hash_alg = handle.best_supported_rsa_hash().await.ok().flatten().flatten();
+            match handle.authenticate_publickey_with(user, key, hash_alg, &mut agent).await {
+                Ok(result) if result.success() => return Ok(true),
+                Ok(_) => continue,
+                Err(e) => println!("{} {}", "The SSH agent could not sign:".yellow(), e),
+            }
+        }
     }
 
-    let remote_file_result = sftp.create(Path::new(remote_dir).join(file_name)).await;
+    if let Some(home) = std::env::var_os("HOME") {
+        for name in ["id_ed25519", "id_ecdsa", "id_rsa"] {
+            let path = PathBuf::from(&home).join(".ssh").join(name);
+            // Keys with a passphrase can only be used through the agent
+            if let Ok(key) = keys::load_secret_key(&path, None)
+                && check_key_file_permissions(&path).is_ok()
+                && authenticate_with_key(handle, user, key).await? {
+                return Ok(true);
+            }
+        }
+    }
 
-    if remote_file_result.is_err() {
-        println!("{}", format!("Failed to create remote file: {}", remote_file_result.err().unwrap()).red());
-        return Err(Error::new(ErrorKind::Other, "Failed to create remote file"));
+    if let Some(password) = password {
+        let result = handle.authenticate_password(user, password).await
+            .map_err(|e| Error::other(format!("Failed to authenticate: {}", e)))?;
+        return Ok(result.success());
     }
+    Ok(false)
+}
 
-    let mut remote_file = remote_file_result.unwrap();
-    let mut local_file = std::fs::File::open(path).unwrap();
+fn remote_path(remote_dir: &str, file_name: &str) -> String {
+    format!("{}/{}", remote_dir.trim_end_matches('/'), file_name)
+}
 
-    // Progress bar in mb
-    let progress_bar = ProgressBar::new(local_file.metadata().unwrap().len());
-    progress_bar.set_style(ProgressStyle::default_bar()
-        .template("{msg:>12.cyan.bold} {bytes}/{total_bytes} ({bytes_per_sec}, {eta})").unwrap());
-    progress_bar.set_message("Uploading");
+/// The SHA-256 of a remote file, from the check-file extension if the server has it,
+/// otherwise by reading the file back (which works on any server, even without shell access).
+async fn remote_sha256(session: &Session, path: &str, progress_bar: &ProgressBar) -> Result<String, Error> {
+    if let Some(hash) = check_file_sha256(session, path).await {
+        return Ok(hash);
+    }
+
+    let mut remote_file = session.sftp.open(path).await
+        .map_err(|e| Error::other(format!("Failed to open remote file {}: {}", path, e)))?;
 
-    // Split the file into chunks to upload
-    const CHUNK_SIZE: usize = 1024 * 1024 * 3; // 3 MB
-    let mut buffer = vec![0; CHUNK_SIZE];
+    let mut hasher = Sha256::new();
+    let mut buffer = vec![0; 1024 * 1024 * 3]; // 3 MB
     loop {
-        // Read a chunk from the local file
-        let bytes_read = local_file.read(&mut buffer).unwrap();
+        let bytes_read = remote_file.read(&mut buffer).await?;
         if bytes_read == 0 {
             break;
         }
-        // Write the chunk to the remote file
-        remote_file.write_all(&buffer[..bytes_read]).await.unwrap();
-
-        // Update the progress bar
+        hasher.update(&buffer[..bytes_read]);
         progress_bar.inc(bytes_read as u64);
-
-        buffer = vec![0; CHUNK_SIZE]; // Reallocate the buffer (why doesn't buffer.clear() work?)
     }
 
-    progress_bar.finish_and_clear();
+    Ok(format!("{:x}", hasher.finalize()))
+}
 
-    // Verify that the file was uploaded correctly (check the hash)
-    let mut command = session.command("sha256sum".to_string());
-    command.arg(format!("{}/{}", remote_dir, file_name));
-    let output = command.output().await.unwrap();
-    let output_string = String::from_utf8_lossy(&output.stdout);
-    let remote_hash = output_string.split(" ").collect::<Vec<&str>>()[0].to_string();
+/// Asks the server for the hash with the check-file extension, None if it doesn't support it (OpenSSH doesn't).
+async fn check_file_sha256(session: &Session, path: &str) -> Option<String> {
+    // The high level session doesn't expose extensions, so this uses a second sftp channel
+    let channel = session.handle.channel_open_session().await.ok()?;
+    channel.request_subsystem(true, "sftp").await.ok()?;
+    let raw = RawSftpSession::new(channel.into_stream());
+    let version = raw.init().await.ok()?;
+    if !version.extensions.contains_key("check-file") && !version.extensions.contains_key("check-file-name") {
+        return None;
+    }
 
-    if remote_hash != local_hash {
-        println!("{}", format!("Failed to upload backup archive to SFTP server: The hash of the local file ({}) does not match the hash of the remote file ({})", local_hash, remote_hash).red());
-        return Err(Error::new(ErrorKind::Other, "Local and remote hash do not match"));
+    // string filename, string hash algorithms, uint64 start offset, uint64 length (0 = everything), uint32 block size (0 = one hash)
+    let mut data = Vec::new();
+    for value in [path, "sha256"] {
+        data.extend_from_slice(&(value.len() as u32).to_be_bytes());
+        data.extend_from_slice(value.as_bytes());
     }
+    data.extend_from_slice(&0u64.to_be_bytes());
+    data.extend_from_slice(&0u64.to_be_bytes());
+    data.extend_from_slice(&0u32.to_be_bytes());
 
-    println!("{}", format!("Hash of local file matches hash of remote file").green());
-    println!("{}", format!("Successfully uploaded backup archive to SFTP server").green());
-    Ok(())
+    match raw.extended("check-file-name", data).await.ok()? {
+        Packet::ExtendedReply(reply) => parse_check_file_reply(&reply.data),
+        _ => None,
+    }
 }
 
-pub async fn delete_after_time(session: &Session, backup_name: &str, input: &str, remote_dir: &str) {
-    // Deletes backups after a certain amount of time in the SFTP server
-    // Example input: 1m = 1 month, 1w = 1 week, 1d = 1 day
-
-    // Parse the input duration
-    let (amount, unit) = input.split_at(input.len() - 1);
-    let amount: i64 = amount.parse().unwrap_or(0);
+/// Reads the hex SHA-256 out of a check-file reply: string "check-file", string hash algorithm, then the hash.
+fn parse_check_file_reply(data: &[u8]) -> Option<String> {
+    let read_string = |data: &[u8]| -> Option<(String, usize)> {
+        let length = u32::from_be_bytes(data.get(0..4)?.try_into().ok()?) as usize;
+        let value = String::from_utf8(data.get(4..4 + length)?.to_vec()).ok()?;
+        Some((value, 4 + length))
+    };
+
+    let (mut value, mut offset) = read_string(data)?;
+    if value == "check-file" {
+        let (algorithm, length) = read_string(&data[offset..])?;
+        value = algorithm;
+        offset += length;
+    }
 
-    // Get the current date
-    let current_date = Utc::now().naive_utc().date();
+    let hash = &data[offset..];
+    (value == "sha256" && hash.len() == 32).then(|| hash.iter().map(|byte| format!("{:02x}", byte)).collect())
+}
 
-    // For each file in the backup directory
-    let file_names = list_files(session, remote_dir).await.unwrap();
+/// Reads `length` bytes of a remote file starting at `offset`.
+async fn read_remote_range(session: &Session, path: &str, offset: u64, length: u64) -> Result<Vec<u8>, Error> {
+    let mut remote_file = session.sftp.open(path).await
+        .map_err(|e| Error::other(format!("Failed to open remote file {}: {}", path, e)))?;
+    remote_file.seek(SeekFrom::Start(offset)).await?;
+    let mut data = vec![0; length as usize];
+    remote_file.read_exact(&mut data).await?;
+    Ok(data)
+}
 
-    for file_name in file_names {
-        let date = extract_date_from_file_name(&file_name.to_string());
+/// The (file name, size) of every file in `remote_dir`, folders are skipped.
+async fn read_dir_with_sizes(session: &Session, remote_dir: &str) -> Result<Vec<(String, u64)>, Error> {
+    let read_dir = session.sftp.read_dir(remote_dir).await
+        .map_err(|e| Error::other(format!("Failed to read remote directory {}: {}", remote_dir, e)))?;
+    Ok(read_dir
+        .filter(|entry| entry.file_type().is_file())
+        .map(|entry| (entry.file_name(), entry.metadata().len()))
+        .collect())
+}
 
-        // If the file name does not contain a date or does not start with the backup name, skip it
-        if date.is_empty() || !file_name.starts_with(backup_name) {
-            continue;
-        }
+/// The backup folder on a SFTP server.
+pub struct SftpTarget {
+    session: Session,
+    remote_dir: String,
+    transfer: TransferOptions,
+}
 
-        let date_chrono = NaiveDate::parse_from_str(&date, "%-m-%-d-%Y").unwrap();
+impl SftpTarget {
+    pub fn new(session: Session, remote_dir: String, transfer: TransferOptions) -> SftpTarget {
+        SftpTarget { session, remote_dir, transfer }
+    }
+}
 
-        // Calculate the difference in days between the current date and the backup date
-        let days_difference = (current_date - date_chrono).num_days();
+#[async_trait]
+impl UploadTarget for SftpTarget {
+    fn protocol_name(&self) -> &'static str {
+        "SFTP"
+    }
 
-        // Delete if it's older than the input duration
-        match unit {
-            "m" => {
-                if days_difference > amount * 30 {
-                    // Delete the file
-                    delete_file(session, &file_name, remote_dir).await.unwrap_or_else(|e| {
-                        eprintln!("Error deleting file: {}", e);
-                    });
-                }
-            }
-            "w" => {
-                if days_difference > amount * 7 {
-                    // Delete the file
-                    delete_file(session, &file_name, remote_dir).await.unwrap_or_else(|e| {
-                        eprintln!("Error deleting file: {}", e);
-                    });
-                }
+    async fn upload(&self, path: &Path, file_name: &str, _sha256_hash: &str, progress_bar: &ProgressBar) -> Result<(), Error> {
+        // Ignore error the directory probably already exists
+        let _ = self.session.sftp.create_dir(&self.remote_dir).await;
+
+        // A partial upload from an earlier attempt is continued if its end matches the local file
+        let remote_file_path = remote_path(&self.remote_dir, &upload_target::partial_name(file_name));
+        let mut offset = 0;
+        if let Ok(metadata) = self.session.sftp.metadata(&remote_file_path).await {
+            let (start, length) = upload_target::resume_check_range(metadata.len());
+            if let Ok(remote_end) = read_remote_range(&self.session, &remote_file_path, start, length).await {
+                offset = upload_target::resume_offset(path, metadata.len(), &remote_end)?.unwrap_or(0);
             }
-            "d" => {
-                if days_difference > amount {
-                    // Delete the file
-                    delete_file(session, &file_name, remote_dir).await.unwrap_or_else(|e| {
-                        eprintln!("Error deleting file: {}", e);
-                    });
-                }
+        }
+
+        let mut remote_file = if offset > 0 {
+            let mut remote_file = self.session.sftp.open_with_flags(&remote_file_path, OpenFlags::WRITE).await
+                .map_err(|e| Error::other(format!("Failed to open remote file {}: {}", remote_file_path, e)))?;
+            remote_file.seek(SeekFrom::Start(offset)).await?;
+            remote_file
+        } else {
+            self.session.sftp.create(&remote_file_path).await
+                .map_err(|e| Error::other(format!("Failed to create remote file {}: {}", remote_file_path, e)))?
+        };
+        let mut local_file = fs::File::open(path)?;
+        local_file.seek(SeekFrom::Start(offset))?;
+        progress_bar.set_position(offset);
+
+        // Split the file into chunks to upload, with --bwlimit a chunk is sent in smaller throttled pieces
+        const CHUNK_SIZE: usize = 1024 * 1024 * 3; // 3 MB
+        let mut buffer = vec![0; self.transfer.chunk_size(CHUNK_SIZE)];
+        let piece_size = self.transfer.piece_size(buffer.len());
+        loop {
+            let bytes_read = local_file.read(&mut buffer)?;
+            if bytes_read == 0 {
+                break;
             }
-            _ => {
-                eprintln!("Invalid time unit: {}", unit);
+            for piece in buffer[..bytes_read].chunks(piece_size) {
+                self.transfer.throttle(piece.len()).await;
+                remote_file.write_all(piece).await
+                    .map_err(|e| Error::other(format!("Failed to write remote file {}: {}", remote_file_path, e)))?;
+                progress_bar.inc(piece.len() as u64);
             }
         }
+        remote_file.flush().await?;
+        remote_file.close().await?;
+        Ok(())
     }
-}
 
-async fn list_files(session: &Session, remote_dir: &str) -> Result<Vec<String>, Error> {
-    // Just use ls command
-    let mut command = session.command("ls".to_string());
-    command.arg(remote_dir);
+    /// Checks the hash of the partial upload. A broken one is deleted, so the next attempt starts over.
+    async fn verify(&self, file_name: &str, _size: u64, sha256_hash: &str, progress_bar: &ProgressBar) -> Result<(), Error> {
+        let remote_file_path = remote_path(&self.remote_dir, &upload_target::partial_name(file_name));
+        let remote_hash = remote_sha256(&self.session, &remote_file_path, progress_bar).await?;
+        if remote_hash != sha256_hash {
+            let _ = self.session.sftp.remove_file(&remote_file_path).await;
+            return Err(Error::other(format!("The hash of the local file ({}) does not match the hash of the remote file ({})", sha256_hash, remote_hash)));
+        }
+        Ok(())
+    }
 
-    // Execute the command and capture the output
-    let output = command.output().await.map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, e))?;
+    /// Renames the partial upload, SFTP can't rename over an existing file so an old file with the same name is deleted first.
+    async fn commit(&self, file_name: &str) -> Result<(), Error> {
+        let remote_file_path = remote_path(&self.remote_dir, file_name);
+        let _ = self.session.sftp.remove_file(&remote_file_path).await;
+        self.session.sftp.rename(remote_path(&self.remote_dir, &upload_target::partial_name(file_name)), &remote_file_path).await
+            .map_err(|e| Error::other(format!("Failed to rename the upload to {}: {}", remote_file_path, e)))
+    }
 
-    // Check if the command was successful
-    if !output.status.success() {
-        return Err(Error::new(
-            ErrorKind::Other,
-            format!("ls command failed: {}", output.status),
-        ));
+    async fn list(&self) -> Result<Vec<(String, u64)>, Error> {
+        read_dir_with_sizes(&self.session, &self.remote_dir).await
     }
 
-    // Convert the output bytes to a string
-    let output_str = String::from_utf8(output.stdout).map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e))?;
+    async fn delete(&self, file_name: &str) -> Result<(), Error> {
+        self.session.sftp.remove_file(remote_path(&self.remote_dir, file_name)).await
+            .map_err(|e| Error::other(format!("Failed to delete {} on the SFTP server: {}", file_name, e)))
+    }
 
-    // Split the output by newline characters and collect the file names
-    let file_names = output_str.split('\n').map(|s| s.to_string()).collect();
+    async fn download(&self, file_name: &str, destination: &Path) -> Result<(), Error> {
+        let mut remote_file = self.session.sftp.open(remote_path(&self.remote_dir, file_name)).await
+            .map_err(|e| Error::other(format!("Failed to open remote file {}: {}", file_name, e)))?;
+        let remote_size = remote_file.metadata().await.map(|metadata| metadata.len()).unwrap_or(0);
+
+        let progress_bar = ProgressBar::new(remote_size);
+        progress_bar.set_style(ProgressStyle::default_bar()
+            .template("{msg:>12.cyan.bold} {bytes}/{total_bytes} ({bytes_per_sec}, {eta})").unwrap());
+        progress_bar.set_message("Downloading");
+
+        let mut local_file = fs::File::create(destination)?;
+        let mut buffer = vec![0; 1024 * 1024 * 3]; // 3 MB
+        loop {
+            let bytes_read = remote_file.read(&mut buffer).await?;
+            if bytes_read == 0 {
+                break;
+            }
+            local_file.write_all(&buffer[..bytes_read])?;
+            progress_bar.inc(bytes_read as u64);
+        }
 
-    Ok(file_names)
+        progress_bar.finish_and_clear();
+        println!("{}", format!("Downloaded {} from the SFTP server", file_name).green());
+        Ok(())
+    }
+
+    /// Mirrors a local backup repository into the backup folder, in the order of `Repository::plan_sync`.
+    /// Files are written under a temporary name first, so an interrupted upload never looks like a finished chunk.
+    async fn sync_repository(&self, repository: &Repository) -> Result<(), Error> {
+        let session = &self.session;
+        let repository_name = repository.path().file_name().unwrap_or_default().to_string_lossy().to_string();
+        let remote_repository = remote_path(&self.remote_dir, &repository_name);
+        for directory in [self.remote_dir.clone(), remote_repository.clone(), remote_path(&remote_repository, "chunks"), remote_path(&remote_repository, "snapshots")] {
+            // Ignore errors, the directory probably already exists
+            let _ = session.sftp.create_dir(directory).await;
+        }
+
+        let names = |files: Vec<(String, u64)>| files.into_iter().map(|(name, _)| name).collect::<Vec<String>>();
+        let remote_chunks = names(read_dir_with_sizes(session, &remote_path(&remote_repository, "chunks")).await?);
+        let remote_snapshots = names(read_dir_with_sizes(session, &remote_path(&remote_repository, "snapshots")).await?);
+        let plan = repository.plan_sync(&remote_chunks, &remote_snapshots)?;
+
+        let uploads = plan.upload_chunks.iter().map(|name| format!("chunks/{}", name))
+            .chain(plan.upload_snapshots.iter().map(|name| format!("snapshots/{}", name)))
+            .chain(std::iter::once("repository.json".to_string()))
+            .collect::<Vec<String>>();
+
+        let progress_bar = ProgressBar::new(uploads.len() as u64);
+        progress_bar.set_style(ProgressStyle::default_bar()
+            .template("{msg:>12.cyan.bold} {pos}/{len} files ({per_sec}, {eta})").unwrap());
+        progress_bar.set_message("Uploading");
+
+        for relative in &uploads {
+            let data = fs::read(repository.path().join(relative))?;
+            let remote_file_path = remote_path(&remote_repository, relative);
+            let temp_path = format!("{}.tmp", remote_file_path);
+
+            self.transfer.throttle(data.len()).await;
+            session.sftp.write(&temp_path, &data).await
+                .map_err(|e| Error::other(format!("Failed to write remote file {}: {}", temp_path, e)))?;
+
+            // repository.json is replaced every time, SFTP can't rename over an existing file
+            let _ = session.sftp.remove_file(&remote_file_path).await;
+            session.sftp.rename(&temp_path, &remote_file_path).await
+                .map_err(|e| Error::other(format!("Failed to rename remote file {}: {}", temp_path, e)))?;
+            progress_bar.inc(1);
+        }
+        progress_bar.finish_and_clear();
+
+        for name in &plan.delete_snapshots {
+            session.sftp.remove_file(remote_path(&remote_repository, &format!("snapshots/{}", name))).await.map_err(Error::other)?;
+        }
+        for name in &plan.delete_chunks {
+            session.sftp.remove_file(remote_path(&remote_repository, &format!("chunks/{}", name))).await.map_err(Error::other)?;
+        }
+
+        println!(
+            "{}",
+            format!(
+                "Synced the repository to the SFTP server ({} chunks and {} snapshots uploaded, {} snapshots and {} chunks deleted)",
+                plan.upload_chunks.len(), plan.upload_snapshots.len(), plan.delete_snapshots.len(), plan.delete_chunks.len()
+            ).green()
+        );
+        Ok(())
+    }
 }
 
-async fn delete_file(session: &Session, file_name: &str, remote_dir: &str) -> Result<(), Error> {
-    // Delete file using rm command
-    let mut command = session.command("rm".to_string());
-    command.arg(format!("{}/{}", remote_dir, file_name));
-    let output = command.output().await.unwrap();
-    let output_string = String::from_utf8_lossy(&output.stdout);
+#[cfg(test)]
+mod sftp_testing {
+    use super::*;
 
-    if output_string.contains("No such file") {
-        println!("{}", format!("The file {} does not exist on the SFTP server", file_name).red());
+    #[test]
+    fn test_check_key_file_permissions() {
+        let directory = tempdir::TempDir::new("sftp-key").unwrap();
+        let key_file = directory.path().join("id_ed25519");
+        fs::write(&key_file, "not a real key").unwrap();
+
+        for (mode, allowed) in [(0o600, true), (0o400, true), (0o640, false), (0o644, false)] {
+            fs::set_permissions(&key_file, fs::Permissions::from_mode(mode)).unwrap();
+            assert_eq!(check_key_file_permissions(&key_file).is_ok(), allowed, "mode {:o}", mode);
+        }
     }
 
-    println!("{}", format!("(delete-after-time) Deleted file {} in the backup directory on the SFTP server it was too OLD", file_name).yellow());
-    Ok(())
+    #[test]
+    fn test_parse_check_file_reply() {
+        let hash = Sha256::digest(b"backup");
+        let mut reply = Vec::new();
+        for value in ["check-file", "sha256"] {
+            reply.extend_from_slice(&(value.len() as u32).to_be_bytes());
+            reply.extend_from_slice(value.as_bytes());
+        }
+        reply.extend_from_slice(&hash);
+
+        assert_eq!(parse_check_file_reply(&reply), Some(format!("{:x}", hash)));
+        // Some servers leave out the "check-file" string
+        assert_eq!(parse_check_file_reply(&reply[14..]), Some(format!("{:x}", hash)));
+        // Only sha256 is trusted
+        let mut md5_reply = reply.clone();
+        md5_reply[18..24].copy_from_slice(b"md5\0\0\0");
+        assert_eq!(parse_check_file_reply(&md5_reply), None);
+        assert_eq!(parse_check_file_reply(&[0, 0]), None);
+    }
 }
-
@@ -0,0 +1,185 @@
+use std::io::{Error, ErrorKind, Read};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use futures_util::stream;
+use indicatif::ProgressBar;
+use tokio::sync::Mutex;
+
+/// Pieces sent between two checks of the rate limiter are at least this big.
+const MIN_PIECE_SIZE: usize = 16 * 1024;
+
+/// Caps the combined speed of every upload that shares it (--bwlimit), as a token bucket.
+/// Waiting callers hold the lock, so concurrent uploads take turns instead of bursting together.
+pub struct RateLimiter {
+    bytes_per_second: u64,
+    state: Mutex<LimiterState>,
+}
+
+struct LimiterState {
+    /// Bytes that can be sent right away, negative while a caller is waiting for its bytes.
+    available: f64,
+    last_refill: Instant,
+}
+
+impl RateLimiter {
+    pub fn new(bytes_per_second: u64) -> RateLimiter {
+        RateLimiter {
+            bytes_per_second: bytes_per_second.max(1),
+            state: Mutex::new(LimiterState { available: 0.0, last_refill: Instant::now() }),
+        }
+    }
+
+    /// Waits until `bytes` can be sent without going over the limit. At most one second of unused bandwidth is saved up.
+    pub async fn acquire(&self, bytes: u64) {
+        let rate = self.bytes_per_second as f64;
+        let mut state = self.state.lock().await;
+
+        let now = Instant::now();
+        state.available = (state.available + now.duration_since(state.last_refill).as_secs_f64() * rate).min(rate);
+        state.last_refill = now;
+        state.available -= bytes as f64;
+
+        if state.available < 0.0 {
+            tokio::time::sleep(Duration::from_secs_f64(-state.available / rate)).await;
+        }
+    }
+}
+
+/// How uploads send a file (--bwlimit, --chunk-size and --pipeline-depth), shared by every upload target.
+#[derive(Clone)]
+pub struct TransferOptions {
+    /// Overrides the chunk size of the target (SFTP and WebDAV 3 MB, S3 16 MB parts).
+    pub chunk_size: Option<usize>,
+    /// SFTP writes and S3 parts that are in flight at the same time.
+    pub pipeline_depth: usize,
+    pub limiter: Option<Arc<RateLimiter>>,
+}
+
+impl Default for TransferOptions {
+    fn default() -> Self {
+        TransferOptions {
+            chunk_size: None,
+            pipeline_depth: 4,
+            limiter: None,
+        }
+    }
+}
+
+impl TransferOptions {
+    pub fn chunk_size(&self, default: usize) -> usize {
+        self.chunk_size.unwrap_or(default)
+    }
+
+    pub fn is_limited(&self) -> bool {
+        self.limiter.is_some()
+    }
+
+    /// How much of a chunk is sent at once. With a limit the pieces are about 100 ms worth of bandwidth,
+    /// so a big chunk isn't sent at full speed followed by a long pause.
+    pub fn piece_size(&self, chunk_size: usize) -> usize {
+        match &self.limiter {
+            Some(limiter) => chunk_size.min(((limiter.bytes_per_second / 10) as usize).max(MIN_PIECE_SIZE)),
+            None => chunk_size,
+        }.max(1)
+    }
+
+    /// Waits until `bytes` can be sent, returns right away without a limit.
+    pub async fn throttle(&self, bytes: usize) {
+        if let Some(limiter) = &self.limiter {
+            limiter.acquire(bytes as u64).await;
+        }
+    }
+
+    /// A request body that reads `reader` in pieces of `chunk_size` (smaller with a limit), throttled and counted on `progress_bar`.
+    pub fn body<R: Read + Send + 'static>(&self, reader: R, chunk_size: usize, progress_bar: Option<ProgressBar>) -> reqwest::Body {
+        let piece_size = self.piece_size(chunk_size);
+        let body_stream = stream::unfold((reader, self.clone()), move |(mut reader, transfer)| {
+            let progress_bar = progress_bar.clone();
+            async move {
+                let mut buffer = vec![0u8; piece_size];
+                match reader.read(&mut buffer) {
+                    Ok(0) => None,
+                    Ok(bytes_read) => {
+                        buffer.truncate(bytes_read);
+                        transfer.throttle(bytes_read).await;
+                        if let Some(progress_bar) = &progress_bar {
+                            progress_bar.inc(bytes_read as u64);
+                        }
+                        Some((Ok::<_, Error>(buffer), (reader, transfer)))
+                    }
+                    Err(e) => Some((Err(e), (reader, transfer))),
+                }
+            }
+        });
+        reqwest::Body::wrap_stream(body_stream)
+    }
+}
+
+/// Parses a size like "512K", "3M", "1.5G" or "1048576" (bytes). K, M and G are powers of 1024.
+pub fn parse_size(input: &str) -> Result<u64, Error> {
+    let invalid = || Error::new(ErrorKind::InvalidInput, format!("Invalid size: {} (examples: 512K, 3M, 1G)", input));
+    let input = input.trim();
+    let (amount, multiplier) = match input.char_indices().last() {
+        Some((index, 'k' | 'K')) => (&input[..index], 1024.0),
+        Some((index, 'm' | 'M')) => (&input[..index], 1024.0 * 1024.0),
+        Some((index, 'g' | 'G')) => (&input[..index], 1024.0 * 1024.0 * 1024.0),
+        Some(_) => (input, 1.0),
+        None => return Err(invalid()),
+    };
+
+    let amount: f64 = amount.parse().map_err(|_| invalid())?;
+    let size = (amount * multiplier).round();
+    if !size.is_finite() || size < 1.0 {
+        return Err(invalid());
+    }
+    Ok(size as u64)
+}
+
+#[cfg(test)]
+mod transfer_testing {
+    use super::*;
+
+    #[test]
+    fn test_parse_size() {
+        assert_eq!(parse_size("1048576").unwrap(), 1048576);
+        assert_eq!(parse_size("512K").unwrap(), 512 * 1024);
+        assert_eq!(parse_size("3m").unwrap(), 3 * 1024 * 1024);
+        assert_eq!(parse_size("1.5G").unwrap(), 1024 * 1024 * 1536);
+        assert!(parse_size("").is_err());
+        assert!(parse_size("0").is_err());
+        assert!(parse_size("-5M").is_err());
+        assert!(parse_size("fast").is_err());
+    }
+
+    #[test]
+    fn test_piece_size() {
+        let unlimited = TransferOptions::default();
+        assert_eq!(unlimited.piece_size(3 * 1024 * 1024), 3 * 1024 * 1024);
+
+        let limited = TransferOptions { limiter: Some(Arc::new(RateLimiter::new(1024 * 1024))), ..Default::default() };
+        assert_eq!(limited.piece_size(3 * 1024 * 1024), 104857);
+        assert_eq!(limited.piece_size(4096), 4096);
+
+        let slow = TransferOptions { limiter: Some(Arc::new(RateLimiter::new(1024))), ..Default::default() };
+        assert_eq!(slow.piece_size(3 * 1024 * 1024), MIN_PIECE_SIZE);
+    }
+
+    #[tokio::test]
+    async fn test_rate_limiter_is_shared() {
+        let limiter = Arc::new(RateLimiter::new(1_000_000));
+        let started = Instant::now();
+
+        // Two uploads of 150 KB each through the same 1 MB/s limit take about 0.3 seconds together
+        let upload = |limiter: Arc<RateLimiter>| async move {
+            for _ in 0..3 {
+                limiter.acquire(50_000).await;
+            }
+        };
+        futures_util::future::join(upload(limiter.clone()), upload(limiter.clone())).await;
+
+        let elapsed = started.elapsed();
+        assert!(elapsed >= Duration::from_millis(280), "{:?}", elapsed);
+        assert!(elapsed < Duration::from_secs(2), "{:?}", elapsed);
+    }
+}
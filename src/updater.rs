@@ -0,0 +1,241 @@
+use std::env;
+use std::fs::{self, File};
+use std::io::{Error, ErrorKind};
+use std::path::{Path, PathBuf};
+
+use colored::Colorize;
+use semver::Version;
+
+static REPO_OWNER: &str = "andrew121410";
+static REPO_NAME: &str = "limonium";
+
+// Exit codes used by `limonium self-update --check`
+pub const CHECK_UP_TO_DATE: i32 = 0;
+pub const CHECK_UPDATE_AVAILABLE: i32 = 100;
+pub const CHECK_FAILED: i32 = 102;
+
+#[derive(PartialEq, Clone, Copy)]
+pub enum UpdateChannel {
+    Stable,
+    Prerelease,
+}
+
+impl UpdateChannel {
+    pub fn from_name(name: &str) -> Option<UpdateChannel> {
+        match name.to_lowercase().as_str() {
+            "stable" => Some(UpdateChannel::Stable),
+            "prerelease" | "pre" | "beta" => Some(UpdateChannel::Prerelease),
+            _ => None,
+        }
+    }
+}
+
+/// What `self_update` did, or found when only checking.
+#[derive(Debug, PartialEq)]
+pub enum UpdateStatus {
+    UpToDate,
+    /// Only checked, this version would be installed
+    Available(String),
+    Updated(String),
+}
+
+impl UpdateStatus {
+    /// The exit code of `limonium self-update --check`.
+    pub fn check_exit_code(&self) -> i32 {
+        match self {
+            UpdateStatus::Available(_) => CHECK_UPDATE_AVAILABLE,
+            _ => CHECK_UP_TO_DATE,
+        }
+    }
+}
+
+pub struct SelfUpdateOptions {
+    pub to_version: Option<String>,
+    pub channel: UpdateChannel,
+    pub check_only: bool,
+}
+
+impl Default for SelfUpdateOptions {
+    fn default() -> Self {
+        SelfUpdateOptions {
+            to_version: None,
+            channel: UpdateChannel::Stable,
+            check_only: false,
+        }
+    }
+}
+
+/// The release asset name for the architecture we are running on.
+fn release_target() -> Result<&'static str, Error> {
+    match env::consts::ARCH {
+        "x86_64" => Ok("limonium-x86_64-unknown-linux-gnu.zip"),
+        "aarch64" => Ok("limonium-aarch64-unknown-linux-gnu.zip"),
+        arch => Err(Error::other(format!("There are no Limonium releases for the {} architecture", arch))),
+    }
+}
+
+/// Where the previous binary is kept after an update (next to the current binary).
+pub fn old_binary_path() -> Result<PathBuf, Error> {
+    let current_exe = env::current_exe()?;
+    let directory = current_exe.parent()
+        .ok_or_else(|| Error::new(ErrorKind::NotFound, "Could not find the directory of the current binary"))?;
+    Ok(directory.join("limonium.old"))
+}
+
+/// Picks the newest version from `versions` that is newer than `current`, respecting the channel.
+pub(crate) fn pick_newest_version(versions: &[String], current: &str, channel: UpdateChannel) -> Option<String> {
+    let current = Version::parse(current.trim_start_matches('v')).ok()?;
+
+    versions.iter()
+        .filter_map(|v| Version::parse(v.trim_start_matches('v')).ok().map(|parsed| (parsed, v)))
+        .filter(|(parsed, _)| channel == UpdateChannel::Prerelease || parsed.pre.is_empty())
+        .filter(|(parsed, _)| *parsed > current)
+        .max_by(|(a, _), (b, _)| a.cmp(b))
+        .map(|(_, v)| v.trim_start_matches('v').to_string())
+}
+
+fn fetch_release_versions(target: &str) -> Result<Vec<String>, Error> {
+    let releases = self_update::backends::github::ReleaseList::configure()
+        .repo_owner(REPO_OWNER)
+        .repo_name(REPO_NAME)
+        .with_target(target)
+        .build()
+        .map_err(Error::other)?
+        .fetch()
+        .map_err(Error::other)?;
+
+    Ok(releases.into_iter().map(|release| release.version).collect())
+}
+
+/// Downloads the release of `version` and unpacks its binary into `directory`, returns the path of the binary.
+fn download_release(target: &str, version: &str, directory: &Path) -> Result<PathBuf, Error> {
+    let release = self_update::backends::github::Update::configure()
+        .repo_owner(REPO_OWNER)
+        .repo_name(REPO_NAME)
+        .target(target)
+        .bin_name("limonium")
+        .current_version(cargo_crate_version!())
+        .build()
+        .map_err(Error::other)?
+        .get_release_version(version)
+        .map_err(Error::other)?;
+    let asset = release.asset_for(target, None)
+        .ok_or_else(|| Error::new(ErrorKind::NotFound, format!("Limonium {} has no release for {}", version, target)))?;
+
+    let archive_path = directory.join(&asset.name);
+    let mut download = self_update::Download::from_url(&asset.download_url);
+    let mut headers = reqwest::header::HeaderMap::new();
+    headers.insert(reqwest::header::ACCEPT, reqwest::header::HeaderValue::from_static("application/octet-stream"));
+    download.set_headers(headers);
+    download.download_to(File::create(&archive_path)?).map_err(Error::other)?;
+
+    self_update::Extract::from_source(&archive_path).extract_file(directory, "limonium").map_err(Error::other)?;
+    Ok(directory.join("limonium"))
+}
+
+/// Updates Limonium, or with `check_only` only looks for an update.
+pub fn self_update(options: &SelfUpdateOptions) -> Result<UpdateStatus, Error> {
+    let current_version = cargo_crate_version!();
+    println!("Current Version: {}", current_version);
+
+    let target = release_target()?;
+    println!("Target: {}", target);
+
+    // Figure out which version we would install
+    let wanted_version = match &options.to_version {
+        Some(version) => Some(version.trim_start_matches('v').to_string()),
+        None => {
+            let versions = fetch_release_versions(target)?;
+            pick_newest_version(&versions, current_version, options.channel)
+        }
+    };
+
+    let wanted_version = match wanted_version {
+        Some(version) if version != current_version => version,
+        _ => {
+            println!("{}", "Limonium is already up to date!".green());
+            return Ok(UpdateStatus::UpToDate);
+        }
+    };
+
+    if options.check_only {
+        println!("{} {}", "An update is available:".yellow(), wanted_version.green());
+        return Ok(UpdateStatus::Available(wanted_version));
+    }
+
+    // The new binary is downloaded next to the current one first, so a failed download leaves limonium.old alone
+    let current_exe = env::current_exe()?;
+    let old_binary = old_binary_path()?;
+    let download_directory = old_binary.with_extension("update");
+    if download_directory.exists() {
+        fs::remove_dir_all(&download_directory)?;
+    }
+    fs::create_dir_all(&download_directory)?;
+
+    let replaced = download_release(target, &wanted_version, &download_directory).and_then(|new_binary| {
+        // Keep the current binary around so `limonium self-rollback` can restore it
+        fs::copy(&current_exe, &old_binary)?;
+        println!("{} {}", "Kept the current binary at".yellow(), old_binary.display());
+        self_update::self_replace::self_replace(&new_binary)
+    });
+    let _ = fs::remove_dir_all(&download_directory);
+    replaced?;
+
+    println!("Updated Limonium from {} to {}", current_version, wanted_version);
+    Ok(UpdateStatus::Updated(wanted_version))
+}
+
+/// Swaps the current binary with `limonium.old`, so running it twice undoes the rollback.
+pub fn self_rollback() -> Result<(), Error> {
+    let current_exe = env::current_exe()?;
+    let old_binary = old_binary_path()?;
+
+    if !old_binary.exists() {
+        return Err(Error::new(ErrorKind::NotFound, format!("There is no previous binary at {} to roll back to", old_binary.display())));
+    }
+
+    // Copy the current binary aside first, it becomes the new limonium.old after the swap
+    let current_copy = old_binary.with_extension("rollback");
+    fs::copy(&current_exe, &current_copy)?;
+
+    self_update::self_replace::self_replace(&old_binary)?;
+    fs::rename(&current_copy, &old_binary)?;
+
+    println!("{}", "Rolled back Limonium to the previous binary!".green().bold());
+    println!("{} {}", "The binary you rolled back from was kept at".yellow(), old_binary.display());
+    Ok(())
+}
+
+#[cfg(test)]
+mod updater_testing {
+    use super::*;
+
+    fn versions() -> Vec<String> {
+        vec!["2.3.0".to_string(), "2.3.1".to_string(), "2.4.0-beta.1".to_string(), "2.3.2".to_string()]
+    }
+
+    #[test]
+    fn test_pick_newest_version_stable() {
+        assert_eq!(pick_newest_version(&versions(), "2.3.1", UpdateChannel::Stable), Some("2.3.2".to_string()));
+        assert_eq!(pick_newest_version(&versions(), "2.3.2", UpdateChannel::Stable), None);
+    }
+
+    #[test]
+    fn test_pick_newest_version_prerelease() {
+        assert_eq!(pick_newest_version(&versions(), "2.3.1", UpdateChannel::Prerelease), Some("2.4.0-beta.1".to_string()));
+        assert_eq!(pick_newest_version(&versions(), "2.4.0-beta.1", UpdateChannel::Prerelease), None);
+    }
+
+    #[test]
+    fn test_update_channel_from_name() {
+        assert!(UpdateChannel::from_name("stable") == Some(UpdateChannel::Stable));
+        assert!(UpdateChannel::from_name("Prerelease") == Some(UpdateChannel::Prerelease));
+        assert!(UpdateChannel::from_name("nightly").is_none());
+    }
+
+    #[test]
+    fn test_check_exit_code() {
+        assert_eq!(UpdateStatus::UpToDate.check_exit_code(), CHECK_UP_TO_DATE);
+        assert_eq!(UpdateStatus::Available("2.3.2".to_string()).check_exit_code(), CHECK_UPDATE_AVAILABLE);
+    }
+}
@@ -0,0 +1,388 @@
+use std::fs::File;
+use std::io::{Error, Read, Seek, SeekFrom};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+
+use async_trait::async_trait;
+use colored::Colorize;
+use futures_util::future::join_all;
+use indicatif::{MultiProgress, ProgressBar, ProgressStyle};
+
+use crate::backup::archive;
+use crate::backup::repository::Repository;
+use crate::backup::retention::{self, RetentionPolicy};
+use crate::backup::volume::VolumeSet;
+use crate::destination::Destination;
+use crate::transfer::TransferOptions;
+
+/// A remote folder backups are uploaded to (SFTP, WebDAV or S3).
+#[async_trait]
+pub trait UploadTarget: Send + Sync {
+    /// The name used in messages ("SFTP", "WebDAV", "S3").
+    fn protocol_name(&self) -> &'static str;
+
+    /// Uploads a local file into the backup folder, counting the uploaded bytes on `progress_bar`.
+    /// SFTP and WebDAV upload to "<file_name>.part" and resume a partial upload that is already there.
+    async fn upload(&self, path: &Path, file_name: &str, sha256_hash: &str, progress_bar: &ProgressBar) -> Result<(), Error>;
+
+    /// Checks an uploaded (not yet committed) file against the size and hash of the local file, as far as the server allows.
+    async fn verify(&self, file_name: &str, size: u64, sha256_hash: &str, progress_bar: &ProgressBar) -> Result<(), Error>;
+
+    /// Moves a verified upload into place under its real name. S3 objects only appear once they are complete, so there is nothing to do.
+    async fn commit(&self, _file_name: &str) -> Result<(), Error> {
+        Ok(())
+    }
+
+    /// The (file name, size) of every file in the backup folder, folders are skipped.
+    async fn list(&self) -> Result<Vec<(String, u64)>, Error>;
+
+    async fn delete(&self, file_name: &str) -> Result<(), Error>;
+
+    async fn download(&self, file_name: &str, destination: &Path) -> Result<(), Error>;
+
+    /// Mirrors a local backup repository into the backup folder, only new chunks are uploaded.
+    async fn sync_repository(&self, repository: &Repository) -> Result<(), Error>;
+}
+
+/// How many times an upload is tried before a destination is reported as failed, later attempts resume the partial upload.
+const UPLOAD_ATTEMPTS: u32 = 3;
+const RETRY_DELAY: Duration = Duration::from_secs(10);
+
+/// How much of the end of a partial upload is compared with the local file before resuming it.
+const RESUME_CHECK_SIZE: u64 = 64 * 1024;
+
+/// The name a file is uploaded under until it is verified, so an interrupted upload never looks like a finished backup.
+pub(crate) fn partial_name(file_name: &str) -> String {
+    format!("{}.part", file_name)
+}
+
+/// The (offset, length) of the end of a partial upload of `partial_size` bytes that is compared before resuming.
+pub(crate) fn resume_check_range(partial_size: u64) -> (u64, u64) {
+    let length = partial_size.min(RESUME_CHECK_SIZE);
+    (partial_size - length, length)
+}
+
+/// Where to resume the upload of `local_path` when a partial upload of `partial_size` bytes is on the server, and `remote_end`
+/// is its end (from `resume_check_range`). None if it has to start over, because the partial file is too big, empty,
+/// or left over from another file.
+pub(crate) fn resume_offset(local_path: &Path, partial_size: u64, remote_end: &[u8]) -> Result<Option<u64>, Error> {
+    let (start, length) = resume_check_range(partial_size);
+    if partial_size == 0 || partial_size > std::fs::metadata(local_path)?.len() || remote_end.len() as u64 != length {
+        return Ok(None);
+    }
+
+    let mut local_end = vec![0; length as usize];
+    let mut local_file = File::open(local_path)?;
+    local_file.seek(SeekFrom::Start(start))?;
+    local_file.read_exact(&mut local_end)?;
+    Ok((local_end == remote_end).then_some(partial_size))
+}
+
+/// The names of the files in the backup folder of a target.
+pub async fn list_file_names(target: &dyn UploadTarget) -> Result<Vec<String>, Error> {
+    Ok(target.list().await?.into_iter().map(|(name, _)| name).collect())
+}
+
+/// Applies a retention policy to the backups of `backup_name` on a target.
+pub async fn apply_retention(target: &dyn UploadTarget, backup_name: &str, policy: &RetentionPolicy, dry_run: bool) -> Result<(), Error> {
+    let file_names = list_file_names(target).await?;
+    let decisions = retention::decide_for_files(policy, &file_names, backup_name);
+    retention::print_decisions(target.protocol_name(), &decisions, dry_run);
+
+    if dry_run {
+        return Ok(());
+    }
+    for decision in decisions.iter().filter(|decision| !decision.keep) {
+        match target.delete(&decision.file_name).await {
+            Ok(_) => println!("{}", format!("(retention) Deleted file {} on {}", decision.file_name, target.protocol_name()).yellow()),
+            Err(e) => eprintln!("Error deleting file: {}", e),
+        }
+    }
+    Ok(())
+}
+
+/// A file of a bundle upload: the bundle itself, or a volume or the hash file of a split bundle.
+pub struct UploadFile {
+    pub path: PathBuf,
+    pub file_name: String,
+    pub sha256_hash: String,
+}
+
+impl UploadFile {
+    pub fn new(path: &Path, sha256_hash: &str) -> UploadFile {
+        UploadFile {
+            path: path.to_path_buf(),
+            file_name: path.file_name().unwrap_or_default().to_string_lossy().to_string(),
+            sha256_hash: sha256_hash.to_string(),
+        }
+    }
+
+    /// The volumes and then the hash file of a split bundle in `directory`, in the order they are uploaded and committed.
+    pub fn for_volume_set(directory: &Path, set: &VolumeSet) -> Result<Vec<UploadFile>, Error> {
+        let mut files: Vec<UploadFile> = set.volumes.iter()
+            .map(|volume| UploadFile::new(&directory.join(&volume.file_name), &volume.sha256_hash))
+            .collect();
+        let hash_file = directory.join(set.hash_file_name());
+        files.push(UploadFile::new(&hash_file, &archive::sha256_of_file(&hash_file)?));
+        Ok(files)
+    }
+}
+
+/// A bundle to upload, and the retention policy to apply after it is uploaded.
+pub struct BundleUpload<'a> {
+    /// The bundle, or the volumes and hash file of a split bundle. Nothing is committed until every file is verified,
+    /// and the hash file comes last, so a split bundle only shows up once it is complete.
+    pub files: &'a [UploadFile],
+    pub backup_name: &'a str,
+    pub policy: &'a RetentionPolicy,
+}
+
+/// How the upload to one destination went.
+#[derive(Debug)]
+pub struct UploadReport {
+    pub destination: String,
+    pub protocol: &'static str,
+    pub error: Option<String>,
+    pub duration: Duration,
+}
+
+impl UploadReport {
+    fn new(destination: &Destination, result: Result<(), Error>, duration: Duration) -> UploadReport {
+        UploadReport {
+            destination: destination.label.clone(),
+            protocol: destination.protocol().display_name(),
+            error: result.err().map(|e| e.to_string()),
+            duration,
+        }
+    }
+
+    pub fn ok(&self) -> bool {
+        self.error.is_none()
+    }
+}
+
+fn transfer_progress_bar(progress: &MultiProgress, label: &str, size: u64) -> ProgressBar {
+    let progress_bar = progress.add(ProgressBar::new(size));
+    progress_bar.set_style(ProgressStyle::default_bar()
+        .template("{prefix:>10.bold} {msg:>10.cyan.bold} {bytes}/{total_bytes} ({bytes_per_sec}, {eta})").unwrap());
+    progress_bar.set_prefix(label.to_string());
+    progress_bar.set_message("Uploading");
+    progress_bar
+}
+
+/// Uploads, verifies and commits a bundle on one target, then applies the retention policy (a failing policy is only a warning).
+async fn upload_bundle(target: &dyn UploadTarget, upload: &BundleUpload<'_>, progress_bar: &ProgressBar) -> Result<(), Error> {
+    let count = upload.files.len();
+    for (index, file) in upload.files.iter().enumerate() {
+        let step = |action: &str| if count > 1 { format!("{} {}/{}", action, index + 1, count) } else { action.to_string() };
+        let size = std::fs::metadata(&file.path)?.len();
+        progress_bar.set_length(size);
+        progress_bar.set_position(0);
+        progress_bar.set_message(step("Uploading"));
+        target.upload(&file.path, &file.file_name, &file.sha256_hash, progress_bar).await?;
+
+        progress_bar.set_position(0);
+        progress_bar.set_message(step("Verifying"));
+        target.verify(&file.file_name, size, &file.sha256_hash, progress_bar).await?;
+    }
+    for file in upload.files {
+        target.commit(&file.file_name).await?;
+    }
+    progress_bar.finish_and_clear();
+
+    if !upload.policy.is_empty() {
+        apply_retention(target, upload.backup_name, upload.policy, false).await
+            .unwrap_or_else(|e| println!("{} {}", format!("Failed to apply the retention policy on {}:", target.protocol_name()).red(), e));
+    }
+    Ok(())
+}
+
+/// Uploads a bundle to every destination at the same time. A failing destination doesn't stop the others.
+/// A dropped upload is tried again with a new connection, resuming where it stopped.
+/// The --bwlimit of `transfer` is the combined limit of all destinations.
+pub async fn upload_to_all(destinations: &[Destination], upload: &BundleUpload<'_>, transfer: &TransferOptions) -> Vec<UploadReport> {
+    let progress = MultiProgress::new();
+    let size = upload.files.first().and_then(|file| std::fs::metadata(&file.path).ok()).map(|metadata| metadata.len()).unwrap_or(0);
+
+    join_all(destinations.iter().map(|destination| {
+        let progress_bar = transfer_progress_bar(&progress, &destination.label, size);
+        let progress = progress.clone();
+        async move {
+            let started = Instant::now();
+            let mut attempt = 1;
+            let result = loop {
+                let result = match destination.connect(transfer).await {
+                    Ok(target) => upload_bundle(target.as_ref(), upload, &progress_bar).await,
+                    Err(e) => Err(e),
+                };
+                match result {
+                    Err(e) if attempt < UPLOAD_ATTEMPTS => {
+                        let _ = progress.println(format!("{} {}", format!("Upload to {} failed, trying again ({} of {}):", destination.label, attempt + 1, UPLOAD_ATTEMPTS).yellow(), e));
+                        tokio::time::sleep(RETRY_DELAY * attempt).await;
+                        attempt += 1;
+                    }
+                    result => break result,
+                }
+            };
+            progress_bar.finish_and_clear();
+            UploadReport::new(destination, result, started.elapsed())
+        }
+    })).await
+}
+
+/// Syncs a repository to every destination at the same time. A failing destination doesn't stop the others.
+pub async fn sync_repository_to_all(destinations: &[Destination], repository: &Repository, transfer: &TransferOptions) -> Vec<UploadReport> {
+    join_all(destinations.iter().map(|destination| async move {
+        let started = Instant::now();
+        let result = match destination.connect(transfer).await {
+            Ok(target) => target.sync_repository(repository).await,
+            Err(e) => Err(e),
+        };
+        UploadReport::new(destination, result, started.elapsed())
+    })).await
+}
+
+pub fn print_upload_reports(reports: &[UploadReport]) {
+    println!("{}", format!("{:<20} {:<8} {:<8} {:>8}", "Destination", "Protocol", "Status", "Time").bold());
+    for report in reports {
+        let status = if report.ok() { format!("{:<8}", "OK").green().bold() } else { format!("{:<8}", "FAILED").red().bold() };
+        println!(
+            "{:<20} {:<8} {} {:>8} {}",
+            report.destination,
+            report.protocol,
+            status,
+            format!("{}s", report.duration.as_secs()),
+            report.error.clone().unwrap_or_default().red()
+        );
+    }
+
+    let failed = reports.iter().filter(|report| !report.ok()).count();
+    if failed == 0 {
+        println!("{}", format!("Uploaded to all {} destinations!", reports.len()).green().bold());
+    } else {
+        println!("{}", format!("{} of {} uploads FAILED!", failed, reports.len()).red().bold());
+    }
+}
+
+#[cfg(test)]
+mod upload_target_testing {
+    use super::*;
+    use std::collections::BTreeMap;
+    use std::sync::Mutex;
+
+    /// A target that keeps its files in memory, and can be told to fail uploads.
+    struct MemoryTarget {
+        files: Mutex<BTreeMap<String, Vec<u8>>>,
+        fail_uploads: bool,
+    }
+
+    #[async_trait]
+    impl UploadTarget for MemoryTarget {
+        fn protocol_name(&self) -> &'static str {
+            "memory"
+        }
+
+        async fn upload(&self, path: &Path, file_name: &str, _sha256_hash: &str, _progress_bar: &ProgressBar) -> Result<(), Error> {
+            if self.fail_uploads {
+                return Err(Error::other("connection reset"));
+            }
+            self.files.lock().unwrap().insert(file_name.to_string(), std::fs::read(path)?);
+            Ok(())
+        }
+
+        async fn verify(&self, file_name: &str, size: u64, _sha256_hash: &str, _progress_bar: &ProgressBar) -> Result<(), Error> {
+            match self.files.lock().unwrap().get(file_name) {
+                Some(data) if data.len() as u64 == size => Ok(()),
+                _ => Err(Error::other("size mismatch")),
+            }
+        }
+
+        async fn list(&self) -> Result<Vec<(String, u64)>, Error> {
+            Ok(self.files.lock().unwrap().iter().map(|(name, data)| (name.clone(), data.len() as u64)).collect())
+        }
+
+        async fn delete(&self, file_name: &str) -> Result<(), Error> {
+            self.files.lock().unwrap().remove(file_name);
+            Ok(())
+        }
+
+        async fn download(&self, _file_name: &str, _destination: &Path) -> Result<(), Error> {
+            Err(Error::other("not supported by the memory target"))
+        }
+
+        async fn sync_repository(&self, _repository: &Repository) -> Result<(), Error> {
+            Err(Error::other("not supported by the memory target"))
+        }
+    }
+
+    #[test]
+    fn test_resume_offset() {
+        let directory = tempdir::TempDir::new("upload-target").unwrap();
+        let path = directory.path().join("hub-01-03-2024-1-bundle.tar.gz");
+        let data = (0..200_000u32).map(|i| (i % 251) as u8).collect::<Vec<u8>>();
+        std::fs::write(&path, &data).unwrap();
+
+        // The end of a partial upload of the same file matches
+        assert_eq!(resume_check_range(150_000), (150_000 - RESUME_CHECK_SIZE, RESUME_CHECK_SIZE));
+        assert_eq!(resume_offset(&path, 150_000, &data[150_000 - RESUME_CHECK_SIZE as usize..150_000]).unwrap(), Some(150_000));
+        assert_eq!(resume_check_range(1000), (0, 1000));
+        assert_eq!(resume_offset(&path, 1000, &data[..1000]).unwrap(), Some(1000));
+
+        // Left over from another file, bigger than the file, or empty
+        assert_eq!(resume_offset(&path, 1000, &[0; 1000]).unwrap(), None);
+        assert_eq!(resume_offset(&path, 300_000, &data[..RESUME_CHECK_SIZE as usize]).unwrap(), None);
+        assert_eq!(resume_offset(&path, 0, &[]).unwrap(), None);
+    }
+
+    #[tokio::test]
+    async fn test_upload_bundle() {
+        let directory = tempdir::TempDir::new("upload-target").unwrap();
+        let path = directory.path().join("hub-01-03-2024-1-bundle.tar.gz");
+        std::fs::write(&path, "bundle").unwrap();
+
+        let old_files = ["hub-01-01-2024-1-bundle.tar.gz", "hub-01-02-2024-1-bundle.tar.gz", "lobby-01-01-2024-1-bundle.tar.gz"];
+        let target = MemoryTarget {
+            files: Mutex::new(old_files.iter().map(|name| (name.to_string(), Vec::new())).collect()),
+            fail_uploads: false,
+        };
+        let policy = RetentionPolicy { keep_last: 2, ..Default::default() };
+        let files = [UploadFile::new(&path, "")];
+        let upload = BundleUpload { files: &files, backup_name: "hub", policy: &policy };
+
+        upload_bundle(&target, &upload, &ProgressBar::hidden()).await.unwrap();
+        assert_eq!(list_file_names(&target).await.unwrap(), vec![
+            "hub-01-02-2024-1-bundle.tar.gz",
+            "hub-01-03-2024-1-bundle.tar.gz",
+            "lobby-01-01-2024-1-bundle.tar.gz",
+        ]);
+
+        // Old backups are only deleted after a successful upload
+        let failing = MemoryTarget {
+            files: Mutex::new(old_files.iter().map(|name| (name.to_string(), Vec::new())).collect()),
+            fail_uploads: true,
+        };
+        assert!(upload_bundle(&failing, &upload, &ProgressBar::hidden()).await.is_err());
+        assert_eq!(list_file_names(&failing).await.unwrap().len(), 3);
+    }
+
+    #[tokio::test]
+    async fn test_upload_volume_set() {
+        let directory = tempdir::TempDir::new("upload-target-volumes").unwrap();
+        let path = directory.path().join("hub-01-03-2024-1-bundle.tar.gz");
+        std::fs::write(&path, vec![7; 2500]).unwrap();
+        let set = crate::backup::volume::split_bundle(&path, &archive::sha256_of_file(&path).unwrap(), 1000).unwrap();
+
+        let old_set = ["hub-01-02-2024-1-bundle.tar.gz.001", "hub-01-02-2024-1-bundle.tar.gz.002", "hub-01-02-2024-1-bundle.tar.gz.sha256"];
+        let target = MemoryTarget {
+            files: Mutex::new(old_set.iter().map(|name| (name.to_string(), Vec::new())).collect()),
+            fail_uploads: false,
+        };
+        let policy = RetentionPolicy { keep_last: 1, ..Default::default() };
+        let files = UploadFile::for_volume_set(directory.path(), &set).unwrap();
+        upload_bundle(&target, &BundleUpload { files: &files, backup_name: "hub", policy: &policy }, &ProgressBar::hidden()).await.unwrap();
+
+        // The whole old set is deleted by retention, and the new one is complete
+        assert_eq!(list_file_names(&target).await.unwrap(), files.iter().map(|file| file.file_name.clone()).collect::<Vec<String>>());
+        assert_eq!(files.last().unwrap().file_name, set.hash_file_name());
+    }
+}
@@ -1,227 +1,356 @@
-use std::io::{Error, ErrorKind, Read};
-use std::path::PathBuf;
+use std::io::{Error, ErrorKind, Seek, SeekFrom, Write};
+use std::path::Path;
 
-use chrono::{NaiveDate, Utc};
+use async_trait::async_trait;
 use colored::Colorize;
-use futures_util::stream;
+use futures_util::StreamExt;
 use indicatif::{ProgressBar, ProgressStyle};
 use regex::Regex;
 use reqwest::Client;
 
-use crate::backup::extract_date_from_file_name;
+use crate::backup::repository::Repository;
+use crate::transfer::TransferOptions;
+use crate::upload_target::{self, UploadTarget};
 
 pub struct WebDavClient {
     client: Client,
     base_url: String,
     username: String,
     password: String,
+    transfer: TransferOptions,
 }
 
 impl WebDavClient {
-    pub fn new(base_url: String, username: String, password: String) -> Self {
+    pub fn new(base_url: String, username: String, password: String, transfer: TransferOptions) -> Self {
         let client = Client::new();
         // Remove trailing slash from base_url
         let base_url = base_url.trim_end_matches('/').to_string();
-        WebDavClient { client, base_url, username, password }
+        WebDavClient { client, base_url, username, password, transfer }
     }
 
-    pub async fn upload_file(&self, path: &PathBuf, file_name: &str) -> Result<(), Error> {
-        // Ensure remote directory exists (MKCOL)
-        self.create_directory().await.ok(); // Ignore error if already exists
-
-        let file = std::fs::File::open(path)
-            .map_err(|e| Error::new(ErrorKind::Other, format!("Failed to open file: {}", e)))?;
-        let file_size = file.metadata()
-            .map_err(|e| Error::new(ErrorKind::Other, format!("Failed to get file metadata: {}", e)))?.len();
-
-        // Progress bar
-        let progress_bar = ProgressBar::new(file_size);
-        progress_bar.set_style(ProgressStyle::default_bar()
-            .template("{msg:>12.cyan.bold} {bytes}/{total_bytes} ({bytes_per_sec}, {eta})").unwrap());
-        progress_bar.set_message("Uploading");
-
-        // Stream the file in chunks with progress tracking
-        let pb_clone = progress_bar.clone();
-        let body_stream = stream::unfold(file, move |mut file| {
-            let pb = pb_clone.clone();
-            async move {
-                let mut buf = vec![0u8; 3 * 1024 * 1024]; // 3 MB chunks
-                match file.read(&mut buf) {
-                    Ok(0) => None,
-                    Ok(n) => {
-                        buf.truncate(n);
-                        pb.inc(n as u64);
-                        Some((Ok::<_, std::io::Error>(buf), file))
-                    }
-                    Err(e) => Some((Err(e), file))
-                }
-            }
-        });
-
-        let url = format!("{}/{}", self.base_url, file_name);
-        let response = self.client.put(&url)
+    async fn create_directory(&self) -> Result<(), Error> {
+        let response = self.client.request(
+            reqwest::Method::from_bytes(b"MKCOL").unwrap(),
+            &self.base_url,
+        )
             .basic_auth(&self.username, Some(&self.password))
-            .body(reqwest::Body::wrap_stream(body_stream))
             .send()
             .await
-            .map_err(|e| Error::new(ErrorKind::Other, format!("Failed to upload file: {}", e)))?;
-
-        progress_bar.finish_and_clear();
+            .map_err(|e| Error::new(ErrorKind::Other, format!("Failed to create directory: {}", e)))?;
 
+        // 201 Created, 405 Method Not Allowed (already exists on some servers)
         let status = response.status();
-        if !status.is_success() && status.as_u16() != 201 && status.as_u16() != 204 {
-            return Err(Error::new(ErrorKind::Other, format!("WebDAV upload failed with status: {}", status)));
+        if !status.is_success() && status.as_u16() != 405 {
+            return Err(Error::new(ErrorKind::Other, format!("MKCOL failed with status: {}", status)));
         }
 
-        // Verify upload by checking file size with a HEAD request
-        let head_response = self.client.head(&url)
+        Ok(())
+    }
+
+    /// Lists the files in the WebDAV directory with their sizes (in bytes), folders are skipped.
+    pub(crate) async fn list_files_with_sizes(&self) -> Result<Vec<(String, u64)>, Error> {
+        let response = self.client.request(
+            reqwest::Method::from_bytes(b"PROPFIND").unwrap(),
+            &self.base_url,
+        )
             .basic_auth(&self.username, Some(&self.password))
+            .header("Depth", "1")
+            .header("Content-Type", "application/xml")
+            .body(r#"<?xml version="1.0" encoding="utf-8"?><D:propfind xmlns:D="DAV:"><D:prop><D:getcontentlength/><D:resourcetype/></D:prop></D:propfind>"#)
             .send()
             .await
-            .map_err(|e| Error::new(ErrorKind::Other, format!("Failed to verify upload: {}", e)))?;
+            .map_err(|e| Error::other(format!("PROPFIND failed: {}", e)))?;
 
-        if let Some(content_length) = head_response.content_length() {
-            if content_length != file_size {
-                return Err(Error::new(ErrorKind::Other,
-                    format!("Upload verification failed: local size ({}) != remote size ({})", file_size, content_length)));
-            }
-            println!("{}", "File size verified: local and remote sizes match".green());
-        } else {
-            println!("{}", "Warning: WebDAV server did not return Content-Length, skipping size verification".yellow());
+        let status = response.status();
+        if !status.is_success() && status.as_u16() != 207 {
+            return Err(Error::other(format!("PROPFIND failed with status: {}", status)));
         }
 
-        println!("{}", "Successfully uploaded backup archive to WebDAV server".green());
-        Ok(())
-    }
-
-    pub async fn delete_after_time(&self, backup_name: &str, input: &str) {
-        // Deletes backups after a certain amount of time on the WebDAV server
-        // Example input: 1m = 1 month, 1w = 1 week, 1d = 1 day
-
-        // Parse the input duration
-        let (amount, unit) = input.split_at(input.len() - 1);
-        let amount: i64 = amount.parse().unwrap_or(0);
+        let body = response.text().await
+            .map_err(|e| Error::other(format!("Failed to read PROPFIND response: {}", e)))?;
 
-        let current_date = Utc::now().naive_utc().date();
+        Ok(parse_propfind_sizes(&body))
+    }
 
-        let file_names = match self.list_files().await {
-            Ok(files) => files,
-            Err(e) => {
-                eprintln!("Failed to list files on WebDAV server: {}", e);
-                return;
-            }
-        };
+    /// A client for a folder inside this one, using the same connection and login.
+    fn sub_directory(&self, directory: &str) -> WebDavClient {
+        WebDavClient {
+            client: self.client.clone(),
+            base_url: format!("{}/{}", self.base_url, directory),
+            username: self.username.clone(),
+            password: self.password.clone(),
+            transfer: self.transfer.clone(),
+        }
+    }
 
-        for file_name in file_names {
-            let date = extract_date_from_file_name(&file_name);
+    async fn remove_file(&self, file_name: &str) -> Result<(), Error> {
+        let url = format!("{}/{}", self.base_url, file_name);
+        let response = self.client.delete(&url)
+            .basic_auth(&self.username, Some(&self.password))
+            .send()
+            .await
+            .map_err(|e| Error::other(format!("Failed to delete file: {}", e)))?;
 
-            if date.is_empty() || !file_name.starts_with(backup_name) {
-                continue;
-            }
+        let status = response.status();
+        if !status.is_success() && status.as_u16() != 204 {
+            return Err(Error::other(format!("DELETE failed with status: {}", status)));
+        }
+        Ok(())
+    }
 
-            let date_chrono = match NaiveDate::parse_from_str(&date, "%-m-%-d-%Y") {
-                Ok(d) => d,
-                Err(_) => continue,
-            };
+    async fn put_bytes(&self, file_name: &str, data: Vec<u8>) -> Result<(), Error> {
+        let url = format!("{}/{}", self.base_url, file_name);
+        let response = self.client.put(&url)
+            .basic_auth(&self.username, Some(&self.password))
+            .body(data)
+            .send()
+            .await
+            .map_err(|e| Error::other(format!("Failed to upload file: {}", e)))?;
 
-            let days_difference = (current_date - date_chrono).num_days();
+        let status = response.status();
+        if !status.is_success() {
+            return Err(Error::other(format!("WebDAV upload of {} failed with status: {}", file_name, status)));
+        }
+        Ok(())
+    }
 
-            let should_delete = match unit {
-                "m" => days_difference > amount * 30,
-                "w" => days_difference > amount * 7,
-                "d" => days_difference > amount,
-                _ => {
-                    eprintln!("Invalid time unit: {}", unit);
-                    false
-                }
-            };
+    /// The size of a file from a HEAD request, None if it doesn't exist or the server doesn't say.
+    async fn file_size(&self, file_name: &str) -> Option<u64> {
+        let response = self.client.head(format!("{}/{}", self.base_url, file_name))
+            .basic_auth(&self.username, Some(&self.password))
+            .send()
+            .await
+            .ok()?;
+        if !response.status().is_success() {
+            return None;
+        }
+        response.headers().get(reqwest::header::CONTENT_LENGTH)?.to_str().ok()?.parse().ok()
+    }
 
-            if should_delete {
-                self.delete_file(&file_name).await.unwrap_or_else(|e| {
-                    eprintln!("Error deleting file: {}", e);
-                });
-            }
+    /// Reads `length` bytes of a file starting at `offset`, None if the server doesn't support range requests.
+    async fn read_range(&self, file_name: &str, offset: u64, length: u64) -> Option<Vec<u8>> {
+        let response = self.client.get(format!("{}/{}", self.base_url, file_name))
+            .basic_auth(&self.username, Some(&self.password))
+            .header(reqwest::header::RANGE, format!("bytes={}-{}", offset, offset + length - 1))
+            .send()
+            .await
+            .ok()?;
+        if response.status().as_u16() != 206 {
+            return None;
         }
+        response.bytes().await.ok().map(|bytes| bytes.to_vec())
     }
 
-    async fn create_directory(&self) -> Result<(), Error> {
-        let response = self.client.request(
-            reqwest::Method::from_bytes(b"MKCOL").unwrap(),
-            &self.base_url,
-        )
+    /// PUTs a local file from `offset` on. With an offset the request has a Content-Range, which only some servers
+    /// (like Apache mod_dav) support, the caller has to check the size afterwards.
+    async fn put_file(&self, file_name: &str, path: &Path, offset: u64, progress_bar: &ProgressBar) -> Result<(), Error> {
+        let mut file = std::fs::File::open(path)
+            .map_err(|e| Error::new(ErrorKind::Other, format!("Failed to open file: {}", e)))?;
+        let size = file.metadata()?.len();
+        file.seek(SeekFrom::Start(offset))?;
+        progress_bar.set_position(offset);
+
+        // Stream the file in chunks with progress tracking, throttled with --bwlimit
+        const CHUNK_SIZE: usize = 3 * 1024 * 1024; // 3 MB
+        let body = self.transfer.body(file, self.transfer.chunk_size(CHUNK_SIZE), Some(progress_bar.clone()));
+
+        let url = format!("{}/{}", self.base_url, file_name);
+        let mut request = self.client.put(&url)
             .basic_auth(&self.username, Some(&self.password))
+            .header(reqwest::header::CONTENT_LENGTH, size - offset);
+        if offset > 0 {
+            request = request.header(reqwest::header::CONTENT_RANGE, format!("bytes {}-{}/{}", offset, size - 1, size));
+        }
+        let response = request.body(body)
             .send()
             .await
-            .map_err(|e| Error::new(ErrorKind::Other, format!("Failed to create directory: {}", e)))?;
+            .map_err(|e| Error::new(ErrorKind::Other, format!("Failed to upload file: {}", e)))?;
 
-        // 201 Created, 405 Method Not Allowed (already exists on some servers)
         let status = response.status();
-        if !status.is_success() && status.as_u16() != 405 {
-            return Err(Error::new(ErrorKind::Other, format!("MKCOL failed with status: {}", status)));
+        if !status.is_success() && status.as_u16() != 201 && status.as_u16() != 204 {
+            return Err(Error::new(ErrorKind::Other, format!("WebDAV upload failed with status: {}", status)));
         }
 
         Ok(())
     }
 
-    async fn list_files(&self) -> Result<Vec<String>, Error> {
+    async fn move_file(&self, from: &str, to: &str) -> Result<(), Error> {
         let response = self.client.request(
-            reqwest::Method::from_bytes(b"PROPFIND").unwrap(),
-            &self.base_url,
+            reqwest::Method::from_bytes(b"MOVE").unwrap(),
+            format!("{}/{}", self.base_url, from),
         )
             .basic_auth(&self.username, Some(&self.password))
-            .header("Depth", "1")
-            .header("Content-Type", "application/xml")
-            .body(r#"<?xml version="1.0" encoding="utf-8"?><D:propfind xmlns:D="DAV:"><D:prop><D:displayname/></D:prop></D:propfind>"#)
+            .header("Destination", format!("{}/{}", self.base_url, to))
+            .header("Overwrite", "T")
             .send()
             .await
-            .map_err(|e| Error::new(ErrorKind::Other, format!("PROPFIND failed: {}", e)))?;
+            .map_err(|e| Error::other(format!("Failed to move file: {}", e)))?;
 
         let status = response.status();
-        if !status.is_success() && status.as_u16() != 207 {
-            return Err(Error::new(ErrorKind::Other, format!("PROPFIND failed with status: {}", status)));
+        if !status.is_success() {
+            return Err(Error::other(format!("WebDAV MOVE of {} failed with status: {}", from, status)));
         }
+        Ok(())
+    }
 
-        let body = response.text().await
-            .map_err(|e| Error::new(ErrorKind::Other, format!("Failed to read PROPFIND response: {}", e)))?;
-
-        // Parse href elements from the WebDAV XML response
-        // Handles both <D:href> and <d:href> namespace prefixes, as well as <href> without prefix
-        let href_pattern = Regex::new(r"<(?:[Dd]:)?href>([^<]+)</(?:[Dd]:)?href>").unwrap();
-        let mut file_names = Vec::new();
-
-        for cap in href_pattern.captures_iter(&body) {
-            let href = &cap[1];
-            let decoded = urldecode(href);
-            // Get just the filename (last path segment)
-            let name = decoded.trim_end_matches('/').rsplit('/').next().unwrap_or("").to_string();
-            if !name.is_empty() {
-                file_names.push(name);
+}
+
+#[async_trait]
+impl UploadTarget for WebDavClient {
+    fn protocol_name(&self) -> &'static str {
+        "WebDAV"
+    }
+
+    async fn upload(&self, path: &Path, file_name: &str, _sha256_hash: &str, progress_bar: &ProgressBar) -> Result<(), Error> {
+        // Ensure remote directory exists (MKCOL)
+        self.create_directory().await.ok(); // Ignore error if already exists
+
+        // A partial upload from an earlier attempt is continued if its end matches the local file and the server supports it
+        let part_name = upload_target::partial_name(file_name);
+        let size = std::fs::metadata(path)?.len();
+        if let Some(partial_size) = self.file_size(&part_name).await.filter(|partial_size| *partial_size > 0) {
+            let (start, length) = upload_target::resume_check_range(partial_size);
+            let offset = match self.read_range(&part_name, start, length).await {
+                Some(remote_end) => upload_target::resume_offset(path, partial_size, &remote_end)?,
+                None => None,
+            };
+
+            if let Some(offset) = offset {
+                if offset == size || (self.put_file(&part_name, path, offset, progress_bar).await.is_ok() && self.file_size(&part_name).await == Some(size)) {
+                    return Ok(());
+                }
+                progress_bar.println(format!("{}", "The WebDAV server can't resume uploads, starting over".yellow()));
             }
         }
 
-        // The first entry is typically the directory itself, skip it
-        if !file_names.is_empty() {
-            file_names.remove(0);
+        self.put_file(&part_name, path, 0, progress_bar).await
+    }
+
+    /// WebDAV has no standard way to get a hash, so only the size of the partial upload is compared.
+    /// A broken one is deleted, so the next attempt starts over.
+    async fn verify(&self, file_name: &str, size: u64, _sha256_hash: &str, _progress_bar: &ProgressBar) -> Result<(), Error> {
+        let part_name = upload_target::partial_name(file_name);
+        let url = format!("{}/{}", self.base_url, part_name);
+        let head_response = self.client.head(&url)
+            .basic_auth(&self.username, Some(&self.password))
+            .send()
+            .await
+            .map_err(|e| Error::new(ErrorKind::Other, format!("Failed to verify upload: {}", e)))?;
+
+        // content_length() is the size of the (empty) HEAD body, not the file
+        let content_length = head_response.headers().get(reqwest::header::CONTENT_LENGTH)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.parse::<u64>().ok());
+        if let Some(content_length) = content_length {
+            if content_length != size {
+                self.remove_file(&part_name).await.ok();
+                return Err(Error::new(ErrorKind::Other,
+                    format!("Upload verification failed: local size ({}) != remote size ({})", size, content_length)));
+            }
+        } else {
+            println!("{}", "Warning: WebDAV server did not return Content-Length, skipping size verification".yellow());
         }
 
-        Ok(file_names)
+        Ok(())
+    }
+
+    async fn commit(&self, file_name: &str) -> Result<(), Error> {
+        self.move_file(&upload_target::partial_name(file_name), file_name).await
     }
 
-    async fn delete_file(&self, file_name: &str) -> Result<(), Error> {
+    async fn list(&self) -> Result<Vec<(String, u64)>, Error> {
+        self.list_files_with_sizes().await
+    }
+
+    async fn delete(&self, file_name: &str) -> Result<(), Error> {
+        self.remove_file(file_name).await
+    }
+
+    async fn download(&self, file_name: &str, destination: &Path) -> Result<(), Error> {
         let url = format!("{}/{}", self.base_url, file_name);
-        let response = self.client.delete(&url)
+        let response = self.client.get(&url)
             .basic_auth(&self.username, Some(&self.password))
             .send()
             .await
-            .map_err(|e| Error::new(ErrorKind::Other, format!("Failed to delete file: {}", e)))?;
+            .map_err(|e| Error::other(format!("Failed to download file: {}", e)))?;
 
         let status = response.status();
-        if !status.is_success() && status.as_u16() != 204 {
-            println!("{}", format!("Failed to delete {} on WebDAV server: {}", file_name, status).red());
-            return Err(Error::new(ErrorKind::Other, format!("DELETE failed with status: {}", status)));
+        if !status.is_success() {
+            return Err(Error::other(format!("WebDAV download failed with status: {}", status)));
+        }
+
+        let progress_bar = ProgressBar::new(response.content_length().unwrap_or(0));
+        progress_bar.set_style(ProgressStyle::default_bar()
+            .template("{msg:>12.cyan.bold} {bytes}/{total_bytes} ({bytes_per_sec}, {eta})").unwrap());
+        progress_bar.set_message("Downloading");
+
+        let mut local_file = std::fs::File::create(destination)?;
+        let mut stream = response.bytes_stream();
+        while let Some(chunk) = stream.next().await {
+            let chunk = chunk.map_err(|e| Error::other(format!("Failed to download file: {}", e)))?;
+            local_file.write_all(&chunk)?;
+            progress_bar.inc(chunk.len() as u64);
+        }
+
+        progress_bar.finish_and_clear();
+        println!("{}", format!("Downloaded {} from the WebDAV server", file_name).green());
+        Ok(())
+    }
+
+    /// Mirrors a local backup repository into this folder, in the order of `Repository::plan_sync`.
+    /// Files are uploaded under a temporary name and moved, so an interrupted upload never looks like a finished chunk.
+    async fn sync_repository(&self, repository: &Repository) -> Result<(), Error> {
+        let repository_name = repository.path().file_name().unwrap_or_default().to_string_lossy().to_string();
+        let remote_repository = self.sub_directory(&repository_name);
+        let remote_chunks = remote_repository.sub_directory("chunks");
+        let remote_snapshots = remote_repository.sub_directory("snapshots");
+
+        // Ignore errors, the folders probably already exist
+        for directory in [self, &remote_repository, &remote_chunks, &remote_snapshots] {
+            directory.create_directory().await.ok();
+        }
+
+        let names = |files: Vec<(String, u64)>| files.into_iter().map(|(name, _)| name).collect::<Vec<String>>();
+        let plan = repository.plan_sync(
+            &names(remote_chunks.list_files_with_sizes().await?),
+            &names(remote_snapshots.list_files_with_sizes().await?),
+        )?;
+
+        let uploads = plan.upload_chunks.iter().map(|name| (&remote_chunks, "chunks", name.as_str()))
+            .chain(plan.upload_snapshots.iter().map(|name| (&remote_snapshots, "snapshots", name.as_str())))
+            .collect::<Vec<_>>();
+
+        let progress_bar = ProgressBar::new(uploads.len() as u64);
+        progress_bar.set_style(ProgressStyle::default_bar()
+            .template("{msg:>12.cyan.bold} {pos}/{len} files ({per_sec}, {eta})").unwrap());
+        progress_bar.set_message("Uploading");
+
+        for (remote_directory, local_directory, name) in uploads {
+            let data = std::fs::read(repository.path().join(local_directory).join(name))?;
+            let temp_name = format!("{}.tmp", name);
+            self.transfer.throttle(data.len()).await;
+            remote_directory.put_bytes(&temp_name, data).await?;
+            remote_directory.move_file(&temp_name, name).await?;
+            progress_bar.inc(1);
+        }
+        progress_bar.finish_and_clear();
+
+        remote_repository.put_bytes("repository.json", std::fs::read(repository.path().join("repository.json"))?).await?;
+
+        for name in &plan.delete_snapshots {
+            remote_snapshots.remove_file(name).await?;
+        }
+        for name in &plan.delete_chunks {
+            remote_chunks.remove_file(name).await?;
         }
 
-        println!("{}", format!("(delete-after-time) Deleted file {} on the WebDAV server, it was too OLD", file_name).yellow());
+        println!(
+            "{}",
+            format!(
+                "Synced the repository to the WebDAV server ({} chunks and {} snapshots uploaded, {} snapshots and {} chunks deleted)",
+                plan.upload_chunks.len(), plan.upload_snapshots.len(), plan.delete_snapshots.len(), plan.delete_chunks.len()
+            ).green()
+        );
         Ok(())
     }
 }
@@ -243,3 +372,64 @@ fn urldecode(s: &str) -> String {
     result
 }
 
+/// Reads (file name, size) pairs out of a PROPFIND response, collections (folders) are skipped.
+/// Servers use different namespace prefixes (D:, d:, lp1:) so any prefix is accepted.
+fn parse_propfind_sizes(body: &str) -> Vec<(String, u64)> {
+    let response_pattern = Regex::new(r"(?s)<(?:\w+:)?response\b[^>]*>(.*?)</(?:\w+:)?response>").unwrap();
+    let href_pattern = Regex::new(r"<(?:\w+:)?href>([^<]+)</(?:\w+:)?href>").unwrap();
+    let length_pattern = Regex::new(r"<(?:\w+:)?getcontentlength[^>]*>\s*(\d+)\s*</").unwrap();
+    let collection_pattern = Regex::new(r"<(?:\w+:)?collection\s*/?>").unwrap();
+
+    let mut files = Vec::new();
+    for response in response_pattern.captures_iter(body) {
+        let response = &response[1];
+        if collection_pattern.is_match(response) {
+            continue;
+        }
+
+        let href = match href_pattern.captures(response) {
+            Some(href) => urldecode(&href[1]),
+            None => continue,
+        };
+        let name = href.trim_end_matches('/').rsplit('/').next().unwrap_or("").to_string();
+        if name.is_empty() {
+            continue;
+        }
+
+        let size = length_pattern.captures(response)
+            .and_then(|length| length[1].parse::<u64>().ok())
+            .unwrap_or(0);
+        files.push((name, size));
+    }
+
+    files
+}
+
+#[cfg(test)]
+mod webdav_testing {
+    use super::*;
+
+    #[test]
+    fn test_parse_propfind_sizes() {
+        let body = r#"<?xml version="1.0"?>
+<d:multistatus xmlns:d="DAV:">
+  <d:response>
+    <d:href>/remote.php/dav/files/user/backups/</d:href>
+    <d:propstat><d:prop><d:resourcetype><d:collection/></d:resourcetype></d:prop></d:propstat>
+  </d:response>
+  <d:response>
+    <d:href>/remote.php/dav/files/user/backups/hub%20world-11-15-2023-1-bundle.tar.zst</d:href>
+    <d:propstat><d:prop><d:getcontentlength>12345</d:getcontentlength><d:resourcetype/></d:prop></d:propstat>
+  </d:response>
+  <D:response xmlns:lp1="DAV:">
+    <D:href>/backups/hub-11-16-2023-1-bundle.zip</D:href>
+    <D:propstat><D:prop><lp1:getcontentlength>99</lp1:getcontentlength></D:prop></D:propstat>
+  </D:response>
+</d:multistatus>"#;
+
+        assert_eq!(parse_propfind_sizes(body), vec![
+            ("hub world-11-15-2023-1-bundle.tar.zst".to_string(), 12345),
+            ("hub-11-16-2023-1-bundle.zip".to_string(), 99),
+        ]);
+    }
+}
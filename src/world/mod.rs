@@ -0,0 +1,447 @@
+use std::collections::{HashMap, HashSet};
+use std::fs::{self, File};
+use std::io::{Error, ErrorKind};
+use std::path::{Component, Path, PathBuf};
+
+use colored::Colorize;
+
+use crate::backup::archive::ArchiveEntry;
+use crate::backup::list::human_size;
+
+pub(crate) mod nbt;
+pub(crate) mod region;
+
+use region::{ChunkSlot, RegionFile};
+
+pub const TICKS_PER_SECOND: i64 = 20;
+
+/// The folders of a dimension with region files, a chunk has the same index in the files of all three
+const DIMENSION_FOLDERS: [&str; 3] = ["region", "entities", "poi"];
+/// How deep dimensions are looked for, world/dimensions/<namespace>/<name>/region is the deepest vanilla goes
+const MAX_DIMENSION_DEPTH: usize = 5;
+
+/// Parses an inhabited time like 60s, 5m, 2h, 1d or 1200t (ticks) into ticks.
+pub fn parse_inhabited_time(input: &str) -> Result<i64, Error> {
+    let input = input.trim();
+    let invalid = || Error::new(ErrorKind::InvalidInput, format!("Invalid inhabited time '{}', use a number with t, s, m, h or d (example: 60s)", input));
+    let split = input.find(|character: char| !character.is_ascii_digit()).ok_or_else(invalid)?;
+    let (number, unit) = input.split_at(split);
+    let number: i64 = number.parse().map_err(|_| invalid())?;
+
+    let ticks_per_unit = match unit.trim().to_lowercase().as_str() {
+        "t" => 1,
+        "s" => TICKS_PER_SECOND,
+        "m" => 60 * TICKS_PER_SECOND,
+        "h" => 60 * 60 * TICKS_PER_SECOND,
+        "d" => 24 * 60 * 60 * TICKS_PER_SECOND,
+        _ => return Err(invalid()),
+    };
+    match number.checked_mul(ticks_per_unit) {
+        Some(ticks) if ticks > 0 => Ok(ticks),
+        _ => Err(invalid()),
+    }
+}
+
+/// A dimension of a world: the folder with its region/, entities/ and poi/ folders.
+#[derive(Debug, PartialEq)]
+pub struct Dimension {
+    pub name: String,
+    pub directory: PathBuf,
+}
+
+/// Finds the dimensions in a world folder. Vanilla keeps the nether and the end in DIM-1 and DIM1 of the world,
+/// Bukkit in their own world folders (world_nether/DIM-1), datapack dimensions are in dimensions/<namespace>/<name>.
+pub fn find_dimensions(world: &Path) -> Result<Vec<Dimension>, Error> {
+    if !world.is_dir() {
+        return Err(Error::new(ErrorKind::NotFound, format!("The world folder {} doesn't exist", world.display())));
+    }
+
+    let mut dimensions = Vec::new();
+    find_dimensions_in(world, world, 0, &mut dimensions)?;
+    dimensions.sort_by(|a, b| a.directory.cmp(&b.directory));
+    Ok(dimensions)
+}
+
+fn find_dimensions_in(world: &Path, directory: &Path, depth: usize, dimensions: &mut Vec<Dimension>) -> Result<(), Error> {
+    if directory.join("region").is_dir() {
+        dimensions.push(Dimension { name: dimension_name(world, directory), directory: directory.to_path_buf() });
+    }
+    if depth == MAX_DIMENSION_DEPTH {
+        return Ok(());
+    }
+
+    for entry in fs::read_dir(directory)? {
+        let entry = entry?;
+        let name = entry.file_name().to_string_lossy().to_string();
+        // Symlinks aren't followed, the same dimension would be counted (and pruned) twice
+        if entry.file_type()?.is_dir() && !DIMENSION_FOLDERS.contains(&name.as_str()) && !name.starts_with('.') {
+            find_dimensions_in(world, &entry.path(), depth + 1, dimensions)?;
+        }
+    }
+    Ok(())
+}
+
+fn dimension_name(world: &Path, directory: &Path) -> String {
+    let relative: Vec<String> = directory.strip_prefix(world).unwrap_or(directory).components()
+        .filter_map(|component| match component {
+            Component::Normal(name) => Some(name.to_string_lossy().to_string()),
+            _ => None,
+        })
+        .collect();
+
+    match relative.iter().map(|name| name.as_str()).collect::<Vec<&str>>().as_slice() {
+        [] => "overworld".to_string(),
+        ["DIM-1"] => "the_nether".to_string(),
+        ["DIM1"] => "the_end".to_string(),
+        ["dimensions", namespace, name @ ..] if !name.is_empty() => format!("{}:{}", namespace, name.join("/")),
+        other => other.join("/"),
+    }
+}
+
+/// The chunks of a region file with an InhabitedTime below `below_ticks`, and how many chunks couldn't be read.
+/// Chunks that can't be read, or that don't record an InhabitedTime, are never below it.
+fn chunks_below(region: &RegionFile, below_ticks: i64) -> (Vec<ChunkSlot>, usize) {
+    let mut below = Vec::new();
+    let mut unreadable = 0;
+    for slot in region.chunks() {
+        match region.inhabited_time(&slot) {
+            Ok(Some(inhabited_time)) if inhabited_time < below_ticks => below.push(slot),
+            Ok(_) => {}
+            Err(_) => unreadable += 1,
+        }
+    }
+    (below, unreadable)
+}
+
+/// The chunks to remove from a region file, by their index in it.
+#[derive(Debug)]
+pub struct RegionPlan {
+    pub file_name: String,
+    pub remove: Vec<usize>,
+}
+
+/// The sizes and chunks of a dimension, and what pruning it would remove.
+#[derive(Debug)]
+pub struct DimensionReport {
+    pub dimension: Dimension,
+    pub region_files: usize,
+    pub chunks: usize,
+    /// The bytes of the files in region/, entities/ and poi/
+    pub bytes: [u64; 3],
+    pub unreadable_chunks: usize,
+    pub prunable_chunks: usize,
+    /// The sectors of the prunable chunks in region/, their entities and points of interest aren't counted
+    pub prunable_bytes: u64,
+    pub plans: Vec<RegionPlan>,
+}
+
+impl DimensionReport {
+    pub fn total_bytes(&self) -> u64 {
+        self.bytes.iter().sum()
+    }
+}
+
+fn region_files(folder: &Path) -> Result<Vec<PathBuf>, Error> {
+    if !folder.is_dir() {
+        return Ok(Vec::new());
+    }
+
+    let mut files: Vec<PathBuf> = fs::read_dir(folder)?
+        .filter_map(|entry| entry.ok())
+        .map(|entry| entry.path())
+        .filter(|path| path.is_file() && path.file_name().and_then(|name| name.to_str()).and_then(region::parse_region_name).is_some())
+        .collect();
+    files.sort();
+    Ok(files)
+}
+
+/// Reads the region files of a dimension, with `below_ticks` the chunks with an InhabitedTime below it are planned for pruning.
+pub fn survey(dimension: Dimension, below_ticks: Option<i64>) -> Result<DimensionReport, Error> {
+    let mut report = DimensionReport {
+        dimension,
+        region_files: 0,
+        chunks: 0,
+        bytes: [0; 3],
+        unreadable_chunks: 0,
+        prunable_chunks: 0,
+        prunable_bytes: 0,
+        plans: Vec::new(),
+    };
+
+    for (index, folder) in DIMENSION_FOLDERS.iter().enumerate() {
+        for path in region_files(&report.dimension.directory.join(folder))? {
+            report.bytes[index] += fs::metadata(&path)?.len();
+        }
+    }
+
+    for path in region_files(&report.dimension.directory.join("region"))? {
+        report.region_files += 1;
+        let region = match RegionFile::open(&path) {
+            Ok(region) => region,
+            Err(e) => {
+                println!("{} {} {}", "Could not read".yellow(), path.display().to_string().bright_yellow(), format!("({}), it's left alone", e).yellow());
+                continue;
+            }
+        };
+        report.chunks += region.chunks().len();
+
+        if let Some(below_ticks) = below_ticks {
+            let (below, unreadable) = chunks_below(&region, below_ticks);
+            report.unreadable_chunks += unreadable;
+            if !below.is_empty() {
+                report.prunable_chunks += below.len();
+                report.prunable_bytes += below.iter().map(|slot| slot.size()).sum::<u64>();
+                report.plans.push(RegionPlan {
+                    file_name: path.file_name().unwrap().to_string_lossy().to_string(),
+                    remove: below.iter().map(|slot| slot.index).collect(),
+                });
+            }
+        }
+    }
+
+    Ok(report)
+}
+
+/// Rewrites `path` without the chunks in `remove`, through a temporary file so a failure leaves it as it was.
+/// A region file left without chunks is deleted, like the files of removed chunks that didn't fit in it.
+/// Returns the bytes freed.
+fn remove_chunks(path: &Path, remove: &HashSet<usize>) -> Result<u64, Error> {
+    let region = RegionFile::open(path)?;
+    let before = fs::metadata(path)?.len();
+    let external: Vec<PathBuf> = region.chunks().iter()
+        .filter(|slot| remove.contains(&slot.index) && region.is_external(slot))
+        .map(|slot| region.external_path(slot))
+        .collect();
+
+    let temporary_path = path.with_extension("mca.lmtmp");
+    let kept = match region.write_filtered(&temporary_path, |index| !remove.contains(&index)) {
+        Ok(kept) => kept,
+        Err(e) => {
+            let _ = fs::remove_file(&temporary_path);
+            return Err(e);
+        }
+    };
+
+    let mut freed = before;
+    if kept == 0 {
+        fs::remove_file(&temporary_path)?;
+        fs::remove_file(path)?;
+    } else {
+        freed = freed.saturating_sub(fs::metadata(&temporary_path)?.len());
+        fs::rename(&temporary_path, path)?;
+    }
+
+    for external_path in external {
+        if let Ok(metadata) = fs::metadata(&external_path) {
+            fs::remove_file(&external_path)?;
+            freed += metadata.len();
+        }
+    }
+    Ok(freed)
+}
+
+/// Removes the planned chunks from the region, entity and point of interest files of the dimension.
+/// Minecraft generates removed chunks again when a player comes near them. Returns the bytes freed.
+pub fn prune(report: &DimensionReport) -> Result<u64, Error> {
+    let mut freed = 0;
+    for plan in &report.plans {
+        let remove: HashSet<usize> = plan.remove.iter().copied().collect();
+        for folder in DIMENSION_FOLDERS {
+            let path = report.dimension.directory.join(folder).join(&plan.file_name);
+            if path.is_file() {
+                freed += remove_chunks(&path, &remove)?;
+            }
+        }
+    }
+    Ok(freed)
+}
+
+/// Prints the sizes of the dimensions, with what pruning removes when there was a threshold.
+pub fn print_reports(reports: &[DimensionReport], below: Option<&str>) {
+    let mut header = format!("{:<20} {:>8} {:>9} {:>11} {:>11} {:>11} {:>11}", "Dimension", "Regions", "Chunks", "Blocks", "Entities", "POI", "Total");
+    if let Some(below) = below {
+        header += &format!("  {:>15} {:>11}", format!("Below {}", below), "Removable");
+    }
+    println!("{}", header.bold());
+
+    for report in reports {
+        let mut line = format!(
+            "{:<20} {:>8} {:>9} {:>11} {:>11} {:>11} {:>11}",
+            report.dimension.name,
+            report.region_files,
+            report.chunks,
+            human_size(report.bytes[0]),
+            human_size(report.bytes[1]),
+            human_size(report.bytes[2]),
+            human_size(report.total_bytes())
+        );
+        if below.is_some() {
+            line += &format!("  {:>15} {:>11}", report.prunable_chunks, human_size(report.prunable_bytes));
+        }
+        println!("{} {}", line.green(), report.dimension.directory.display().to_string().bright_cyan());
+    }
+
+    let unreadable: usize = reports.iter().map(|report| report.unreadable_chunks).sum();
+    if unreadable > 0 {
+        println!("{}", format!("{} chunks could not be read (compressed with LZ4, or damaged), they are always kept", unreadable).yellow());
+    }
+}
+
+/// Writes copies of the region files among `entries` without the chunks with an InhabitedTime below `below_ticks` into
+/// `work_directory`, and archives the copies instead. The entities and points of interest of those chunks are left out too.
+/// Region files that can't be read are archived as they are. Returns how many chunks were left out, and their bytes.
+pub(crate) fn leave_out_chunks(entries: &mut [ArchiveEntry], work_directory: &Path, below_ticks: i64) -> Result<(usize, u64), Error> {
+    // The chunks to leave out of a dimension's region file, by the path of that file
+    let mut plans: HashMap<PathBuf, HashSet<usize>> = HashMap::new();
+    let mut left_out = (0, 0);
+    let mut copies = 0;
+
+    for entry in entries.iter_mut() {
+        let file_name = match entry.disk_path.file_name().and_then(|name| name.to_str()) {
+            Some(file_name) if region::parse_region_name(file_name).is_some() => file_name.to_string(),
+            _ => continue,
+        };
+        let folder = entry.disk_path.parent().and_then(|parent| parent.file_name()).and_then(|name| name.to_str()).unwrap_or_default();
+        if !DIMENSION_FOLDERS.contains(&folder) || !entry.disk_path.is_file() {
+            continue;
+        }
+
+        let region_path = entry.disk_path.parent().unwrap().with_file_name("region").join(&file_name);
+        if !plans.contains_key(&region_path) {
+            let remove = match RegionFile::open(&region_path) {
+                Ok(region) => chunks_below(&region, below_ticks).0,
+                Err(_) => Vec::new(),
+            };
+            plans.insert(region_path.clone(), remove.iter().map(|slot| slot.index).collect());
+        }
+
+        let remove = &plans[&region_path];
+        if remove.is_empty() {
+            continue;
+        }
+
+        // The copy keeps the modification time, so the archive looks like it was made from the real file
+        // Copies of region files with the same name are kept apart by a folder each
+        let copy_directory = work_directory.join(copies.to_string());
+        fs::create_dir_all(&copy_directory)?;
+        let copy_path = copy_directory.join(&file_name);
+        copies += 1;
+        let written = RegionFile::open(&entry.disk_path).and_then(|region| region.write_filtered(&copy_path, |index| !remove.contains(&index)));
+        if let Err(e) = written {
+            println!("{} {} {}", "Could not leave chunks out of".yellow(), entry.disk_path.display().to_string().bright_yellow(), format!("({}), it's archived as it is", e).yellow());
+            let _ = fs::remove_file(&copy_path);
+            continue;
+        }
+        File::options().write(true).open(&copy_path)?.set_modified(fs::metadata(&entry.disk_path)?.modified()?)?;
+
+        if folder == "region" {
+            left_out.0 += remove.len();
+        }
+        left_out.1 += fs::metadata(&entry.disk_path)?.len().saturating_sub(fs::metadata(&copy_path)?.len());
+        entry.disk_path = copy_path;
+    }
+
+    Ok(left_out)
+}
+
+#[cfg(test)]
+mod world_testing {
+    use super::*;
+
+    use crate::world::region::region_testing::write_region;
+
+    #[test]
+    fn test_parse_inhabited_time() {
+        assert_eq!(parse_inhabited_time("60s").unwrap(), 1200);
+        assert_eq!(parse_inhabited_time("5m").unwrap(), 6000);
+        assert_eq!(parse_inhabited_time("1h").unwrap(), 72000);
+        assert_eq!(parse_inhabited_time("1d").unwrap(), 1728000);
+        assert_eq!(parse_inhabited_time("30t").unwrap(), 30);
+        assert!(parse_inhabited_time("0s").is_err());
+        assert!(parse_inhabited_time("60").is_err());
+        assert!(parse_inhabited_time("s").is_err());
+        assert!(parse_inhabited_time("5w").is_err());
+    }
+
+    #[test]
+    fn test_find_dimensions() {
+        let temp_dir = tempdir::TempDir::new("world-dimensions").unwrap();
+        let world = temp_dir.path().join("world");
+        for folder in ["region", "DIM-1/region", "DIM1/region", "dimensions/mypack/mining/region", "playerdata", "DIM1/region/nested/region"] {
+            fs::create_dir_all(world.join(folder)).unwrap();
+        }
+
+        let names: Vec<String> = find_dimensions(&world).unwrap().into_iter().map(|dimension| dimension.name).collect();
+        assert_eq!(names, vec!["overworld", "the_nether", "the_end", "mypack:mining"]);
+        assert_eq!(find_dimensions(&temp_dir.path().join("nope")).unwrap_err().kind(), ErrorKind::NotFound);
+
+        // A Bukkit nether world
+        let nether = temp_dir.path().join("world_nether");
+        fs::create_dir_all(nether.join("DIM-1/region")).unwrap();
+        assert_eq!(find_dimensions(&nether).unwrap(), vec![Dimension { name: "the_nether".to_string(), directory: nether.join("DIM-1") }]);
+    }
+
+    fn write_dimension(directory: &Path) {
+        for folder in DIMENSION_FOLDERS {
+            fs::create_dir_all(directory.join(folder)).unwrap();
+            write_region(&directory.join(folder).join("r.0.0.mca"), &[(0, 50000), (1, 0), (2, 1199)]);
+            write_region(&directory.join(folder).join("r.1.0.mca"), &[(0, 0)]);
+        }
+        fs::write(directory.join("region/c.0.0.mcc"), b"unrelated").unwrap();
+    }
+
+    #[test]
+    fn test_survey_and_prune() {
+        let temp_dir = tempdir::TempDir::new("world-prune").unwrap();
+        let world = temp_dir.path().join("world");
+        write_dimension(&world);
+
+        let dimension = find_dimensions(&world).unwrap().remove(0);
+        let report = survey(dimension, Some(1200)).unwrap();
+        assert_eq!((report.region_files, report.chunks, report.prunable_chunks, report.unreadable_chunks), (2, 4, 3, 0));
+        assert_eq!(report.plans.iter().map(|plan| (plan.file_name.as_str(), plan.remove.clone())).collect::<Vec<_>>(), vec![("r.0.0.mca", vec![1, 2]), ("r.1.0.mca", vec![0])]);
+        assert_eq!(report.bytes[0], report.bytes[1]);
+
+        let freed = prune(&report).unwrap();
+        assert!(freed > 0);
+        for folder in DIMENSION_FOLDERS {
+            let region = RegionFile::open(&world.join(folder).join("r.0.0.mca")).unwrap();
+            assert_eq!(region.chunks().iter().map(|slot| slot.index).collect::<Vec<usize>>(), vec![0]);
+            assert!(!world.join(folder).join("r.1.0.mca").exists());
+        }
+        assert!(world.join("region/c.0.0.mcc").exists());
+
+        let after = survey(find_dimensions(&world).unwrap().remove(0), Some(1200)).unwrap();
+        assert_eq!((after.chunks, after.prunable_chunks), (1, 0));
+        assert_eq!(report.total_bytes() - after.total_bytes(), freed);
+    }
+
+    #[test]
+    fn test_leave_out_chunks() {
+        let temp_dir = tempdir::TempDir::new("world-backup").unwrap();
+        let world = temp_dir.path().join("world");
+        write_dimension(&world);
+        fs::write(world.join("level.dat"), b"level").unwrap();
+        let original = fs::read(world.join("region/r.0.0.mca")).unwrap();
+
+        let excludes = crate::backup::archive::ExcludeMatcher::new(None).unwrap();
+        let mut entries = crate::backup::archive::collect_entries(&world.to_string_lossy(), &excludes).unwrap();
+        let (chunks, bytes) = leave_out_chunks(&mut entries, &temp_dir.path().join("work"), 1200).unwrap();
+        assert_eq!(chunks, 3);
+        assert!(bytes > 0);
+
+        for entry in &entries {
+            let in_work = entry.disk_path.starts_with(temp_dir.path().join("work"));
+            assert_eq!(in_work, entry.archive_name.extension().is_some_and(|extension| extension == "mca"), "{}", entry.disk_path.display());
+            if entry.archive_name.ends_with("entities/r.0.0.mca") {
+                let region = RegionFile::open(&world.join("entities/r.0.0.mca")).unwrap();
+                assert_eq!(region.chunks().len(), 3);
+                let copy = RegionFile::open(&entry.disk_path).unwrap_or_else(|_| panic!("{}", entry.disk_path.display()));
+                assert_eq!(fs::metadata(&entry.disk_path).unwrap().modified().unwrap(), fs::metadata(world.join("entities/r.0.0.mca")).unwrap().modified().unwrap());
+                assert_eq!(copy.chunks().len(), 1);
+            }
+        }
+        // The world itself isn't touched
+        assert_eq!(fs::read(world.join("region/r.0.0.mca")).unwrap(), original);
+    }
+}
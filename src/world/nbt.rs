@@ -0,0 +1,169 @@
+use std::io::{Error, ErrorKind};
+
+// Only what's needed to find a value in chunk data, everything else is skipped without being decoded
+const TAG_END: u8 = 0;
+const TAG_LONG: u8 = 4;
+const TAG_COMPOUND: u8 = 10;
+
+/// Minecraft refuses NBT nested deeper than this, so do we
+const MAX_DEPTH: usize = 512;
+
+struct Reader<'a> {
+    data: &'a [u8],
+    position: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, length: usize) -> Result<&'a [u8], Error> {
+        let end = self.position.checked_add(length).filter(|end| *end <= self.data.len())
+            .ok_or_else(|| Error::new(ErrorKind::UnexpectedEof, "The NBT data ends too early"))?;
+        let bytes = &self.data[self.position..end];
+        self.position = end;
+        Ok(bytes)
+    }
+
+    fn byte(&mut self) -> Result<u8, Error> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn u16(&mut self) -> Result<u16, Error> {
+        Ok(u16::from_be_bytes(self.take(2)?.try_into().unwrap()))
+    }
+
+    fn length(&mut self) -> Result<usize, Error> {
+        let length = i32::from_be_bytes(self.take(4)?.try_into().unwrap());
+        usize::try_from(length).map_err(|_| Error::new(ErrorKind::InvalidData, "Negative NBT length"))
+    }
+
+    fn name(&mut self) -> Result<&'a [u8], Error> {
+        let length = self.u16()? as usize;
+        self.take(length)
+    }
+
+    /// Skips the payload of a tag of type `tag`.
+    fn skip(&mut self, tag: u8, depth: usize) -> Result<(), Error> {
+        if depth > MAX_DEPTH {
+            return Err(Error::new(ErrorKind::InvalidData, "The NBT data is nested too deep"));
+        }
+
+        match tag {
+            1 => self.take(1).map(|_| ()),
+            2 => self.take(2).map(|_| ()),
+            3 | 5 => self.take(4).map(|_| ()),
+            4 | 6 => self.take(8).map(|_| ()),
+            7 => {
+                let length = self.length()?;
+                self.take(length).map(|_| ())
+            }
+            8 => self.name().map(|_| ()),
+            9 => {
+                let element = self.byte()?;
+                let length = self.length()?;
+                for _ in 0..length {
+                    self.skip(element, depth + 1)?;
+                }
+                Ok(())
+            }
+            10 => loop {
+                let child = self.byte()?;
+                if child == TAG_END {
+                    return Ok(());
+                }
+                self.name()?;
+                self.skip(child, depth + 1)?;
+            },
+            11 => {
+                let length = self.length()?;
+                self.take(length.checked_mul(4).ok_or_else(|| Error::new(ErrorKind::InvalidData, "NBT array too long"))?).map(|_| ())
+            }
+            12 => {
+                let length = self.length()?;
+                self.take(length.checked_mul(8).ok_or_else(|| Error::new(ErrorKind::InvalidData, "NBT array too long"))?).map(|_| ())
+            }
+            _ => Err(Error::new(ErrorKind::InvalidData, format!("Unknown NBT tag type {}", tag))),
+        }
+    }
+
+    /// Looks for the long at `path` in the compound the reader is in.
+    fn find_long(&mut self, path: &[&str], depth: usize) -> Result<Option<i64>, Error> {
+        loop {
+            let tag = self.byte()?;
+            if tag == TAG_END {
+                return Ok(None);
+            }
+
+            let name = self.name()?;
+            if name == path[0].as_bytes() {
+                match (tag, path.len()) {
+                    (TAG_LONG, 1) => return Ok(Some(i64::from_be_bytes(self.take(8)?.try_into().unwrap()))),
+                    (TAG_COMPOUND, length) if length > 1 => return self.find_long(&path[1..], depth + 1),
+                    _ => return Ok(None),
+                }
+            }
+            self.skip(tag, depth)?;
+        }
+    }
+}
+
+/// Finds a long like ["Level", "InhabitedTime"] in uncompressed NBT data whose root is a compound.
+pub(crate) fn find_long(data: &[u8], path: &[&str]) -> Result<Option<i64>, Error> {
+    let mut reader = Reader { data, position: 0 };
+    if reader.byte()? != TAG_COMPOUND {
+        return Err(Error::new(ErrorKind::InvalidData, "The NBT data doesn't start with a compound"));
+    }
+    reader.name()?;
+    reader.find_long(path, 0)
+}
+
+#[cfg(test)]
+pub(crate) mod nbt_testing {
+    use super::*;
+
+    /// Writes a chunk the way Minecraft does: some tags before InhabitedTime, in `Level` before 1.18.
+    pub(crate) fn chunk_nbt(inhabited_time: i64, in_level: bool) -> Vec<u8> {
+        fn named(data: &mut Vec<u8>, tag: u8, name: &str) {
+            data.push(tag);
+            data.extend_from_slice(&(name.len() as u16).to_be_bytes());
+            data.extend_from_slice(name.as_bytes());
+        }
+
+        let mut data = Vec::new();
+        named(&mut data, TAG_COMPOUND, "");
+        if in_level {
+            named(&mut data, TAG_COMPOUND, "Level");
+        }
+        named(&mut data, 8, "Status");
+        data.extend_from_slice(&14u16.to_be_bytes());
+        data.extend_from_slice(b"minecraft:full");
+        named(&mut data, 9, "sections");
+        data.push(TAG_COMPOUND);
+        data.extend_from_slice(&2i32.to_be_bytes());
+        for _ in 0..2 {
+            named(&mut data, 12, "data");
+            data.extend_from_slice(&3i32.to_be_bytes());
+            data.extend_from_slice(&[1; 24]);
+            data.push(TAG_END);
+        }
+        named(&mut data, 3, "xPos");
+        data.extend_from_slice(&5i32.to_be_bytes());
+        named(&mut data, TAG_LONG, "InhabitedTime");
+        data.extend_from_slice(&inhabited_time.to_be_bytes());
+        if in_level {
+            data.push(TAG_END);
+        }
+        data.push(TAG_END);
+        data
+    }
+
+    #[test]
+    fn test_find_long() {
+        assert_eq!(find_long(&chunk_nbt(1200, false), &["InhabitedTime"]).unwrap(), Some(1200));
+        assert_eq!(find_long(&chunk_nbt(1200, false), &["Level", "InhabitedTime"]).unwrap(), None);
+        assert_eq!(find_long(&chunk_nbt(7, true), &["Level", "InhabitedTime"]).unwrap(), Some(7));
+        assert_eq!(find_long(&chunk_nbt(7, true), &["InhabitedTime"]).unwrap(), None);
+
+        let truncated = chunk_nbt(7, false);
+        assert!(find_long(&truncated[..truncated.len() - 6], &["InhabitedTime"]).is_err());
+        assert!(find_long(&[8, 0, 0], &["InhabitedTime"]).is_err());
+    }
+}
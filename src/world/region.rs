@@ -0,0 +1,240 @@
+use std::fs;
+use std::io::{Error, ErrorKind, Read};
+use std::path::{Path, PathBuf};
+
+use flate2::read::{GzDecoder, ZlibDecoder};
+
+use crate::world::nbt;
+
+pub(crate) const SECTOR_SIZE: usize = 4096;
+pub(crate) const CHUNKS_PER_REGION: usize = 1024;
+/// The chunk locations and then their timestamps, one sector each
+const HEADER_SIZE: usize = 2 * SECTOR_SIZE;
+/// Set in the compression type of a chunk that is too big for the region file and is in "c.<x>.<z>.mcc" instead
+const EXTERNAL_FLAG: u8 = 0x80;
+
+/// Where a chunk is in a region file.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) struct ChunkSlot {
+    /// 0-1023, x + z * 32 inside the region
+    pub index: usize,
+    sector: usize,
+    sectors: usize,
+    timestamp: u32,
+}
+
+impl ChunkSlot {
+    pub fn size(&self) -> u64 {
+        (self.sectors * SECTOR_SIZE) as u64
+    }
+}
+
+/// An Anvil region file ("r.<x>.<z>.mca") with 32x32 chunks, read into memory.
+/// The same format is used for the blocks (region/), entities (entities/) and points of interest (poi/).
+pub(crate) struct RegionFile {
+    path: PathBuf,
+    x: i32,
+    z: i32,
+    data: Vec<u8>,
+}
+
+/// The region coordinates of a file name like "r.-1.2.mca".
+pub(crate) fn parse_region_name(file_name: &str) -> Option<(i32, i32)> {
+    let mut parts = file_name.strip_prefix("r.")?.strip_suffix(".mca")?.split('.');
+    let x = parts.next()?.parse().ok()?;
+    let z = parts.next()?.parse().ok()?;
+    parts.next().is_none().then_some((x, z))
+}
+
+impl RegionFile {
+    pub fn open(path: &Path) -> Result<RegionFile, Error> {
+        let file_name = path.file_name().and_then(|name| name.to_str()).unwrap_or_default();
+        let (x, z) = parse_region_name(file_name)
+            .ok_or_else(|| Error::new(ErrorKind::InvalidInput, format!("{} is not a region file (r.<x>.<z>.mca)", path.display())))?;
+
+        // Minecraft leaves empty region files around, they have no chunks
+        let data = fs::read(path)?;
+        if !data.is_empty() && data.len() < HEADER_SIZE {
+            return Err(Error::new(ErrorKind::InvalidData, format!("The region file {} is too short", path.display())));
+        }
+        Ok(RegionFile { path: path.to_path_buf(), x, z, data })
+    }
+
+    /// The chunks that are in the region.
+    pub fn chunks(&self) -> Vec<ChunkSlot> {
+        if self.data.is_empty() {
+            return Vec::new();
+        }
+
+        (0..CHUNKS_PER_REGION)
+            .filter_map(|index| {
+                let location = u32::from_be_bytes(self.data[index * 4..index * 4 + 4].try_into().unwrap());
+                let timestamp = u32::from_be_bytes(self.data[SECTOR_SIZE + index * 4..SECTOR_SIZE + index * 4 + 4].try_into().unwrap());
+                (location != 0).then_some(ChunkSlot {
+                    index,
+                    sector: (location >> 8) as usize,
+                    sectors: (location & 0xFF) as usize,
+                    timestamp,
+                })
+            })
+            .collect()
+    }
+
+    /// The world coordinates of a chunk.
+    pub fn chunk_position(&self, slot: &ChunkSlot) -> (i32, i32) {
+        (self.x * 32 + (slot.index % 32) as i32, self.z * 32 + (slot.index / 32) as i32)
+    }
+
+    /// The compression type and the (compressed) data of a chunk inside the region file.
+    fn raw_chunk(&self, slot: &ChunkSlot) -> Result<(u8, &[u8]), Error> {
+        let corrupt = || Error::new(ErrorKind::InvalidData, format!("The chunk {} of {} points outside of the file", slot.index, self.path.display()));
+        let start = slot.sector * SECTOR_SIZE;
+        if slot.sector < HEADER_SIZE / SECTOR_SIZE || start + 5 > self.data.len() {
+            return Err(corrupt());
+        }
+
+        let length = u32::from_be_bytes(self.data[start..start + 4].try_into().unwrap()) as usize;
+        let end = (start + 4).checked_add(length).filter(|end| *end <= self.data.len() && length > 0).ok_or_else(corrupt)?;
+        Ok((self.data[start + 4], &self.data[start + 5..end]))
+    }
+
+    pub fn is_external(&self, slot: &ChunkSlot) -> bool {
+        self.raw_chunk(slot).is_ok_and(|(compression, _)| compression & EXTERNAL_FLAG != 0)
+    }
+
+    /// The file of a chunk that is too big for the region file.
+    pub fn external_path(&self, slot: &ChunkSlot) -> PathBuf {
+        let (x, z) = self.chunk_position(slot);
+        self.path.with_file_name(format!("c.{}.{}.mcc", x, z))
+    }
+
+    /// The uncompressed NBT data of a chunk.
+    pub fn chunk_nbt(&self, slot: &ChunkSlot) -> Result<Vec<u8>, Error> {
+        let (compression, data) = self.raw_chunk(slot)?;
+        if compression & EXTERNAL_FLAG != 0 {
+            return decompress(compression & !EXTERNAL_FLAG, &fs::read(self.external_path(slot))?);
+        }
+        decompress(compression, data)
+    }
+
+    /// How long players have been near the chunk, in ticks. None for chunks that don't record it.
+    pub fn inhabited_time(&self, slot: &ChunkSlot) -> Result<Option<i64>, Error> {
+        let nbt = self.chunk_nbt(slot)?;
+        match nbt::find_long(&nbt, &["InhabitedTime"])? {
+            Some(inhabited_time) => Ok(Some(inhabited_time)),
+            // Before 1.18 the chunk data was inside a "Level" compound
+            None => nbt::find_long(&nbt, &["Level", "InhabitedTime"]),
+        }
+    }
+
+    /// Writes the region to `output` with only the chunks `keep` is true for, without the gaps they leave.
+    /// Returns how many chunks were kept.
+    pub fn write_filtered(&self, output: &Path, keep: impl Fn(usize) -> bool) -> Result<usize, Error> {
+        let mut header = vec![0; HEADER_SIZE];
+        let mut body = Vec::new();
+        let mut kept = 0;
+
+        for slot in self.chunks().iter().filter(|slot| keep(slot.index)) {
+            // Checks the chunk is inside the file before copying its sectors
+            self.raw_chunk(slot)?;
+            let start = slot.sector * SECTOR_SIZE;
+            let end = (start + slot.sectors * SECTOR_SIZE).min(self.data.len());
+            let sector = (HEADER_SIZE + body.len()) / SECTOR_SIZE;
+
+            body.extend_from_slice(&self.data[start..end]);
+            body.resize(body.len().div_ceil(SECTOR_SIZE) * SECTOR_SIZE, 0);
+            let sectors = (end - start).div_ceil(SECTOR_SIZE);
+            header[slot.index * 4..slot.index * 4 + 4].copy_from_slice(&(((sector as u32) << 8) | sectors as u32).to_be_bytes());
+            header[SECTOR_SIZE + slot.index * 4..SECTOR_SIZE + slot.index * 4 + 4].copy_from_slice(&slot.timestamp.to_be_bytes());
+            kept += 1;
+        }
+
+        header.extend_from_slice(&body);
+        fs::write(output, header)?;
+        Ok(kept)
+    }
+}
+
+fn decompress(compression: u8, data: &[u8]) -> Result<Vec<u8>, Error> {
+    let mut nbt = Vec::new();
+    match compression {
+        1 => GzDecoder::new(data).read_to_end(&mut nbt)?,
+        2 => ZlibDecoder::new(data).read_to_end(&mut nbt)?,
+        3 => return Ok(data.to_vec()),
+        4 => return Err(Error::new(ErrorKind::Unsupported, "LZ4 compressed chunks aren't supported")),
+        _ => return Err(Error::new(ErrorKind::InvalidData, format!("Unknown chunk compression type {}", compression))),
+    };
+    Ok(nbt)
+}
+
+#[cfg(test)]
+pub(crate) mod region_testing {
+    use super::*;
+    use std::io::Write;
+
+    use flate2::write::ZlibEncoder;
+    use flate2::Compression;
+
+    use crate::world::nbt::nbt_testing::chunk_nbt;
+
+    /// Writes a region file with a zlib compressed chunk for every (index, inhabited time), chunk 0 gets a second sector.
+    pub(crate) fn write_region(path: &Path, chunks: &[(usize, i64)]) {
+        let mut header = vec![0; HEADER_SIZE];
+        let mut body: Vec<u8> = Vec::new();
+        for (index, inhabited_time) in chunks {
+            let mut encoder = ZlibEncoder::new(Vec::new(), Compression::default());
+            encoder.write_all(&chunk_nbt(*inhabited_time, false)).unwrap();
+            let compressed = encoder.finish().unwrap();
+
+            let sector = (HEADER_SIZE + body.len()) / SECTOR_SIZE;
+            body.extend_from_slice(&(compressed.len() as u32 + 1).to_be_bytes());
+            body.push(2);
+            body.extend_from_slice(&compressed);
+            let sectors = if *index == 0 { 2 } else { 1 };
+            body.resize((sector + sectors) * SECTOR_SIZE - HEADER_SIZE, 0);
+            header[index * 4..index * 4 + 4].copy_from_slice(&(((sector as u32) << 8) | sectors as u32).to_be_bytes());
+            header[SECTOR_SIZE + index * 4..SECTOR_SIZE + index * 4 + 4].copy_from_slice(&1_700_000_000u32.to_be_bytes());
+        }
+        header.extend_from_slice(&body);
+        fs::write(path, header).unwrap();
+    }
+
+    #[test]
+    fn test_parse_region_name() {
+        assert_eq!(parse_region_name("r.0.0.mca"), Some((0, 0)));
+        assert_eq!(parse_region_name("r.-1.12.mca"), Some((-1, 12)));
+        assert_eq!(parse_region_name("r.0.0.mcr"), None);
+        assert_eq!(parse_region_name("r.0.mca"), None);
+        assert_eq!(parse_region_name("c.0.0.mcc"), None);
+    }
+
+    #[test]
+    fn test_region_file() {
+        let temp_dir = tempdir::TempDir::new("world-region").unwrap();
+        let path = temp_dir.path().join("r.-1.2.mca");
+        write_region(&path, &[(0, 5000), (33, 0), (1023, 100)]);
+
+        let region = RegionFile::open(&path).unwrap();
+        let chunks = region.chunks();
+        assert_eq!(chunks.iter().map(|slot| slot.index).collect::<Vec<usize>>(), vec![0, 33, 1023]);
+        assert_eq!(region.chunk_position(&chunks[1]), (-31, 65));
+        assert_eq!(region.inhabited_time(&chunks[0]).unwrap(), Some(5000));
+        assert_eq!(region.inhabited_time(&chunks[1]).unwrap(), Some(0));
+        assert!(!region.is_external(&chunks[0]));
+
+        // Dropping a chunk moves the chunks after it forward
+        let filtered_path = temp_dir.path().join("filtered").join("r.-1.2.mca");
+        fs::create_dir_all(filtered_path.parent().unwrap()).unwrap();
+        assert_eq!(region.write_filtered(&filtered_path, |index| index != 33).unwrap(), 2);
+        let filtered = RegionFile::open(&filtered_path).unwrap();
+        let kept = filtered.chunks();
+        assert_eq!(kept.iter().map(|slot| slot.index).collect::<Vec<usize>>(), vec![0, 1023]);
+        assert_eq!(filtered.inhabited_time(&kept[1]).unwrap(), Some(100));
+        assert_eq!(fs::metadata(&filtered_path).unwrap().len(), (HEADER_SIZE + 3 * SECTOR_SIZE) as u64);
+
+        fs::write(&path, vec![0; 100]).unwrap();
+        assert!(RegionFile::open(&path).is_err());
+        fs::write(&path, b"").unwrap();
+        assert!(RegionFile::open(&path).unwrap().chunks().is_empty());
+    }
+}